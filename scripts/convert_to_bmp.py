@@ -14,6 +14,7 @@ Usage:
 Options:
     --dither      Use Floyd-Steinberg dithering (recommended for photos)
     --format FMT  Color format: 'bwry' (4-color, default) or 'bwr' (3-color)
+    --size N      Square panel size in pixels (default 200; 152 for GDEW0154Z17)
 """
 
 import sys
@@ -207,10 +208,25 @@ def parse_args():
                 sys.exit(1)
             break
 
-    return input_arg, output_path, use_dither, format_type
+    # Parse --size argument
+    size = WIDTH
+    for i, arg in enumerate(sys.argv):
+        if arg == '--size' and i + 1 < len(sys.argv):
+            try:
+                size = int(sys.argv[i + 1])
+            except ValueError:
+                size = 0
+            if size <= 0 or size % 8 != 0:
+                print(f"Error: Invalid size '{sys.argv[i + 1]}'. Use a multiple of 8.")
+                sys.exit(1)
+            break
+
+    return input_arg, output_path, use_dither, format_type, size
 
 def main():
-    input_arg, output_path, use_dither, format_type = parse_args()
+    global WIDTH, HEIGHT
+    input_arg, output_path, use_dither, format_type, size = parse_args()
+    WIDTH = HEIGHT = size
 
     if input_arg == 'test':
         create_test_pattern(output_path, format_type)
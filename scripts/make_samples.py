@@ -0,0 +1,95 @@
+#!/usr/bin/env python3
+"""
+Generate the sample images in assets/samples/ (standard library only).
+
+Usage:
+    python make_samples.py [output_dir]
+
+Copy the assets folder to the Flipper as /ext/apps_assets/eink_nfc_img/ and
+pick them with Browse: Samples in the app's main menu.
+"""
+
+import colorsys
+import struct
+import sys
+from pathlib import Path
+
+SIZE = 200
+
+BLACK = (0, 0, 0)
+WHITE = (255, 255, 255)
+YELLOW = (255, 255, 0)
+RED = (255, 0, 0)
+
+
+def write_indexed_bmp(path, width, height, palette, pixel):
+    """8-bit indexed BMP, bottom-up, pixel(x, y) giving a palette index."""
+    row_size = (width + 3) & ~3
+    palette_size = 256 * 4
+    offset = 14 + 40 + palette_size
+    file_size = offset + row_size * height
+    header = b'BM' + struct.pack('<IHHI', file_size, 0, 0, offset)
+    info = struct.pack('<IiiHHIIiiII', 40, width, height, 1, 8, 0,
+                       row_size * height, 2835, 2835, 256, 0)
+    colors = bytearray()
+    for i in range(256):
+        r, g, b = palette[i] if i < len(palette) else BLACK
+        colors += bytes((b, g, r, 0))
+    rows = bytearray()
+    for y in reversed(range(height)):
+        row = bytes(pixel(x, y) for x in range(width))
+        rows += row + bytes(row_size - width)
+    path.write_bytes(header + info + colors + rows)
+
+
+def stripes(path, palette):
+    """Horizontal stripes, one per palette color."""
+    stripe = SIZE // len(palette)
+    write_indexed_bmp(path, SIZE, SIZE, palette,
+                      lambda x, y: min(y // stripe, len(palette) - 1))
+
+
+def gradient(path):
+    """Binary PGM: black to white from left to right."""
+    row = bytes(x * 255 // (SIZE - 1) for x in range(SIZE))
+    path.write_bytes(b'P5\n%d %d\n255\n' % (SIZE, SIZE) + row * SIZE)
+
+
+def checker(path, square=25):
+    """Binary PBM checkerboard."""
+    data = bytearray()
+    for y in range(SIZE):
+        bits = 0
+        for x in range(SIZE):
+            black = (x // square + y // square) % 2 == 0
+            bits = (bits << 1) | black
+            if x % 8 == 7:
+                data.append(bits)
+                bits = 0
+    path.write_bytes(b'P4\n%d %d\n' % (SIZE, SIZE) + bytes(data))
+
+
+def hues(path, size=100):
+    """Binary PPM: hue around, saturation down; half the panel size."""
+    data = bytearray()
+    for y in range(size):
+        for x in range(size):
+            r, g, b = colorsys.hsv_to_rgb(x / size, 1 - y / size, 1)
+            data += bytes((round(r * 255), round(g * 255), round(b * 255)))
+    path.write_bytes(b'P6\n%d %d\n255\n' % (size, size) + bytes(data))
+
+
+def main():
+    default = Path(__file__).parent.parent / 'assets' / 'samples'
+    out = Path(sys.argv[1]) if len(sys.argv) > 1 else default
+    out.mkdir(parents=True, exist_ok=True)
+    stripes(out / 'stripes_bwry.bmp', [BLACK, WHITE, YELLOW, RED])
+    stripes(out / 'stripes_bwr.bmp', [BLACK, WHITE, RED])
+    gradient(out / 'gradient.pgm')
+    checker(out / 'checker.pbm')
+    hues(out / 'hues_100x100.ppm')
+    print(f"Wrote samples to {out}")
+
+
+if __name__ == '__main__':
+    main()
@@ -0,0 +1,269 @@
+//! Tag alignment helper shown before a write
+//!
+//! While waiting for the tag, a worker thread keeps running a short detect
+//! on the tag's NFC protocol and records each outcome in a sliding window.
+//! The view draws the share of successful polls as a signal meter, which
+//! fills up as the tag is moved over the sweet spot of the Flipper's coil.
+//! Once every poll in the window succeeds the write starts on its own; OK
+//! starts it straight away and Back returns to the write menu.
+
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicU8, Ordering};
+use flipperzero_sys as sys;
+
+use crate::input;
+use crate::nfc_guard::NfcGuard;
+
+/// Polls in the sliding window
+const WINDOW: u8 = 10;
+
+/// Delay between detect attempts (ms)
+const POLL_INTERVAL_MS: u32 = 100;
+
+/// Bars in the signal meter
+const BARS: u8 = 5;
+
+/// Decision not taken yet
+const PENDING: u8 = 0;
+/// Start the write
+const WRITE: u8 = 1;
+/// Back to the write menu
+const CANCEL: u8 = 2;
+
+/// What ended the alignment screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlignOutcome {
+    /// Placement is good (or the user chose to write anyway)
+    Write,
+    /// User backed out
+    Cancel,
+}
+
+/// View model shared with the GUI thread
+struct AlignModel {
+    /// Last `WINDOW` polls, newest in bit 0 (1 = tag answered)
+    history: u16,
+    /// Polls recorded so far, capped at `WINDOW`
+    polls: u8,
+}
+
+impl AlignModel {
+    /// Successful polls in the window
+    fn hits(&self) -> u8 {
+        (self.history & ((1 << WINDOW) - 1)).count_ones() as u8
+    }
+}
+
+/// Alignment view plus the probe loop state
+pub struct Align {
+    view: *mut sys::View,
+    decision: AtomicU8,
+    protocol: sys::NfcProtocol,
+    view_dispatcher: *mut sys::ViewDispatcher,
+}
+
+impl Align {
+    pub fn new() -> Self {
+        Self {
+            view: core::ptr::null_mut(),
+            decision: AtomicU8::new(PENDING),
+            protocol: sys::NfcProtocolIso14443_4a,
+            view_dispatcher: core::ptr::null_mut(),
+        }
+    }
+
+    /// Allocate the view; `self` must stay at a fixed address while it exists
+    pub unsafe fn alloc(&mut self) -> bool {
+        unsafe {
+            self.view = sys::view_alloc();
+            if self.view.is_null() {
+                return false;
+            }
+            sys::view_allocate_model(
+                self.view,
+                sys::ViewModelTypeLocking,
+                core::mem::size_of::<AlignModel>(),
+            );
+            sys::view_set_context(self.view, self as *mut _ as *mut c_void);
+            sys::view_set_draw_callback(self.view, Some(draw_callback));
+            sys::view_set_input_callback(self.view, Some(input_callback));
+            true
+        }
+    }
+
+    pub unsafe fn free(&mut self) {
+        unsafe {
+            if !self.view.is_null() {
+                sys::view_free(self.view);
+                self.view = core::ptr::null_mut();
+            }
+        }
+    }
+
+    pub fn view(&self) -> *mut sys::View {
+        self.view
+    }
+
+    /// Clear the meter before probing for a tag that speaks `protocol`
+    ///
+    /// `view_dispatcher` receives the completion event from the worker.
+    pub unsafe fn reset(&mut self, protocol: sys::NfcProtocol, view_dispatcher: *mut sys::ViewDispatcher) {
+        self.protocol = protocol;
+        self.view_dispatcher = view_dispatcher;
+        self.decision.store(PENDING, Ordering::Relaxed);
+        unsafe { self.publish(0, 0) };
+    }
+
+    /// Ask a running probe loop to stop without writing
+    pub fn cancel(&self) {
+        let _ = self.decision.compare_exchange(PENDING, CANCEL, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Dispatcher to notify when the probe loop ends
+    pub fn view_dispatcher(&self) -> *mut sys::ViewDispatcher {
+        self.view_dispatcher
+    }
+
+    /// How the last probe loop ended
+    pub fn outcome(&self) -> AlignOutcome {
+        match self.decision.load(Ordering::Relaxed) {
+            WRITE => AlignOutcome::Write,
+            _ => AlignOutcome::Cancel,
+        }
+    }
+
+    /// Poll for the tag until the window is full of hits or the user decides
+    ///
+    /// Runs on the worker thread; the NFC instance is released before the
+    /// write opens its own.
+    pub unsafe fn run(&self) {
+        unsafe {
+            let Ok(nfc) = NfcGuard::acquire() else {
+                self.cancel();
+                return;
+            };
+            let poller = sys::nfc_poller_alloc(nfc.nfc(), self.protocol);
+            if poller.is_null() {
+                self.cancel();
+                return;
+            }
+
+            let mut history: u16 = 0;
+            let mut polls: u8 = 0;
+            while self.decision.load(Ordering::Relaxed) == PENDING {
+                let hit = sys::nfc_poller_detect(poller);
+                history = (history << 1) | hit as u16;
+                polls = (polls + 1).min(WINDOW);
+                self.publish(history, polls);
+
+                // A full window of answers means the tag is sitting well
+                let mask = (1 << WINDOW) - 1;
+                if polls == WINDOW && history & mask == mask {
+                    let _ = self.decision.compare_exchange(
+                        PENDING,
+                        WRITE,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                    break;
+                }
+                sys::furi_delay_ms(POLL_INTERVAL_MS);
+            }
+
+            sys::nfc_poller_free(poller);
+            drop(nfc);
+        }
+    }
+
+    /// Copy the poll window into the view model
+    unsafe fn publish(&self, history: u16, polls: u8) {
+        unsafe {
+            let model = sys::view_get_model(self.view) as *mut AlignModel;
+            (*model).history = history;
+            (*model).polls = polls;
+            sys::view_commit_model(self.view, true);
+        }
+    }
+}
+
+/// Draw the signal meter and the recent poll outcomes
+unsafe extern "C" fn draw_callback(canvas: *mut sys::Canvas, model: *mut c_void) {
+    unsafe {
+        let model = &*(model as *const AlignModel);
+        let hits = model.hits();
+
+        sys::canvas_set_font(canvas, sys::FontPrimary);
+        sys::canvas_draw_str(canvas, 2, 12, c"Align tag".as_ptr());
+
+        // Signal meter: rising bars, filled by the hit ratio
+        let lit = hits * BARS / WINDOW;
+        for bar in 0..BARS {
+            let x = 78 + bar as i32 * 9;
+            let h = 6 + bar as usize * 6;
+            let y = 34 - h as i32;
+            if bar < lit {
+                sys::canvas_draw_box(canvas, x, y, 7, h);
+            } else {
+                sys::canvas_draw_frame(canvas, x, y, 7, h);
+            }
+        }
+
+        sys::canvas_set_font(canvas, sys::FontSecondary);
+        let hint = if model.polls == 0 || hits == 0 {
+            c"Hold tag to back"
+        } else if hits < WINDOW / 2 {
+            c"Move slowly..."
+        } else {
+            c"Almost there"
+        };
+        sys::canvas_draw_str(canvas, 2, 26, hint.as_ptr());
+
+        // Recent polls, oldest on the left
+        for i in 0..model.polls {
+            let age = model.polls - 1 - i;
+            let x = 2 + i as i32 * 6;
+            if model.history & (1 << age) != 0 {
+                sys::canvas_draw_box(canvas, x, 38, 4, 4);
+            } else {
+                sys::canvas_draw_frame(canvas, x, 38, 4, 4);
+            }
+        }
+
+        let text = alloc::format!("{}/{} polls answered", hits, model.polls);
+        if let Ok(c_text) = alloc::ffi::CString::new(text) {
+            sys::canvas_draw_str(canvas, 2, 52, c_text.as_ptr());
+        }
+        let keys = alloc::format!(
+            "{}: write  {}: menu",
+            input::label(sys::InputKeyOk).to_str().unwrap_or(""),
+            input::label(sys::InputKeyBack).to_str().unwrap_or(""),
+        );
+        if let Ok(c_keys) = alloc::ffi::CString::new(keys) {
+            sys::canvas_draw_str(canvas, 2, 62, c_keys.as_ptr());
+        }
+    }
+}
+
+/// OK writes without waiting for a full window; Back cancels
+unsafe extern "C" fn input_callback(event: *mut sys::InputEvent, context: *mut c_void) -> bool {
+    unsafe {
+        let align = &*(context as *const Align);
+        let key = input::remap((*event).key);
+        let decision = if key == sys::InputKeyOk {
+            WRITE
+        } else if key == sys::InputKeyBack {
+            CANCEL
+        } else {
+            return false;
+        };
+        if (*event).type_ == sys::InputTypeShort {
+            let _ = align.decision.compare_exchange(
+                PENDING,
+                decision,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+        true
+    }
+}
@@ -0,0 +1,242 @@
+//! APDU scripts for working out new panels
+//!
+//! "Run APDU Script" in the main menu sends the commands of a `.apdu` file
+//! to the next tag held to the reader and shows what came back, so a new
+//! panel's sequence can be tried on the Flipper before it becomes a step
+//! list (see `engine`). Each line is one command as hex bytes, spaced as
+//! you like. `= SW` after the bytes is the status word the tag has to
+//! answer with (`X` matches any digit); the script stops at the first other
+//! answer, or at a command the tag doesn't answer. `delay MS` waits, and
+//! `#` starts a comment.
+//!
+//! ```text
+//! # INIT with the factory key, power the panel, read its status register
+//! 74 B1 00 00 08 00 11 22 33 44 55 66 77 = 9000
+//! 74 97 00 08 00 = 9000
+//! delay 50
+//! 74 97 01 08 00 = 9000
+//! delay 50
+//! 74 99 00 0D 01 2F = 9000
+//! 74 9B 00 0F 01
+//! ```
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt;
+use flipperzero_sys as sys;
+
+use crate::protocol_common::{self, hex_bytes, log_info, NfcResult};
+use crate::storage;
+use crate::transport::Transport;
+
+/// Largest script read, a few hundred commands
+const MAX_SCRIPT_SIZE: usize = 16 * 1024;
+
+/// Longest command: header, 255 data bytes and Le
+const MAX_APDU: usize = 261;
+
+/// Bytes of a command or response shown on screen; the log has more
+const SHOWN_BYTES: usize = 6;
+
+/// Status word a command has to be answered with, one hex digit at a time;
+/// None matches any digit
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StatusWord([Option<u8>; 4]);
+
+impl StatusWord {
+    fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.len() != 4 {
+            return None;
+        }
+        let mut digits = [None; 4];
+        for (digit, c) in digits.iter_mut().zip(text.chars()) {
+            *digit = match c {
+                'X' | 'x' => None,
+                c => Some(c.to_digit(16)? as u8),
+            };
+        }
+        Some(Self(digits))
+    }
+
+    fn matches(self, sw1: u8, sw2: u8) -> bool {
+        let got = [sw1 >> 4, sw1 & 0x0F, sw2 >> 4, sw2 & 0x0F];
+        self.0.iter().zip(got).all(|(want, got)| want.is_none_or(|want| want == got))
+    }
+}
+
+impl fmt::Display for StatusWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for digit in self.0 {
+            match digit {
+                Some(digit) => write!(f, "{:X}", digit)?,
+                None => f.write_str("X")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One line of a script that does something
+#[derive(Debug, Clone)]
+enum Line {
+    Command { apdu: Vec<u8>, expect: Option<StatusWord> },
+    Delay(u32),
+}
+
+/// Why a script can't be run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptError {
+    /// The file could not be read
+    Unreadable,
+    /// The file has no commands
+    Empty,
+    /// This line (counting from 1) is neither a command, a delay nor a
+    /// comment
+    Line(usize),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Unreadable => f.write_str("Unreadable script"),
+            ScriptError::Empty => f.write_str("No commands in script"),
+            ScriptError::Line(number) => write!(f, "Bad script line {}", number),
+        }
+    }
+}
+
+/// Hex bytes with any whitespace between digits; None if a digit is
+/// missing or not hex
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()?;
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    Some(digits.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect())
+}
+
+/// `HEX BYTES` or `HEX BYTES = SW`
+fn parse_command(line: &str) -> Option<Line> {
+    let (bytes, expect) = match line.split_once('=') {
+        Some((bytes, sw)) => (bytes, Some(StatusWord::parse(sw)?)),
+        None => (line, None),
+    };
+    let apdu = parse_hex(bytes).filter(|apdu| (1..=MAX_APDU).contains(&apdu.len()))?;
+    Some(Line::Command { apdu, expect })
+}
+
+/// Up to `SHOWN_BYTES` of `bytes` as hex, with a count of the rest
+fn shown(bytes: &[u8]) -> String {
+    let mut text = hex_bytes(&bytes[..bytes.len().min(SHOWN_BYTES)]);
+    if bytes.len() > SHOWN_BYTES {
+        text.push_str(&format!(" +{}", bytes.len() - SHOWN_BYTES));
+    }
+    text
+}
+
+/// A script read from the SD card
+#[derive(Debug, Clone)]
+pub struct ApduScript {
+    /// Lines that do something, with their line numbers
+    lines: Vec<(usize, Line)>,
+}
+
+impl ApduScript {
+    /// Read the script at `path`
+    pub unsafe fn load(path: &CStr) -> Result<Self, ScriptError> {
+        unsafe {
+            let contents = storage::read_file(path, MAX_SCRIPT_SIZE);
+            let contents = contents.ok_or(ScriptError::Unreadable)?;
+            Self::parse(&String::from_utf8_lossy(&contents))
+        }
+    }
+
+    /// Read a script from its text
+    ///
+    /// A line that isn't understood fails the whole script rather than
+    /// being skipped, since the commands after it would then run out of
+    /// order.
+    pub fn parse(text: &str) -> Result<Self, ScriptError> {
+        let mut lines = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = if let Some(ms) = line.strip_prefix("delay") {
+                ms.trim().parse().ok().map(Line::Delay)
+            } else {
+                parse_command(line)
+            };
+            lines.push((number, parsed.ok_or(ScriptError::Line(number))?));
+        }
+        if !lines.iter().any(|(_, line)| matches!(line, Line::Command { .. })) {
+            return Err(ScriptError::Empty);
+        }
+        Ok(Self { lines })
+    }
+
+    /// Commands in the script
+    pub fn commands(&self) -> usize {
+        self.lines.iter().filter(|(_, line)| matches!(line, Line::Command { .. })).count()
+    }
+
+    /// Activate the tag, send the script, release the tag
+    ///
+    /// Returns what was sent and answered, a few lines per command, ending
+    /// with why the script stopped.
+    pub fn run(&self, transport: &mut dyn Transport) -> NfcResult<String> {
+        unsafe {
+            transport.activate().map(|()| {
+                let transcript = self.send_all(transport);
+                transport.deactivate();
+                transcript
+            })
+        }
+    }
+
+    unsafe fn send_all(&self, transport: &mut dyn Transport) -> String {
+        unsafe {
+            let mut response = Vec::new();
+            let mut transcript = String::new();
+            for (number, line) in &self.lines {
+                let (apdu, expect) = match line {
+                    Line::Delay(ms) => {
+                        sys::furi_delay_ms(*ms);
+                        transcript.push_str(&format!("wait {} ms\n", ms));
+                        continue;
+                    }
+                    Line::Command { apdu, expect } => (apdu, expect),
+                };
+                transcript.push_str(&format!(">{}\n", shown(apdu)));
+                if !protocol_common::exchange_command(transport, &mut response, apdu) {
+                    transcript.push_str(&format!("Line {}: no answer\n", number));
+                    return transcript;
+                }
+                transcript.push_str(&format!("<{}\n", shown(&response)));
+
+                let sw = response.len().checked_sub(2).map(|at| (response[at], response[at + 1]));
+                if let Some(expect) = expect
+                    && !sw.is_some_and(|(sw1, sw2)| expect.matches(sw1, sw2))
+                {
+                    let got = hex_bytes(&response);
+                    log_info!("Script line {}: wanted {}, got {}", number, expect, got);
+                    transcript.push_str(&format!("Line {}: wanted {}\n", number, expect));
+                    return transcript;
+                }
+            }
+            transcript.push_str(&format!("Done, {} commands\n", self.commands()));
+            transcript
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Folder conversion
+//!
+//! "Convert Folder" decodes every image file in one folder for the chosen
+//! tag ahead of time and stores each result in the conversion cache, in the
+//! slot selecting that image later looks up. Labels prepared at a desk then
+//! load at once in the field instead of dithering first. Each image is
+//! converted with the current choices, or with its remembered options if it
+//! has a sidecar. An image of another size than the tag needs a remembered
+//! fit, as nobody is there to answer the fit menu; without one it is
+//! reported as a failure.
+
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use crate::image::{self, DecodeOptions, ImageError};
+use crate::resample::Fit;
+use crate::storage;
+
+/// Most images one conversion takes on; the rest of the folder is left
+pub const MAX_IMAGES: usize = 100;
+
+/// One image of the folder and how to convert it
+pub struct BatchItem {
+    pub path: CString,
+    /// Choices the image is converted with, fitted exactly
+    pub options: DecodeOptions,
+    /// Fit remembered for the image, used if it is not the tag's size
+    pub fit: Option<Fit>,
+    /// Conversion note for `options`, the start of the cache stamp
+    pub note: String,
+}
+
+/// Why an image of the folder is not in the cache
+#[derive(Debug, Clone, Copy)]
+pub enum BatchError {
+    /// It could not be converted
+    Image(ImageError),
+    /// It was converted, but the cache file could not be written
+    Store,
+}
+
+impl From<ImageError> for BatchError {
+    fn from(error: ImageError) -> Self {
+        BatchError::Image(error)
+    }
+}
+
+/// How a folder conversion went
+#[derive(Default)]
+pub struct BatchReport {
+    /// Images decoded and cached
+    pub converted: usize,
+    /// Images whose cached conversion was still current
+    pub current: usize,
+    /// File name of each image that failed, and why
+    pub failures: Vec<(String, BatchError)>,
+    /// Images not reached because Back was pressed or the card went away
+    pub skipped: usize,
+}
+
+impl BatchReport {
+    /// Counts, then one line per failure
+    pub fn summary(&self) -> String {
+        let mut text = alloc::format!("Converted: {}\n", self.converted);
+        if self.current > 0 {
+            text.push_str(&alloc::format!("Already cached: {}\n", self.current));
+        }
+        if self.skipped > 0 {
+            text.push_str(&alloc::format!("Stopped, {} left\n", self.skipped));
+        }
+        if !self.failures.is_empty() {
+            text.push_str(&alloc::format!("Failed: {}\n", self.failures.len()));
+        }
+        for (name, error) in &self.failures {
+            text.push_str(&alloc::format!("{}: {}\n", name, reason(*error)));
+        }
+        text
+    }
+}
+
+/// Short text for why an image failed
+fn reason(error: BatchError) -> String {
+    let BatchError::Image(error) = error else {
+        return String::from("can't write cache");
+    };
+    match error {
+        ImageError::SizeMismatch { width, height } => {
+            alloc::format!("{}x{}, no saved fit", width, height)
+        }
+        error => alloc::format!("{}", error),
+    }
+}
+
+/// Paths of the image files in `folder`, sorted by name, at most
+/// `MAX_IMAGES`
+pub unsafe fn list_images(folder: &CStr) -> Vec<CString> {
+    unsafe {
+        let mut names = storage::list_dir(folder);
+        names.sort_unstable();
+        let folder = folder.to_str().unwrap_or("");
+        names
+            .iter()
+            .filter(|name| !name.starts_with('.'))
+            .filter_map(|name| CString::new(alloc::format!("{}/{}", folder, name)).ok())
+            .filter(|path| image::is_image_file(path))
+            .take(MAX_IMAGES)
+            .collect()
+    }
+}
+
+/// Folder holding the file at `path`
+pub fn folder_of(path: &CStr) -> Option<CString> {
+    let path = path.to_str().ok()?;
+    let slash = path.rfind('/').filter(|&slash| slash > 0)?;
+    CString::new(&path[..slash]).ok()
+}
+
+/// File name part of `path`, for the report
+pub fn file_name(path: &CStr) -> String {
+    let path = path.to_string_lossy();
+    String::from(path.rsplit('/').next().unwrap_or(&path))
+}
@@ -0,0 +1,75 @@
+//! Cache of converted images
+//!
+//! Dithering a photo takes a while, and writing the same label again is the
+//! common case. Once an image file is decoded, its packed buffer is kept as
+//! an `.eink` container under `cache/`, one slot per source file and tag
+//! type. The container's transforms field holds a stamp of the file's
+//! modification time, its size and every conversion choice, so decoding
+//! the file again the same way loads the buffer without parsing the image.
+//! A changed file or different options miss and overwrite the slot.
+
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::string::String;
+use core::ffi::CStr;
+
+use crate::checkpoint::checksum;
+use crate::container::Container;
+use crate::image::{self, AnyImage};
+use crate::storage;
+use crate::tag_type::TagType;
+
+/// Directory holding the cached buffers
+const CACHE_DIR: &CStr = c"/ext/apps_data/eink_nfc_img/cache";
+
+/// Where and under which stamp one conversion is cached
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    path: CString,
+    stamp: String,
+}
+
+impl CacheEntry {
+    /// Slot for `source` converted for `tag` as `conversion` describes
+    ///
+    /// `conversion` must name everything that changes the packed bytes.
+    /// None if the source can't be looked up.
+    pub unsafe fn new(source: &CStr, tag: &TagType, conversion: &str) -> Option<Self> {
+        unsafe {
+            let (modified, size) = storage::file_stamp(source)?;
+            let slot = alloc::format!("{}|{}", source.to_str().ok()?, tag.part_number);
+            let path = alloc::format!(
+                "{}/{:08X}.eink",
+                CACHE_DIR.to_str().ok()?,
+                checksum(slot.as_bytes())
+            );
+            let stamp = alloc::format!(
+                "mtime={};size={};conversion={:08X}",
+                modified,
+                size,
+                checksum(conversion.as_bytes())
+            );
+            Some(Self { path: CString::new(path).ok()?, stamp })
+        }
+    }
+
+    /// The cached buffer, if it is there and still current
+    pub fn load(&self, tag: &TagType) -> Option<AnyImage> {
+        image::load_cached(&self.path, tag, &self.stamp).ok()
+    }
+
+    /// Replace the slot with `image`; false if it could not be written
+    pub unsafe fn store(&self, tag: &TagType, image: &AnyImage) -> bool {
+        unsafe {
+            storage::make_data_dir();
+            storage::make_dir(CACHE_DIR);
+            let container = Container {
+                part_number: tag.part_number,
+                transforms: &self.stamp,
+                payload: image.as_slice(),
+            };
+            storage::write_file(&self.path, &container.encode())
+        }
+    }
+}
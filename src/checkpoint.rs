@@ -0,0 +1,140 @@
+//! Resume points for interrupted writes
+//!
+//! Long transfers save a checkpoint every `CHECKPOINT_INTERVAL` packets: the
+//! tag UID, the last acknowledged packet and a checksum of the image, plus
+//! the file it came from. If the app dies mid-write (crash, flat battery) the
+//! next launch offers "Resume write" instead of starting from zero.
+//!
+//! Only writers that address each packet explicitly (ESL UPDATE BINARY
+//! offsets) can skip what the tag already has. BWRY and GenB stream into an
+//! auto-incrementing controller register and always start over.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use crate::protocol_common::CHUNK_SIZE;
+use crate::storage;
+use crate::tag_type::TagType;
+
+/// Checkpoint file path
+const CHECKPOINT_PATH: &CStr = c"/ext/apps_data/eink_nfc_img/checkpoint.txt";
+
+/// Packets between checkpoint saves
+pub const CHECKPOINT_INTERVAL: usize = 16;
+
+/// Progress of one write, enough to pick it up again
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// Part number of the tag type being written
+    pub part_number: String,
+    /// Image file the write was decoded from
+    pub image_path: String,
+    /// `checksum` of the packed image
+    pub checksum: u32,
+    /// UID of the tag being written (empty until it is detected)
+    pub uid: Vec<u8>,
+    /// Packets the tag has acknowledged
+    pub packets_acked: usize,
+}
+
+/// CRC-32 (IEEE) of `data`
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Parse a hex string into bytes
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Checkpoint {
+    /// Fresh checkpoint for writing the image at `image_path` to a `part_number` tag
+    pub fn new(part_number: &str, image_path: &str, checksum: u32) -> Self {
+        Self {
+            part_number: String::from(part_number),
+            image_path: String::from(image_path),
+            checksum,
+            uid: Vec::new(),
+            packets_acked: 0,
+        }
+    }
+
+    /// Saved checkpoint, if an earlier write was interrupted
+    pub unsafe fn load() -> Option<Self> {
+        unsafe {
+            let entries = storage::read_kv(CHECKPOINT_PATH);
+            let get = |key: &str| entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+            let checkpoint = Self {
+                part_number: String::from(get("tag")?),
+                image_path: String::from(get("image")?),
+                checksum: u32::from_str_radix(get("checksum")?, 16).ok()?,
+                uid: parse_hex(get("uid")?)?,
+                packets_acked: get("packet")?.parse().ok()?,
+            };
+            // Packets of another size (an older build) don't map to offsets
+            if get("chunk")?.parse::<usize>().ok()? != CHUNK_SIZE {
+                return None;
+            }
+            // Nothing was acknowledged; there is nothing to resume
+            (checkpoint.packets_acked > 0).then_some(checkpoint)
+        }
+    }
+
+    /// Write the checkpoint to the SD card
+    pub unsafe fn save(&self) -> bool {
+        unsafe {
+            let entries = [
+                ("tag", self.part_number.clone()),
+                ("image", self.image_path.clone()),
+                ("checksum", alloc::format!("{:08X}", self.checksum)),
+                ("uid", self.uid_hex()),
+                ("packet", alloc::format!("{}", self.packets_acked)),
+                ("chunk", alloc::format!("{}", CHUNK_SIZE)),
+            ];
+            storage::write_kv(CHECKPOINT_PATH, &entries)
+        }
+    }
+
+    /// Forget the checkpoint once the write has completed
+    pub unsafe fn clear() {
+        unsafe {
+            storage::remove(CHECKPOINT_PATH);
+        }
+    }
+
+    /// Tag type the write was for
+    pub fn tag(&self) -> Option<&'static TagType> {
+        TagType::ALL.iter().find(|tag| tag.part_number == self.part_number)
+    }
+
+    /// UID as uppercase hex
+    fn uid_hex(&self) -> String {
+        self.uid.iter().map(|b| alloc::format!("{:02X}", b)).collect()
+    }
+
+    /// Main menu label, e.g. "Resume write to 04A2..."
+    pub fn label(&self) -> String {
+        alloc::format!("Resume write to {}", self.uid_hex())
+    }
+
+    /// True if this checkpoint can continue a write of the same image to the same tag type
+    pub fn resumes(&self, part_number: &str, checksum: u32) -> bool {
+        self.part_number == part_number && self.checksum == checksum
+    }
+}
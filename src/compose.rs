@@ -0,0 +1,666 @@
+//! Images generated on the Flipper rather than loaded from a file
+//!
+//! Holds a small QR code encoder (byte mode, error correction level M,
+//! versions 1 to 10, enough for a URL or a Wi-Fi join string) and the
+//! Code 128 and EAN-13 barcodes, and lays codes out on the tag: whole panel
+//! pixels per module so every edge stays sharp, with the quiet zone
+//! scanners need around it.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::image::{self, AnyImage, DecodeObserver, ImageError, ImageResult, BLACK};
+use crate::protocol_common::log_info;
+use crate::tag_type::TagType;
+
+/// Light modules a scanner needs around a QR code
+const QUIET_ZONE: usize = 4;
+
+/// Largest QR version encoded (57x57 modules)
+const MAX_VERSION: usize = 10;
+
+/// Error correction codewords per block at level M, by version
+const ECC_PER_BLOCK: [usize; MAX_VERSION + 1] = [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26];
+/// Error correction blocks at level M, by version
+const ECC_BLOCKS: [usize; MAX_VERSION + 1] = [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5];
+/// Format bits for level M (before the mask number)
+const ECC_LEVEL_M: u32 = 0b00;
+
+/// A QR code: a square of dark and light modules
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode `data` in the smallest version that holds it
+    ///
+    /// None if it needs more than version 10 (about 200 bytes).
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        let version =
+            (1..=MAX_VERSION).find(|&v| data_bits(data.len(), v) <= data_capacity(v) * 8)?;
+        let codewords = add_ecc(&data_codewords(data, version), version);
+
+        let mut grid = Grid::new(version);
+        grid.draw_function_patterns();
+        grid.draw_codewords(&codewords);
+
+        // Keep the mask that leaves the fewest scanner-confusing patterns
+        let mut best = (u32::MAX, 0);
+        for mask in 0..8 {
+            grid.apply_mask(mask);
+            grid.draw_format_bits(mask);
+            let penalty = grid.penalty();
+            if penalty < best.0 {
+                best = (penalty, mask);
+            }
+            grid.apply_mask(mask);
+        }
+        grid.apply_mask(best.1);
+        grid.draw_format_bits(best.1);
+
+        Some(Self { size: grid.size, modules: grid.modules })
+    }
+
+    /// Modules along each side
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// True for a dark module
+    pub fn dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+}
+
+/// Modules of one version's symbol that carry codewords, in bits
+fn raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let aligns = version / 7 + 2;
+        result -= (25 * aligns - 10) * aligns - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+/// Data codewords a version holds at level M
+fn data_capacity(version: usize) -> usize {
+    raw_data_modules(version) / 8 - ECC_PER_BLOCK[version] * ECC_BLOCKS[version]
+}
+
+/// Bits of a byte-mode segment of `len` bytes
+fn data_bits(len: usize, version: usize) -> usize {
+    4 + count_bits(version) + len * 8
+}
+
+/// Width of the byte-mode character count field
+fn count_bits(version: usize) -> usize {
+    if version < 10 { 8 } else { 16 }
+}
+
+/// Appends bits, most significant first, to a codeword buffer
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u32, bits: usize) {
+        for i in (0..bits).rev() {
+            if self.len == self.bytes.len() * 8 {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 != 0 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+/// Byte-mode segment, terminator and padding, filling `version` at level M
+fn data_codewords(data: &[u8], version: usize) -> Vec<u8> {
+    let capacity = data_capacity(version);
+    let mut writer = BitWriter { bytes: Vec::with_capacity(capacity), len: 0 };
+    writer.push(0b0100, 4);
+    writer.push(data.len() as u32, count_bits(version));
+    for &byte in data {
+        writer.push(byte as u32, 8);
+    }
+    let terminator = (capacity * 8 - writer.len).min(4);
+    writer.push(0, terminator);
+    let mut bytes = writer.bytes;
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if bytes.len() >= capacity {
+            break;
+        }
+        bytes.push(pad);
+    }
+    bytes
+}
+
+/// Product of two elements of GF(2^8) modulo the QR polynomial 0x11D
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u16 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u16 >> i) & 1) * x as u16;
+    }
+    z as u8
+}
+
+/// Reed-Solomon generator polynomial of `degree`, highest term dropped
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_mul(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_mul(root, 0x02);
+    }
+    result
+}
+
+/// Error correction codewords for `data`
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (r, &d) in result.iter_mut().zip(divisor) {
+            *r ^= gf_mul(d, factor);
+        }
+    }
+    result
+}
+
+/// Split data into blocks, add each block's error correction and interleave
+fn add_ecc(data: &[u8], version: usize) -> Vec<u8> {
+    let blocks = ECC_BLOCKS[version];
+    let ecc_len = ECC_PER_BLOCK[version];
+    let raw = raw_data_modules(version) / 8;
+    let short_blocks = blocks - raw % blocks;
+    let short_len = raw / blocks;
+    let divisor = rs_divisor(ecc_len);
+
+    let mut split = Vec::with_capacity(blocks);
+    let mut start = 0;
+    for i in 0..blocks {
+        let len = short_len - ecc_len + usize::from(i >= short_blocks);
+        let mut block = data[start..start + len].to_vec();
+        start += len;
+        let ecc = rs_remainder(&block, &divisor);
+        if i < short_blocks {
+            // Placeholder so every block lines up; skipped when interleaving
+            block.push(0);
+        }
+        block.extend_from_slice(&ecc);
+        split.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw);
+    for i in 0..split[0].len() {
+        for (j, block) in split.iter().enumerate() {
+            if i != short_len - ecc_len || j >= short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+/// Centers of the alignment patterns along each axis
+fn alignment_positions(version: usize, size: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = (version * 4 + 4).div_ceil(count * 2 - 2) * 2;
+    let mut result: Vec<usize> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    result.push(6);
+    result.reverse();
+    result
+}
+
+/// Symbol under construction
+struct Grid {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+    /// Modules of fixed patterns, which masks and data leave alone
+    function: Vec<bool>,
+}
+
+impl Grid {
+    fn new(version: usize) -> Self {
+        let size = version * 4 + 17;
+        Self {
+            version,
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    /// Pattern of `radius` centered on (`cx`, `cy`); `dark` picks by ring
+    fn draw_rings(&mut self, cx: usize, cy: usize, radius: isize, dark: fn(isize) -> bool) {
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let (x, y) = (cx as isize + dx, cy as isize + dy);
+                if (0..self.size as isize).contains(&x) && (0..self.size as isize).contains(&y) {
+                    self.set_function(x as usize, y as usize, dark(dx.abs().max(dy.abs())));
+                }
+            }
+        }
+    }
+
+    fn draw_function_patterns(&mut self) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        // Finders with their light separators
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            self.draw_rings(cx, cy, 4, |ring| ring != 2 && ring != 4);
+        }
+
+        let positions = alignment_positions(self.version, size);
+        let last = positions.len().saturating_sub(1);
+        for (i, &cx) in positions.iter().enumerate() {
+            for (j, &cy) in positions.iter().enumerate() {
+                // The finder corners have no alignment pattern
+                if [(0, 0), (0, last), (last, 0)].contains(&(i, j)) {
+                    continue;
+                }
+                self.draw_rings(cx, cy, 2, |ring| ring != 1);
+            }
+        }
+
+        // Reserve the format areas; the real bits go in per mask
+        self.draw_format_bits(0);
+        self.draw_version();
+    }
+
+    /// Error correction level and mask, with their BCH code, twice over
+    fn draw_format_bits(&mut self, mask: u32) {
+        let data = (ECC_LEVEL_M << 3) | mask;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = ((data << 10) | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        // Around the top-left finder
+        for i in 0..=5 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        // Split between the other two finders
+        let size = self.size;
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Version number blocks, from version 7 on
+    fn draw_version(&mut self) {
+        if self.version < 7 {
+            return;
+        }
+        let mut rem = self.version as u32;
+        for _ in 0..12 {
+            rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+        }
+        let bits = ((self.version as u32) << 12) | rem;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// Lay codewords out in the zigzag of two-module columns
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            if right == 6 {
+                // Skip the vertical timing pattern
+                right = 5;
+            }
+            for vert in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i / 8] >> (7 - i % 8)) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Flip the data modules `mask` selects (applying it twice undoes it)
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let index = y * self.size + x;
+                if flip && !self.function[index] {
+                    self.modules[index] = !self.modules[index];
+                }
+            }
+        }
+    }
+
+    /// The standard's mask penalty: long runs, 2x2 blocks, finder look-alikes
+    /// and dark/light imbalance
+    fn penalty(&self) -> u32 {
+        let size = self.size;
+        let at = |x: usize, y: usize| self.modules[y * size + x];
+        let mut penalty = 0;
+
+        for transpose in [false, true] {
+            let line = |a: usize, b: usize| if transpose { at(b, a) } else { at(a, b) };
+            for b in 0..size {
+                let mut run = 1;
+                for a in 1..size {
+                    if line(a, b) == line(a - 1, b) {
+                        run += 1;
+                        if run == 5 {
+                            penalty += 3;
+                        } else if run > 5 {
+                            penalty += 1;
+                        }
+                    } else {
+                        run = 1;
+                    }
+                }
+                // 1:1:3:1:1 with four light modules on one side
+                const FINDER: [bool; 11] =
+                    [true, false, true, true, true, false, true, false, false, false, false];
+                for a in 0..size.saturating_sub(10) {
+                    let forward = (0..11).all(|k| line(a + k, b) == FINDER[k]);
+                    let backward = (0..11).all(|k| line(a + k, b) == FINDER[10 - k]);
+                    penalty += 40 * (u32::from(forward) + u32::from(backward));
+                }
+            }
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let c = at(x, y);
+                if c == at(x + 1, y) && c == at(x, y + 1) && c == at(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+
+        let dark = self.modules.iter().filter(|&&m| m).count();
+        let total = size * size;
+        let percent_off = (dark * 100 / total).abs_diff(50);
+        penalty + (percent_off / 5) as u32 * 10
+    }
+}
+
+/// Render `text` as a QR code centered on `tag`, as large as fits
+///
+/// Fails with `InvalidSize` if the text is too long for a QR code or the
+/// code doesn't fit the panel at one pixel per module.
+pub fn load_qr(
+    text: &str,
+    tag: &TagType,
+    observer: &mut dyn DecodeObserver,
+) -> ImageResult<AnyImage> {
+    let code = QrCode::encode(text.as_bytes()).ok_or(ImageError::InvalidSize)?;
+    let modules = code.size();
+    let scale = tag.width.min(tag.height) / (modules + 2 * QUIET_ZONE);
+    if scale == 0 {
+        return Err(ImageError::InvalidSize);
+    }
+    log_info!("QR code {}x{} modules at {} px each", modules, modules, scale);
+
+    let side = modules * scale;
+    let (left, top) = ((tag.width - side) / 2, (tag.height - side) / 2);
+    image::render(tag, observer, &mut |y, colors| {
+        if !(top..top + side).contains(&y) {
+            return;
+        }
+        let my = (y - top) / scale;
+        for mx in (0..modules).filter(|&mx| code.dark(mx, my)) {
+            colors[left + mx * scale..left + (mx + 1) * scale].fill(BLACK);
+        }
+    })
+}
+
+/// A one-dimensional barcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbology {
+    /// Any printable ASCII; digit runs are packed two to a symbol
+    Code128,
+    /// 12 digits plus a check digit, as on retail goods
+    Ean13,
+}
+
+/// Code 128 symbols 0 to 105 as 11 modules each, first module in bit 10
+const CODE128: [u16; 106] = [
+    0x6CC, 0x66C, 0x666, 0x498, 0x48C, 0x44C, 0x4C8, 0x4C4,
+    0x464, 0x648, 0x644, 0x624, 0x59C, 0x4DC, 0x4CE, 0x5CC,
+    0x4EC, 0x4E6, 0x672, 0x65C, 0x64E, 0x6E4, 0x674, 0x76E,
+    0x74C, 0x72C, 0x726, 0x764, 0x734, 0x732, 0x6D8, 0x6C6,
+    0x636, 0x518, 0x458, 0x446, 0x588, 0x468, 0x462, 0x688,
+    0x628, 0x622, 0x5B8, 0x58E, 0x46E, 0x5D8, 0x5C6, 0x476,
+    0x776, 0x68E, 0x62E, 0x6E8, 0x6E2, 0x6EE, 0x758, 0x746,
+    0x716, 0x768, 0x762, 0x71A, 0x77A, 0x642, 0x78A, 0x530,
+    0x50C, 0x4B0, 0x486, 0x42C, 0x426, 0x590, 0x584, 0x4D0,
+    0x4C2, 0x434, 0x432, 0x612, 0x650, 0x7BA, 0x614, 0x47A,
+    0x53C, 0x4BC, 0x49E, 0x5E4, 0x4F4, 0x4F2, 0x7A4, 0x794,
+    0x792, 0x6DE, 0x6F6, 0x7B6, 0x578, 0x51E, 0x45E, 0x5E8,
+    0x5E2, 0x7A8, 0x7A2, 0x5DE, 0x5EE, 0x75E, 0x7AE, 0x684,
+    0x690, 0x69C,
+];
+/// Code 128 stop symbol with its final bar (13 modules)
+const CODE128_STOP: u16 = 0x18EB;
+const CODE128_CODE_B: usize = 100;
+const CODE128_START_B: usize = 104;
+const CODE128_START_C: usize = 105;
+
+/// Blank rows above the bars and below the caption
+const BARCODE_MARGIN: usize = 4;
+
+/// Bars and spaces of a barcode, one entry per module (true for a bar)
+struct Bars(Vec<bool>);
+
+impl Bars {
+    /// Append the low `bits` bits of `pattern`, most significant first
+    fn push(&mut self, pattern: u32, bits: usize) {
+        self.0.extend((0..bits).rev().map(|i| (pattern >> i) & 1 != 0));
+    }
+}
+
+/// Code 128 modules for `text`, or None if it has non-printable characters
+///
+/// All-digit text uses code set C, with a final odd digit in set B;
+/// anything else is set B throughout.
+fn code128(text: &str) -> Option<Vec<bool>> {
+    let bytes = text.as_bytes();
+    if bytes.is_empty() || !bytes.iter().all(|b| (b' '..=b'~').contains(b)) {
+        return None;
+    }
+    let mut symbols = Vec::new();
+    if bytes.len() >= 4 && bytes.iter().all(u8::is_ascii_digit) {
+        symbols.push(CODE128_START_C);
+        let pairs = bytes.chunks_exact(2);
+        let odd = pairs.remainder();
+        symbols.extend(pairs.map(|pair| ((pair[0] - b'0') * 10 + pair[1] - b'0') as usize));
+        if let [digit] = odd {
+            symbols.push(CODE128_CODE_B);
+            symbols.push((digit - b' ') as usize);
+        }
+    } else {
+        symbols.push(CODE128_START_B);
+        symbols.extend(bytes.iter().map(|b| (b - b' ') as usize));
+    }
+    let check = symbols.iter().enumerate().map(|(i, &s)| i.max(1) * s).sum::<usize>() % 103;
+    symbols.push(check);
+
+    let mut bars = Bars(Vec::with_capacity(symbols.len() * 11 + 13));
+    for symbol in symbols {
+        bars.push(CODE128[symbol] as u32, 11);
+    }
+    bars.push(CODE128_STOP as u32, 13);
+    Some(bars.0)
+}
+
+/// EAN-13 left-half digits in odd parity (L code); R is the complement
+/// and G (even parity) the reverse of R
+const EAN_L: [u8; 10] = [0x0D, 0x19, 0x13, 0x3D, 0x23, 0x31, 0x2F, 0x3B, 0x37, 0x0B];
+/// Which left-half digits use G, by the first digit (bit 5 = second digit)
+const EAN_PARITY: [u8; 10] = [0x00, 0x0B, 0x0D, 0x0E, 0x13, 0x19, 0x1C, 0x15, 0x16, 0x1A];
+
+/// The 13 digits of an EAN-13 from 12 (check digit added) or 13 (checked)
+fn ean13_digits(text: &str) -> Option<[u8; 13]> {
+    let text = text.trim();
+    if !matches!(text.len(), 12 | 13) || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let mut digits = [0u8; 13];
+    for (digit, b) in digits.iter_mut().zip(text.bytes()) {
+        *digit = b - b'0';
+    }
+    let sum: u32 = digits[..12].iter().enumerate().map(|(i, &d)| d as u32 * [1, 3][i % 2]).sum();
+    let check = ((10 - sum % 10) % 10) as u8;
+    if text.len() == 13 && digits[12] != check {
+        return None;
+    }
+    digits[12] = check;
+    Some(digits)
+}
+
+/// EAN-13 modules for `digits`
+fn ean13(digits: &[u8; 13]) -> Vec<bool> {
+    let mut bars = Bars(Vec::with_capacity(95));
+    bars.push(0b101, 3);
+    let parity = EAN_PARITY[digits[0] as usize];
+    for (i, &digit) in digits[1..7].iter().enumerate() {
+        let l = EAN_L[digit as usize] as u32;
+        let g = (parity >> (5 - i)) & 1 != 0;
+        let code = if g { (!l & 0x7F).reverse_bits() >> 25 } else { l };
+        bars.push(code, 7);
+    }
+    bars.push(0b01010, 5);
+    for &digit in &digits[7..] {
+        bars.push((!EAN_L[digit as usize] & 0x7F) as u32, 7);
+    }
+    bars.push(0b101, 3);
+    bars.0
+}
+
+/// Render `text` as a barcode across `tag` with the text printed below
+///
+/// Fails with `InvalidFormat` if the symbology can't encode the text and
+/// `InvalidSize` if the bars don't fit the panel at one pixel per module.
+pub fn load_barcode(
+    text: &str,
+    symbology: Symbology,
+    tag: &TagType,
+    observer: &mut dyn DecodeObserver,
+) -> ImageResult<AnyImage> {
+    let (modules, caption, quiet) = match symbology {
+        Symbology::Code128 => {
+            let modules = code128(text).ok_or(ImageError::InvalidFormat)?;
+            (modules, String::from(text), 10)
+        }
+        Symbology::Ean13 => {
+            let digits = ean13_digits(text).ok_or(ImageError::InvalidFormat)?;
+            (ean13(&digits), digits.iter().map(|&d| (b'0' + d) as char).collect(), 11)
+        }
+    };
+    let (width, height) = (tag.width, tag.height);
+    let scale = width / (modules.len() + 2 * quiet);
+    if scale == 0 {
+        return Err(ImageError::InvalidSize);
+    }
+
+    // Largest caption that fits under the bars, if any does
+    let chars = caption.chars().count();
+    let caption_scale = (1..=2).rev().find(|&s| chars * (GLYPH_WIDTH + 1) * s - s <= width);
+    let caption_height = caption_scale.map_or(0, |s| (GLYPH_HEIGHT + 2) * s);
+    let bars_height = height.saturating_sub(2 * BARCODE_MARGIN + caption_height);
+    if bars_height == 0 {
+        return Err(ImageError::InvalidSize);
+    }
+    log_info!("{:?}: {} modules at {} px each", symbology, modules.len(), scale);
+
+    let left = (width - modules.len() * scale) / 2;
+    let bars_end = BARCODE_MARGIN + bars_height;
+    image::render(tag, observer, &mut |y, colors| {
+        if (BARCODE_MARGIN..bars_end).contains(&y) {
+            for (i, &bar) in modules.iter().enumerate() {
+                if bar {
+                    colors[left + i * scale..left + (i + 1) * scale].fill(BLACK);
+                }
+            }
+        } else if let Some(s) = caption_scale {
+            // The caption sits two dots below the bars
+            let dot_y = y.saturating_sub(bars_end) / s;
+            if y < bars_end || !(2..2 + GLYPH_HEIGHT).contains(&dot_y) {
+                return;
+            }
+            let dot_y = dot_y - 2;
+            let advance = (GLYPH_WIDTH + 1) * s;
+            let start = (width - (chars * advance - s)) / 2;
+            for (i, c) in caption.chars().enumerate() {
+                for dot_x in (0..GLYPH_WIDTH).filter(|&dot_x| font::dot(c, dot_x, dot_y)) {
+                    let x = start + i * advance + dot_x * s;
+                    colors[x..x + s].fill(BLACK);
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,119 @@
+//! `.eink` container for packed tag buffers
+//!
+//! A bare `.bin` is only bytes, so a buffer packed for one panel loads onto
+//! any tag with the same buffer size. The container adds the part number of
+//! the tag it was packed for, a note of the transforms that produced it and
+//! a CRC-32, so saved and shared buffers describe themselves and corrupt or
+//! mismatched files are turned away when they are loaded.
+//!
+//! Layout (integers little-endian):
+//!
+//! | Offset      | Size | Field                                        |
+//! |-------------|------|----------------------------------------------|
+//! | 0           | 4    | Magic `EINK`                                 |
+//! | 4           | 1    | Version (1)                                  |
+//! | 5           | 1    | Part number length P                         |
+//! | 6           | P    | Part number (ASCII)                          |
+//! | 6+P         | 1    | Transforms length T                          |
+//! | 7+P         | T    | Transforms, `key=value` pairs joined by `;`  |
+//! | 7+P+T       | 4    | Payload length N                             |
+//! | 11+P+T      | N    | Packed buffer, as sent to the tag            |
+//! | 11+P+T+N    | 4    | CRC-32 of every byte before it               |
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use crate::checkpoint::checksum;
+use crate::image::{ImageError, ImageResult};
+
+/// File magic
+pub const MAGIC: [u8; 4] = *b"EINK";
+
+/// Container format version
+pub const VERSION: u8 = 1;
+
+/// Largest header: magic, version, both length-prefixed strings, payload length
+pub const MAX_OVERHEAD: usize = 4 + 1 + (1 + 255) + (1 + 255) + 4 + 4;
+
+/// A packed buffer with the tag and transforms it was made for
+#[derive(Debug, Clone, Copy)]
+pub struct Container<'a> {
+    /// `TagType::part_number` the payload was packed for
+    pub part_number: &'a str,
+    /// How the payload was produced, e.g. `colors=bayer`
+    pub transforms: &'a str,
+    /// Packed buffer
+    pub payload: &'a [u8],
+}
+
+impl<'a> Container<'a> {
+    /// Serialize to the `.eink` layout
+    ///
+    /// Strings longer than 255 bytes are cut short.
+    pub fn encode(&self) -> Vec<u8> {
+        let part = &self.part_number.as_bytes()[..self.part_number.len().min(255)];
+        let transforms = &self.transforms.as_bytes()[..self.transforms.len().min(255)];
+
+        let mut out = Vec::with_capacity(MAX_OVERHEAD + self.payload.len());
+        out.extend_from_slice(&MAGIC);
+        out.push(VERSION);
+        out.push(part.len() as u8);
+        out.extend_from_slice(part);
+        out.push(transforms.len() as u8);
+        out.extend_from_slice(transforms);
+        out.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.payload);
+        let crc = checksum(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Parse and check a `.eink` file
+    ///
+    /// Bad magic, an unknown version, inconsistent lengths or a CRC mismatch
+    /// all give `InvalidFormat`.
+    pub fn decode(data: &'a [u8]) -> ImageResult<Self> {
+        let (body, crc) = data.split_last_chunk::<4>().ok_or(ImageError::InvalidFormat)?;
+        if checksum(body) != u32::from_le_bytes(*crc) {
+            return Err(ImageError::InvalidFormat);
+        }
+
+        let mut reader = Reader { data: body };
+        if reader.take(4)? != MAGIC || reader.take(1)? != [VERSION] {
+            return Err(ImageError::InvalidFormat);
+        }
+        let part_number = reader.string()?;
+        let transforms = reader.string()?;
+        let len = u32::from_le_bytes(reader.take(4)?.try_into().unwrap_or_default()) as usize;
+        let payload = reader.take(len)?;
+        if !reader.data.is_empty() {
+            return Err(ImageError::InvalidFormat);
+        }
+
+        Ok(Self { part_number, transforms, payload })
+    }
+}
+
+/// Cursor over the container body
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Next `len` bytes
+    fn take(&mut self, len: usize) -> ImageResult<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(ImageError::InvalidFormat);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    /// Length-prefixed ASCII string
+    fn string(&mut self) -> ImageResult<&'a str> {
+        let len = self.take(1)?[0] as usize;
+        core::str::from_utf8(self.take(len)?).map_err(|_| ImageError::InvalidFormat)
+    }
+}
@@ -0,0 +1,367 @@
+//! E-paper controller families and their register tables
+//!
+//! Tags that forward register writes straight to the panel controller (the
+//! `74 99` select / `74 9A` write pair) only differ in which controller sits
+//! behind the NFC bridge. A controller profile holds the init table, the data
+//! registers for each color plane and the refresh table, so a tag entry only
+//! has to name its controller. Window and resolution registers are filled in
+//! from the panel geometry when the table is sent, or from a smaller RAM
+//! `Window` when only part of the panel is written. Controllers that can
+//! read their RAM back also name the registers for that (`RamRead`), so a
+//! write can check what arrived before it refreshes.
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Panel geometry used to fill in window and resolution registers
+#[derive(Debug, Clone, Copy)]
+pub struct Geometry {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Geometry {
+    /// Bytes in one 1bpp color plane
+    pub fn plane_size(&self) -> usize {
+        self.width.div_ceil(8) * self.height
+    }
+
+    /// Last gate line, little-endian (9 bits on SSD16xx)
+    fn last_y(&self) -> [u8; 2] {
+        ((self.height - 1) as u16).to_le_bytes()
+    }
+
+    /// RAM line buffer row `row` lands on, little-endian (see `Window`)
+    pub fn ram_line(&self, row: usize) -> [u8; 2] {
+        ((self.height - 1 - row) as u16).to_le_bytes()
+    }
+}
+
+/// Rectangle of controller RAM, in packed buffer terms
+///
+/// Rows count from the first row of the buffer, which is sent first (and,
+/// with the Y-decrement entry mode the SSD16xx tables use, lands on the
+/// last RAM line). Columns are whole bytes of 8 pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub first_byte: usize,
+    pub last_byte: usize,
+    pub first_row: usize,
+    pub last_row: usize,
+}
+
+impl Window {
+    /// The whole panel
+    pub fn full(geometry: Geometry) -> Self {
+        Self {
+            first_byte: 0,
+            last_byte: geometry.width.div_ceil(8) - 1,
+            first_row: 0,
+            last_row: geometry.height - 1,
+        }
+    }
+
+    /// Bytes per window row
+    pub fn row_bytes(&self) -> usize {
+        self.last_byte - self.first_byte + 1
+    }
+
+    /// Bytes of one color plane inside the window
+    pub fn plane_bytes(&self) -> usize {
+        self.row_bytes() * (self.last_row - self.first_row + 1)
+    }
+
+    /// Smallest window covering every byte that differs between two packed
+    /// BWR buffers (B/W plane, then red plane) for `geometry`
+    ///
+    /// None if they are the same or not both the panel's size.
+    pub fn of_changes(old: &[u8], new: &[u8], geometry: Geometry) -> Option<Self> {
+        let plane = geometry.plane_size();
+        if old.len() != 2 * plane || new.len() != 2 * plane {
+            return None;
+        }
+        let stride = geometry.width.div_ceil(8);
+        let mut window: Option<Self> = None;
+        for (i, _) in old.iter().zip(new).enumerate().filter(|(_, (a, b))| a != b) {
+            let (row, col) = ((i % plane) / stride, i % stride);
+            let w = window.get_or_insert(Self {
+                first_byte: col,
+                last_byte: col,
+                first_row: row,
+                last_row: row,
+            });
+            w.first_byte = w.first_byte.min(col);
+            w.last_byte = w.last_byte.max(col);
+            w.first_row = w.first_row.min(row);
+            w.last_row = w.last_row.max(row);
+        }
+        window
+    }
+
+    /// Copy plane bytes `offset..offset + buf.len()` of the window, counted
+    /// row by row, out of a packed plane with the panel's row stride
+    pub fn gather(&self, plane: &[u8], stride: usize, offset: usize, buf: &mut [u8]) {
+        let row_bytes = self.row_bytes();
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let (row, col) = ((offset + i) / row_bytes, (offset + i) % row_bytes);
+            *byte = plane[(self.first_row + row) * stride + self.first_byte + col];
+        }
+    }
+}
+
+/// Parameter bytes of a register access
+#[derive(Debug, Clone, Copy)]
+pub enum RegData {
+    /// Fixed bytes; empty for command-only registers
+    Fixed(&'static [u8]),
+    /// SSD16xx driver output control: gate count - 1, then the scan flags byte
+    DriverOutput(u8),
+    /// SSD16xx RAM X address range: first to last byte column
+    RamXRange,
+    /// SSD16xx RAM Y address range: last to first line (Y decrement)
+    RamYRange,
+    /// SSD16xx RAM X address counter: start at the first byte column
+    RamXCounter,
+    /// SSD16xx RAM Y address counter: start at the last line
+    RamYCounter,
+    /// UC8151 resolution: horizontal then vertical pixel count
+    Resolution,
+}
+
+impl RegData {
+    /// True for command-only registers
+    pub fn is_empty(&self) -> bool {
+        matches!(self, RegData::Fixed(data) if data.is_empty())
+    }
+
+    /// Parameter bytes for a panel of the given geometry
+    pub fn encode(&self, geometry: Geometry) -> Vec<u8> {
+        self.encode_window(geometry, Window::full(geometry))
+    }
+
+    /// Parameter bytes for writing `window` of a `geometry` panel
+    ///
+    /// Only the RAM address registers depend on the window.
+    pub fn encode_window(&self, geometry: Geometry, window: Window) -> Vec<u8> {
+        let [y_lo, y_hi] = geometry.last_y();
+        let [start_lo, start_hi] = geometry.ram_line(window.first_row);
+        let [end_lo, end_hi] = geometry.ram_line(window.last_row);
+        match *self {
+            RegData::Fixed(data) => data.to_vec(),
+            RegData::DriverOutput(flags) => vec![y_lo, y_hi, flags],
+            RegData::RamXRange => vec![window.first_byte as u8, window.last_byte as u8],
+            RegData::RamYRange => vec![start_lo, start_hi, end_lo, end_hi],
+            RegData::RamXCounter => vec![window.first_byte as u8],
+            RegData::RamYCounter => vec![start_lo, start_hi],
+            RegData::Resolution => {
+                let [h_lo, h_hi] = (geometry.height as u16).to_le_bytes();
+                vec![(geometry.width & 0xF8) as u8, h_hi & 0x01, h_lo]
+            }
+        }
+    }
+}
+
+/// One register access: select `reg`, then write `data` (if any)
+#[derive(Debug, Clone, Copy)]
+pub struct RegWrite {
+    /// Controller register (command byte)
+    pub reg: u8,
+    /// Parameter bytes
+    pub data: RegData,
+    /// Delay after the access completes (ms)
+    pub delay_ms: u32,
+}
+
+impl RegWrite {
+    pub const fn new(reg: u8, data: &'static [u8]) -> Self {
+        Self::computed(reg, RegData::Fixed(data))
+    }
+
+    /// Register whose parameters depend on the panel geometry
+    pub const fn computed(reg: u8, data: RegData) -> Self {
+        Self { reg, data, delay_ms: 0 }
+    }
+
+    pub const fn with_delay(self, delay_ms: u32) -> Self {
+        Self { delay_ms, ..self }
+    }
+}
+
+/// Registers for reading a controller's RAM back
+///
+/// The plane is chosen with `option_reg`, the start with the RAM address
+/// counters; reading `read_reg` then returns `dummy` bytes followed by RAM
+/// along the data entry direction.
+#[derive(Debug)]
+pub struct RamRead {
+    pub option_reg: u8,
+    /// `option_reg` value selecting the B/W plane
+    pub bw_option: u8,
+    /// `option_reg` value selecting the red plane
+    pub red_option: u8,
+    pub x_counter_reg: u8,
+    pub y_counter_reg: u8,
+    pub read_reg: u8,
+    pub dummy: usize,
+}
+
+/// SSD16xx read-back: Read RAM option (0x41), then Read RAM (0x27)
+const SSD16XX_RAM_READ: RamRead = RamRead {
+    option_reg: 0x41,
+    bw_option: 0x00,
+    red_option: 0x01,
+    x_counter_reg: 0x4E,
+    y_counter_reg: 0x4F,
+    read_reg: 0x27,
+    dummy: 1,
+};
+
+/// Register tables for one controller family
+#[derive(Debug)]
+pub struct ControllerProfile {
+    /// Human-readable controller name
+    pub name: &'static str,
+    /// Registers written before image data
+    pub init: &'static [RegWrite],
+    /// Data register for the B/W plane
+    pub bw_data_reg: u8,
+    /// Data register for the red plane
+    pub red_data_reg: u8,
+    /// Registers written after image data to start the refresh
+    pub refresh: &'static [RegWrite],
+    /// Refresh for a write of part of the panel; empty if the controller
+    /// has no partial update, in which case `refresh` is used
+    pub partial_refresh: &'static [RegWrite],
+    /// READ_STATUS byte reported once the refresh has finished
+    pub ready_status: u8,
+    /// How to read the RAM back; None if the controller can't
+    pub ram_read: Option<RamRead>,
+    /// Register holding the temperature measured for the last refresh, in
+    /// 1/16 degC (12-bit two's complement, high byte first); None if the
+    /// controller has none to read
+    pub temperature_reg: Option<u8>,
+}
+
+/// Data registers of one display on a multi-display bridge
+///
+/// Dual-face badges drive their second panel through a separate pair of
+/// buffer-select registers on the same bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaceSelect {
+    /// Data register for the B/W plane
+    pub bw_data_reg: u8,
+    /// Data register for the red plane
+    pub red_data_reg: u8,
+}
+
+impl ControllerProfile {
+    /// Buffer-select registers of the panel the profile drives directly
+    pub const fn front_face(&self) -> FaceSelect {
+        FaceSelect {
+            bw_data_reg: self.bw_data_reg,
+            red_data_reg: self.red_data_reg,
+        }
+    }
+}
+
+/// Supported controller families
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    /// Solomon SSD1680/SSD1681 (1.54" e-Paper B)
+    Ssd1680,
+    /// UltraChip UC8151 / IL0373
+    Uc8151,
+    /// Solomon SSD1619/SSD1675
+    #[allow(dead_code)]
+    Ssd1619,
+}
+
+impl Controller {
+    /// Register tables for this controller
+    pub fn profile(self) -> &'static ControllerProfile {
+        match self {
+            Controller::Ssd1680 => &SSD1680,
+            Controller::Uc8151 => &UC8151,
+            Controller::Ssd1619 => &SSD1619,
+        }
+    }
+}
+
+/// SSD1680 tables (matches the GenB sequence of the official app at 200x200)
+static SSD1680: ControllerProfile = ControllerProfile {
+    name: "SSD1680",
+    init: &[
+        RegWrite::computed(0x01, RegData::DriverOutput(0x01)), // Driver output control
+        RegWrite::new(0x11, &[0x01]),                          // Data entry mode
+        RegWrite::computed(0x44, RegData::RamXRange),          // RAM X address range
+        RegWrite::computed(0x45, RegData::RamYRange),          // RAM Y address range
+        RegWrite::new(0x3C, &[0x05]),                          // Border waveform
+        RegWrite::new(0x18, &[0x80]),                          // Temperature sensor
+        RegWrite::computed(0x4E, RegData::RamXCounter),        // RAM X address counter
+        RegWrite::computed(0x4F, RegData::RamYCounter),        // RAM Y address counter
+    ],
+    bw_data_reg: 0x24,
+    red_data_reg: 0x26,
+    refresh: &[
+        RegWrite::new(0x22, &[0xF7]), // Display update control
+        RegWrite::new(0x20, &[]),     // Master activation
+    ],
+    partial_refresh: &[
+        RegWrite::new(0x22, &[0xFF]), // Display update control: display mode 2
+        RegWrite::new(0x20, &[]),     // Master activation
+    ],
+    ready_status: 0x01,
+    ram_read: Some(SSD16XX_RAM_READ),
+    temperature_reg: Some(0x1B),
+};
+
+/// UC8151 tables for a BWR panel
+static UC8151: ControllerProfile = ControllerProfile {
+    name: "UC8151",
+    init: &[
+        RegWrite::new(0x01, &[0x03, 0x00, 0x2B, 0x2B, 0x09]), // Power setting
+        RegWrite::new(0x06, &[0x17, 0x17, 0x17]),             // Booster soft start
+        RegWrite::new(0x04, &[]).with_delay(100),             // Power on
+        RegWrite::new(0x00, &[0x0F]),                         // Panel setting: BWR, OTP LUT
+        RegWrite::computed(0x61, RegData::Resolution),        // Resolution
+        RegWrite::new(0x50, &[0x77]),                         // VCOM and data interval
+    ],
+    bw_data_reg: 0x10,
+    red_data_reg: 0x13,
+    refresh: &[
+        RegWrite::new(0x12, &[]), // Display refresh
+    ],
+    partial_refresh: &[],
+    ready_status: 0x01,
+    ram_read: None,
+    temperature_reg: None,
+};
+
+/// SSD1619 tables
+static SSD1619: ControllerProfile = ControllerProfile {
+    name: "SSD1619",
+    init: &[
+        RegWrite::new(0x74, &[0x54]),                          // Analog block control
+        RegWrite::new(0x7E, &[0x3B]),                          // Digital block control
+        RegWrite::computed(0x01, RegData::DriverOutput(0x00)), // Driver output control
+        RegWrite::new(0x11, &[0x01]),                          // Data entry mode
+        RegWrite::computed(0x44, RegData::RamXRange),          // RAM X address range
+        RegWrite::computed(0x45, RegData::RamYRange),          // RAM Y address range
+        RegWrite::new(0x3C, &[0x01]),                          // Border waveform
+        RegWrite::new(0x18, &[0x80]),                          // Temperature sensor
+        RegWrite::computed(0x4E, RegData::RamXCounter),        // RAM X address counter
+        RegWrite::computed(0x4F, RegData::RamYCounter),        // RAM Y address counter
+    ],
+    bw_data_reg: 0x24,
+    red_data_reg: 0x26,
+    refresh: &[
+        RegWrite::new(0x22, &[0xC7]), // Display update control
+        RegWrite::new(0x20, &[]),     // Master activation
+    ],
+    partial_refresh: &[],
+    ready_status: 0x01,
+    ram_read: Some(SSD16XX_RAM_READ),
+    temperature_reg: Some(0x1B),
+};
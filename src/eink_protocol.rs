@@ -0,0 +1,125 @@
+//! Tag protocols behind one interface
+//!
+//! Each protocol module drives its tags its own way: bridge step lists,
+//! a label file upload, NTAG page windows, ST25DV mailbox messages. The
+//! app only needs a few things of any of them, and `EinkProtocol` is that
+//! part: the status line, the write itself, its command listing for
+//! "Preview Commands", and what it measured on the way. `handler` is the
+//! registry: it sets up the protocol a tag type's `Protocol` names from
+//! its `TagType` entry, so a new protocol is one implementation and one
+//! arm there.
+
+extern crate alloc;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use core::ffi::CStr;
+
+use crate::checkpoint::Checkpoint;
+use crate::controller::{Controller, Window};
+use crate::protocol_bwry::BwryProtocol;
+use crate::protocol_common::{log_error, AuthKey, ImageStream, NfcError, NfcResult, RetryMap};
+use crate::protocol_esl::EslProtocol;
+use crate::protocol_genb::GenbProtocol;
+use crate::protocol_ntag::{NtagConfig, NtagProtocol};
+use crate::protocol_st25dv::{MailboxConfig, St25dvProtocol};
+use crate::tag_type::{Protocol, TagType};
+use crate::transport::Transport;
+
+/// Packed front image of a write
+pub enum Front<'a> {
+    /// The whole frame in memory
+    Packed(&'a [u8]),
+    /// An image file packed as it is read (see `image::FileStream`)
+    Streamed(&'a mut dyn ImageStream),
+}
+
+impl Front<'_> {
+    /// The whole frame; a stream is read into memory first, for protocols
+    /// that can't send one as it goes
+    pub fn bytes(&mut self) -> NfcResult<Cow<'_, [u8]>> {
+        match self {
+            Front::Packed(image) => Ok(Cow::Borrowed(*image)),
+            Front::Streamed(stream) => {
+                let mut bytes = vec![0u8; stream.size()];
+                if !stream.read(0, &mut bytes) {
+                    log_error!("Streamed image unreadable");
+                    return Err(NfcError::ImageReadFailed);
+                }
+                Ok(Cow::Owned(bytes))
+            }
+        }
+    }
+}
+
+/// What one write sends
+pub struct Payload<'a> {
+    pub front: Front<'a>,
+    /// Packed rear image, for dual-face tags
+    pub rear: Option<&'a [u8]>,
+    /// Only this part of the panel, on tags with a partial refresh
+    pub window: Option<Window>,
+}
+
+/// What the app needs of a tag protocol
+pub trait EinkProtocol {
+    /// Line on the "Writing..." screen
+    fn status(&self) -> &'static CStr;
+
+    /// Send `payload` to the tag
+    ///
+    /// IsoDep protocols send their APDUs through `transport` (see
+    /// `Protocol::is_isodep`); the others open the built-in reader
+    /// themselves and are passed None.
+    fn write(
+        &mut self,
+        payload: Payload<'_>,
+        transport: Option<&mut dyn Transport>,
+    ) -> NfcResult<()>;
+
+    /// List the commands a write of `front`, and `rear` on dual-face tags,
+    /// would send
+    fn plan(&mut self, front: &[u8], rear: Option<&[u8]>) -> String;
+
+    /// Save progress to `checkpoint`, resuming from it if it belongs to
+    /// the tag in the field; protocols that always start over ignore it
+    fn resume(&mut self, _checkpoint: Checkpoint) {}
+
+    /// Per-packet resends of the last write
+    fn take_retries(&mut self) -> RetryMap {
+        RetryMap::default()
+    }
+
+    /// Panel temperature read during the last write, in degC
+    fn temperature(&self) -> Option<i8> {
+        None
+    }
+}
+
+/// Transport an IsoDep protocol was passed; a write without one fails as
+/// if no tag had been found
+pub fn isodep_transport(transport: Option<&mut dyn Transport>) -> NfcResult<&mut dyn Transport> {
+    transport.ok_or(NfcError::DetectFailed)
+}
+
+/// Protocol for `tag`, set up from its entry and unlocking bridge tags
+/// with `auth_key`
+pub fn handler(tag: &'static TagType, auth_key: AuthKey) -> Box<dyn EinkProtocol> {
+    match tag.protocol {
+        Protocol::IsodepBwry => Box::new(BwryProtocol::new(tag, auth_key)),
+        Protocol::IsodepGenb => {
+            let controller = tag.controller.unwrap_or(Controller::Ssd1680);
+            let mut proto = GenbProtocol::new(controller.profile(), tag.geometry(), auth_key)
+                .with_timing(tag.timing);
+            if let Some(rear) = tag.rear_face {
+                proto = proto.with_rear_face(rear);
+            }
+            Box::new(proto)
+        }
+        Protocol::St25dvMailbox => Box::new(St25dvProtocol::new(MailboxConfig::DEFAULT)),
+        Protocol::NtagPages => Box::new(NtagProtocol::new(NtagConfig::DEFAULT)),
+        Protocol::IsodepEsl => Box::new(EslProtocol::new(tag.geometry()).with_timing(tag.timing)),
+    }
+}
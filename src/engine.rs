@@ -0,0 +1,541 @@
+//! Register sequence engine for the `74 xx` bridge protocols
+//!
+//! BWRY and GenB tags only differ in what they send: commands, register
+//! selects and writes, image planes, waits and status polls, in a fixed
+//! order. A protocol lists one write as `Steps`, built from the tag's
+//! controller tables and `TimingProfile`; `Engine` sends them one APDU per
+//! step, and `Steps::plan` lists the same steps for "Preview Commands". A
+//! new panel behind the same bridge is a new step list, not a new state
+//! machine.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use flipperzero_sys as sys;
+
+use crate::protocol_common::{
+    self, commands, hex_bytes, log_error, log_info,
+    AuthKey, Failure, ImageStream, NfcError, NfcResult, Plan, RetryMap, CHUNK_SIZE,
+};
+use crate::transport::Transport;
+
+/// Status byte that ends a `PollUntilReady`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ready {
+    /// The controller reports this value once the refresh has finished
+    Is(u8),
+    /// Any value but this one, which the controller reports while busy
+    Not(u8),
+}
+
+impl Ready {
+    fn matches(self, status: u8) -> bool {
+        match self {
+            Ready::Is(ready) => status == ready,
+            Ready::Not(busy) => status != busy,
+        }
+    }
+
+    /// Condition as listed in a plan
+    fn describe(self) -> String {
+        match self {
+            Ready::Is(ready) => format!("{:02X}", ready),
+            Ready::Not(busy) => format!("not {:02X}", busy),
+        }
+    }
+}
+
+/// Bytes read back from controller RAM and compared with the image
+///
+/// The image stream is read again at `offset`, so it has to be one that
+/// allows that, such as an image in memory.
+#[derive(Debug, Clone)]
+pub struct ReadBack {
+    /// Registers written to point the controller at the bytes
+    pub setup: Vec<(u8, Vec<u8>)>,
+    /// Register the bytes are read from
+    pub read_reg: u8,
+    /// Bytes the controller answers with before the data
+    pub dummy: usize,
+    /// Where the bytes sent to that spot are in the image stream
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl ReadBack {
+    /// Frames one read-back takes: select and write per setup register,
+    /// select the read register, read
+    fn frames(&self) -> usize {
+        2 * self.setup.len() + 2
+    }
+}
+
+/// One step of a write
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Send a whole command APDU; the name labels it in the plan
+    Command(&'static str, Vec<u8>),
+    /// Select a controller register (74 99)
+    SelectReg(u8),
+    /// Write parameter bytes to the selected register (74 9A)
+    WriteData(Vec<u8>),
+    /// Fixed wait, in ms
+    Delay(u32),
+    /// Send image bytes `offset..offset + len` in `CHUNK_SIZE` packets,
+    /// resending failed ones
+    SendBuffer { name: &'static str, offset: usize, len: usize },
+    /// Head start after a refresh command (see `protocol_common::wait_refresh`)
+    WaitRefresh(u32),
+    /// Read status every `interval_ms` until it is `ready`, at most
+    /// `max_polls` times
+    PollUntilReady { ready: Ready, interval_ms: u32, max_polls: u32 },
+    /// Stop with `VerifyFailed` if the bytes differ; a read the bridge does
+    /// not answer skips the read-backs that follow
+    ReadBack(ReadBack),
+    /// Read the temperature the controller measured from this register; the
+    /// write does not fail without it
+    ReadTemperature(u8),
+}
+
+impl Step {
+    /// Name of the step on the error screen
+    fn name(&self) -> &'static str {
+        match *self {
+            Step::Command(name, _) => name,
+            Step::SelectReg(_) => "REG SELECT",
+            Step::WriteData(_) => "REG DATA",
+            Step::Delay(_) => "DELAY",
+            Step::SendBuffer { name, .. } => name.trim(),
+            Step::WaitRefresh(_) => "WAIT",
+            Step::PollUntilReady { .. } => "STATUS",
+            Step::ReadBack(_) => "READ BACK",
+            Step::ReadTemperature(_) => "TEMPERATURE",
+        }
+    }
+}
+
+/// Steps of one write, added in the order they are sent
+///
+/// Delays of zero are left out and a delay right after another one is
+/// added to it, so a step list reads the same as its plan.
+#[derive(Debug, Default)]
+pub struct Steps {
+    steps: Vec<Step>,
+    /// First step that is also sent when the write is cancelled
+    cleanup: Option<usize>,
+}
+
+impl Steps {
+    /// The opening every bridge write shares: INIT with `auth_key`, then
+    /// GPIO0 and GPIO1 to power the panel, each followed by its `gpio_ms`
+    pub fn bridge(&mut self, auth_key: &AuthKey, gpio_ms: [u32; 2]) {
+        self.command("INIT", &commands::init(auth_key), 0);
+        self.command("GPIO0", commands::GPIO_0, gpio_ms[0]);
+        self.command("GPIO1", commands::GPIO_1, gpio_ms[1]);
+    }
+
+    /// A command, then `delay_ms`
+    pub fn command(&mut self, name: &'static str, cmd: &[u8], delay_ms: u32) {
+        self.steps.push(Step::Command(name, cmd.to_vec()));
+        self.delay(delay_ms);
+    }
+
+    /// Select `reg` and, unless `data` is empty, write it; then `delay_ms`
+    pub fn register(&mut self, reg: u8, data: &[u8], delay_ms: u32) {
+        self.steps.push(Step::SelectReg(reg));
+        if !data.is_empty() {
+            self.steps.push(Step::WriteData(data.to_vec()));
+        }
+        self.delay(delay_ms);
+    }
+
+    /// A fixed wait
+    pub fn delay(&mut self, ms: u32) {
+        if ms == 0 {
+            return;
+        }
+        match self.steps.last_mut() {
+            Some(Step::Delay(before)) => *before += ms,
+            _ => self.steps.push(Step::Delay(ms)),
+        }
+    }
+
+    /// `len` image bytes from `offset` to the selected register, then
+    /// `delay_ms`
+    pub fn buffer(&mut self, name: &'static str, offset: usize, len: usize, delay_ms: u32) {
+        self.steps.push(Step::SendBuffer { name, offset, len });
+        self.delay(delay_ms);
+    }
+
+    /// Wait `head_start_ms` for a refresh unless the tag held its reply
+    pub fn wait_refresh(&mut self, head_start_ms: u32) {
+        self.steps.push(Step::WaitRefresh(head_start_ms));
+    }
+
+    /// Poll status every `interval_ms` until it is `ready`; still busy after
+    /// `max_polls` polls fails the write with `RefreshTimeout`
+    pub fn poll_until(&mut self, ready: Ready, interval_ms: u32, max_polls: u32) {
+        self.steps.push(Step::PollUntilReady { ready, interval_ms, max_polls });
+    }
+
+    /// Compare a spot of controller RAM with the image
+    pub fn read_back(&mut self, read: ReadBack) {
+        self.steps.push(Step::ReadBack(read));
+    }
+
+    /// Read the panel temperature from `reg`
+    pub fn read_temperature(&mut self, reg: u8) {
+        self.steps.push(Step::ReadTemperature(reg));
+    }
+
+    /// Mark the steps added from here on as cleanup: a write cancelled
+    /// part way skips to them, so the controller is not left powered
+    pub fn cleanup(&mut self) {
+        self.cleanup = Some(self.steps.len());
+    }
+
+    /// Index of the first cleanup step; the end if there are none
+    fn cleanup_at(&self) -> usize {
+        self.cleanup.unwrap_or(self.steps.len())
+    }
+
+    /// List the steps without sending anything
+    pub fn plan(&self) -> String {
+        let mut plan = Plan::default();
+        let (steps, cleanup) = self.steps.split_at(self.cleanup_at());
+        list(&mut plan, steps);
+        if !cleanup.is_empty() {
+            plan.note("Cleanup, also on cancel:");
+            list(&mut plan, cleanup);
+        }
+        plan.finish()
+    }
+}
+
+/// Add `steps` to `plan`
+fn list(plan: &mut Plan, steps: &[Step]) {
+    let mut steps = steps.iter().peekable();
+    while let Some(step) = steps.next() {
+        match step {
+            Step::Command(name, cmd) => plan.command(name, cmd, 0),
+            Step::SelectReg(reg) => {
+                match steps.next_if(|next| matches!(next, Step::WriteData(_))) {
+                    Some(Step::WriteData(data)) => plan.register(*reg, data, 0),
+                    _ => plan.register(*reg, &[], 0),
+                }
+            }
+            Step::WriteData(data) => plan.send(&format!("DATA: {}", hex_bytes(data)), 0),
+            Step::Delay(ms) => plan.delay(*ms),
+            Step::SendBuffer { name, len, .. } => plan.packets(name, *len, CHUNK_SIZE, 0),
+            Step::WaitRefresh(ms) => plan.wait(*ms),
+            Step::PollUntilReady { ready, interval_ms, .. } => {
+                plan.poll("STATUS", 1, *interval_ms, &ready.describe());
+            }
+            Step::ReadBack(read) => {
+                // A run of read-backs is one line: they only differ in where they point
+                let mut count = 1;
+                while steps.next_if(|next| matches!(next, Step::ReadBack(_))).is_some() {
+                    count += 1;
+                }
+                plan.note(&format!("VERIFY {} rows of {} B", count, read.len));
+                let mut regs: Vec<u8> = read.setup.iter().map(|(reg, _)| *reg).collect();
+                regs.push(read.read_reg);
+                let line = format!("  REG {} + READ frames", hex_bytes(&regs));
+                plan.repeat(&line, count * read.frames());
+            }
+            Step::ReadTemperature(reg) => {
+                plan.register(*reg, &[], 0);
+                plan.command("TEMP", &commands::read_data(1), 0);
+            }
+        }
+    }
+}
+
+/// Sends a step list over a transport
+pub struct Engine<'a> {
+    steps: &'a [Step],
+    /// Where the cleanup steps start
+    cleanup_at: usize,
+    /// First step sent even after a cancel: the refresh wait, since a panel
+    /// that has started refreshing is seen through, or else the cleanup
+    cancel_until: usize,
+    /// Step being sent
+    at: usize,
+    /// Packet of the `SendBuffer` being sent
+    packet: usize,
+    /// Status polls of the `PollUntilReady` being sent
+    polls: u32,
+    /// False once the bridge has not answered a read-back
+    verify: bool,
+    /// The tag's answer to the last command
+    rx: Vec<u8>,
+    /// Image packets that needed resending
+    retries: RetryMap,
+    /// Panel temperature from a `ReadTemperature` step (degC)
+    temperature: Option<i8>,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(steps: &'a Steps) -> Self {
+        Self {
+            steps: &steps.steps,
+            cleanup_at: steps.cleanup_at(),
+            cancel_until: steps
+                .steps
+                .iter()
+                .position(|step| matches!(step, Step::WaitRefresh(_)))
+                .map_or(steps.cleanup_at(), |refresh| refresh.min(steps.cleanup_at())),
+            at: 0,
+            packet: 0,
+            polls: 0,
+            verify: true,
+            rx: Vec::new(),
+            retries: RetryMap::default(),
+            temperature: None,
+        }
+    }
+
+    /// Per-packet resends of the run
+    pub fn take_retries(&mut self) -> RetryMap {
+        core::mem::take(&mut self.retries)
+    }
+
+    /// Panel temperature read during the run, in degC
+    pub fn temperature(&self) -> Option<i8> {
+        self.temperature
+    }
+
+    /// Activate the tag, send every step with image bytes from `image`,
+    /// release the tag
+    pub fn run(
+        &mut self,
+        transport: &mut dyn Transport,
+        image: &mut dyn ImageStream,
+    ) -> NfcResult<()> {
+        unsafe {
+            transport.activate()?;
+            log_info!("Tag ready, sending {} steps", self.steps.len());
+            let mut result = Ok(());
+            while result.is_ok() && self.at < self.steps.len() {
+                if self.at < self.cancel_until && protocol_common::cancel_requested() {
+                    result = self.cancel(transport, image);
+                    break;
+                }
+                result = self.step(transport, image);
+            }
+            transport.deactivate();
+            result
+        }
+    }
+
+    /// Skip to the cleanup steps and send them, unless nothing has been
+    /// sent yet; a failed cleanup step ends them early
+    unsafe fn cancel(
+        &mut self,
+        transport: &mut dyn Transport,
+        image: &mut dyn ImageStream,
+    ) -> NfcResult<()> {
+        unsafe {
+            log_info!("Cancelled at step {} of {}", self.at + 1, self.steps.len());
+            if self.at > 0 {
+                self.at = self.cleanup_at;
+                while self.at < self.steps.len() && self.step(transport, image).is_ok() {}
+            }
+            Err(NfcError::Cancelled)
+        }
+    }
+
+    /// Send the current step, or one packet or status poll of it, and move
+    /// on once it is done
+    unsafe fn step(
+        &mut self,
+        transport: &mut dyn Transport,
+        image: &mut dyn ImageStream,
+    ) -> NfcResult<()> {
+        unsafe {
+            let steps = self.steps;
+            let rx = &mut self.rx;
+            let sent = match &steps[self.at] {
+                Step::Command(_, cmd) => protocol_common::send_command(transport, rx, cmd),
+                Step::SelectReg(reg) => protocol_common::send_select_register(transport, rx, *reg),
+                Step::WriteData(data) => protocol_common::send_write_data(transport, rx, data),
+                Step::Delay(ms) => {
+                    sys::furi_delay_ms(*ms);
+                    true
+                }
+                Step::SendBuffer { name, offset, len } => {
+                    return self.send_packet(transport, image, *name, *offset, *len);
+                }
+                Step::WaitRefresh(ms) => {
+                    protocol_common::wait_refresh(transport, *ms);
+                    true
+                }
+                Step::PollUntilReady { ready, interval_ms, max_polls } => {
+                    return self.poll_status(transport, *ready, *interval_ms, *max_polls);
+                }
+                Step::ReadBack(_) if !self.verify => true,
+                Step::ReadBack(read) => match self.read_back(transport, image, read) {
+                    Some(true) => {
+                        if !matches!(steps.get(self.at + 1), Some(Step::ReadBack(_))) {
+                            log_info!("Read-back matches");
+                        }
+                        true
+                    }
+                    Some(false) => return Err(NfcError::VerifyFailed),
+                    None => {
+                        // Bridges that don't forward reads still get the rest of the write
+                        log_info!("Read-back not answered, going on unchecked");
+                        self.verify = false;
+                        true
+                    }
+                },
+                Step::ReadTemperature(reg) => {
+                    // The image is already on the panel; a missing reading is no error
+                    self.temperature = self.read_temperature(transport, *reg);
+                    match self.temperature {
+                        Some(celsius) => log_info!("Panel temperature: {} C", celsius),
+                        None => log_info!("Panel temperature not read"),
+                    }
+                    true
+                }
+            };
+            if !sent {
+                log_error!("Step {} of {} failed", self.at + 1, steps.len());
+                let failure = Failure::at(steps[self.at].name()).answer(&self.rx);
+                return Err(NfcError::TransmitFailed(failure));
+            }
+            self.at += 1;
+            Ok(())
+        }
+    }
+
+    /// Send the next packet of a `SendBuffer`; a failed one stays next
+    /// until its resends run out
+    fn send_packet(
+        &mut self,
+        transport: &mut dyn Transport,
+        image: &mut dyn ImageStream,
+        name: &'static str,
+        offset: usize,
+        len: usize,
+    ) -> NfcResult<()> {
+        let done = self.packet * CHUNK_SIZE;
+        if done == 0 {
+            log_info!("Sending {}: {} bytes", name.trim(), len);
+        }
+        let chunk_len = CHUNK_SIZE.min(len - done);
+        let mut chunk = [0u8; CHUNK_SIZE];
+        if !image.read(offset + done, &mut chunk[..chunk_len]) {
+            log_error!("Image bytes at {} unavailable", offset + done);
+            return Err(NfcError::ImageReadFailed);
+        }
+
+        if protocol_common::send_write_data(transport, &mut self.rx, &chunk[..chunk_len]) {
+            self.retries.packet_sent();
+            self.packet += 1;
+            if done + chunk_len >= len {
+                self.packet = 0;
+                self.at += 1;
+            }
+            Ok(())
+        } else if self.retries.retry() {
+            Ok(())
+        } else {
+            let failure = Failure::at(name.trim()).packet(self.packet).answer(&self.rx);
+            Err(NfcError::TransmitFailed(failure))
+        }
+    }
+
+    /// Read the busy status once; stay on the step, after `interval_ms`,
+    /// while it is not `ready`, until `max_polls` have been read
+    ///
+    /// Cancelling no longer stops the write at this point, so the limit is
+    /// what ends it if the panel never reports ready.
+    unsafe fn poll_status(
+        &mut self,
+        transport: &mut dyn Transport,
+        ready: Ready,
+        interval_ms: u32,
+        max_polls: u32,
+    ) -> NfcResult<()> {
+        unsafe {
+            let read = commands::READ_STATUS;
+            if !protocol_common::send_command(transport, &mut self.rx, read) {
+                let failure = Failure::at(self.steps[self.at].name()).answer(&self.rx);
+                return Err(NfcError::TransmitFailed(failure));
+            }
+            // Response format: [STATUS_BYTE, SW1, SW2]
+            let rx_size = self.rx.len();
+            if rx_size >= 3 {
+                let status_byte = self.rx[0];
+                log_info!("Status poll: byte={:02X}", status_byte);
+                if !ready.matches(status_byte) {
+                    self.polls += 1;
+                    if self.polls >= max_polls {
+                        log_error!("Still busy after {} status polls", self.polls);
+                        return Err(NfcError::RefreshTimeout);
+                    }
+                    // Still busy, wait and poll again
+                    sys::furi_delay_ms(interval_ms);
+                    return Ok(());
+                }
+                log_info!("Display ready!");
+            } else {
+                log_info!("Unexpected status response len={}, assuming ready", rx_size);
+            }
+            self.polls = 0;
+            self.at += 1;
+            Ok(())
+        }
+    }
+
+    /// Point the controller at `read`'s spot and compare what it holds with
+    /// the image; None if the bridge did not answer
+    fn read_back(
+        &mut self,
+        transport: &mut dyn Transport,
+        image: &mut dyn ImageStream,
+        read: &ReadBack,
+    ) -> Option<bool> {
+        let rx = &mut self.rx;
+        let pointed = read
+            .setup
+            .iter()
+            .all(|(reg, data)| protocol_common::send_register(transport, rx, *reg, data))
+            && protocol_common::send_select_register(transport, rx, read.read_reg);
+        if !pointed {
+            return None;
+        }
+        let len = (read.dummy + read.len) as u8;
+        let data = protocol_common::send_read_data(transport, rx, len)?;
+        let data = data.get(read.dummy..read.dummy + read.len)?;
+
+        let mut sent = vec![0u8; read.len];
+        if !image.read(read.offset, &mut sent) {
+            return None;
+        }
+        if data != sent.as_slice() {
+            log_error!(
+                "Read-back at image byte {}: {} (sent {})",
+                read.offset, hex_bytes(data), hex_bytes(&sent)
+            );
+            return Some(false);
+        }
+        Some(true)
+    }
+
+    /// Temperature the controller measured for the refresh, in whole degC;
+    /// None if the bridge did not answer
+    fn read_temperature(&mut self, transport: &mut dyn Transport, reg: u8) -> Option<i8> {
+        let rx = &mut self.rx;
+        if !protocol_common::send_select_register(transport, rx, reg) {
+            return None;
+        }
+        // The high byte of the 12-bit reading is whole degrees
+        let data = protocol_common::send_read_data(transport, rx, 1)?;
+        data.first().map(|&high| high as i8)
+    }
+}
@@ -0,0 +1,186 @@
+//! Tag recognition before a write
+//!
+//! Panels of different sizes answer with the same bridge chip, so nothing
+//! in the ATS names the geometry. Instead every successful write records
+//! the tag's UID and ATS historical bytes against the tag type it was
+//! written as. The next write checks the activated tag against that record
+//! before INIT: a tag last written as another type is stopped with
+//! `NfcError::WrongTag` instead of receiving a frame of the wrong geometry.
+//!
+//! UIDs identify one tag; historical bytes identify a batch, so they are
+//! only used for tags never seen before and are dropped as soon as two
+//! types share them. Records are `key=value` lines:
+//!
+//! ```text
+//! uid.04A1B2C3D4E5F6=GDEY0154D67
+//! ats.8031805F=GDEY0213Z98
+//! ```
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use crate::protocol_common::{log_info, NfcError, NfcResult};
+use crate::storage;
+use crate::tag_type::TagType;
+use crate::transport::Transport;
+
+/// Known tags file path
+const TAGS_PATH: &CStr = c"/ext/apps_data/eink_nfc_img/tags.txt";
+
+/// Part number stored for historical bytes seen on more than one tag type
+const AMBIGUOUS: &str = "?";
+
+/// Most records kept; the oldest go first
+const MAX_ENTRIES: usize = 64;
+
+/// Record key for `bytes` under `prefix`, e.g. `uid.04A1B2C3`
+fn key(prefix: &str, bytes: &[u8]) -> String {
+    let mut key = String::from(prefix);
+    for byte in bytes {
+        key.push_str(&alloc::format!("{:02X}", byte));
+    }
+    key
+}
+
+/// Which record a tag was recognised by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchedBy {
+    /// Its UID: this very tag was written before
+    Uid,
+    /// Its historical bytes: another tag of its batch was
+    Ats,
+}
+
+/// Tag type a tag with `uid` and `historical` bytes was last written as,
+/// and whether that was this tag or one of its batch
+pub unsafe fn known_as(uid: &[u8], historical: &[u8]) -> Option<(&'static TagType, MatchedBy)> {
+    unsafe {
+        let entries = storage::read_kv(TAGS_PATH);
+        let get = |key: String| entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone());
+        let by_uid = (!uid.is_empty()).then(|| get(key("uid.", uid))).flatten();
+        let by_ats = || (!historical.is_empty()).then(|| get(key("ats.", historical))).flatten();
+        let (part, by) = match by_uid {
+            Some(part) => (part, MatchedBy::Uid),
+            None => (by_ats()?, MatchedBy::Ats),
+        };
+        TagType::ALL.iter().find(|tag| tag.part_number == part).map(|tag| (tag, by))
+    }
+}
+
+/// Record that the tag with `uid` and `historical` bytes was written as `tag`
+unsafe fn learn(uid: &[u8], historical: &[u8], tag: &TagType) {
+    unsafe {
+        let mut entries = storage::read_kv(TAGS_PATH);
+        if !uid.is_empty() {
+            let uid_key = key("uid.", uid);
+            entries.retain(|(k, _)| *k != uid_key);
+            entries.push((uid_key, String::from(tag.part_number)));
+        }
+        if !historical.is_empty() {
+            let ats_key = key("ats.", historical);
+            let part = match entries.iter().position(|(k, _)| *k == ats_key) {
+                Some(index) => {
+                    let (_, seen) = entries.remove(index);
+                    if seen == tag.part_number { seen } else { String::from(AMBIGUOUS) }
+                }
+                None => String::from(tag.part_number),
+            };
+            entries.push((ats_key, part));
+        }
+        let excess = entries.len().saturating_sub(MAX_ENTRIES);
+        entries.drain(..excess);
+        if !storage::write_kv(TAGS_PATH, &entries) {
+            log_info!("Known tags not saved");
+        }
+    }
+}
+
+/// Transport that stops a write before the first command if the tag was
+/// last written as another type than the one selected
+pub struct CheckedTransport {
+    inner: Box<dyn Transport>,
+    tag: &'static TagType,
+    /// False once the user has chosen to write anyway
+    check: bool,
+    /// Type the tag was recognised as, and by what, once the check has
+    /// stopped a write
+    suspect: Option<(&'static TagType, MatchedBy)>,
+    /// UID and historical bytes of the last tag activated, kept for
+    /// `learn` since the reader may forget them once it lets go
+    seen: (Vec<u8>, Vec<u8>),
+}
+
+impl CheckedTransport {
+    /// Wrap `inner` for a write of `tag`
+    pub fn new(inner: Box<dyn Transport>, tag: &'static TagType, check: bool) -> Self {
+        Self { inner, tag, check, suspect: None, seen: (Vec::new(), Vec::new()) }
+    }
+
+    /// Type the activated tag, or its batch, was last written as, if the
+    /// check stopped the write
+    pub fn suspect(&self) -> Option<(&'static TagType, MatchedBy)> {
+        self.suspect
+    }
+
+    /// Remember the tag just written as the selected type
+    pub unsafe fn learn(&self) {
+        unsafe {
+            let (uid, historical) = &self.seen;
+            learn(uid, historical, self.tag);
+        }
+    }
+}
+
+impl Transport for CheckedTransport {
+    unsafe fn activate(&mut self) -> NfcResult<()> {
+        unsafe {
+            self.inner.activate()?;
+            self.seen = (self.inner.uid(), self.inner.historical_bytes());
+            if !self.check {
+                return Ok(());
+            }
+            let known = known_as(&self.seen.0, &self.seen.1);
+            let other = known.filter(|(known, _)| known.part_number != self.tag.part_number);
+            if let Some((known, by)) = other {
+                let (part, selected) = (known.part_number, self.tag.part_number);
+                log_info!("Tag was written as {} (by {:?}), not {}", part, by, selected);
+                self.suspect = Some((known, by));
+                self.inner.deactivate();
+                return Err(NfcError::WrongTag);
+            }
+            Ok(())
+        }
+    }
+
+    fn exchange(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool {
+        self.inner.exchange(tx, rx)
+    }
+
+    fn exchange_chained(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool {
+        self.inner.exchange_chained(tx, rx)
+    }
+
+    fn max_block(&self) -> usize {
+        self.inner.max_block()
+    }
+
+    fn held_ms(&self) -> u32 {
+        self.inner.held_ms()
+    }
+
+    unsafe fn deactivate(&mut self) {
+        unsafe { self.inner.deactivate() }
+    }
+
+    fn uid(&self) -> Vec<u8> {
+        self.inner.uid()
+    }
+
+    fn historical_bytes(&self) -> Vec<u8> {
+        self.inner.historical_bytes()
+    }
+}
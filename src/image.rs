@@ -1,16 +1,33 @@
 //! Image loading and e-ink format handling
 //!
-//! Supports loading 8-bit indexed BMP files from SD card and encoding them
-//! for different e-ink display formats (BWR 3-color, BWRY 4-color).
+//! Supports loading 8-bit indexed and 24/32-bit true-color BMP, Netpbm
+//! (PBM/PGM/PPM) and XBM files from SD card (or a captured Flipper screen)
+//! and encoding them for different e-ink display formats (BWR 3-color, BWRY
+//! 4-color). True-color pixels are quantized to the panel palette as each
+//! row is read. Images can be turned in quarter steps on the way, so one
+//! authored in landscape fits a portrait panel, and images of another size
+//! can be fitted to the tag (see `resample`). Typed text can be rendered
+//! straight onto a tag with the built-in `font`.
 
 use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::string::String;
 use alloc::vec;
-use core::ffi::c_char;
+use alloc::vec::Vec;
+use core::ffi::{c_char, CStr};
 use core::marker::PhantomData;
 use flipperzero_sys as sys;
 
-use crate::protocol_common::{DISPLAY_HEIGHT, DISPLAY_WIDTH, IMAGE_DATA_SIZE};
-use crate::tag_type::{Bwr, Bwry, ImageFormat};
+use crate::container::{self, Container};
+use crate::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::netpbm::NetpbmReader;
+use crate::xbm::XbmReader;
+use crate::pack::{self, Packer};
+use crate::protocol_common::{log_info, ImageStream};
+use crate::quantize::{AccentPull, Quantizer, QuantizerKind, Rgb, Threshold};
+use crate::resample::{Anchor, Filter, Fit, Rect, Resampler};
+use crate::screen::{Frame, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::tag_type::{Acep, Bwr, Bwry, ImageFormat, Mono, ScanDirection, ScanOrder, TagType};
 
 /// Helper macro for C strings
 macro_rules! c_str {
@@ -30,37 +47,574 @@ pub enum ImageError {
     InvalidFormat,
     /// Wrong image dimensions
     InvalidSize,
+    /// Image of another size than the tag, which could be fitted
+    /// (`DecodeOptions::fit`); carries the image's width and height
+    SizeMismatch { width: usize, height: usize },
+    /// BMP with a bit depth the reader doesn't handle (not 8, 24 or 32)
+    UnsupportedDepth(u16),
+    /// Run-length or otherwise compressed BMP
+    Compressed,
+    /// File ended before the header or pixel data it declares
+    Truncated,
+    /// Packed buffer (`.bin`, `.eink`) of `len` bytes where the tag takes
+    /// `expected`
+    BufferSize { len: usize, expected: usize },
+    /// Decode cancelled by the user
+    Cancelled,
+    /// SD card was removed or unmounted
+    SdCardRemoved,
+    /// Packed for a different tag type (`.eink` part number mismatch)
+    WrongTag,
+}
+
+/// What went wrong, short enough for a message title
+impl core::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            ImageError::OpenFailed => write!(f, "Can't open file"),
+            ImageError::ReadFailed => write!(f, "Read error"),
+            ImageError::InvalidFormat => write!(f, "Unsupported file"),
+            ImageError::InvalidSize => write!(f, "Bad dimensions"),
+            ImageError::SizeMismatch { width, height } => {
+                write!(f, "Image is {}x{}", width, height)
+            }
+            ImageError::UnsupportedDepth(bits) => write!(f, "{}-bit BMP", bits),
+            ImageError::Compressed => write!(f, "Compressed BMP"),
+            ImageError::Truncated => write!(f, "File cut short"),
+            ImageError::BufferSize { len, .. } => write!(f, "{} byte buffer", len),
+            ImageError::Cancelled => write!(f, "Cancelled"),
+            ImageError::SdCardRemoved => write!(f, "SD card removed"),
+            ImageError::WrongTag => write!(f, "Wrong Tag"),
+        }
+    }
+}
+
+impl ImageError {
+    /// What to do about it, for the line under the title
+    pub fn advice(self) -> String {
+        let advice = match self {
+            ImageError::OpenFailed => "Check the file is there",
+            ImageError::ReadFailed => "Try the file again",
+            ImageError::InvalidFormat => "Use BMP, Netpbm or XBM",
+            ImageError::InvalidSize => "Empty or too wide",
+            ImageError::SizeMismatch { .. } => "Resize it for the tag",
+            ImageError::UnsupportedDepth(_) => "Save as 8, 24 or 32-bit",
+            ImageError::Compressed => "Save it uncompressed",
+            ImageError::Truncated => "Copy the file again",
+            ImageError::BufferSize { expected, .. } => {
+                return alloc::format!("Tag takes {} bytes", expected);
+            }
+            ImageError::Cancelled => "",
+            ImageError::SdCardRemoved => "Insert card and retry",
+            ImageError::WrongTag => "File is for another tag",
+        };
+        String::from(advice)
+    }
 }
 
 pub type ImageResult<T> = Result<T, ImageError>;
 
+/// Quarter turns applied to a source image before it is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    /// As authored
+    #[default]
+    None,
+    /// 90 degrees clockwise
+    Cw90,
+    /// Upside down
+    Cw180,
+    /// 90 degrees counter-clockwise
+    Cw270,
+}
+
+impl Rotation {
+    /// Every rotation, in menu order
+    pub const ALL: [Rotation; 4] =
+        [Rotation::None, Rotation::Cw90, Rotation::Cw180, Rotation::Cw270];
+
+    /// Clockwise angle in degrees, also the value stored in files
+    pub fn degrees(self) -> u16 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Cw90 => 90,
+            Rotation::Cw180 => 180,
+            Rotation::Cw270 => 270,
+        }
+    }
+
+    /// Rotation for a clockwise angle in degrees, as written by `degrees`
+    pub fn from_degrees(value: &str) -> Option<Self> {
+        let degrees = value.trim().parse::<u16>().ok()?;
+        Self::ALL.into_iter().find(|rotation| rotation.degrees() == degrees)
+    }
+
+    /// The rotation after this one, for cycling through them in a menu
+    pub fn next(self) -> Self {
+        match self {
+            Rotation::None => Rotation::Cw90,
+            Rotation::Cw90 => Rotation::Cw180,
+            Rotation::Cw180 => Rotation::Cw270,
+            Rotation::Cw270 => Rotation::None,
+        }
+    }
+
+    /// The rotation a quarter turn counter-clockwise of this one
+    pub fn previous(self) -> Self {
+        match self {
+            Rotation::None => Rotation::Cw270,
+            Rotation::Cw90 => Rotation::None,
+            Rotation::Cw180 => Rotation::Cw90,
+            Rotation::Cw270 => Rotation::Cw180,
+        }
+    }
+
+    /// Width and height a source image needs to cover `tag` after turning
+    pub fn source_size(self, tag: &TagType) -> (usize, usize) {
+        match self {
+            Rotation::None | Rotation::Cw180 => (tag.width, tag.height),
+            Rotation::Cw90 | Rotation::Cw270 => (tag.height, tag.width),
+        }
+    }
+}
+
+/// How a source file is turned into panel colors
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeOptions {
+    pub quantizer: QuantizerKind,
+    pub rotation: Rotation,
+    /// What to do if the image is not the tag's size
+    pub fit: Fit,
+    /// Gray level split into black and white
+    pub threshold: Threshold,
+    /// Push toward the accent colors, for panels that draw them weakly
+    pub accent_pull: AccentPull,
+}
+
+impl DecodeOptions {
+    /// Quantizer for rows of `width` pixels, with the threshold and accent
+    /// pull applied ahead of the color mode
+    pub fn build_quantizer(&self, palette: &[Rgb], width: usize) -> Box<dyn Quantizer> {
+        let quantizer = self.accent_pull.apply(self.quantizer.build(palette, width));
+        self.threshold.apply(quantizer)
+    }
+}
+
+/// Widest image accepted for fitting (one row of it is buffered)
+const MAX_SOURCE_WIDTH: usize = 4096;
+
+/// Accept an image of `size` for `tag`, or say why not
+fn check_size(size: (usize, usize), tag: &TagType, options: DecodeOptions) -> ImageResult<()> {
+    let (width, height) = size;
+    if size == options.rotation.source_size(tag) {
+        Ok(())
+    } else if width == 0 || height == 0 || width > MAX_SOURCE_WIDTH {
+        Err(ImageError::InvalidSize)
+    } else if options.fit == Fit::Exact {
+        log_info!("Image is {}x{}, not the tag's size", width, height);
+        Err(ImageError::SizeMismatch { width, height })
+    } else {
+        Ok(())
+    }
+}
+
+/// Quantizes, packs and reports rows of the (turned) tag size
+struct RowEncoder<'a> {
+    quantizer: Box<dyn Quantizer>,
+    orienter: Orienter<'a>,
+    observer: &'a mut dyn DecodeObserver,
+    /// Caption laid out on the image, and its palette index
+    caption: Option<(TextBlock<'a>, u8)>,
+    /// Overlay laid over the image, under the caption
+    layer: Option<Layer>,
+    colors: Vec<u8>,
+    data: Box<[u8]>,
+}
+
+impl<'a> RowEncoder<'a> {
+    fn new(
+        tag: &TagType,
+        packer: &'a dyn Packer,
+        options: DecodeOptions,
+        caption: Option<&'a Caption>,
+        layer: Option<Layer>,
+        observer: &'a mut dyn DecodeObserver,
+    ) -> Self {
+        let size = options.rotation.source_size(tag);
+        let width = size.0;
+        Self {
+            quantizer: options.build_quantizer(packer.palette(), width),
+            orienter: Orienter::new(tag, packer, options.rotation),
+            observer,
+            caption: caption.and_then(|caption| Some((caption.layout(size)?, caption.color))),
+            layer,
+            colors: vec![0u8; width],
+            data: vec![0u8; packer.buffer_size()].into_boxed_slice(),
+        }
+    }
+
+    /// Encode row `y`; `Cancelled` if the observer asks to stop
+    fn row(&mut self, y: usize, pixels: &[Rgb]) -> ImageResult<()> {
+        for (x, (color, rgb)) in self.colors.iter_mut().zip(pixels.iter()).enumerate() {
+            *color = self.quantizer.quantize(x, *rgb);
+        }
+        // Drawn after quantizing, so overlay and text stay solid over dithering
+        if let Some(layer) = &self.layer {
+            layer.paint(y, &mut self.colors);
+        }
+        if let Some((block, color)) = &self.caption {
+            block.paint(y, &mut self.colors, *color);
+        }
+        self.orienter.push_row(&mut self.data, y, &self.colors);
+        self.quantizer.next_row();
+        if self.observer.on_row(y, &self.colors) {
+            Ok(())
+        } else {
+            Err(ImageError::Cancelled)
+        }
+    }
+
+    /// Packed buffer once every row is in
+    fn finish(mut self) -> Box<[u8]> {
+        self.orienter.finish(&mut self.data);
+        self.data
+    }
+}
+
+/// Takes the rows of an image file as they are decoded
+trait RowSink {
+    /// Called once with the image's size before any row; rows then come
+    /// bottom row first if `descending`
+    fn begin(&mut self, size: (usize, usize), descending: bool) -> ImageResult<()>;
+
+    /// Row `y`, counted from the top
+    fn row(&mut self, y: usize, pixels: &[Rgb]) -> ImageResult<()>;
+}
+
+/// Fits a file's rows to the tag and encodes them
+struct TagSink<'a> {
+    tag: &'a TagType,
+    options: DecodeOptions,
+    encoder: RowEncoder<'a>,
+    resampler: Option<Resampler>,
+}
+
+impl<'a> TagSink<'a> {
+    fn new(
+        tag: &'a TagType,
+        packer: &'a dyn Packer,
+        options: DecodeOptions,
+        caption: Option<&'a Caption>,
+        layer: Option<Layer>,
+        observer: &'a mut dyn DecodeObserver,
+    ) -> Self {
+        let encoder = RowEncoder::new(tag, packer, options, caption, layer, observer);
+        Self { tag, options, encoder, resampler: None }
+    }
+
+    /// Packed buffer once the file has been read
+    fn finish(self) -> ImageResult<Box<[u8]>> {
+        let Self { mut encoder, resampler, .. } = self;
+        let mut resampler = resampler.ok_or(ImageError::ReadFailed)?;
+        resampler.finish(&mut |y, pixels| encoder.row(y, pixels))?;
+        Ok(encoder.finish())
+    }
+}
+
+impl RowSink for TagSink<'_> {
+    fn begin(&mut self, size: (usize, usize), descending: bool) -> ImageResult<()> {
+        check_size(size, self.tag, self.options)?;
+        let target = self.options.rotation.source_size(self.tag);
+        self.resampler = Some(Resampler::new(size, target, self.options.fit, descending));
+        Ok(())
+    }
+
+    fn row(&mut self, y: usize, pixels: &[Rgb]) -> ImageResult<()> {
+        let Self { encoder, resampler, .. } = self;
+        let resampler = resampler.as_mut().ok_or(ImageError::ReadFailed)?;
+        resampler.push(y, pixels, &mut |y, pixels| encoder.row(y, pixels))
+    }
+}
+
+/// Quantizes rows for the observer only, without packing them
+struct RowPreview<'a> {
+    quantizer: Box<dyn Quantizer>,
+    observer: &'a mut dyn DecodeObserver,
+    colors: Vec<u8>,
+}
+
+impl RowPreview<'_> {
+    /// Show row `y`; `Cancelled` if the observer asks to stop
+    fn row(&mut self, y: usize, pixels: &[Rgb]) -> ImageResult<()> {
+        for (x, (color, rgb)) in self.colors.iter_mut().zip(pixels.iter()).enumerate() {
+            *color = self.quantizer.quantize(x, *rgb);
+        }
+        self.quantizer.next_row();
+        if self.observer.on_row(y, &self.colors) {
+            Ok(())
+        } else {
+            Err(ImageError::Cancelled)
+        }
+    }
+}
+
+/// Fits a file's rows to the tag and only shows them
+struct PreviewSink<'a> {
+    tag: &'a TagType,
+    options: DecodeOptions,
+    preview: RowPreview<'a>,
+    resampler: Option<Resampler>,
+}
+
+impl PreviewSink<'_> {
+    fn finish(self) -> ImageResult<()> {
+        let Self { mut preview, resampler, .. } = self;
+        let mut resampler = resampler.ok_or(ImageError::ReadFailed)?;
+        resampler.finish(&mut |y, pixels| preview.row(y, pixels))
+    }
+}
+
+impl RowSink for PreviewSink<'_> {
+    fn begin(&mut self, size: (usize, usize), descending: bool) -> ImageResult<()> {
+        check_size(size, self.tag, self.options)?;
+        let target = self.options.rotation.source_size(self.tag);
+        self.resampler = Some(Resampler::new(size, target, self.options.fit, descending));
+        Ok(())
+    }
+
+    fn row(&mut self, y: usize, pixels: &[Rgb]) -> ImageResult<()> {
+        let Self { preview, resampler, .. } = self;
+        let resampler = resampler.as_mut().ok_or(ImageError::ReadFailed)?;
+        resampler.push(y, pixels, &mut |y, pixels| preview.row(y, pixels))
+    }
+}
+
+/// Marks a see-through pixel of a `Layer`
+const TRANSPARENT: u8 = 0x0F;
+
+/// Default see-through color of overlays, magenta as in most sprite tools
+pub const OVERLAY_KEY: Rgb = Rgb::new(255, 0, 255);
+
+/// Keyed image laid over a decoded one, such as a frame or a watermark
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlay {
+    /// Image file, the size of the (turned) image it covers
+    pub path: CString,
+    /// Pixels of this color are see-through
+    pub key: Rgb,
+}
+
+/// An overlay matched to the panel palette, two pixels per byte
+struct Layer {
+    width: usize,
+    nibbles: Vec<u8>,
+}
+
+impl Layer {
+    /// Put the overlay's opaque pixels of row `y` over `colors`
+    fn paint(&self, y: usize, colors: &mut [u8]) {
+        for (x, color) in colors.iter_mut().take(self.width).enumerate() {
+            let i = y * self.width + x;
+            let pixel = (self.nibbles[i / 2] >> (4 - 4 * (i % 2))) & 0x0F;
+            if pixel != TRANSPARENT {
+                *color = pixel;
+            }
+        }
+    }
+}
+
+/// Decodes an overlay file, which must be exactly `size`, into a `Layer`
+///
+/// Opaque pixels take the nearest panel color, without dithering, so a
+/// frame or logo keeps clean edges.
+struct LayerSink {
+    size: (usize, usize),
+    key: Rgb,
+    quantizer: Box<dyn Quantizer>,
+    nibbles: Vec<u8>,
+}
+
+impl RowSink for LayerSink {
+    fn begin(&mut self, size: (usize, usize), _descending: bool) -> ImageResult<()> {
+        if size != self.size {
+            log_info!("Overlay is {}x{}, not {}x{}", size.0, size.1, self.size.0, self.size.1);
+            return Err(ImageError::InvalidSize);
+        }
+        Ok(())
+    }
+
+    fn row(&mut self, y: usize, pixels: &[Rgb]) -> ImageResult<()> {
+        for (x, rgb) in pixels.iter().take(self.size.0).enumerate() {
+            let pixel = if *rgb == self.key {
+                TRANSPARENT
+            } else {
+                self.quantizer.quantize(x, *rgb)
+            };
+            let i = y * self.size.0 + x;
+            let shift = 4 - 4 * (i % 2);
+            self.nibbles[i / 2] = (self.nibbles[i / 2] & !(0x0F << shift)) | (pixel << shift);
+        }
+        self.quantizer.next_row();
+        Ok(())
+    }
+}
+
+/// Decode `overlay` for an image of `size` on `tag`
+fn load_layer(overlay: &Overlay, size: (usize, usize), tag: &TagType) -> ImageResult<Layer> {
+    let packer = pack::for_tag(tag);
+    let mut sink = LayerSink {
+        size,
+        key: overlay.key,
+        quantizer: QuantizerKind::Nearest.build(packer.palette(), size.0),
+        nibbles: vec![0xFF; (size.0 * size.1).div_ceil(2)],
+    };
+    read_image_file(&overlay.path, &mut sink)?;
+    Ok(Layer { width: size.0, nibbles: sink.nibbles })
+}
+
+/// Fits a file's rows into a box and keeps them as palette indices
+struct RegionSink {
+    size: (usize, usize),
+    quantizer: Box<dyn Quantizer>,
+    resampler: Option<Resampler>,
+    colors: Vec<u8>,
+}
+
+impl RegionSink {
+    /// Row `y` of the box
+    fn emit(
+        size: (usize, usize),
+        quantizer: &mut dyn Quantizer,
+        colors: &mut [u8],
+        y: usize,
+        pixels: &[Rgb],
+    ) -> ImageResult<()> {
+        let row = &mut colors[y * size.0..(y + 1) * size.0];
+        for (x, (color, rgb)) in row.iter_mut().zip(pixels).enumerate() {
+            *color = quantizer.quantize(x, *rgb);
+        }
+        quantizer.next_row();
+        Ok(())
+    }
+
+    fn finish(self) -> ImageResult<Vec<u8>> {
+        let Self { size, mut quantizer, resampler, mut colors } = self;
+        let mut resampler = resampler.ok_or(ImageError::ReadFailed)?;
+        resampler.finish(&mut |y, pixels| {
+            Self::emit(size, &mut *quantizer, &mut colors, y, pixels)
+        })?;
+        Ok(colors)
+    }
+}
+
+impl RowSink for RegionSink {
+    fn begin(&mut self, size: (usize, usize), descending: bool) -> ImageResult<()> {
+        if size.0 == 0 || size.1 == 0 || size.0 > MAX_SOURCE_WIDTH {
+            return Err(ImageError::InvalidSize);
+        }
+        // Averaging only works when shrinking; small logos are blown up
+        let fits = size.0 <= self.size.0 && size.1 <= self.size.1;
+        let fit = Fit::Shrink(if fits { Filter::Nearest } else { Filter::Box });
+        self.resampler = Some(Resampler::new(size, self.size, fit, descending));
+        Ok(())
+    }
+
+    fn row(&mut self, y: usize, pixels: &[Rgb]) -> ImageResult<()> {
+        let Self { size, quantizer, resampler, colors } = self;
+        let resampler = resampler.as_mut().ok_or(ImageError::ReadFailed)?;
+        resampler.push(y, pixels, &mut |y, pixels| {
+            Self::emit(*size, &mut **quantizer, colors, y, pixels)
+        })
+    }
+}
+
+/// Fits a file's rows into a box and keeps their colors
+struct ThumbnailSink {
+    size: (usize, usize),
+    resampler: Option<Resampler>,
+    pixels: Vec<Rgb>,
+}
+
+impl ThumbnailSink {
+    fn finish(self) -> ImageResult<Vec<Rgb>> {
+        let Self { size, resampler, mut pixels } = self;
+        let mut resampler = resampler.ok_or(ImageError::ReadFailed)?;
+        resampler.finish(&mut |y, row| {
+            pixels[y * size.0..(y + 1) * size.0].copy_from_slice(row);
+            Ok(())
+        })?;
+        Ok(pixels)
+    }
+}
+
+impl RowSink for ThumbnailSink {
+    fn begin(&mut self, size: (usize, usize), descending: bool) -> ImageResult<()> {
+        if size.0 == 0 || size.1 == 0 || size.0 > MAX_SOURCE_WIDTH {
+            return Err(ImageError::InvalidSize);
+        }
+        let fits = size.0 <= self.size.0 && size.1 <= self.size.1;
+        let fit = Fit::Shrink(if fits { Filter::Nearest } else { Filter::Box });
+        self.resampler = Some(Resampler::new(size, self.size, fit, descending));
+        Ok(())
+    }
+
+    fn row(&mut self, y: usize, pixels: &[Rgb]) -> ImageResult<()> {
+        let Self { size, resampler, pixels: out } = self;
+        let resampler = resampler.as_mut().ok_or(ImageError::ReadFailed)?;
+        resampler.push(y, pixels, &mut |y, row| {
+            out[y * size.0..(y + 1) * size.0].copy_from_slice(row);
+            Ok(())
+        })
+    }
+}
+
 /// BMP file header size
 const BMP_FILE_HEADER_SIZE: usize = 14;
-/// BMP info header size (BITMAPINFOHEADER)
+/// BMP info header size (BITMAPINFOHEADER); the V4 (108 bytes) and V5 (124
+/// bytes) headers GIMP and Photoshop write extend it
 const BMP_INFO_HEADER_SIZE: usize = 40;
+/// Most palette entries an 8-bit BMP can have
+const BMP_MAX_COLORS: usize = 256;
+/// Red, green and blue masks of a BI_BITFIELDS file laid out as BGRX, the
+/// only layout read
+const BMP_BGRX_MASKS: [u32; 3] = [0x00FF_0000, 0x0000_FF00, 0x0000_00FF];
+
+/// Observer notified as rows are decoded
+pub trait DecodeObserver {
+    /// Called after output row `y` has been quantized to palette indices
+    ///
+    /// Returning false cancels the decode with `ImageError::Cancelled`.
+    fn on_row(&mut self, y: usize, colors: &[u8]) -> bool;
+}
 
 /// Marker trait for image formats
 #[allow(dead_code)]
 pub trait ImageFormatMarker {
     /// Runtime format identifier
     const FORMAT: ImageFormat;
-    /// Total data size in bytes
-    const DATA_SIZE: usize;
 }
 
 impl ImageFormatMarker for Bwry {
     const FORMAT: ImageFormat = ImageFormat::Bwry;
-    const DATA_SIZE: usize = IMAGE_DATA_SIZE; // 200*200*2bits/8 = 10,000
 }
 
 impl ImageFormatMarker for Bwr {
     const FORMAT: ImageFormat = ImageFormat::Bwr;
-    const DATA_SIZE: usize = IMAGE_DATA_SIZE; // 5,000 B/W + 5,000 Red = 10,000
+}
+
+impl ImageFormatMarker for Mono {
+    const FORMAT: ImageFormat = ImageFormat::Mono;
+}
+
+impl ImageFormatMarker for Acep {
+    const FORMAT: ImageFormat = ImageFormat::Acep;
 }
 
 /// Type-safe image container for a specific format
+///
+/// The buffer is sized for the tag it was loaded for (`TagType::image_data_size`).
 pub struct Image<F: ImageFormatMarker> {
-    data: Box<[u8; IMAGE_DATA_SIZE]>,
+    data: Box<[u8]>,
     _marker: PhantomData<F>,
 }
 
@@ -72,7 +626,7 @@ impl<F: ImageFormatMarker> Image<F> {
     }
 
     /// Get image data as byte slice
-    pub fn as_slice(&self) -> &[u8; IMAGE_DATA_SIZE] {
+    pub fn as_slice(&self) -> &[u8] {
         &self.data
     }
 }
@@ -83,59 +637,206 @@ pub enum AnyImage {
     Bwry(Image<Bwry>),
     /// BWR 3-color image
     Bwr(Image<Bwr>),
+    /// Black/white image
+    Mono(Image<Mono>),
+    /// ACeP 7-color image
+    Acep(Image<Acep>),
 }
 
-/// Calculate squared distance between two RGB colors
-fn color_distance_sq(r1: u8, g1: u8, b1: u8, r2: u8, g2: u8, b2: u8) -> u32 {
-    let dr = r1 as i32 - r2 as i32;
-    let dg = g1 as i32 - g2 as i32;
-    let db = b1 as i32 - b2 as i32;
-    (dr * dr + dg * dg + db * db) as u32
+impl AnyImage {
+    /// Packed frame buffer regardless of format
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            AnyImage::Bwry(image) => image.as_slice(),
+            AnyImage::Bwr(image) => image.as_slice(),
+            AnyImage::Mono(image) => image.as_slice(),
+            AnyImage::Acep(image) => image.as_slice(),
+        }
+    }
 }
 
-/// Map an RGB color to 2-bit BWRY e-ink color code
-/// 0=Black, 1=White, 2=Yellow, 3=Red
-fn map_to_bwry_color(r: u8, g: u8, b: u8) -> u8 {
-    let black_dist = color_distance_sq(r, g, b, 0, 0, 0);
-    let white_dist = color_distance_sq(r, g, b, 255, 255, 255);
-    let yellow_dist = color_distance_sq(r, g, b, 255, 255, 0);
-    let red_dist = color_distance_sq(r, g, b, 255, 0, 0);
-
-    let min_dist = black_dist.min(white_dist).min(yellow_dist).min(red_dist);
+/// Check whether the SD card is mounted and readable
+pub unsafe fn sd_card_present() -> bool {
+    unsafe {
+        let storage = sys::furi_record_open(c_str!("storage")) as *mut sys::Storage;
+        let present = sys::storage_sd_status(storage) == sys::FSE_OK;
+        sys::furi_record_close(c_str!("storage"));
+        present
+    }
+}
 
-    if min_dist == black_dist {
-        0
-    } else if min_dist == white_dist {
-        1
-    } else if min_dist == yellow_dist {
-        2
-    } else {
-        3
+/// Report `SdCardRemoved` instead of `fallback` when a storage call failed
+/// because the card is gone
+unsafe fn storage_error(storage: *mut sys::Storage, fallback: ImageError) -> ImageError {
+    unsafe {
+        if sys::storage_sd_status(storage) != sys::FSE_OK {
+            ImageError::SdCardRemoved
+        } else {
+            fallback
+        }
     }
 }
 
-/// Map an RGB color to BWR encoding
-/// Returns (is_white, is_red) for dual-buffer encoding
-fn map_to_bwr_color(r: u8, g: u8, b: u8) -> (bool, bool) {
-    let black_dist = color_distance_sq(r, g, b, 0, 0, 0);
-    let white_dist = color_distance_sq(r, g, b, 255, 255, 255);
-    let red_dist = color_distance_sq(r, g, b, 255, 0, 0);
+/// Feeds decoded image rows to the packer in the panel's native RAM order
+///
+/// Rows arrive top to bottom as the source image is viewed. Reversed panels
+/// get each row mirrored into the opposite RAM row. Panels a quarter turn
+/// from the image, and images the user asked to turn, need whole columns,
+/// so those are buffered (one byte per pixel) and packed in `finish`.
+struct Orienter<'a> {
+    packer: &'a dyn Packer,
+    width: usize,
+    height: usize,
+    rotate: bool,
+    reverse: bool,
+    turn: Rotation,
+    source_width: usize,
+    pixels: Vec<u8>,
+    scratch: Vec<u8>,
+}
 
-    let min_dist = black_dist.min(white_dist).min(red_dist);
+impl<'a> Orienter<'a> {
+    fn new(tag: &TagType, packer: &'a dyn Packer, turn: Rotation) -> Self {
+        let rotate = tag.needs_rotation();
+        let buffered = rotate || turn != Rotation::None;
+        Self {
+            packer,
+            width: tag.width,
+            height: tag.height,
+            rotate,
+            reverse: tag.scan == ScanDirection::Reversed,
+            turn,
+            source_width: turn.source_size(tag).0,
+            pixels: if buffered { vec![0u8; tag.width * tag.height] } else { Vec::new() },
+            scratch: vec![0u8; core::cmp::max(tag.width, tag.height)],
+        }
+    }
 
-    if min_dist == white_dist {
-        (true, false)  // White: BW=1, Red=0
-    } else if min_dist == red_dist {
-        (false, true)  // Red: BW=0, Red=1
-    } else {
-        (false, false) // Black: BW=0, Red=0
+    fn buffered(&self) -> bool {
+        self.rotate || self.turn != Rotation::None
+    }
+
+    /// Buffered color of pixel (`x`, `y`) of the turned image
+    fn pixel(&self, x: usize, y: usize) -> u8 {
+        let (w, h) = (self.width, self.height);
+        let (sx, sy) = match self.turn {
+            Rotation::None => (x, y),
+            Rotation::Cw90 => (y, w - 1 - x),
+            Rotation::Cw180 => (w - 1 - x, h - 1 - y),
+            Rotation::Cw270 => (h - 1 - y, x),
+        };
+        self.pixels[sy * self.source_width + sx]
+    }
+
+    /// Hand over source row `y`
+    fn push_row(&mut self, data: &mut [u8], y: usize, colors: &[u8]) {
+        let w = self.width;
+        if self.buffered() {
+            let sw = self.source_width;
+            self.pixels[y * sw..(y + 1) * sw].copy_from_slice(&colors[..sw]);
+        } else if self.reverse {
+            let row = &mut self.scratch[..w];
+            for (dst, &src) in row.iter_mut().zip(colors[..w].iter().rev()) {
+                *dst = src;
+            }
+            self.packer.pack_row(data, self.height - 1 - y, row);
+        } else {
+            self.packer.pack_row(data, y, colors);
+        }
+    }
+
+    /// Pack buffered rows once the whole image has been pushed
+    fn finish(&mut self, data: &mut [u8]) {
+        if !self.buffered() {
+            return;
+        }
+        let (w, h) = (self.width, self.height);
+        let mut row = core::mem::take(&mut self.scratch);
+        if self.rotate {
+            // Quarter turn clockwise: RAM row r is image column r, bottom to top
+            for r in 0..w {
+                for (c, dst) in row[..h].iter_mut().enumerate() {
+                    *dst = self.pixel(r, h - 1 - c);
+                }
+                if self.reverse {
+                    row[..h].reverse();
+                    self.packer.pack_row(data, w - 1 - r, &row[..h]);
+                } else {
+                    self.packer.pack_row(data, r, &row[..h]);
+                }
+            }
+        } else {
+            for y in 0..h {
+                for (x, dst) in row[..w].iter_mut().enumerate() {
+                    *dst = self.pixel(x, y);
+                }
+                if self.reverse {
+                    row[..w].reverse();
+                    self.packer.pack_row(data, h - 1 - y, &row[..w]);
+                } else {
+                    self.packer.pack_row(data, y, &row[..w]);
+                }
+            }
+        }
+        self.scratch = row;
+    }
+}
+
+/// Read the BMP color palette (BGRA quads) as RGB colors
+///
+/// Files may list fewer than 256 colors; the rest read as black, so every
+/// byte value indexes the result. The file is left at its pixel data.
+unsafe fn read_bmp_palette(file: *mut sys::File, info: &BmpInfo) -> ImageResult<Vec<Rgb>> {
+    unsafe {
+        if !sys::storage_file_seek(file, info.palette_offset as u32, true) {
+            return Err(ImageError::ReadFailed);
+        }
+        let palette_size = info.colors * 4;
+        let mut palette = vec![0u8; palette_size];
+        let read = sys::storage_file_read(file, palette.as_mut_ptr() as *mut _, palette_size);
+        if read != palette_size {
+            return Err(ImageError::Truncated);
+        }
+        if !sys::storage_file_seek(file, info.data_offset as u32, true) {
+            return Err(ImageError::ReadFailed);
+        }
+
+        let mut colors: Vec<Rgb> = palette
+            .chunks_exact(4)
+            .map(|quad| Rgb::new(quad[2], quad[1], quad[0]))
+            .collect();
+        colors.resize(BMP_MAX_COLORS, Rgb::new(0, 0, 0));
+        Ok(colors)
     }
 }
 
+/// Layout of a BMP file's pixel data
+#[derive(Clone, Copy)]
+struct BmpInfo {
+    width: usize,
+    height: usize,
+    /// Bytes per stored row, padded to 4
+    row_size: usize,
+    /// Rows are stored bottom row first (positive height)
+    bottom_up: bool,
+    /// 1 for indexed, 3 or 4 for true color
+    bytes_per_pixel: usize,
+    /// File offset of the palette, right after the info header
+    palette_offset: usize,
+    /// Palette entries stored (indexed files only)
+    colors: usize,
+    /// File offset of the first stored row
+    data_offset: usize,
+}
+
 /// Read and validate BMP headers, returning file handle and metadata
+///
+/// The file is left positioned at its pixel data, which starts at the
+/// offset the file header gives rather than right after the palette:
+/// editors may put color profiles or gaps in between.
 unsafe fn read_bmp_headers(
     path: *const c_char,
-) -> ImageResult<(*mut sys::File, *mut sys::Storage, usize, bool)> {
+) -> ImageResult<(*mut sys::File, *mut sys::Storage, BmpInfo)> {
     unsafe {
         // Open file
         let storage = sys::furi_record_open(c_str!("storage")) as *mut sys::Storage;
@@ -147,19 +848,21 @@ unsafe fn read_bmp_headers(
             sys::FSAM_READ,
             sys::FSOM_OPEN_EXISTING,
         ) {
+            let err = storage_error(storage, ImageError::OpenFailed);
             sys::storage_file_free(file);
             sys::furi_record_close(c_str!("storage"));
-            return Err(ImageError::OpenFailed);
+            return Err(err);
         }
 
         // Read BMP file header (14 bytes)
         let mut file_header = [0u8; BMP_FILE_HEADER_SIZE];
         let read = sys::storage_file_read(file, file_header.as_mut_ptr() as *mut _, BMP_FILE_HEADER_SIZE);
         if read != BMP_FILE_HEADER_SIZE {
+            let err = storage_error(storage, ImageError::Truncated);
             sys::storage_file_close(file);
             sys::storage_file_free(file);
             sys::furi_record_close(c_str!("storage"));
-            return Err(ImageError::ReadFailed);
+            return Err(err);
         }
 
         // Check BMP magic "BM"
@@ -171,52 +874,146 @@ unsafe fn read_bmp_headers(
         }
 
         // Get pixel data offset from file header
-        let _pixel_offset = u32::from_le_bytes([file_header[10], file_header[11], file_header[12], file_header[13]]) as usize;
+        let pixel_offset = u32::from_le_bytes([file_header[10], file_header[11], file_header[12], file_header[13]]) as usize;
 
-        // Read BMP info header (40 bytes)
+        // Read the BITMAPINFOHEADER fields (the first 40 bytes of any later
+        // header version too)
         let mut info_header = [0u8; BMP_INFO_HEADER_SIZE];
         let read = sys::storage_file_read(file, info_header.as_mut_ptr() as *mut _, BMP_INFO_HEADER_SIZE);
         if read != BMP_INFO_HEADER_SIZE {
+            let err = storage_error(storage, ImageError::Truncated);
             sys::storage_file_close(file);
             sys::storage_file_free(file);
             sys::furi_record_close(c_str!("storage"));
-            return Err(ImageError::ReadFailed);
+            return Err(err);
+        }
+
+        // Older OS/2 core headers are smaller and laid out differently
+        let header_size = u32::from_le_bytes([info_header[0], info_header[1], info_header[2], info_header[3]]) as usize;
+        if header_size < BMP_INFO_HEADER_SIZE {
+            sys::storage_file_close(file);
+            sys::storage_file_free(file);
+            sys::furi_record_close(c_str!("storage"));
+            return Err(ImageError::InvalidFormat);
         }
 
         // Parse dimensions
         let width = i32::from_le_bytes([info_header[4], info_header[5], info_header[6], info_header[7]]);
         let height = i32::from_le_bytes([info_header[8], info_header[9], info_header[10], info_header[11]]);
         let bits_per_pixel = u16::from_le_bytes([info_header[14], info_header[15]]);
+        let compression = u32::from_le_bytes([info_header[16], info_header[17], info_header[18], info_header[19]]);
+        let colors_used = u32::from_le_bytes([info_header[32], info_header[33], info_header[34], info_header[35]]) as usize;
 
-        // Validate dimensions (height can be negative for top-down DIB)
-        let abs_height = height.abs() as usize;
-        if width as usize != DISPLAY_WIDTH || abs_height != DISPLAY_HEIGHT {
+        // Height can be negative for top-down DIB
+        if width <= 0 || height == 0 || height == i32::MIN {
+            sys::storage_file_close(file);
+            sys::storage_file_free(file);
+            sys::furi_record_close(c_str!("storage"));
+            return Err(ImageError::InvalidFormat);
+        }
+        let (width, abs_height) = (width as usize, height.unsigned_abs() as usize);
+        // A row is buffered before `check_size` sees the header, so a huge
+        // width must not get as far as sizing that buffer
+        if width > MAX_SOURCE_WIDTH {
+            log_info!("BMP is {} pixels wide", width);
             sys::storage_file_close(file);
             sys::storage_file_free(file);
             sys::furi_record_close(c_str!("storage"));
             return Err(ImageError::InvalidSize);
         }
 
-        // Must be 8-bit indexed
-        if bits_per_pixel != 8 {
+        // Bitfield masks follow the 40-byte header, inside it for V4 and V5
+        let bitfields = (bits_per_pixel, compression) == (32, 3);
+        if bitfields {
+            let mut masks = [0u8; 12];
+            let read = sys::storage_file_read(file, masks.as_mut_ptr() as *mut _, masks.len());
+            if read != masks.len() {
+                let err = storage_error(storage, ImageError::Truncated);
+                sys::storage_file_close(file);
+                sys::storage_file_free(file);
+                sys::furi_record_close(c_str!("storage"));
+                return Err(err);
+            }
+            let mask = |at: usize| {
+                u32::from_le_bytes([masks[at], masks[at + 1], masks[at + 2], masks[at + 3]])
+            };
+            let (red, green, blue) = (mask(0), mask(4), mask(8));
+            if [red, green, blue] != BMP_BGRX_MASKS {
+                log_info!("BMP masks {:08X}/{:08X}/{:08X} are not BGRX", red, green, blue);
+                sys::storage_file_close(file);
+                sys::storage_file_free(file);
+                sys::furi_record_close(c_str!("storage"));
+                return Err(ImageError::Compressed);
+            }
+        }
+
+        // 8-bit indexed, 24-bit BGR or 32-bit BGRX (uncompressed or with the
+        // standard bitfield masks)
+        let bytes_per_pixel = match (bits_per_pixel, compression) {
+            (8, 0) => 1,
+            (24, 0) => 3,
+            (32, 0) | (32, 3) => 4,
+            (8 | 24 | 32, _) => {
+                log_info!("BMP compression {} is not supported", compression);
+                sys::storage_file_close(file);
+                sys::storage_file_free(file);
+                sys::furi_record_close(c_str!("storage"));
+                return Err(ImageError::Compressed);
+            }
+            _ => {
+                sys::storage_file_close(file);
+                sys::storage_file_free(file);
+                sys::furi_record_close(c_str!("storage"));
+                return Err(ImageError::UnsupportedDepth(bits_per_pixel));
+            }
+        };
+
+        // A 40-byte header is followed by the masks it doesn't hold
+        let masks_size = if bitfields && header_size == BMP_INFO_HEADER_SIZE { 12 } else { 0 };
+        let palette_offset = BMP_FILE_HEADER_SIZE + header_size + masks_size;
+        // 0 colors used means all of them
+        let colors = match colors_used {
+            _ if bytes_per_pixel > 1 => 0,
+            0 => BMP_MAX_COLORS,
+            used => used.min(BMP_MAX_COLORS),
+        };
+        // Some writers leave the offset at zero; assume the rows follow the
+        // palette then
+        let data_offset = if pixel_offset < palette_offset {
+            palette_offset + colors * 4
+        } else {
+            pixel_offset
+        };
+        if !sys::storage_file_seek(file, data_offset as u32, true) {
+            let err = storage_error(storage, ImageError::ReadFailed);
             sys::storage_file_close(file);
             sys::storage_file_free(file);
             sys::furi_record_close(c_str!("storage"));
-            return Err(ImageError::InvalidFormat);
+            return Err(err);
         }
 
         // BMP can be bottom-up (positive height) or top-down (negative height)
         let bottom_up = height > 0;
 
         // BMP rows are padded to 4-byte boundaries
-        let row_size = (DISPLAY_WIDTH + 3) & !3;
+        let row_size = (width * bytes_per_pixel + 3) & !3;
 
-        Ok((file, storage, row_size, bottom_up))
+        let info = BmpInfo {
+            width,
+            height: abs_height,
+            row_size,
+            bottom_up,
+            bytes_per_pixel,
+            palette_offset,
+            colors,
+            data_offset,
+        };
+        Ok((file, storage, info))
     }
 }
 
-/// Close BMP file and release resources
-unsafe fn close_bmp_file(file: *mut sys::File, storage: *mut sys::Storage) {
+/// Close an image file and release resources
+unsafe fn close_image_file(file: *mut sys::File, storage: *mut sys::Storage) {
     unsafe {
         sys::storage_file_close(file);
         sys::storage_file_free(file);
@@ -225,161 +1022,997 @@ unsafe fn close_bmp_file(file: *mut sys::File, storage: *mut sys::Storage) {
     }
 }
 
-/// Load an 8-bit indexed BMP file and encode as BWRY 4-color
-pub fn load_bmp_bwry(path: *const c_char) -> ImageResult<Image<Bwry>> {
-    unsafe {
-        let (file, storage, row_size, bottom_up) = read_bmp_headers(path)?;
+/// Colors of one stored BMP row
+fn bmp_row_pixels(row: &[u8], palette: &[Rgb], bytes_per_pixel: usize, pixels: &mut [Rgb]) {
+    for (x, rgb) in pixels.iter_mut().enumerate() {
+        *rgb = if bytes_per_pixel == 1 {
+            palette[row[x] as usize]
+        } else {
+            // True-color pixels are stored blue first
+            let px = &row[x * bytes_per_pixel..];
+            Rgb::new(px[2], px[1], px[0])
+        };
+    }
+}
 
-        // Read color palette (256 entries x 4 bytes each = 1024 bytes)
-        let palette_size = 256 * 4;
-        let mut palette = vec![0u8; palette_size];
-        let read = sys::storage_file_read(file, palette.as_mut_ptr() as *mut _, palette_size);
-        if read != palette_size {
-            close_bmp_file(file, storage);
-            return Err(ImageError::ReadFailed);
+/// Top-to-bottom rows of an open BMP file, for pulling rows on demand
+///
+/// Bottom-up files are read backwards, one seek per row. The file handle
+/// stays owned by the caller, which closes it.
+struct BmpReader {
+    file: *mut sys::File,
+    info: BmpInfo,
+    palette: Vec<Rgb>,
+    /// Rows handed out so far
+    row: usize,
+    buffer: Vec<u8>,
+}
+
+impl BmpReader {
+    /// Read the palette, if any, of a file `read_bmp_headers` opened
+    unsafe fn new(file: *mut sys::File, info: BmpInfo) -> ImageResult<Self> {
+        unsafe {
+            let palette = if info.bytes_per_pixel == 1 {
+                read_bmp_palette(file, &info)?
+            } else {
+                Vec::new()
+            };
+            Ok(Self {
+                file,
+                info,
+                palette,
+                row: 0,
+                buffer: vec![0u8; info.row_size],
+            })
+        }
+    }
+}
+
+impl RowReader for BmpReader {
+    fn size(&self) -> (usize, usize) {
+        (self.info.width, self.info.height)
+    }
+
+    fn format_name(&self) -> &'static str {
+        "BMP"
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        self.info.bytes_per_pixel * 8
+    }
+
+    fn palette_size(&self) -> usize {
+        self.info.colors
+    }
+
+    unsafe fn read_row(&mut self, row: &mut [Rgb]) -> ImageResult<()> {
+        unsafe {
+            let BmpInfo { height, row_size, bottom_up, bytes_per_pixel, data_offset, .. } =
+                self.info;
+            if bottom_up {
+                let offset = data_offset + (height - 1 - self.row) * row_size;
+                if !sys::storage_file_seek(self.file, offset as u32, true) {
+                    return Err(ImageError::ReadFailed);
+                }
+            }
+            let buffer = self.buffer.as_mut_ptr() as *mut _;
+            let read = sys::storage_file_read(self.file, buffer, row_size);
+            if read != row_size {
+                return Err(ImageError::Truncated);
+            }
+            self.row += 1;
+            bmp_row_pixels(&self.buffer, &self.palette, bytes_per_pixel, row);
+            Ok(())
         }
+    }
+}
 
-        // Build BWRY color code lookup table from palette
-        let mut color_map = vec![0u8; 256];
-        for i in 0..256 {
-            let b = palette[i * 4];
-            let g = palette[i * 4 + 1];
-            let r = palette[i * 4 + 2];
-            color_map[i] = map_to_bwry_color(r, g, b);
+/// Stream the rows of an 8-bit indexed or 24/32-bit true-color BMP file
+///
+/// Rows go to `sink` in the order they are stored, which for most BMPs is
+/// bottom row first.
+fn read_bmp(path: *const c_char, sink: &mut dyn RowSink) -> ImageResult<()> {
+    unsafe {
+        let (file, storage, info) = read_bmp_headers(path)?;
+        let BmpInfo { width, height, row_size, bottom_up, bytes_per_pixel, .. } = info;
+        if let Err(err) = sink.begin((width, height), bottom_up) {
+            close_image_file(file, storage);
+            return Err(err);
         }
+        log_info!("Decoding {}x{} BMP, {} byte(s) per pixel", width, height, bytes_per_pixel);
 
-        // Allocate output buffer
-        let mut data = Box::new([0u8; IMAGE_DATA_SIZE]);
+        // Read the color palette (up to 256 entries x 4 bytes each)
+        let palette = if bytes_per_pixel == 1 {
+            match read_bmp_palette(file, &info) {
+                Ok(p) => p,
+                Err(e) => {
+                    let err = storage_error(storage, e);
+                    close_image_file(file, storage);
+                    return Err(err);
+                }
+            }
+        } else {
+            Vec::new()
+        };
 
         // Read pixel data row by row
         let mut row_buffer = vec![0u8; row_size];
+        let mut pixels = vec![Rgb::new(255, 255, 255); width];
 
-        for row in 0..DISPLAY_HEIGHT {
+        for row in 0..height {
             let read = sys::storage_file_read(file, row_buffer.as_mut_ptr() as *mut _, row_size);
             if read != row_size {
-                close_bmp_file(file, storage);
-                return Err(ImageError::ReadFailed);
+                let err = storage_error(storage, ImageError::Truncated);
+                close_image_file(file, storage);
+                return Err(err);
             }
 
             // Determine output row based on orientation
             let out_row = if bottom_up {
-                DISPLAY_HEIGHT - 1 - row
+                height - 1 - row
             } else {
                 row
             };
 
-            // Pack 4 pixels per byte (2 bits each, MSB first)
-            let bytes_per_row = DISPLAY_WIDTH / 4; // 50 bytes
-            for x_byte in 0..bytes_per_row {
-                let mut byte_val: u8 = 0;
-                for bit in 0..4 {
-                    let x = x_byte * 4 + bit;
-                    let palette_idx = row_buffer[x] as usize;
-                    let color_code = color_map[palette_idx];
-                    byte_val = (byte_val << 2) | (color_code & 0x03);
-                }
-                data[out_row * bytes_per_row + x_byte] = byte_val;
+            bmp_row_pixels(&row_buffer, &palette, bytes_per_pixel, &mut pixels);
+            if let Err(err) = sink.row(out_row, &pixels) {
+                close_image_file(file, storage);
+                return Err(err);
             }
         }
 
-        close_bmp_file(file, storage);
+        close_image_file(file, storage);
+        Ok(())
+    }
+}
+
+/// Wrap a packed buffer in the image type for the tag's format
+fn into_image(tag: &TagType, data: Box<[u8]>) -> AnyImage {
+    match tag.image_format {
+        ImageFormat::Bwry => AnyImage::Bwry(Image { data, _marker: PhantomData }),
+        ImageFormat::Bwr => AnyImage::Bwr(Image { data, _marker: PhantomData }),
+        ImageFormat::Mono => AnyImage::Mono(Image { data, _marker: PhantomData }),
+        ImageFormat::Acep => AnyImage::Acep(Image { data, _marker: PhantomData }),
+    }
+}
+
+/// Image file formats, chosen by extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileFormat {
+    Bmp,
+    Netpbm,
+    Xbm,
+    /// Buffer already packed for the tag
+    Raw,
+    /// Packed buffer in an `.eink` container
+    Eink,
+}
+
+impl FileFormat {
+    /// Format for `path`; anything unrecognised is tried as BMP
+    fn of(path: &CStr) -> Self {
+        let bytes = path.to_bytes();
+        let ext = match bytes.iter().rposition(|&b| b == b'.') {
+            Some(dot) => &bytes[dot + 1..],
+            None => return FileFormat::Bmp,
+        };
+        if [b"pbm", b"pgm", b"ppm", b"pnm"].iter().any(|e| ext.eq_ignore_ascii_case(*e)) {
+            FileFormat::Netpbm
+        } else if ext.eq_ignore_ascii_case(b"xbm") {
+            FileFormat::Xbm
+        } else if ext.eq_ignore_ascii_case(b"bin") {
+            FileFormat::Raw
+        } else if ext.eq_ignore_ascii_case(b"eink") {
+            FileFormat::Eink
+        } else {
+            FileFormat::Bmp
+        }
+    }
+}
+
+/// Source of top-to-bottom RGB rows from an open image file
+///
+/// Formats without BMP's palette and bottom-up quirks implement this and
+/// share `read_rows`.
+pub trait RowReader {
+    /// Image width and height from the file header
+    fn size(&self) -> (usize, usize);
 
-        Ok(Image {
-            data,
-            _marker: PhantomData,
-        })
+    /// Short name of the file format, e.g. `BMP` or `PGM`
+    fn format_name(&self) -> &'static str;
+
+    /// Bits per pixel the header declares
+    fn bits_per_pixel(&self) -> usize;
+
+    /// Colors in the file's palette; 0 if pixels hold their color
+    fn palette_size(&self) -> usize {
+        0
     }
+
+    /// Decode the next row into `row`, which is `width` long
+    unsafe fn read_row(&mut self, row: &mut [Rgb]) -> ImageResult<()>;
 }
 
-/// Load an 8-bit indexed BMP file and encode as BWR 3-color (dual buffer)
-pub fn load_bmp_bwr(path: *const c_char) -> ImageResult<Image<Bwr>> {
+/// Open `path` and stream its rows with the `RowReader` for `format`
+fn read_with_reader(
+    path: *const c_char,
+    format: FileFormat,
+    sink: &mut dyn RowSink,
+) -> ImageResult<()> {
     unsafe {
-        let (file, storage, row_size, bottom_up) = read_bmp_headers(path)?;
+        let storage = sys::furi_record_open(c_str!("storage")) as *mut sys::Storage;
+        let file = sys::storage_file_alloc(storage);
+        if !sys::storage_file_open(file, path, sys::FSAM_READ, sys::FSOM_OPEN_EXISTING) {
+            let err = storage_error(storage, ImageError::OpenFailed);
+            sys::storage_file_free(file);
+            sys::furi_record_close(c_str!("storage"));
+            return Err(err);
+        }
 
-        // Read color palette (256 entries x 4 bytes each = 1024 bytes)
-        let palette_size = 256 * 4;
-        let mut palette = vec![0u8; palette_size];
-        let read = sys::storage_file_read(file, palette.as_mut_ptr() as *mut _, palette_size);
-        if read != palette_size {
-            close_bmp_file(file, storage);
-            return Err(ImageError::ReadFailed);
+        let result = match format {
+            FileFormat::Xbm => XbmReader::new(file).map(|r| Box::new(r) as Box<dyn RowReader>),
+            _ => NetpbmReader::new(file).map(|r| Box::new(r) as Box<dyn RowReader>),
         }
+        .and_then(|mut reader| read_rows(&mut *reader, sink))
+        .map_err(|e| match e {
+            ImageError::ReadFailed | ImageError::Truncated => storage_error(storage, e),
+            e => e,
+        });
+        close_image_file(file, storage);
+        result
+    }
+}
 
-        // Build BWR color maps from palette (is_white, is_red)
-        let mut bw_map = vec![false; 256];
-        let mut red_map = vec![false; 256];
-        for i in 0..256 {
-            let b = palette[i * 4];
-            let g = palette[i * 4 + 1];
-            let r = palette[i * 4 + 2];
-            let (is_white, is_red) = map_to_bwr_color(r, g, b);
-            bw_map[i] = is_white;
-            red_map[i] = is_red;
+/// Hand every row from `reader` to `sink`
+unsafe fn read_rows(reader: &mut dyn RowReader, sink: &mut dyn RowSink) -> ImageResult<()> {
+    unsafe {
+        let (width, height) = reader.size();
+        sink.begin((width, height), false)?;
+        log_info!("Decoding {}x{} image rows", width, height);
+
+        let mut pixels = vec![Rgb::new(255, 255, 255); width];
+        for y in 0..height {
+            reader.read_row(&mut pixels)?;
+            sink.row(y, &pixels)?;
         }
+        Ok(())
+    }
+}
 
-        // Allocate output buffer
-        // First 5000 bytes: B/W buffer (white=1, black=0)
-        // Second 5000 bytes: Red buffer (red=1, not-red=0)
-        let mut data = Box::new([0u8; IMAGE_DATA_SIZE]);
+/// Stream the rows of a BMP, Netpbm or XBM file to `sink`
+fn read_image_file(path: &CStr, sink: &mut dyn RowSink) -> ImageResult<()> {
+    match FileFormat::of(path) {
+        FileFormat::Bmp => read_bmp(path.as_ptr(), sink),
+        format @ (FileFormat::Netpbm | FileFormat::Xbm) => {
+            read_with_reader(path.as_ptr(), format, sink)
+        }
+        // Packed buffers have no pixels to fit
+        FileFormat::Raw | FileFormat::Eink => Err(ImageError::InvalidFormat),
+    }
+}
 
-        // Read pixel data row by row
-        let mut row_buffer = vec![0u8; row_size];
+/// Read a whole file of at most `limit` bytes
+fn read_file(path: *const c_char, limit: usize) -> ImageResult<Box<[u8]>> {
+    unsafe {
+        let storage = sys::furi_record_open(c_str!("storage")) as *mut sys::Storage;
+        let file = sys::storage_file_alloc(storage);
+        if !sys::storage_file_open(file, path, sys::FSAM_READ, sys::FSOM_OPEN_EXISTING) {
+            let err = storage_error(storage, ImageError::OpenFailed);
+            sys::storage_file_free(file);
+            sys::furi_record_close(c_str!("storage"));
+            return Err(err);
+        }
 
-        for row in 0..DISPLAY_HEIGHT {
-            let read = sys::storage_file_read(file, row_buffer.as_mut_ptr() as *mut _, row_size);
-            if read != row_size {
-                close_bmp_file(file, storage);
-                return Err(ImageError::ReadFailed);
+        let file_size = sys::storage_file_size(file) as usize;
+        if file_size > limit {
+            log_info!("File is {} bytes, at most {} expected", file_size, limit);
+            close_image_file(file, storage);
+            return Err(ImageError::BufferSize { len: file_size, expected: limit });
+        }
+
+        let mut data = vec![0u8; file_size].into_boxed_slice();
+        let read = sys::storage_file_read(file, data.as_mut_ptr() as *mut _, file_size);
+        if read != file_size {
+            let err = storage_error(storage, ImageError::ReadFailed);
+            close_image_file(file, storage);
+            return Err(err);
+        }
+
+        close_image_file(file, storage);
+        Ok(data)
+    }
+}
+
+/// Read a pre-encoded buffer that is exactly the tag's image data size
+///
+/// The bytes are sent as they are, so desktop converters can do their own
+/// dithering and packing. There are no rows to preview.
+fn load_raw(path: *const c_char, tag: &TagType) -> ImageResult<Box<[u8]>> {
+    let size = tag.image_data_size();
+    let data = read_file(path, size)?;
+    if data.len() != size {
+        log_info!("Raw buffer is {} bytes, tag expects {}", data.len(), size);
+        return Err(ImageError::BufferSize { len: data.len(), expected: size });
+    }
+    Ok(data)
+}
+
+/// Read an `.eink` container packed for `tag`
+///
+/// Like `load_raw`, but the CRC must check out and the container must name
+/// this tag's part number, so a buffer for another panel of the same size
+/// is refused instead of being written. With `transforms`, the container
+/// must also have been made by exactly those transforms.
+fn load_container(
+    path: *const c_char,
+    tag: &TagType,
+    transforms: Option<&str>,
+) -> ImageResult<Box<[u8]>> {
+    let size = tag.image_data_size();
+    let data = read_file(path, size + container::MAX_OVERHEAD)?;
+    let container = Container::decode(&data)?;
+    if container.part_number != tag.part_number {
+        log_info!("Container is for {}, not {}", container.part_number, tag.part_number);
+        return Err(ImageError::WrongTag);
+    }
+    if transforms.is_some_and(|transforms| transforms != container.transforms) {
+        log_info!("Container was made by {}", container.transforms);
+        return Err(ImageError::WrongTag);
+    }
+    if container.payload.len() != size {
+        log_info!("Container payload is {} bytes, tag expects {}", container.payload.len(), size);
+        return Err(ImageError::BufferSize { len: container.payload.len(), expected: size });
+    }
+    log_info!("Container transforms: {}", container.transforms);
+    Ok(container.payload.into())
+}
+
+/// Load an `.eink` container for `tag` made by exactly `transforms`
+///
+/// For the conversion cache, where a stale buffer has to read as a miss.
+pub fn load_cached(path: &CStr, tag: &TagType, transforms: &str) -> ImageResult<AnyImage> {
+    Ok(into_image(tag, load_container(path.as_ptr(), tag, Some(transforms))?))
+}
+
+/// True for files holding an already packed buffer (`.bin`, `.eink`)
+pub fn is_packed(path: &CStr) -> bool {
+    matches!(FileFormat::of(path), FileFormat::Raw | FileFormat::Eink)
+}
+
+/// True for files whose extension names an image decoder (`.bmp`, Netpbm,
+/// `.xbm`)
+///
+/// Unlike `load_file`, which tries anything else as BMP, this is strict:
+/// folder conversion uses it to pass over sidecars, recipes and the like.
+pub fn is_image_file(path: &CStr) -> bool {
+    let bytes = path.to_bytes();
+    let bmp = bytes.len() > 4 && bytes[bytes.len() - 4..].eq_ignore_ascii_case(b".bmp");
+    bmp || matches!(FileFormat::of(path), FileFormat::Netpbm | FileFormat::Xbm)
+}
+
+/// Load an image file for a tag, picking the decoder from the file extension
+///
+/// `.pbm`/`.pgm`/`.ppm`/`.pnm` and `.xbm` have their own readers, `.bin`
+/// is taken as already packed and `.eink` is a checked container around a
+/// packed buffer; anything else is read as BMP. An `overlay` and then a
+/// `caption` are drawn over decoded images; packed ones are written as
+/// they are.
+pub fn load_file(
+    path: &CStr,
+    tag: &TagType,
+    options: DecodeOptions,
+    caption: Option<&Caption>,
+    overlay: Option<&Overlay>,
+    observer: &mut dyn DecodeObserver,
+) -> ImageResult<AnyImage> {
+    match FileFormat::of(path) {
+        FileFormat::Raw => return Ok(into_image(tag, load_raw(path.as_ptr(), tag)?)),
+        FileFormat::Eink => return Ok(into_image(tag, load_container(path.as_ptr(), tag, None)?)),
+        FileFormat::Bmp | FileFormat::Netpbm | FileFormat::Xbm => {}
+    }
+    let size = options.rotation.source_size(tag);
+    let layer = overlay.map(|overlay| load_layer(overlay, size, tag)).transpose()?;
+    let packer = pack::for_tag(tag);
+    let mut sink = TagSink::new(tag, &*packer, options, caption, layer, observer);
+    read_image_file(path, &mut sink)?;
+    Ok(into_image(tag, sink.finish()?))
+}
+
+/// Decode the image file at `path` for `tag` into `observer` only
+///
+/// For files written through a `FileStream`: the preview shows what
+/// `load_file` would make of them, but the packed frame is never built.
+/// Packed files give `InvalidFormat`.
+pub fn preview_file(
+    path: &CStr,
+    tag: &TagType,
+    options: DecodeOptions,
+    observer: &mut dyn DecodeObserver,
+) -> ImageResult<()> {
+    if is_packed(path) {
+        return Err(ImageError::InvalidFormat);
+    }
+    let width = options.rotation.source_size(tag).0;
+    let preview = RowPreview {
+        quantizer: options.build_quantizer(pack::for_tag(tag).palette(), width),
+        observer,
+        colors: vec![0u8; width],
+    };
+    let mut sink = PreviewSink { tag, options, preview, resampler: None };
+    read_image_file(path, &mut sink)?;
+    sink.finish()
+}
+
+/// Decode the image file at `path` into a `size` box for `tag`
+///
+/// The image is scaled, aspect kept, to the largest size that fits the box
+/// and centered on white. Returns the box's palette indices, row by row.
+pub fn load_region(
+    path: &CStr,
+    size: (usize, usize),
+    tag: &TagType,
+    quantizer: QuantizerKind,
+) -> ImageResult<Vec<u8>> {
+    let packer = pack::for_tag(tag);
+    let mut sink = RegionSink {
+        size,
+        quantizer: quantizer.build(packer.palette(), size.0),
+        resampler: None,
+        colors: vec![WHITE; size.0 * size.1],
+    };
+    read_image_file(path, &mut sink)?;
+    sink.finish()
+}
+
+/// The image file at `path` fitted into a `size` box like `load_region`,
+/// with its colors kept, row by row
+///
+/// For previews that match the colors again as settings change.
+pub fn load_thumbnail(path: &CStr, size: (usize, usize)) -> ImageResult<Vec<Rgb>> {
+    let mut sink = ThumbnailSink {
+        size,
+        resampler: None,
+        pixels: vec![Rgb::new(255, 255, 255); size.0 * size.1],
+    };
+    read_image_file(path, &mut sink)?;
+    sink.finish()
+}
+
+/// What the header of an image file says, shown before it is decoded
+#[derive(Debug, Clone, Copy)]
+pub struct ImageInfo {
+    /// From `RowReader::format_name`
+    pub format: &'static str,
+    pub size: (usize, usize),
+    pub bits_per_pixel: usize,
+    /// Palette colors, 0 for files without a palette
+    pub palette: usize,
+    /// Length of the file in bytes
+    pub file_size: u64,
+}
+
+/// Header details of the image file at `path`
+pub fn image_info(path: &CStr) -> ImageResult<ImageInfo> {
+    unsafe {
+        let (file, storage, reader) = open_rows(path)?;
+        let info = ImageInfo {
+            format: reader.format_name(),
+            size: reader.size(),
+            bits_per_pixel: reader.bits_per_pixel(),
+            palette: reader.palette_size(),
+            file_size: sys::storage_file_size(file),
+        };
+        close_image_file(file, storage);
+        Ok(info)
+    }
+}
+
+/// Width and height of the image file at `path`, from its header
+pub fn image_size(path: &CStr) -> ImageResult<(usize, usize)> {
+    image_info(path).map(|info| info.size)
+}
+
+/// Open `path` with the `RowReader` for its format
+///
+/// The caller closes the returned file with `close_image_file`.
+unsafe fn open_rows(
+    path: &CStr,
+) -> ImageResult<(*mut sys::File, *mut sys::Storage, Box<dyn RowReader>)> {
+    unsafe {
+        let format = FileFormat::of(path);
+        if format == FileFormat::Bmp {
+            let (file, storage, info) = read_bmp_headers(path.as_ptr())?;
+            return match BmpReader::new(file, info) {
+                Ok(reader) => Ok((file, storage, Box::new(reader))),
+                Err(e) => {
+                    let err = storage_error(storage, e);
+                    close_image_file(file, storage);
+                    Err(err)
+                }
+            };
+        }
+        if matches!(format, FileFormat::Raw | FileFormat::Eink) {
+            return Err(ImageError::InvalidFormat);
+        }
+
+        let storage = sys::furi_record_open(c_str!("storage")) as *mut sys::Storage;
+        let file = sys::storage_file_alloc(storage);
+        if !sys::storage_file_open(file, path.as_ptr(), sys::FSAM_READ, sys::FSOM_OPEN_EXISTING) {
+            let err = storage_error(storage, ImageError::OpenFailed);
+            sys::storage_file_free(file);
+            sys::furi_record_close(c_str!("storage"));
+            return Err(err);
+        }
+        let reader = match format {
+            FileFormat::Xbm => XbmReader::new(file).map(|r| Box::new(r) as Box<dyn RowReader>),
+            _ => NetpbmReader::new(file).map(|r| Box::new(r) as Box<dyn RowReader>),
+        };
+        match reader {
+            Ok(reader) => Ok((file, storage, reader)),
+            Err(e) => {
+                close_image_file(file, storage);
+                Err(e)
             }
+        }
+    }
+}
 
-            // Determine output row based on orientation
-            let out_row = if bottom_up {
-                DISPLAY_HEIGHT - 1 - row
-            } else {
-                row
+/// An image file packed for the tag only as far as the protocol has asked
+///
+/// Holds one source row, one packed row and the bytes of the packet being
+/// sent instead of the whole packed frame, so a write needs a fraction of
+/// the heap `load_file` does. Only tags whose RAM rows are the image's rows
+/// can be streamed: see `supports`.
+pub struct FileStream {
+    file: *mut sys::File,
+    storage: *mut sys::Storage,
+    reader: Box<dyn RowReader>,
+    resampler: Resampler,
+    quantizer: Box<dyn Quantizer>,
+    packer: Box<dyn Packer>,
+    pixels: Vec<Rgb>,
+    colors: Vec<u8>,
+    /// Bytes per packed row
+    stride: usize,
+    /// Packed bytes from `start` on that have been produced
+    window: Vec<u8>,
+    start: usize,
+    /// Source rows read so far
+    rows_read: usize,
+    /// Background rows after the image have been added
+    finished: bool,
+    size: usize,
+}
+
+impl FileStream {
+    /// Whether images for `tag` decoded with `options` can be streamed
+    ///
+    /// Turned and quarter-turned images need every row before the first
+    /// packed one, and mirrored panels want the last row first. Rows are
+    /// packed one at a time as row 0, so only row-major frames come out
+    /// right.
+    pub fn supports(tag: &TagType, options: DecodeOptions) -> bool {
+        tag.image_format == ImageFormat::Bwry
+            && !tag.needs_rotation()
+            && tag.scan != ScanDirection::Reversed
+            && tag.scan_order == ScanOrder::RowMajor
+            && options.rotation == Rotation::None
+    }
+
+    /// Open the image file at `path` for streaming to `tag`
+    ///
+    /// Fails like `load_file` would on an unreadable or misfit file;
+    /// packed files and tags `supports` refuses give `InvalidFormat`.
+    pub fn open(path: &CStr, tag: &TagType, options: DecodeOptions) -> ImageResult<Self> {
+        if !Self::supports(tag, options) {
+            return Err(ImageError::InvalidFormat);
+        }
+        unsafe {
+            let (file, storage, reader) = open_rows(path)?;
+            let source = reader.size();
+            if let Err(err) = check_size(source, tag, options) {
+                close_image_file(file, storage);
+                return Err(err);
+            }
+            log_info!("Streaming {}x{} image", source.0, source.1);
+            let target = (tag.width, tag.height);
+            let packer = pack::for_tag(tag);
+            let size = packer.buffer_size();
+            let stride = size / tag.height;
+            Ok(Self {
+                file,
+                storage,
+                reader,
+                resampler: Resampler::new(source, target, options.fit, false),
+                quantizer: options.build_quantizer(packer.palette(), tag.width),
+                pixels: vec![Rgb::new(255, 255, 255); source.0],
+                colors: vec![0u8; tag.width],
+                stride,
+                window: Vec::new(),
+                start: 0,
+                rows_read: 0,
+                finished: false,
+                size,
+                packer,
+            })
+        }
+    }
+
+    /// Produce packed bytes until the window reaches `end`
+    fn fill_to(&mut self, end: usize) -> ImageResult<()> {
+        while self.start + self.window.len() < end {
+            let Self { reader, resampler, quantizer, packer, pixels, colors, window, .. } = self;
+            let stride = self.stride;
+            let mut emit = |_y: usize, row: &[Rgb]| -> ImageResult<()> {
+                for (x, (color, rgb)) in colors.iter_mut().zip(row).enumerate() {
+                    *color = quantizer.quantize(x, *rgb);
+                }
+                quantizer.next_row();
+                let at = window.len();
+                window.resize(at + stride, 0);
+                packer.pack_row(&mut window[at..], 0, colors);
+                Ok(())
             };
+            if self.rows_read < reader.size().1 {
+                unsafe { reader.read_row(pixels)? };
+                resampler.push(self.rows_read, pixels, &mut emit)?;
+                self.rows_read += 1;
+            } else if !self.finished {
+                resampler.finish(&mut emit)?;
+                self.finished = true;
+            } else {
+                return Err(ImageError::ReadFailed);
+            }
+        }
+        Ok(())
+    }
+}
 
-            // Pack 8 pixels per byte (1 bit each, MSB first)
-            let bytes_per_row = DISPLAY_WIDTH / 8; // 25 bytes
-            for x_byte in 0..bytes_per_row {
-                let mut bw_byte: u8 = 0;
-                let mut red_byte: u8 = 0;
+impl ImageStream for FileStream {
+    fn size(&self) -> usize {
+        self.size
+    }
 
-                for bit in 0..8 {
-                    let x = x_byte * 8 + bit;
-                    let palette_idx = row_buffer[x] as usize;
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> bool {
+        // Bytes before the packet being sent are done with; a resend of it
+        // reads the same offset again
+        let Some(skip) = offset.checked_sub(self.start) else {
+            return false;
+        };
+        let skip = skip.min(self.window.len());
+        self.window.drain(..skip);
+        self.start += skip;
+        if let Err(err) = self.fill_to(offset + buf.len()) {
+            log_info!("Streaming stopped at byte {}: {:?}", offset, err);
+            return false;
+        }
+        let from = offset - self.start;
+        buf.copy_from_slice(&self.window[from..from + buf.len()]);
+        true
+    }
+}
 
-                    bw_byte <<= 1;
-                    red_byte <<= 1;
+impl Drop for FileStream {
+    fn drop(&mut self) {
+        unsafe { close_image_file(self.file, self.storage) };
+    }
+}
 
-                    if bw_map[palette_idx] {
-                        bw_byte |= 1; // White pixel
-                    }
-                    if red_map[palette_idx] {
-                        red_byte |= 1; // Red pixel
+/// Palette index of black in every packer palette
+pub const BLACK: u8 = 0;
+/// Palette index of white in every packer palette
+pub const WHITE: u8 = 1;
+
+/// Draw an image for `tag` row by row, top to bottom
+///
+/// `paint` gets each row's y and its palette indices, all white to start
+/// with, and marks what it needs. For images made in memory rather than
+/// decoded from a file (text, codes, fills).
+pub fn render(
+    tag: &TagType,
+    observer: &mut dyn DecodeObserver,
+    paint: &mut dyn FnMut(usize, &mut [u8]),
+) -> ImageResult<AnyImage> {
+    let packer = pack::for_tag(tag);
+    let mut data = vec![0u8; packer.buffer_size()].into_boxed_slice();
+    let mut orienter = Orienter::new(tag, &*packer, Rotation::None);
+    let mut colors = vec![WHITE; tag.width];
+
+    for y in 0..tag.height {
+        colors.fill(WHITE);
+        paint(y, &mut colors);
+        orienter.push_row(&mut data, y, &colors);
+
+        if !observer.on_row(y, &colors) {
+            return Err(ImageError::Cancelled);
+        }
+    }
+    orienter.finish(&mut data);
+
+    Ok(into_image(tag, data))
+}
+
+/// Fill a tag's whole display with one color, as for clearing it
+///
+/// `color` must be in the tag's palette (black, white and red always are).
+pub fn load_solid(
+    tag: &TagType,
+    color: Rgb,
+    observer: &mut dyn DecodeObserver,
+) -> ImageResult<AnyImage> {
+    let index = pack::for_tag(tag)
+        .palette()
+        .iter()
+        .position(|&c| c == color)
+        .ok_or(ImageError::InvalidFormat)?;
+    render(tag, observer, &mut |_, colors| colors.fill(index as u8))
+}
+
+/// Horizontal placement of each line of rendered text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+impl TextAlign {
+    /// The alignment after this one, for cycling through them in a menu
+    pub fn next(self) -> Self {
+        match self {
+            TextAlign::Left => TextAlign::Center,
+            TextAlign::Center => TextAlign::Right,
+            TextAlign::Right => TextAlign::Left,
+        }
+    }
+
+    /// Alignment for a name in a file (`left`, `center` or `right`)
+    pub fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "left" => Some(TextAlign::Left),
+            "center" => Some(TextAlign::Center),
+            "right" => Some(TextAlign::Right),
+            _ => None,
+        }
+    }
+}
+
+/// How text is laid out (`TextBlock`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TextStyle {
+    /// Panel pixels per font dot; None picks the largest that fits
+    pub scale: Option<usize>,
+    pub align: TextAlign,
+}
+
+/// Largest font scale offered
+pub const MAX_TEXT_SCALE: usize = 8;
+/// Blank panel pixels kept along every edge, which bezels tend to cover
+const TEXT_MARGIN: usize = 4;
+/// Font dots between characters and between lines
+const CHAR_GAP: usize = 1;
+const LINE_GAP: usize = 2;
+
+/// Break `text` into lines of at most `columns` characters, at spaces where
+/// possible; words longer than a line are split
+fn wrap_text(text: &str, columns: usize) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let fits = rest.char_indices().nth(columns).map_or(rest.len(), |(i, _)| i);
+        let end = if fits == rest.len() || rest[fits..].starts_with(' ') {
+            fits
+        } else {
+            rest[..fits].rfind(' ').unwrap_or(fits)
+        };
+        lines.push(rest[..end].trim_end());
+        rest = rest[end..].trim_start();
+    }
+    lines
+}
+
+/// Wrap `text` at `scale` for an area of `width` x `height`, if it fits
+fn layout_text(text: &str, scale: usize, width: usize, height: usize) -> Option<Vec<&str>> {
+    let columns = (width + CHAR_GAP * scale) / ((GLYPH_WIDTH + CHAR_GAP) * scale);
+    if columns == 0 {
+        return None;
+    }
+    let lines = wrap_text(text, columns);
+    let pitch = (GLYPH_HEIGHT + LINE_GAP) * scale;
+    (lines.len() * pitch - LINE_GAP * scale <= height).then_some(lines)
+}
+
+/// Text word-wrapped into a box of the panel, drawn a row at a time
+pub struct TextBlock<'a> {
+    lines: Vec<&'a str>,
+    /// Left edge of each line
+    lefts: Vec<usize>,
+    top: usize,
+    scale: usize,
+}
+
+impl<'a> TextBlock<'a> {
+    /// Lay `text` out in `area`, centered vertically
+    ///
+    /// None if the text is empty or doesn't fit at the style's scale.
+    pub fn fit(text: &'a str, style: TextStyle, area: Rect) -> Option<Self> {
+        if text.trim().is_empty() {
+            return None;
+        }
+        let at_scale = |scale| Some((scale, layout_text(text, scale, area.width, area.height)?));
+        let (scale, lines) = match style.scale {
+            Some(scale) => at_scale(scale),
+            None => (1..=MAX_TEXT_SCALE).rev().find_map(at_scale),
+        }?;
+
+        let advance = (GLYPH_WIDTH + CHAR_GAP) * scale;
+        let pitch = (GLYPH_HEIGHT + LINE_GAP) * scale;
+        let top = area.top + (area.height - (lines.len() * pitch - LINE_GAP * scale)) / 2;
+        let lefts = lines
+            .iter()
+            .map(|line| {
+                let line_w = line.chars().count() * advance - CHAR_GAP * scale;
+                area.left
+                    + match style.align {
+                        TextAlign::Left => 0,
+                        TextAlign::Center => (area.width - line_w) / 2,
+                        TextAlign::Right => area.width - line_w,
                     }
-                }
+            })
+            .collect();
+        Some(Self { lines, lefts, top, scale })
+    }
+
+    /// Panel pixels per font dot
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Number of wrapped lines
+    pub fn lines(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Panel rows from the top of the first line to the bottom of the last
+    fn height(&self) -> usize {
+        self.lines.len() * (GLYPH_HEIGHT + LINE_GAP) * self.scale - LINE_GAP * self.scale
+    }
 
-                // B/W buffer: first 5000 bytes
-                data[out_row * bytes_per_row + x_byte] = bw_byte;
-                // Red buffer: second 5000 bytes
-                data[5000 + out_row * bytes_per_row + x_byte] = red_byte;
+    /// Mark the text's dots on panel row `y` in `color`
+    pub fn paint(&self, y: usize, colors: &mut [u8], color: u8) {
+        let scale = self.scale;
+        let pitch = (GLYPH_HEIGHT + LINE_GAP) * scale;
+        let Some(offset) = y.checked_sub(self.top) else {
+            return;
+        };
+        let (index, dot_y) = (offset / pitch, offset % pitch / scale);
+        let Some(line) = self.lines.get(index).filter(|_| dot_y < GLYPH_HEIGHT) else {
+            return;
+        };
+        let advance = (GLYPH_WIDTH + CHAR_GAP) * scale;
+        for (i, c) in line.chars().enumerate() {
+            let left = self.lefts[index] + i * advance;
+            for dot_x in (0..GLYPH_WIDTH).filter(|&dot_x| font::dot(c, dot_x, dot_y)) {
+                colors[left + dot_x * scale..left + (dot_x + 1) * scale].fill(color);
             }
         }
+    }
+}
 
-        close_bmp_file(file, storage);
+/// Largest caption scale offered
+pub const MAX_CAPTION_SCALE: usize = 4;
 
-        Ok(Image {
-            data,
-            _marker: PhantomData,
-        })
+/// Short text drawn over a decoded image, such as a date stamp on a photo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caption {
+    pub text: String,
+    /// Corner, edge or middle of the image the text sits at
+    pub anchor: Anchor,
+    /// Palette index of the text
+    pub color: u8,
+    /// Panel pixels per font dot
+    pub scale: usize,
+}
+
+impl Caption {
+    /// Lay the caption out on an image of `size`, inside the margin
+    fn layout(&self, size: (usize, usize)) -> Option<TextBlock<'_>> {
+        let area = Rect {
+            left: TEXT_MARGIN,
+            top: TEXT_MARGIN,
+            width: size.0.saturating_sub(2 * TEXT_MARGIN),
+            height: size.1.saturating_sub(2 * TEXT_MARGIN),
+        };
+        let (x, y) = self.anchor.position();
+        let align = [TextAlign::Left, TextAlign::Center, TextAlign::Right][x];
+        let style = TextStyle { scale: Some(self.scale), align };
+        let mut block = TextBlock::fit(&self.text, style, area)?;
+        block.top = area.top + (area.height - block.height()) * y / 2;
+        Some(block)
+    }
+
+    /// Whether the caption fits on an image of `size`
+    pub fn fits(&self, size: (usize, usize)) -> bool {
+        self.layout(size).is_some()
     }
 }
 
-/// Load a BMP file with runtime format selection
-pub fn load_bmp(path: *const c_char, format: ImageFormat) -> ImageResult<AnyImage> {
-    match format {
-        ImageFormat::Bwry => Ok(AnyImage::Bwry(load_bmp_bwry(path)?)),
-        ImageFormat::Bwr => Ok(AnyImage::Bwr(load_bmp_bwr(path)?)),
+/// Render `text` in black on white across a tag
+///
+/// Lines are word-wrapped to the panel and the block is centered vertically.
+/// Fails with `InvalidSize` if the text is empty or doesn't fit at the
+/// chosen scale.
+pub fn load_text(
+    text: &str,
+    tag: &TagType,
+    style: TextStyle,
+    observer: &mut dyn DecodeObserver,
+) -> ImageResult<AnyImage> {
+    let area = Rect {
+        left: TEXT_MARGIN,
+        top: TEXT_MARGIN,
+        width: tag.width.saturating_sub(2 * TEXT_MARGIN),
+        height: tag.height.saturating_sub(2 * TEXT_MARGIN),
+    };
+    let block = TextBlock::fit(text, style, area).ok_or(ImageError::InvalidSize)?;
+    log_info!("Text at {}x in {} lines", block.scale(), block.lines());
+
+    render(tag, observer, &mut |y, colors| block.paint(y, colors, BLACK))
+}
+
+/// How a captured screen is drawn on a tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScreenStyle {
+    /// Blow the screen up by a whole factor, so every LCD pixel becomes an
+    /// equal square, rather than filling as much of the panel as it can
+    pub whole_pixels: bool,
+    /// Lit pixels white on black instead of black on white
+    pub invert: bool,
+}
+
+/// Render a captured Flipper screen onto a tag
+///
+/// The 128x64 frame is scaled (nearest neighbour, aspect preserved) to the
+/// largest size that fits the panel, or by the largest whole factor with
+/// `ScreenStyle::whole_pixels`, and centered. Lit LCD pixels become black
+/// on white, or white on black if inverted. Panels too small for a whole
+/// factor get the plain fit.
+pub fn load_screen(
+    frame: &Frame,
+    tag: &TagType,
+    style: ScreenStyle,
+    observer: &mut dyn DecodeObserver,
+) -> ImageResult<AnyImage> {
+    let (width, height) = (tag.width, tag.height);
+
+    // Fit the 2:1 screen into the panel
+    let factor = (width / SCREEN_WIDTH).min(height / SCREEN_HEIGHT);
+    let (scaled_w, scaled_h) = if style.whole_pixels && factor > 0 {
+        (SCREEN_WIDTH * factor, SCREEN_HEIGHT * factor)
+    } else if width * SCREEN_HEIGHT <= height * SCREEN_WIDTH {
+        (width, width * SCREEN_HEIGHT / SCREEN_WIDTH)
+    } else {
+        (height * SCREEN_WIDTH / SCREEN_HEIGHT, height)
+    };
+    if scaled_w == 0 || scaled_h == 0 {
+        return Err(ImageError::InvalidSize);
     }
+    let left = (width - scaled_w) / 2;
+    let top = (height - scaled_h) / 2;
+    let (ink, paper) = if style.invert { (WHITE, BLACK) } else { (BLACK, WHITE) };
+    log_info!("Screen at {}x{} on {}x{}", scaled_w, scaled_h, width, height);
+
+    render(tag, observer, &mut |y, colors| {
+        colors.fill(paper);
+        if (top..top + scaled_h).contains(&y) {
+            let src_y = (y - top) * SCREEN_HEIGHT / scaled_h;
+            for (x, color) in colors[left..left + scaled_w].iter_mut().enumerate() {
+                let src_x = x * SCREEN_WIDTH / scaled_w;
+                if frame.pixel(src_x, src_y) {
+                    *color = ink;
+                }
+            }
+        }
+    })
 }
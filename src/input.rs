@@ -0,0 +1,56 @@
+//! Left-handed key mapping
+//!
+//! With a tag held against the Flipper's back, the free thumb reaches the
+//! d-pad from the other side. The left-handed setting swaps OK with Back and
+//! Up with Down in the app's own interactive views, and their on-screen hints
+//! name the swapped keys. Firmware views (menus, result widgets) handle their
+//! own input and keep the standard layout.
+
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use flipperzero_sys as sys;
+
+/// Set while the left-handed mapping is on
+static LEFT_HANDED: AtomicBool = AtomicBool::new(false);
+
+/// Turn the left-handed mapping on or off
+pub fn set_left_handed(enabled: bool) {
+    LEFT_HANDED.store(enabled, Ordering::Relaxed);
+}
+
+/// Logical key for a physical key press
+pub fn remap(key: sys::InputKey) -> sys::InputKey {
+    if !LEFT_HANDED.load(Ordering::Relaxed) {
+        return key;
+    }
+    if key == sys::InputKeyOk {
+        sys::InputKeyBack
+    } else if key == sys::InputKeyBack {
+        sys::InputKeyOk
+    } else if key == sys::InputKeyUp {
+        sys::InputKeyDown
+    } else if key == sys::InputKeyDown {
+        sys::InputKeyUp
+    } else {
+        key
+    }
+}
+
+/// Name of the physical key that produces logical `key`, for hints
+pub fn label(key: sys::InputKey) -> &'static CStr {
+    // The mapping is its own inverse
+    let physical = remap(key);
+    if physical == sys::InputKeyOk {
+        c"OK"
+    } else if physical == sys::InputKeyBack {
+        c"Back"
+    } else if physical == sys::InputKeyUp {
+        c"Up"
+    } else if physical == sys::InputKeyDown {
+        c"Down"
+    } else if physical == sys::InputKeyLeft {
+        c"Left"
+    } else {
+        c"Right"
+    }
+}
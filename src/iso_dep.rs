@@ -0,0 +1,283 @@
+//! ISO 14443-4 block layer (ISO-DEP)
+//!
+//! `PollerTransport` selects tags with the HAL's ISO 14443-3A poller and
+//! frames the 14443-4 blocks itself. The HAL's 14443-4 poller only pairs
+//! one I-block with one reply, so it can neither chain a long command nor
+//! answer a tag that asks for more time: e-ink tags may hold their reply
+//! to a refresh command with S(WTX) requests until the panel has finished.
+//!
+//! No CID or NAD is used, so every block is a PCB byte plus payload.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use flipperzero_sys as sys;
+
+use crate::protocol_common::{self, hex_bytes, log_error, log_info};
+
+/// RATS: start byte, FSDI 5 (frames of up to 64 bytes to us), CID 0
+const RATS: [u8; 2] = [0xE0, 0x50];
+
+/// Wait for the ATS: FWT at FWI 4 (about 4.8 ms)
+const ATS_FWT_FC: u32 = 65_536;
+
+/// Frame size by FSCI; higher values are RFU and read as 256
+const FRAME_SIZES: [usize; 9] = [16, 24, 32, 40, 48, 64, 96, 128, 256];
+
+/// FSCI of a tag whose ATS has no format byte
+const DEFAULT_FSCI: usize = 2;
+
+/// FWI of a tag whose ATS has no TB(1)
+const DEFAULT_FWI: u8 = 4;
+
+/// Largest valid FWI; also the cap for waits extended by WTX
+const MAX_FWI: u8 = 14;
+
+/// Bytes of a frame that are not block payload: PCB and two CRC bytes
+const BLOCK_OVERHEAD: usize = 3;
+
+/// Protocol control byte values and fields
+mod pcb {
+    pub const I_BLOCK: u8 = 0x02;
+    pub const R_ACK: u8 = 0xA2;
+    pub const S_DESELECT: u8 = 0xC2;
+    /// Block type field: I-block, R-block or S-block
+    pub const TYPE: u8 = 0xC0;
+    pub const TYPE_I: u8 = 0x00;
+    pub const TYPE_R: u8 = 0x80;
+    pub const TYPE_S: u8 = 0xC0;
+    /// I-block: more of the same command or response follows
+    pub const CHAINING: u8 = 0x10;
+    /// R-block: negative acknowledgement
+    pub const NAK: u8 = 0x10;
+    /// S-block: waiting time extension rather than DESELECT
+    pub const WTX: u8 = 0x30;
+    /// INF byte of S(WTX): the multiplier
+    pub const WTXM: u8 = 0x3F;
+    pub const BLOCK_NUMBER: u8 = 0x01;
+}
+
+/// Frame waiting time for `fwi`, in carrier cycles
+fn fwt_fc(fwi: u8) -> u32 {
+    (256 * 16) << fwi.min(MAX_FWI)
+}
+
+/// Carrier cycles in milliseconds, for the log
+fn fc_ms(fc: u32) -> u32 {
+    fc / 13_560
+}
+
+/// Historical bytes of an ATS: what follows TL, T0 and the interface
+/// bytes T0 announces
+pub fn historical_bytes(ats: &[u8]) -> Vec<u8> {
+    let Some(&t0) = ats.get(1) else {
+        return Vec::new();
+    };
+    let interface = ((t0 >> 4) & 0x07).count_ones() as usize;
+    let end = (ats[0] as usize).min(ats.len());
+    ats.get(2 + interface..end).map(<[u8]>::to_vec).unwrap_or_default()
+}
+
+/// Bytes of `buf`, starting at `from`
+unsafe fn bytes(buf: *const sys::BitBuffer, from: usize) -> Vec<u8> {
+    unsafe {
+        let size = sys::bit_buffer_get_size_bytes(buf);
+        (from..size).map(|i| sys::bit_buffer_get_byte(buf, i)).collect()
+    }
+}
+
+/// Block layer state for one activated tag
+#[derive(Debug, Clone)]
+pub struct IsoDep {
+    /// Current block number (0 or 1)
+    block_number: u8,
+    /// Largest frame the tag accepts (FSC)
+    frame_size: usize,
+    /// Frame waiting time from the ATS, in carrier cycles
+    fwt_fc: u32,
+    /// How long the tag's WTX requests held the last exchange
+    held_ms: u32,
+    /// Historical bytes of the ATS
+    historical: Vec<u8>,
+}
+
+impl IsoDep {
+    /// Settings for a tag that announced nothing: 32-byte frames, short FWT
+    pub const DEFAULT: Self = Self {
+        block_number: 0,
+        frame_size: FRAME_SIZES[DEFAULT_FSCI],
+        fwt_fc: ATS_FWT_FC,
+        held_ms: 0,
+        historical: Vec::new(),
+    };
+
+    /// Settings from an ATS: TL T0 [TA(1)] [TB(1)] [TC(1)] historical bytes
+    fn from_ats(ats: &[u8]) -> Self {
+        let mut fsci = DEFAULT_FSCI;
+        let mut fwi = DEFAULT_FWI;
+        if ats.first().is_some_and(|&tl| tl >= 2)
+            && let Some(&t0) = ats.get(1)
+        {
+            fsci = (t0 & 0x0F) as usize;
+            // TB(1) follows TA(1) if that is present
+            let tb = 2 + (t0 & 0x10 != 0) as usize;
+            if t0 & 0x20 != 0
+                && let Some(&tb1) = ats.get(tb)
+                && tb1 >> 4 <= MAX_FWI
+            {
+                fwi = tb1 >> 4;
+            }
+        }
+        Self {
+            frame_size: FRAME_SIZES[fsci.min(FRAME_SIZES.len() - 1)],
+            fwt_fc: fwt_fc(fwi),
+            historical: historical_bytes(ats),
+            ..Self::DEFAULT
+        }
+    }
+
+    /// Send RATS to the tag the poller has selected and set up the layer
+    /// from its ATS; `tx_buf` and `rx_buf` are scratch
+    pub unsafe fn activate(
+        poller: *mut sys::Iso14443_3aPoller,
+        tx_buf: *mut sys::BitBuffer,
+        rx_buf: *mut sys::BitBuffer,
+    ) -> Option<Self> {
+        unsafe {
+            sys::bit_buffer_reset(tx_buf);
+            sys::bit_buffer_reset(rx_buf);
+            sys::bit_buffer_copy_bytes(tx_buf, RATS.as_ptr(), RATS.len());
+            let error =
+                sys::iso14443_3a_poller_send_standard_frame(poller, tx_buf, rx_buf, ATS_FWT_FC);
+            if error != sys::Iso14443_3aErrorNone {
+                log_error!("RATS error code: {}", error.0);
+                return None;
+            }
+            let ats = bytes(rx_buf, 0);
+            let layer = Self::from_ats(&ats);
+            let fwt_ms = fc_ms(layer.fwt_fc);
+            log_info!("ATS {}: frames {} B, FWT {} ms", hex_bytes(&ats), layer.frame_size, fwt_ms);
+            Some(layer)
+        }
+    }
+
+    /// Most payload bytes one I-block carries
+    pub fn max_block(&self) -> usize {
+        self.frame_size - BLOCK_OVERHEAD
+    }
+
+    /// How long the tag held the last exchange with WTX requests; 0 if it
+    /// answered within its frame waiting time
+    pub fn held_ms(&self) -> u32 {
+        self.held_ms
+    }
+
+    /// Historical bytes the tag sent in its ATS
+    pub fn historical(&self) -> &[u8] {
+        &self.historical
+    }
+
+    /// Send `tx_buf` as one I-block, the chaining bit set if `chain`, and
+    /// put the payload of the tag's reply in `rx_buf`
+    ///
+    /// The tag's S(WTX) requests are answered and the next wait stretched
+    /// by the multiplier it asks for. A reply the tag chains over several
+    /// I-blocks is acknowledged piece by piece and put together. A chained
+    /// block is answered with R(ACK) and leaves `rx_buf` empty.
+    pub unsafe fn exchange(
+        &mut self,
+        poller: *mut sys::Iso14443_3aPoller,
+        frames: (*mut sys::BitBuffer, *mut sys::BitBuffer),
+        tx_buf: *const sys::BitBuffer,
+        rx_buf: *mut sys::BitBuffer,
+        chain: bool,
+    ) -> bool {
+        unsafe {
+            let (frame_tx, frame_rx) = frames;
+            let chaining = if chain { pcb::CHAINING } else { 0 };
+            sys::bit_buffer_reset(frame_tx);
+            sys::bit_buffer_append_byte(frame_tx, pcb::I_BLOCK | chaining | self.block_number);
+            for byte in bytes(tx_buf, 0) {
+                sys::bit_buffer_append_byte(frame_tx, byte);
+            }
+            sys::bit_buffer_reset(rx_buf);
+
+            self.held_ms = 0;
+            let mut held_since = None;
+            let mut fwt = self.fwt_fc;
+            loop {
+                sys::bit_buffer_reset(frame_rx);
+                let error =
+                    sys::iso14443_3a_poller_send_standard_frame(poller, frame_tx, frame_rx, fwt);
+                if error != sys::Iso14443_3aErrorNone {
+                    // Error codes: 0=None, 1=NotPresent, 3=Timeout, 4=WrongCrc
+                    log_error!("NFC send error code: {}", error.0);
+                    protocol_common::note_link_error(error.0 as u32);
+                    return false;
+                }
+                fwt = self.fwt_fc;
+                let reply = bytes(frame_rx, 0);
+                let Some(&pcb) = reply.first() else {
+                    log_error!("Empty block from tag");
+                    return false;
+                };
+                match pcb & pcb::TYPE {
+                    pcb::TYPE_S if pcb & pcb::WTX == pcb::WTX && reply.len() >= 2 => {
+                        // Same S(WTX) back, then wait as many FWTs as asked
+                        let wtxm = (reply[1] & pcb::WTXM).max(1);
+                        sys::bit_buffer_reset(frame_tx);
+                        sys::bit_buffer_append_byte(frame_tx, pcb);
+                        sys::bit_buffer_append_byte(frame_tx, wtxm);
+                        fwt = self.fwt_fc.saturating_mul(wtxm as u32).min(fwt_fc(MAX_FWI));
+                        held_since.get_or_insert(sys::furi_get_tick());
+                    }
+                    pcb::TYPE_R if chain => {
+                        if pcb & pcb::NAK != 0 || pcb & pcb::BLOCK_NUMBER != self.block_number {
+                            log_error!("Chained block not acknowledged (PCB {:02X})", pcb);
+                            return false;
+                        }
+                        self.block_number ^= 1;
+                        break;
+                    }
+                    pcb::TYPE_I if !chain => {
+                        for &byte in &reply[1..] {
+                            sys::bit_buffer_append_byte(rx_buf, byte);
+                        }
+                        self.block_number ^= 1;
+                        if pcb & pcb::CHAINING == 0 {
+                            break;
+                        }
+                        // The tag chains its reply: ask for the next part
+                        sys::bit_buffer_reset(frame_tx);
+                        sys::bit_buffer_append_byte(frame_tx, pcb::R_ACK | self.block_number);
+                    }
+                    _ => {
+                        log_error!("Unexpected block from tag (PCB {:02X})", pcb);
+                        return false;
+                    }
+                }
+            }
+
+            if let Some(since) = held_since {
+                self.held_ms = sys::furi_get_tick().wrapping_sub(since);
+                log_info!("Tag held the reply for {} ms (WTX)", self.held_ms);
+            }
+            true
+        }
+    }
+
+    /// Send S(DESELECT) so the tag goes idle before the field is dropped;
+    /// the reply is not checked
+    pub unsafe fn deselect(
+        &self,
+        poller: *mut sys::Iso14443_3aPoller,
+        frames: (*mut sys::BitBuffer, *mut sys::BitBuffer),
+    ) {
+        unsafe {
+            let (frame_tx, frame_rx) = frames;
+            sys::bit_buffer_reset(frame_tx);
+            sys::bit_buffer_append_byte(frame_tx, pcb::S_DESELECT);
+            sys::iso14443_3a_poller_send_standard_frame(poller, frame_tx, frame_rx, self.fwt_fc);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Session log ring buffer, its CLI command and the log filters
+//!
+//! Every `log_info!`/`log_error!` line is also kept here, so the protocol log
+//! of the last write can be copied over the CLI after the fact instead of
+//! reproducing the problem with `log` streaming. The firmware already owns
+//! the `log` command; the buffer is printed by `eink_log` (and emptied by
+//! `eink_log clear`) while the app is open.
+//!
+//! Info lines can be switched off per `LogModule` and the furi log tag can be
+//! changed, both from the debug keys in the settings file. Errors are never
+//! filtered.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ffi::{c_char, c_void, CStr};
+use core::sync::atomic::{AtomicU8, Ordering};
+use flipperzero_sys as sys;
+
+/// Ring capacity in bytes; the oldest lines are overwritten first
+const CAPACITY: usize = 4096;
+
+/// Longest message kept per line (bytes, before the prefix)
+const MAX_LINE: usize = 120;
+
+/// CLI command name
+const COMMAND: &CStr = c"eink_log";
+
+/// Default furi log tag
+const DEFAULT_TAG: &[u8] = b"EINK_NFC";
+
+/// Log tag buffer size (including NUL)
+const TAG_LEN: usize = 16;
+
+/// Source areas whose info lines can be filtered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogModule {
+    /// Menus and app flow (`main`)
+    Ui,
+    /// BMP decoding, quantizing and packing
+    Image,
+    /// Tag protocol state machines
+    Protocol,
+    /// NFC front ends and the alignment meter
+    Reader,
+    /// Settings, stats and other SD card files
+    Storage,
+}
+
+impl LogModule {
+    pub const ALL: [LogModule; 5] = [
+        LogModule::Ui,
+        LogModule::Image,
+        LogModule::Protocol,
+        LogModule::Reader,
+        LogModule::Storage,
+    ];
+
+    /// Name used in the settings file (`log.<key>`)
+    pub fn key(self) -> &'static str {
+        match self {
+            LogModule::Ui => "ui",
+            LogModule::Image => "image",
+            LogModule::Protocol => "protocol",
+            LogModule::Reader => "reader",
+            LogModule::Storage => "storage",
+        }
+    }
+
+    /// Area of the source module at `path` (from `module_path!()`)
+    pub fn of(path: &str) -> Self {
+        match path.rsplit("::").next().unwrap_or("") {
+            "image" | "quantize" | "resample" | "pack" | "preview" | "screen" => LogModule::Image,
+            "controller" | "engine" | "apdu_script" => LogModule::Protocol,
+            "transport" | "iso_dep" | "identify" | "pn532" | "align" | "nfc_guard" => LogModule::Reader,
+            "storage" | "settings" | "stats" => LogModule::Storage,
+            name if name.starts_with("protocol_") => LogModule::Protocol,
+            _ => LogModule::Ui,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Enabled modules, one bit per `LogModule`
+static ENABLED: AtomicU8 = AtomicU8::new(0xFF);
+
+/// True if info lines from `module` should be logged
+pub fn enabled(module: LogModule) -> bool {
+    ENABLED.load(Ordering::Relaxed) & module.bit() != 0
+}
+
+/// Switch info lines from `module` on or off
+pub fn set_enabled(module: LogModule, on: bool) {
+    if on {
+        ENABLED.fetch_or(module.bit(), Ordering::Relaxed);
+    } else {
+        ENABLED.fetch_and(!module.bit(), Ordering::Relaxed);
+    }
+}
+
+/// NUL-terminated furi log tag
+struct Tag(UnsafeCell<[u8; TAG_LEN]>);
+
+// Written only by `set_tag` during app init, before other threads log
+unsafe impl Sync for Tag {}
+
+static TAG: Tag = Tag(UnsafeCell::new(default_tag()));
+
+const fn default_tag() -> [u8; TAG_LEN] {
+    let mut buf = [0u8; TAG_LEN];
+    let mut i = 0;
+    while i < DEFAULT_TAG.len() {
+        buf[i] = DEFAULT_TAG[i];
+        i += 1;
+    }
+    buf
+}
+
+/// Furi log tag for the log macros
+pub fn tag() -> *const c_char {
+    TAG.0.get() as *const c_char
+}
+
+/// Change the furi log tag; empty or invalid tags restore the default
+///
+/// Call during init only, before any other thread logs.
+pub unsafe fn set_tag(tag: &str) {
+    let valid = !tag.is_empty()
+        && tag.len() < TAG_LEN
+        && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+    let mut buf = default_tag();
+    if valid {
+        buf = [0u8; TAG_LEN];
+        buf[..tag.len()].copy_from_slice(tag.as_bytes());
+    }
+    unsafe {
+        *TAG.0.get() = buf;
+    }
+}
+
+/// Byte ring shared by every thread that logs
+struct Ring {
+    buf: [u8; CAPACITY],
+    /// Next byte to write
+    head: usize,
+    /// Bytes in use
+    len: usize,
+}
+
+impl Ring {
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[self.head] = byte;
+            self.head = (self.head + 1) % CAPACITY;
+        }
+        self.len = (self.len + bytes.len()).min(CAPACITY);
+    }
+
+    /// Append the contents to `out`, oldest first; true if the ring has wrapped
+    fn copy_into(&self, out: &mut Vec<u8>) -> bool {
+        let start = (self.head + CAPACITY - self.len) % CAPACITY;
+        out.extend((0..self.len).map(|i| self.buf[(start + i) % CAPACITY]));
+        self.len == CAPACITY
+    }
+}
+
+/// The ring, guarded by furi critical sections
+struct Shared(UnsafeCell<Ring>);
+
+// Only touched inside a critical section
+unsafe impl Sync for Shared {}
+
+static RING: Shared = Shared(UnsafeCell::new(Ring {
+    buf: [0; CAPACITY],
+    head: 0,
+    len: 0,
+}));
+
+/// Run `f` on the ring with interrupts and the scheduler held off
+fn with_ring<R>(f: impl FnOnce(&mut Ring) -> R) -> R {
+    unsafe {
+        let info = sys::__furi_critical_enter();
+        let result = f(&mut *RING.0.get());
+        sys::__furi_critical_exit(info);
+        result
+    }
+}
+
+/// Append one log line: level letter, tick, message
+pub fn push(level: char, msg: &str) {
+    let tick = unsafe { sys::furi_get_tick() };
+    let prefix = alloc::format!("{} {:>8} ", level, tick);
+    let mut end = msg.len().min(MAX_LINE);
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    with_ring(|ring| {
+        ring.push_bytes(prefix.as_bytes());
+        ring.push_bytes(&msg.as_bytes()[..end]);
+        ring.push_bytes(b"\n");
+    });
+}
+
+/// Drop every buffered line
+pub fn clear() {
+    with_ring(|ring| {
+        ring.head = 0;
+        ring.len = 0;
+    });
+}
+
+/// Buffered lines, oldest first
+pub fn snapshot() -> Vec<u8> {
+    // Allocate up front; the copy runs with the scheduler held off
+    let mut out = Vec::with_capacity(CAPACITY);
+    if with_ring(|ring| ring.copy_into(&mut out)) {
+        // The first line was partly overwritten
+        let cut = out.iter().position(|&b| b == b'\n').map_or(out.len(), |i| i + 1);
+        out.drain(..cut);
+    }
+    out
+}
+
+/// Register the `eink_log` CLI command
+pub unsafe fn register_cli() {
+    unsafe {
+        let cli = sys::furi_record_open(c"cli".as_ptr()) as *mut sys::CliRegistry;
+        sys::cli_registry_add_command(
+            cli,
+            COMMAND.as_ptr(),
+            sys::CliCommandFlagParallelSafe,
+            Some(cli_callback),
+            core::ptr::null_mut(),
+        );
+        sys::furi_record_close(c"cli".as_ptr());
+    }
+}
+
+/// Remove the `eink_log` CLI command
+pub unsafe fn unregister_cli() {
+    unsafe {
+        let cli = sys::furi_record_open(c"cli".as_ptr()) as *mut sys::CliRegistry;
+        sys::cli_registry_delete_command(cli, COMMAND.as_ptr());
+        sys::furi_record_close(c"cli".as_ptr());
+    }
+}
+
+/// `eink_log [clear]`: print (or empty) the session log
+unsafe extern "C" fn cli_callback(pipe: *mut sys::PipeSide, args: *mut sys::FuriString, _context: *mut c_void) {
+    unsafe {
+        let args = CStr::from_ptr(sys::furi_string_get_cstr(args));
+        if args.to_bytes().trim_ascii() == b"clear" {
+            clear();
+            return;
+        }
+
+        let text = snapshot();
+        if text.is_empty() {
+            let empty = b"(log is empty)\r\n";
+            sys::pipe_send(pipe, empty.as_ptr() as *const c_void, empty.len());
+            return;
+        }
+        // Terminals want CRLF
+        for line in text.split_inclusive(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            sys::pipe_send(pipe, line.as_ptr() as *const c_void, line.len());
+            sys::pipe_send(pipe, b"\r\n".as_ptr() as *const c_void, 2);
+        }
+    }
+}
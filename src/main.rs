@@ -9,20 +9,92 @@
 extern crate alloc;
 extern crate flipperzero_alloc;
 
+mod align;
+mod apdu_script;
+mod batch;
+mod cache;
+mod checkpoint;
+mod compose;
+mod container;
+mod controller;
+mod eink_protocol;
+mod engine;
+mod font;
+mod identify;
 mod image;
+mod input;
+mod iso_dep;
+mod log_ring;
+mod netpbm;
+mod nfc_guard;
+mod pack;
+mod pn532;
+mod preview;
 mod protocol_bwry;
 mod protocol_common;
+mod protocol_esl;
+mod protocol_info;
 mod protocol_genb;
+mod protocol_ntag;
+mod protocol_st25dv;
+mod quantize;
+mod recipe;
+mod report;
+mod resample;
+mod screen;
+mod sequence;
+mod settings;
+mod sidecar;
+mod stats;
+mod storage;
 mod tag_type;
+mod template;
+mod threshold;
+mod transport;
+mod worker;
+mod xbm;
 
-use core::ffi::c_void;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::{c_void, CStr};
 use core::ptr::null_mut;
 
 use flipperzero_rt::{entry, manifest};
 use flipperzero_sys as sys;
 
-use image::AnyImage;
-use tag_type::{Protocol, TagType};
+use align::{Align, AlignOutcome};
+use apdu_script::ApduScript;
+use batch::{BatchError, BatchItem, BatchReport};
+use cache::CacheEntry;
+use checkpoint::Checkpoint;
+use container::Container;
+use controller::Window;
+use eink_protocol::{EinkProtocol, Front, Payload};
+use identify::{CheckedTransport, MatchedBy};
+use image::{
+    AnyImage, Caption, DecodeOptions, FileStream, ImageError, ImageResult, Overlay, Rotation,
+    ScreenStyle, TextAlign, TextStyle,
+};
+use preview::Preview;
+use quantize::{AccentPull, QuantizerKind, Rgb, Threshold, Tuning};
+use recipe::Recipe;
+use report::{ColorReport, ColorWarning};
+use compose::Symbology;
+use resample::{Anchor, Background, Filter, Fit};
+use screen::{Frame, ScreenCapture};
+use sequence::{Sequence, DWELL_PRESETS};
+use sidecar::ImageOptions;
+use pn532::Pn532;
+use protocol_common::{hex_bytes, log_info, ImageStream, NfcResult, RetryMap};
+use settings::{BrowseRoot, ReaderKind, Settings};
+use stats::Stats;
+use tag_type::{ImageFormat, Protocol, ScanOrder, TagType};
+use template::Template;
+use threshold::{ThresholdOutcome, ThresholdView};
+use transport::{MockTransport, PollerTransport, Transport};
+use worker::Worker;
 
 // App manifest
 manifest!(
@@ -39,11 +111,228 @@ struct App {
     submenu: *mut sys::Submenu,
     tag_submenu: *mut sys::Submenu,
     write_submenu: *mut sys::Submenu,
+    fit_submenu: *mut sys::Submenu,
+    text_submenu: *mut sys::Submenu,
+    caption_submenu: *mut sys::Submenu,
     widget: *mut sys::Widget,
+    text_input: *mut sys::TextInput,
+    byte_input: *mut sys::ByteInput,
+    key_buf: protocol_common::AuthKey,
+    search_buf: [u8; SEARCH_LEN],
+    /// Text typed for Text to Tag, kept for the next label
+    label_buf: [u8; LABEL_LEN],
+    text_kind: TextKind,
+    text_style: TextStyle,
+    /// Template being filled in, and the answers to its fields so far
+    template: Option<Template>,
+    template_answers: Vec<String>,
+    field_buf: [u8; FIELD_LEN],
+    /// Caption drawn over the front image, and the one being edited
+    caption: Option<Caption>,
+    /// Keyed image laid over the front image (a frame or watermark)
+    overlay: Option<Overlay>,
+    caption_draft: Caption,
+    caption_buf: [u8; CAPTION_LEN],
+    /// The text input asks for a caption; Back returns to the write menu
+    caption_input: bool,
+    /// Last captured screen, kept so its style can be changed without
+    /// capturing again
+    screen_frame: Option<Box<Frame>>,
+    screen_style: ScreenStyle,
+    size_filter: Option<(usize, usize)>,
+    experimental_menu: bool,
+    /// The widget shows a command preview or color report; Back returns to
+    /// the write menu
+    plan_shown: bool,
+    preview: Preview,
+    align: Align,
+    threshold_view: ThresholdView,
     gui: *mut sys::Gui,
     selected_tag: Option<&'static TagType>,
     image_data: Option<AnyImage>,
+    /// File the front image was decoded from (None for screen captures)
+    image_path: Option<CString>,
+    rear_image: Option<AnyImage>,
+    /// Interrupted write saved on the SD card
+    resume: Option<Checkpoint>,
+    /// Tag type whose last full write is kept for Write Changes Only
+    partial_base: Option<&'static TagType>,
+    /// RAM window the next GenB write is limited to
+    partial_window: Option<Window>,
+    /// Numbered sequence being played, one frame per write
+    sequence: Option<Sequence>,
+    /// Wait between sequence frames (s), one of `DWELL_PRESETS`
+    sequence_dwell: u32,
+    /// Play the sequence again from its first frame when it ends
+    sequence_loop: bool,
+    target_face: Face,
+    quantizer: QuantizerKind,
+    rotation: Rotation,
+    /// Black/white split of the front image, set on the threshold screen
+    threshold: Threshold,
+    /// Push toward the accent colors, for panels that draw them weakly
+    accent_pull: AccentPull,
+    /// How the next decode treats an image of another size than the tag
+    fit: Fit,
+    /// How the current front image was fitted, for decoding it again
+    image_fit: Fit,
+    /// Image whose file details are shown, waiting for Convert
+    details_path: Option<CString>,
+    /// Tag type the last write's tag was recognised as, while the warning
+    /// that stopped it is shown
+    mismatch_tag: Option<&'static TagType>,
+    /// Write the next time without checking the tag against its record
+    skip_tag_check: bool,
+    /// The last write found no tag in time and Retry is offered
+    no_tag_prompt: bool,
+    /// Send the next write to a `MockTransport` instead of a tag
+    simulate: bool,
+    /// Image waiting for a choice in the fit menu, and its size
+    fit_path: Option<CString>,
+    fit_size: (usize, usize),
+    /// Part of an oversized image the fit menu's crop keeps
+    crop_anchor: Anchor,
+    /// Color the fit menu pads a small image with
+    pad_background: Background,
+    /// Fit from the selected image's sidecar, taken instead of the fit menu
+    remembered_fit: Option<Fit>,
+    /// Image file whose options are saved in a sidecar
+    remembered_path: Option<CString>,
+    image_source: ImageSource,
+    stats: Stats,
+    settings: Settings,
     current_view: u32,
+    decode_job: Option<Box<DecodeJob>>,
+    batch_job: Option<Box<BatchJob>>,
+    write_job: Option<Box<WriteJob>>,
+    worker: Option<Worker>,
+}
+
+/// Where the next image comes from once a tag has been chosen
+#[derive(Clone, Copy)]
+enum ImageSource {
+    /// BMP file picked in the file browser
+    File,
+    /// Capture of the Flipper's own screen
+    Screen,
+    /// One color over the whole display, made in memory
+    Fill(Rgb),
+    /// Typed text, rendered with the built-in font
+    Text,
+    /// Answers to the open template's fields
+    Template,
+    /// Every image in a folder, converted into the cache without writing
+    Folder,
+}
+
+/// What Text to Tag makes of the typed text
+#[derive(Clone, Copy, PartialEq)]
+enum TextKind {
+    /// The text itself, in the built-in font
+    Text,
+    /// A QR code holding it (URLs, Wi-Fi join strings)
+    Qr,
+    /// A barcode of it, printed underneath
+    Barcode(Symbology),
+}
+
+/// Which display of the selected tag a decoded image is for
+#[derive(Clone, Copy, PartialEq)]
+enum Face {
+    /// The only display, or the front of a dual-face badge
+    Front,
+    /// Second display of a dual-face badge
+    Rear,
+}
+
+/// Input for a decode job
+enum DecodeSource {
+    File(CString),
+    Screen(Box<ScreenCapture>),
+}
+
+/// Image decode running on the worker thread
+struct DecodeJob {
+    source: DecodeSource,
+    face: Face,
+    tag: &'static TagType,
+    options: DecodeOptions,
+    /// Only show the file in the preview; it is packed again when written
+    preview_only: bool,
+    /// Drawn over the image (front only)
+    caption: Option<Caption>,
+    /// Laid over the image under the caption (front only)
+    overlay: Option<Overlay>,
+    /// Where a file's conversion is cached, if it can be
+    cache: Option<CacheEntry>,
+    screen_style: ScreenStyle,
+    /// Frame a screen capture was rendered from
+    frame: Option<Box<Frame>>,
+    preview: *mut Preview,
+    view_dispatcher: *mut sys::ViewDispatcher,
+    /// The decoded image; none for a preview-only decode
+    result: Option<ImageResult<Option<AnyImage>>>,
+}
+
+/// Folder conversion running on the worker thread
+struct BatchJob {
+    tag: &'static TagType,
+    items: Vec<BatchItem>,
+    /// Part of every cache stamp, like the conversion note
+    tuning: Tuning,
+    preview: *mut Preview,
+    view_dispatcher: *mut sys::ViewDispatcher,
+    report: BatchReport,
+}
+
+/// Tag write running on the worker thread
+struct WriteJob {
+    tag: &'static TagType,
+    /// The tag's protocol, already given the checkpoint an ESL upload
+    /// starts from
+    proto: Box<dyn EinkProtocol>,
+    /// Front and rear image, taken from the app for the write and handed
+    /// back when it ends
+    front: Option<AnyImage>,
+    rear: Option<AnyImage>,
+    /// Image file packed as it is sent, instead of `front`
+    stream: Option<FileStream>,
+    partial_window: Option<Window>,
+    /// None for tags that are not IsoDep
+    transport: Option<CheckedTransport>,
+    simulate: bool,
+    /// Image bytes sent, for the timing stats
+    bytes: usize,
+    view_dispatcher: *mut sys::ViewDispatcher,
+    /// How long the protocol took
+    elapsed: u32,
+    /// Panel temperature read back by GenB tags
+    temperature: Option<i8>,
+    /// Protocol result and resent packets, once the write has run
+    result: Option<(NfcResult<()>, RetryMap)>,
+}
+
+impl WriteJob {
+    /// Send the image with the tag's protocol
+    ///
+    /// IsoDep writers also report which image packets needed resending,
+    /// GenB also the panel temperature.
+    fn run(&mut self) -> (NfcResult<()>, RetryMap) {
+        let front = match (self.stream.as_mut(), &self.front) {
+            (Some(stream), _) => Front::Streamed(stream),
+            (None, front) => Front::Packed(front.as_ref().map_or(&[][..], AnyImage::as_slice)),
+        };
+        let rear = self.rear.as_ref().filter(|_| self.tag.rear_face.is_some());
+        let payload = Payload {
+            front,
+            rear: rear.map(AnyImage::as_slice),
+            window: self.partial_window,
+        };
+        let transport = self.transport.as_mut().map(|transport| transport as &mut dyn Transport);
+        let result = self.proto.write(payload, transport);
+        self.temperature = self.proto.temperature();
+        (result, self.proto.take_retries())
+    }
 }
 
 /// View IDs
@@ -51,14 +340,118 @@ const VIEW_MENU: u32 = 0;
 const VIEW_TAG_MENU: u32 = 1;
 const VIEW_WRITE_MENU: u32 = 2;
 const VIEW_WIDGET: u32 = 3;
+const VIEW_PREVIEW: u32 = 4;
+const VIEW_TEXT_INPUT: u32 = 5;
+const VIEW_BYTE_INPUT: u32 = 6;
+const VIEW_ALIGN: u32 = 7;
+const VIEW_FIT_MENU: u32 = 8;
+const VIEW_TEXT_MENU: u32 = 9;
+const VIEW_CAPTION_MENU: u32 = 10;
+const VIEW_THRESHOLD: u32 = 11;
+
+/// Custom event IDs
+const EVENT_DECODE_DONE: u32 = 0;
+const EVENT_ALIGN_DONE: u32 = 1;
+const EVENT_SEQUENCE_NEXT: u32 = 2;
+const EVENT_THRESHOLD_DONE: u32 = 3;
+const EVENT_BATCH_DONE: u32 = 4;
+const EVENT_WRITE_DONE: u32 = 5;
+
+/// Time the screen underneath the app gets to draw before it is captured (ms)
+const SCREEN_SETTLE_MS: u32 = 500;
 
 /// Main menu item IDs
 const MENU_SELECT_IMAGE: u32 = 0;
 const MENU_ABOUT: u32 = 1;
+const MENU_COLOR_MODE: u32 = 2;
+const MENU_SCREEN_TO_TAG: u32 = 3;
+const MENU_TAG_INFO: u32 = 4;
+const MENU_STATS: u32 = 5;
+const MENU_READER: u32 = 6;
+const MENU_EXPERIMENTAL: u32 = 7;
+const MENU_RESUME: u32 = 8;
+const MENU_LEFT_HANDED: u32 = 9;
+const MENU_OPEN_RECIPE: u32 = 10;
+const MENU_ROTATION: u32 = 11;
+const MENU_CLEAR_WHITE: u32 = 12;
+const MENU_FILL_BLACK: u32 = 13;
+const MENU_FILL_RED: u32 = 14;
+const MENU_TEXT_TO_TAG: u32 = 15;
+const MENU_OPEN_TEMPLATE: u32 = 16;
+const MENU_ACCENT_PULL: u32 = 17;
+const MENU_CONVERT_FOLDER: u32 = 18;
+const MENU_BROWSE_ROOT: u32 = 19;
+const MENU_RUN_SCRIPT: u32 = 20;
+
+/// Tag menu item IDs (tags use their index in `TagType::ALL`)
+const TAG_MENU_SEARCH: u32 = 0x100;
+const TAG_MENU_SIZE: u32 = 0x101;
+const TAG_MENU_EXPERIMENTAL: u32 = 0x102;
+
+/// Resend sparkline: bars across the screen and their maximum height (px)
+const RETRY_BARS: usize = 64;
+const RETRY_BAR_HEIGHT: u32 = 12;
+
+/// Part number search buffer size (including NUL)
+const SEARCH_LEN: usize = 24;
+/// Text to Tag buffer size (including NUL), room for a Wi-Fi QR string
+const LABEL_LEN: usize = 128;
+/// Template field buffer size (including NUL)
+const FIELD_LEN: usize = 64;
+/// Caption buffer size (including NUL), a date or a short line
+const CAPTION_LEN: usize = 32;
 
 /// Write menu item IDs
 const WRITE_MENU_WRITE: u32 = 0;
 const WRITE_MENU_CANCEL: u32 = 1;
+const WRITE_MENU_AUTH_KEY: u32 = 2;
+const WRITE_MENU_REAR_IMAGE: u32 = 3;
+const WRITE_MENU_SAVE_ENCODED: u32 = 4;
+const WRITE_MENU_PREVIEW_COMMANDS: u32 = 5;
+const WRITE_MENU_EXPORT_RECIPE: u32 = 6;
+const WRITE_MENU_CAPTION: u32 = 7;
+const WRITE_MENU_SCREEN_SCALE: u32 = 8;
+const WRITE_MENU_SCREEN_INVERT: u32 = 9;
+const WRITE_MENU_REMEMBER: u32 = 10;
+const WRITE_MENU_PARTIAL: u32 = 11;
+const WRITE_MENU_PLAY_SEQUENCE: u32 = 12;
+const WRITE_MENU_SEQUENCE_DWELL: u32 = 13;
+const WRITE_MENU_SEQUENCE_LOOP: u32 = 14;
+const WRITE_MENU_COLOR_REPORT: u32 = 15;
+const WRITE_MENU_OVERLAY: u32 = 16;
+const WRITE_MENU_THRESHOLD: u32 = 17;
+const WRITE_MENU_SIMULATE: u32 = 18;
+
+/// Packed buffer of the last write to a partial-refresh tag, to diff against
+const LAST_WRITE_PATH: &CStr = c"/ext/apps_data/eink_nfc_img/last_write.eink";
+/// Transforms note of that container
+const LAST_WRITE_NOTE: &str = "source=last_write";
+
+/// Fit menu item IDs
+const FIT_MENU_SHRINK: u32 = 0;
+const FIT_MENU_SHRINK_SMOOTH: u32 = 1;
+const FIT_MENU_UPSCALE: u32 = 2;
+const FIT_MENU_CROP: u32 = 3;
+const FIT_MENU_CROP_ANCHOR: u32 = 4;
+const FIT_MENU_PAD: u32 = 5;
+const FIT_MENU_PAD_COLOR: u32 = 6;
+const FIT_MENU_ROTATE_CW: u32 = 7;
+const FIT_MENU_ROTATE_CCW: u32 = 8;
+
+/// Text menu item IDs
+const TEXT_MENU_CONTINUE: u32 = 0;
+const TEXT_MENU_SIZE: u32 = 1;
+const TEXT_MENU_ALIGN: u32 = 2;
+const TEXT_MENU_EDIT: u32 = 3;
+const TEXT_MENU_KIND: u32 = 4;
+
+/// Caption menu item IDs
+const CAPTION_MENU_APPLY: u32 = 0;
+const CAPTION_MENU_POSITION: u32 = 1;
+const CAPTION_MENU_COLOR: u32 = 2;
+const CAPTION_MENU_SIZE: u32 = 3;
+const CAPTION_MENU_EDIT: u32 = 4;
+const CAPTION_MENU_REMOVE: u32 = 5;
 
 /// Helper macro for C string literals (returns *const c_char)
 macro_rules! c_str {
@@ -74,16 +467,89 @@ impl App {
             submenu: null_mut(),
             tag_submenu: null_mut(),
             write_submenu: null_mut(),
+            fit_submenu: null_mut(),
+            text_submenu: null_mut(),
+            caption_submenu: null_mut(),
             widget: null_mut(),
+            text_input: null_mut(),
+            byte_input: null_mut(),
+            key_buf: protocol_common::DEFAULT_AUTH_KEY,
+            search_buf: [0; SEARCH_LEN],
+            label_buf: [0; LABEL_LEN],
+            text_kind: TextKind::Text,
+            text_style: TextStyle::default(),
+            template: None,
+            template_answers: Vec::new(),
+            field_buf: [0; FIELD_LEN],
+            caption: None,
+            overlay: None,
+            caption_draft: Caption {
+                text: String::new(),
+                anchor: Anchor::BottomRight,
+                color: image::BLACK,
+                scale: 2,
+            },
+            caption_buf: [0; CAPTION_LEN],
+            caption_input: false,
+            screen_frame: None,
+            screen_style: ScreenStyle::default(),
+            size_filter: None,
+            experimental_menu: false,
+            plan_shown: false,
+            preview: Preview::new(),
+            align: Align::new(),
+            threshold_view: ThresholdView::new(),
             gui: null_mut(),
             selected_tag: None,
             image_data: None,
+            image_path: None,
+            rear_image: None,
+            resume: None,
+            partial_base: None,
+            partial_window: None,
+            sequence: None,
+            sequence_dwell: DWELL_PRESETS[2],
+            sequence_loop: false,
+            target_face: Face::Front,
+            quantizer: QuantizerKind::Nearest,
+            rotation: Rotation::None,
+            threshold: Threshold::DEFAULT,
+            accent_pull: AccentPull::OFF,
+            fit: Fit::Exact,
+            image_fit: Fit::Exact,
+            details_path: None,
+            mismatch_tag: None,
+            skip_tag_check: false,
+            no_tag_prompt: false,
+            simulate: false,
+            fit_path: None,
+            fit_size: (0, 0),
+            crop_anchor: Anchor::Center,
+            pad_background: Background::White,
+            remembered_fit: None,
+            remembered_path: None,
+            image_source: ImageSource::File,
+            stats: Stats::default(),
+            settings: Settings::default(),
             current_view: VIEW_MENU,
+            decode_job: None,
+            batch_job: None,
+            write_job: None,
+            worker: None,
         }
     }
 
     unsafe fn init(&mut self) -> bool {
         unsafe {
+            // Load lifetime stats and settings
+            self.stats = Stats::load();
+            self.settings = Settings::load();
+            self.settings.apply_logging();
+            self.settings.apply_colors();
+            self.settings.apply_tag_timeout();
+            input::set_left_handed(self.settings.left_handed);
+            log_firmware_version();
+
             // Allocate view dispatcher
             self.view_dispatcher = sys::view_dispatcher_alloc();
             if self.view_dispatcher.is_null() {
@@ -108,61 +574,62 @@ impl App {
                 return false;
             }
 
+            // Allocate fit menu for images of another size
+            self.fit_submenu = sys::submenu_alloc();
+            if self.fit_submenu.is_null() {
+                return false;
+            }
+
+            // Allocate text options menu for Text to Tag
+            self.text_submenu = sys::submenu_alloc();
+            if self.text_submenu.is_null() {
+                return false;
+            }
+
+            // Allocate caption options menu for the write menu
+            self.caption_submenu = sys::submenu_alloc();
+            if self.caption_submenu.is_null() {
+                return false;
+            }
+
             // Allocate widget for status display
             self.widget = sys::widget_alloc();
             if self.widget.is_null() {
                 return false;
             }
 
-            // Add main menu items
-            sys::submenu_add_item(
-                self.submenu,
-                c_str!("Select Image"),
-                MENU_SELECT_IMAGE,
-                Some(menu_callback),
-                self as *mut _ as *mut c_void,
-            );
-            sys::submenu_add_item(
-                self.submenu,
-                c_str!("About"),
-                MENU_ABOUT,
-                Some(menu_callback),
-                self as *mut _ as *mut c_void,
-            );
+            // Allocate text input for part number search
+            self.text_input = sys::text_input_alloc();
+            if self.text_input.is_null() {
+                return false;
+            }
 
-            // Add tag selection menu items
-            for (idx, _tag) in TagType::ALL.iter().enumerate() {
-                // Create C string for tag name
-                // Note: tag.name is &'static str, we need to make it a C string
-                let name_cstr = match idx {
-                    0 => c_str!("1.54inch e-Paper Y"),
-                    1 => c_str!("1.54inch e-Paper B"),
-                    _ => c_str!("Unknown"),
-                };
-                sys::submenu_add_item(
-                    self.tag_submenu,
-                    name_cstr,
-                    idx as u32,
-                    Some(tag_menu_callback),
-                    self as *mut _ as *mut c_void,
-                );
+            // Allocate byte input for auth keys
+            self.byte_input = sys::byte_input_alloc();
+            if self.byte_input.is_null() {
+                return false;
             }
 
-            // Add write menu items
-            sys::submenu_add_item(
-                self.write_submenu,
-                c_str!("Write to Tag"),
-                WRITE_MENU_WRITE,
-                Some(write_menu_callback),
-                self as *mut _ as *mut c_void,
-            );
-            sys::submenu_add_item(
-                self.write_submenu,
-                c_str!("Cancel"),
-                WRITE_MENU_CANCEL,
-                Some(write_menu_callback),
-                self as *mut _ as *mut c_void,
-            );
+            // Allocate decode preview
+            if !self.preview.alloc() {
+                return false;
+            }
+
+            // Allocate alignment helper
+            if !self.align.alloc() {
+                return false;
+            }
+
+            // Allocate threshold screen
+            if !self.threshold_view.alloc() {
+                return false;
+            }
+
+            // Add main menu items, offering to resume an interrupted write
+            self.refresh_resume();
+
+            // Add tag selection menu items from the tag database
+            self.rebuild_tag_menu();
 
             // Add views to dispatcher
             sys::view_dispatcher_add_view(
@@ -185,6 +652,46 @@ impl App {
                 VIEW_WIDGET,
                 sys::widget_get_view(self.widget),
             );
+            sys::view_dispatcher_add_view(
+                self.view_dispatcher,
+                VIEW_PREVIEW,
+                self.preview.view(),
+            );
+            sys::view_dispatcher_add_view(
+                self.view_dispatcher,
+                VIEW_TEXT_INPUT,
+                sys::text_input_get_view(self.text_input),
+            );
+            sys::view_dispatcher_add_view(
+                self.view_dispatcher,
+                VIEW_BYTE_INPUT,
+                sys::byte_input_get_view(self.byte_input),
+            );
+            sys::view_dispatcher_add_view(
+                self.view_dispatcher,
+                VIEW_ALIGN,
+                self.align.view(),
+            );
+            sys::view_dispatcher_add_view(
+                self.view_dispatcher,
+                VIEW_FIT_MENU,
+                sys::submenu_get_view(self.fit_submenu),
+            );
+            sys::view_dispatcher_add_view(
+                self.view_dispatcher,
+                VIEW_TEXT_MENU,
+                sys::submenu_get_view(self.text_submenu),
+            );
+            sys::view_dispatcher_add_view(
+                self.view_dispatcher,
+                VIEW_CAPTION_MENU,
+                sys::submenu_get_view(self.caption_submenu),
+            );
+            sys::view_dispatcher_add_view(
+                self.view_dispatcher,
+                VIEW_THRESHOLD,
+                self.threshold_view.view(),
+            );
 
             // Get GUI record
             self.gui = sys::furi_record_open(c_str!("gui")) as *mut sys::Gui;
@@ -204,11 +711,18 @@ impl App {
                 self.view_dispatcher,
                 Some(navigation_callback),
             );
+            sys::view_dispatcher_set_custom_event_callback(
+                self.view_dispatcher,
+                Some(custom_event_callback),
+            );
             sys::view_dispatcher_set_event_callback_context(
                 self.view_dispatcher,
                 self as *mut _ as *mut c_void,
             );
 
+            // Session log over the CLI; removed again in cleanup
+            log_ring::register_cli();
+
             true
         }
     }
@@ -225,11 +739,33 @@ impl App {
 
     unsafe fn cleanup(&mut self) {
         unsafe {
+            // Never free views under a running job
+            self.align.cancel();
+            // A pending frame timer would post to the freed dispatcher
+            self.sequence = None;
+            if let Some(worker) = self.worker.take() {
+                worker.join();
+            }
+            self.decode_job = None;
+            self.batch_job = None;
+            self.write_job = None;
+
+            // The command's callback lives in the app image
+            log_ring::unregister_cli();
+
             // Remove views
             sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_MENU);
             sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_TAG_MENU);
             sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_WRITE_MENU);
             sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_WIDGET);
+            sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_PREVIEW);
+            sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_TEXT_INPUT);
+            sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_BYTE_INPUT);
+            sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_ALIGN);
+            sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_FIT_MENU);
+            sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_TEXT_MENU);
+            sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_CAPTION_MENU);
+            sys::view_dispatcher_remove_view(self.view_dispatcher, VIEW_THRESHOLD);
 
             // Free resources
             if !self.submenu.is_null() {
@@ -241,9 +777,27 @@ impl App {
             if !self.write_submenu.is_null() {
                 sys::submenu_free(self.write_submenu);
             }
+            if !self.fit_submenu.is_null() {
+                sys::submenu_free(self.fit_submenu);
+            }
+            if !self.text_submenu.is_null() {
+                sys::submenu_free(self.text_submenu);
+            }
+            if !self.caption_submenu.is_null() {
+                sys::submenu_free(self.caption_submenu);
+            }
             if !self.widget.is_null() {
                 sys::widget_free(self.widget);
             }
+            if !self.text_input.is_null() {
+                sys::text_input_free(self.text_input);
+            }
+            if !self.byte_input.is_null() {
+                sys::byte_input_free(self.byte_input);
+            }
+            self.preview.free();
+            self.align.free();
+            self.threshold_view.free();
             if !self.view_dispatcher.is_null() {
                 sys::view_dispatcher_free(self.view_dispatcher);
             }
@@ -253,6 +807,93 @@ impl App {
         }
     }
 
+    /// Main menu label for the active quantizer
+    fn color_mode_label(quantizer: QuantizerKind) -> *const core::ffi::c_char {
+        match quantizer {
+            QuantizerKind::Nearest => c_str!("Colors: Nearest"),
+            QuantizerKind::FloydSteinberg => c_str!("Colors: Dithered"),
+            QuantizerKind::Bayer => c_str!("Colors: Bayer"),
+            QuantizerKind::BlueNoise => c_str!("Colors: Blue noise"),
+            QuantizerKind::Halftone => c_str!("Colors: Halftone"),
+        }
+    }
+
+    /// Main menu label for how hard pixels are pushed toward accent colors
+    fn accent_pull_label(pull: AccentPull) -> *const core::ffi::c_char {
+        match pull.0 {
+            0 => c_str!("Accent Pull: Off"),
+            1 => c_str!("Accent Pull: Low"),
+            2 => c_str!("Accent Pull: Medium"),
+            _ => c_str!("Accent Pull: High"),
+        }
+    }
+
+    /// Main menu label for the rotation applied to file images
+    fn rotation_label(rotation: Rotation) -> *const core::ffi::c_char {
+        match rotation {
+            Rotation::None => c_str!("Rotate: Off"),
+            Rotation::Cw90 => c_str!("Rotate: 90 CW"),
+            Rotation::Cw180 => c_str!("Rotate: 180"),
+            Rotation::Cw270 => c_str!("Rotate: 90 CCW"),
+        }
+    }
+
+    /// Fit menu label for the part of the image a crop keeps
+    fn crop_anchor_label(anchor: Anchor) -> *const core::ffi::c_char {
+        match anchor {
+            Anchor::Center => c_str!("Crop Anchor: Center"),
+            Anchor::TopLeft => c_str!("Crop Anchor: Top Left"),
+            Anchor::Top => c_str!("Crop Anchor: Top"),
+            Anchor::TopRight => c_str!("Crop Anchor: Top Right"),
+            Anchor::Right => c_str!("Crop Anchor: Right"),
+            Anchor::BottomRight => c_str!("Crop Anchor: Bottom Right"),
+            Anchor::Bottom => c_str!("Crop Anchor: Bottom"),
+            Anchor::BottomLeft => c_str!("Crop Anchor: Bottom Left"),
+            Anchor::Left => c_str!("Crop Anchor: Left"),
+        }
+    }
+
+    /// Fit menu label for the color a small image is padded with
+    fn pad_background_label(background: Background) -> *const core::ffi::c_char {
+        match background {
+            Background::White => c_str!("Background: White"),
+            Background::Black => c_str!("Background: Black"),
+            Background::Red => c_str!("Background: Red"),
+            Background::Yellow => c_str!("Background: Yellow"),
+        }
+    }
+
+    fn reader_label(reader: ReaderKind) -> *const core::ffi::c_char {
+        match reader {
+            ReaderKind::Internal => c_str!("Reader: Internal"),
+            ReaderKind::Pn532 => c_str!("Reader: PN532"),
+        }
+    }
+
+    fn browse_root_label(root: BrowseRoot) -> *const core::ffi::c_char {
+        match root {
+            BrowseRoot::SdCard => c_str!("Browse: SD Card"),
+            BrowseRoot::Internal => c_str!("Browse: Internal"),
+            BrowseRoot::Samples => c_str!("Browse: Samples"),
+        }
+    }
+
+    fn experimental_label(enabled: bool) -> *const core::ffi::c_char {
+        if enabled {
+            c_str!("Experimental: On")
+        } else {
+            c_str!("Experimental: Off")
+        }
+    }
+
+    fn left_handed_label(enabled: bool) -> *const core::ffi::c_char {
+        if enabled {
+            c_str!("Left-handed: On")
+        } else {
+            c_str!("Left-handed: Off")
+        }
+    }
+
     unsafe fn show_message(&mut self, title: *const core::ffi::c_char, message: *const core::ffi::c_char) {
         unsafe {
             sys::widget_reset(self.widget);
@@ -279,182 +920,3169 @@ impl App {
         }
     }
 
-    unsafe fn show_tag_menu(&mut self) {
-        unsafe {
-            self.current_view = VIEW_TAG_MENU;
-            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_TAG_MENU);
-        }
-    }
-
-    unsafe fn show_write_menu(&mut self) {
-        unsafe {
-            self.current_view = VIEW_WRITE_MENU;
-            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_WRITE_MENU);
-        }
-    }
-
-    unsafe fn show_main_menu(&mut self) {
-        unsafe {
-            self.current_view = VIEW_MENU;
-            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_MENU);
-        }
-    }
-
-    unsafe fn on_menu_select(&mut self, index: u32) {
+    /// Fill the main menu, led by "Resume write" while a checkpoint is saved
+    unsafe fn rebuild_main_menu(&mut self) {
         unsafe {
-            match index {
-                MENU_SELECT_IMAGE => {
-                    // Show tag selection menu first
-                    self.show_tag_menu();
-                }
-                MENU_ABOUT => {
-                    self.show_message(
-                        c_str!("E-Ink NFC Writer"),
-                        c_str!("BWR/BWRY e-ink tags"),
+            sys::submenu_reset(self.submenu);
+            if let Some(checkpoint) = &self.resume {
+                if let Ok(c_label) = CString::new(checkpoint.label()) {
+                    sys::submenu_add_item(
+                        self.submenu,
+                        c_label.as_ptr(),
+                        MENU_RESUME,
+                        Some(menu_callback),
+                        self as *mut _ as *mut c_void,
                     );
                 }
-                _ => {}
-            }
-        }
-    }
-
-    unsafe fn on_tag_menu_select(&mut self, index: u32) {
-        unsafe {
-            if let Some(tag) = TagType::get(index as usize) {
-                self.selected_tag = Some(tag);
-                self.select_image();
-            }
-        }
-    }
-
-    unsafe fn on_write_menu_select(&mut self, index: u32) {
-        unsafe {
-            match index {
-                WRITE_MENU_WRITE => {
-                    self.write_to_tag();
-                }
-                WRITE_MENU_CANCEL => {
-                    self.image_data = None;
-                    self.selected_tag = None;
-                    self.show_main_menu();
-                }
-                _ => {}
             }
-        }
-    }
-
-    unsafe fn select_image(&mut self) {
-        unsafe {
-            let tag = match self.selected_tag {
-                Some(t) => t,
-                None => {
-                    self.show_main_menu();
-                    return;
-                }
-            };
-
-            // Open dialogs app
-            let dialogs = sys::furi_record_open(c_str!("dialogs")) as *mut sys::DialogsApp;
-
-            // Allocate path string
-            let path = sys::furi_string_alloc();
-            sys::furi_string_set_str(path, c_str!("/ext"));
-
-            // Configure file browser
-            let mut options: sys::DialogsFileBrowserOptions = core::mem::zeroed();
-            sys::dialog_file_browser_set_basic_options(
-                &mut options,
-                c_str!(".bmp"),
-                null_mut(),
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Select Image"),
+                MENU_SELECT_IMAGE,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
             );
-            options.base_path = c_str!("/ext");
-            options.hide_dot_files = true;
-
-            // Show file browser
-            if sys::dialog_file_browser_show(dialogs, path, path, &options) {
-                // Get selected path
-                let path_ptr = sys::furi_string_get_cstr(path);
-
-                // Try to load the image with the appropriate format
-                match image::load_bmp(path_ptr, tag.image_format) {
-                    Ok(data) => {
-                        self.image_data = Some(data);
-                        // Cleanup and show write menu
-                        sys::furi_string_free(path);
-                        sys::furi_record_close(c_str!("dialogs"));
-                        self.show_write_menu();
-                        return;
-                    }
-                    Err(_) => {
-                        self.show_message(c_str!("Error"), c_str!("Failed to load image"));
-                    }
-                }
-            }
-
-            // Cleanup
-            sys::furi_string_free(path);
-            sys::furi_record_close(c_str!("dialogs"));
-        }
-    }
-
-    unsafe fn write_to_tag(&mut self) {
-        unsafe {
-            let tag = match self.selected_tag {
-                Some(t) => t,
-                None => {
-                    self.show_message(c_str!("Error"), c_str!("No tag type selected"));
-                    return;
-                }
-            };
-
-            if self.image_data.is_none() {
-                self.show_message(c_str!("No Image"), c_str!("Select an image first"));
-                return;
-            }
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Open Recipe"),
+                MENU_OPEN_RECIPE,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Open Template"),
+                MENU_OPEN_TEMPLATE,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Convert Folder"),
+                MENU_CONVERT_FOLDER,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Screen to Tag"),
+                MENU_SCREEN_TO_TAG,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Text to Tag"),
+                MENU_TEXT_TO_TAG,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Clear to White"),
+                MENU_CLEAR_WHITE,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Fill Black"),
+                MENU_FILL_BLACK,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Fill Red"),
+                MENU_FILL_RED,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                Self::color_mode_label(self.quantizer),
+                MENU_COLOR_MODE,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                Self::accent_pull_label(self.accent_pull),
+                MENU_ACCENT_PULL,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                Self::rotation_label(self.rotation),
+                MENU_ROTATION,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                Self::browse_root_label(self.settings.browse_root),
+                MENU_BROWSE_ROOT,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                Self::reader_label(self.settings.reader),
+                MENU_READER,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                Self::experimental_label(self.settings.experimental_tags),
+                MENU_EXPERIMENTAL,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                Self::left_handed_label(self.settings.left_handed),
+                MENU_LEFT_HANDED,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Tag Info"),
+                MENU_TAG_INFO,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Run APDU Script"),
+                MENU_RUN_SCRIPT,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("Statistics"),
+                MENU_STATS,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::submenu_add_item(
+                self.submenu,
+                c_str!("About"),
+                MENU_ABOUT,
+                Some(menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+        }
+    }
+
+    /// Reload the saved checkpoint after a write and update the main menu
+    unsafe fn refresh_resume(&mut self) {
+        unsafe {
+            self.resume = Checkpoint::load().filter(|checkpoint| checkpoint.tag().is_some());
+            self.rebuild_main_menu();
+        }
+    }
+
+    /// Fill the tag menu from the tag database, honoring the size filter
+    ///
+    /// Experimental tags get a list of their own behind "Experimental tags",
+    /// which only appears when the setting is on.
+    unsafe fn rebuild_tag_menu(&mut self) {
+        unsafe {
+            sys::submenu_reset(self.tag_submenu);
+            if self.experimental_menu {
+                for (idx, tag) in TagType::ALL.iter().enumerate().filter(|(_, tag)| tag.experimental) {
+                    if let Ok(c_label) = CString::new(tag.label()) {
+                        sys::submenu_add_item(
+                            self.tag_submenu,
+                            c_label.as_ptr(),
+                            idx as u32,
+                            Some(tag_menu_callback),
+                            self as *mut _ as *mut c_void,
+                        );
+                    }
+                }
+                return;
+            }
+
+            sys::submenu_add_item(
+                self.tag_submenu,
+                c_str!("Search part no."),
+                TAG_MENU_SEARCH,
+                Some(tag_menu_callback),
+                self as *mut _ as *mut c_void,
+            );
+
+            let size_label = match self.size_filter {
+                Some((w, h)) => alloc::format!("Size: {}x{}", w, h),
+                None => String::from("Size: All"),
+            };
+            if let Ok(c_label) = CString::new(size_label) {
+                sys::submenu_add_item(
+                    self.tag_submenu,
+                    c_label.as_ptr(),
+                    TAG_MENU_SIZE,
+                    Some(tag_menu_callback),
+                    self as *mut _ as *mut c_void,
+                );
+            }
+
+            for (idx, tag) in TagType::ALL.iter().enumerate().filter(|(_, tag)| !tag.experimental) {
+                if let Some((w, h)) = self.size_filter {
+                    if (tag.width, tag.height) != (w, h) {
+                        continue;
+                    }
+                }
+                // Submenu copies the label, so a temporary C string is fine
+                if let Ok(c_label) = CString::new(tag.label()) {
+                    sys::submenu_add_item(
+                        self.tag_submenu,
+                        c_label.as_ptr(),
+                        idx as u32,
+                        Some(tag_menu_callback),
+                        self as *mut _ as *mut c_void,
+                    );
+                }
+            }
+
+            if self.settings.experimental_tags {
+                sys::submenu_add_item(
+                    self.tag_submenu,
+                    c_str!("Experimental tags"),
+                    TAG_MENU_EXPERIMENTAL,
+                    Some(tag_menu_callback),
+                    self as *mut _ as *mut c_void,
+                );
+            }
+        }
+    }
+
+    /// Leave the experimental list for the main tag list
+    unsafe fn close_experimental_menu(&mut self) {
+        unsafe {
+            self.experimental_menu = false;
+            self.rebuild_tag_menu();
+            sys::submenu_set_selected_item(self.tag_submenu, TAG_MENU_EXPERIMENTAL);
+        }
+    }
+
+    /// Step the size filter: All, then each size in the database
+    unsafe fn cycle_size_filter(&mut self) {
+        unsafe {
+            let sizes = TagType::sizes();
+            let next = match self.size_filter {
+                None => 0,
+                Some(size) => sizes.iter().position(|&s| s == size).map_or(sizes.len(), |i| i + 1),
+            };
+            self.size_filter = sizes.get(next).copied();
+            self.rebuild_tag_menu();
+            sys::submenu_set_selected_item(self.tag_submenu, TAG_MENU_SIZE);
+        }
+    }
+
+    /// Ask for a part number or alias to search the tag database
+    unsafe fn show_tag_search(&mut self) {
+        unsafe {
+            sys::text_input_reset(self.text_input);
+            sys::text_input_set_header_text(self.text_input, c_str!("Part number or name"));
+            sys::text_input_set_result_callback(
+                self.text_input,
+                Some(text_input_callback),
+                self as *mut _ as *mut c_void,
+                self.search_buf.as_mut_ptr() as *mut core::ffi::c_char,
+                SEARCH_LEN,
+                true,
+            );
+            self.current_view = VIEW_TEXT_INPUT;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_TEXT_INPUT);
+        }
+    }
+
+    /// Select the first tag matching the search text
+    unsafe fn on_tag_search_done(&mut self) {
+        unsafe {
+            let query = CStr::from_bytes_until_nul(&self.search_buf)
+                .ok()
+                .and_then(|s| s.to_str().ok())
+                .unwrap_or("");
+            match TagType::find(query, self.settings.experimental_tags) {
+                Some(idx) => self.on_tag_menu_select(idx as u32),
+                None => self.show_message(c_str!("Not found"), c_str!("No tag matches")),
+            }
+        }
+    }
+
+    /// Edit the INIT auth key of the selected tag type
+    unsafe fn show_auth_key_input(&mut self) {
+        unsafe {
+            let tag = match self.selected_tag {
+                Some(t) => t,
+                None => return,
+            };
+            self.key_buf = self.settings.auth_key(tag);
+            sys::byte_input_set_header_text(self.byte_input, c_str!("INIT auth key"));
+            sys::byte_input_set_result_callback(
+                self.byte_input,
+                Some(byte_input_callback),
+                None,
+                self as *mut _ as *mut c_void,
+                self.key_buf.as_mut_ptr(),
+                self.key_buf.len() as u8,
+            );
+            self.current_view = VIEW_BYTE_INPUT;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_BYTE_INPUT);
+        }
+    }
+
+    /// Store the edited auth key for the selected tag type
+    unsafe fn on_auth_key_done(&mut self) {
+        unsafe {
+            if let Some(tag) = self.selected_tag {
+                self.settings.set_auth_key(tag, self.key_buf);
+                if !self.settings.save() {
+                    self.show_message(c_str!("Not saved"), c_str!("Key used until exit"));
+                    return;
+                }
+            }
+            self.show_write_menu();
+        }
+    }
+
+    unsafe fn show_tag_menu(&mut self) {
+        unsafe {
+            self.current_view = VIEW_TAG_MENU;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_TAG_MENU);
+        }
+    }
+
+    /// "Colors: OK", or what looks wrong with them
+    fn color_report_label(report: &ColorReport) -> CString {
+        let label = match report.warning() {
+            Some(ColorWarning::Unused(name)) => alloc::format!("Colors: no {}!", name),
+            Some(ColorWarning::Dominant(name, percent)) => {
+                alloc::format!("Colors: {} {}%!", name, percent)
+            }
+            None => String::from("Colors: OK"),
+        };
+        CString::new(label).unwrap_or_default()
+    }
+
+    /// Fill the write menu for the selected tag
+    ///
+    /// Dual-face badges get an extra entry for picking the rear image;
+    /// "Preview Commands" and, for IsoDep tags, "Simulate Write" are only
+    /// offered with Experimental on.
+    unsafe fn rebuild_write_menu(&mut self) {
+        unsafe {
+            let ctx = self as *mut _ as *mut c_void;
+            sys::submenu_reset(self.write_submenu);
+            // The color split heads the menu, to be seen before writing
+            let image = self.selected_tag.zip(self.image_data.as_ref());
+            let report = image.map(|(tag, image)| ColorReport::of(image, tag));
+            if let Some(report) = &report {
+                let summary = CString::new(report.summary()).unwrap_or_default();
+                sys::submenu_set_header(self.write_submenu, summary.as_ptr());
+            }
+            sys::submenu_add_item(
+                self.write_submenu,
+                c_str!("Write to Tag"),
+                WRITE_MENU_WRITE,
+                Some(write_menu_callback),
+                ctx,
+            );
+            if let Some(report) = &report {
+                let label = Self::color_report_label(report);
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    label.as_ptr(),
+                    WRITE_MENU_COLOR_REPORT,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            if self.selected_tag.is_some_and(|tag| tag.rear_face.is_some()) {
+                let label = if self.rear_image.is_some() {
+                    c_str!("Rear Image: set")
+                } else {
+                    c_str!("Rear Image: none")
+                };
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    label,
+                    WRITE_MENU_REAR_IMAGE,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            sys::submenu_add_item(
+                self.write_submenu,
+                c_str!("Auth Key"),
+                WRITE_MENU_AUTH_KEY,
+                Some(write_menu_callback),
+                ctx,
+            );
+            // A streamed image has no packed frame to save
+            if self.image_data.is_some() {
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    c_str!("Save Encoded Data"),
+                    WRITE_MENU_SAVE_ENCODED,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            // Recipes reference an image file, so screen captures can't be shared
+            if self.image_path.is_some() {
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    c_str!("Export Recipe"),
+                    WRITE_MENU_EXPORT_RECIPE,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            if self.screen_capture_shown() {
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    Self::screen_scale_label(self.screen_style),
+                    WRITE_MENU_SCREEN_SCALE,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    Self::screen_invert_label(self.screen_style),
+                    WRITE_MENU_SCREEN_INVERT,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            // A caption is drawn while decoding, so the image must be decoded again
+            if self.image_path.as_deref().is_some_and(|path| !image::is_packed(path)) {
+                let label = if self.caption.is_some() {
+                    c_str!("Edit Caption")
+                } else {
+                    c_str!("Add Caption")
+                };
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    label,
+                    WRITE_MENU_CAPTION,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+                let label = if self.overlay.is_some() {
+                    c_str!("Overlay: set")
+                } else {
+                    c_str!("Overlay: none")
+                };
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    label,
+                    WRITE_MENU_OVERLAY,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+                let label = Self::threshold_label(self.threshold);
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    label.as_ptr(),
+                    WRITE_MENU_THRESHOLD,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            if let Some(path) = self.image_path.as_deref().filter(|path| !image::is_packed(path)) {
+                let label = if self.remembered_path.as_deref() == Some(path) {
+                    c_str!("Forget Options")
+                } else {
+                    c_str!("Remember Options")
+                };
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    label,
+                    WRITE_MENU_REMEMBER,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            let partial = self.selected_tag.zip(self.partial_base);
+            if partial.is_some_and(|(tag, base)| tag.part_number == base.part_number) {
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    c_str!("Write Changes Only"),
+                    WRITE_MENU_PARTIAL,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            if self.sequence_playable() {
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    c_str!("Play Sequence"),
+                    WRITE_MENU_PLAY_SEQUENCE,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+                let label = Self::sequence_dwell_label(self.sequence_dwell);
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    label.as_ptr(),
+                    WRITE_MENU_SEQUENCE_DWELL,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    Self::sequence_loop_label(self.sequence_loop),
+                    WRITE_MENU_SEQUENCE_LOOP,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+            }
+            if self.settings.experimental_tags {
+                sys::submenu_add_item(
+                    self.write_submenu,
+                    c_str!("Preview Commands"),
+                    WRITE_MENU_PREVIEW_COMMANDS,
+                    Some(write_menu_callback),
+                    ctx,
+                );
+                if self.selected_tag.is_some_and(|tag| tag.protocol.is_isodep()) {
+                    sys::submenu_add_item(
+                        self.write_submenu,
+                        c_str!("Simulate Write"),
+                        WRITE_MENU_SIMULATE,
+                        Some(write_menu_callback),
+                        ctx,
+                    );
+                }
+            }
+            sys::submenu_add_item(
+                self.write_submenu,
+                c_str!("Cancel"),
+                WRITE_MENU_CANCEL,
+                Some(write_menu_callback),
+                ctx,
+            );
+        }
+    }
+
+    unsafe fn show_write_menu(&mut self) {
+        unsafe {
+            self.rebuild_write_menu();
+            self.current_view = VIEW_WRITE_MENU;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_WRITE_MENU);
+        }
+    }
+
+    unsafe fn show_main_menu(&mut self) {
+        unsafe {
+            self.current_view = VIEW_MENU;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_MENU);
+        }
+    }
+
+    unsafe fn on_menu_select(&mut self, index: u32) {
+        unsafe {
+            match index {
+                MENU_SELECT_IMAGE => {
+                    // Show tag selection menu first
+                    self.image_source = ImageSource::File;
+                    self.show_tag_menu();
+                }
+                MENU_CONVERT_FOLDER => {
+                    self.image_source = ImageSource::Folder;
+                    self.show_tag_menu();
+                }
+                MENU_SCREEN_TO_TAG => {
+                    self.image_source = ImageSource::Screen;
+                    self.show_tag_menu();
+                }
+                MENU_TEXT_TO_TAG => {
+                    self.image_source = ImageSource::Text;
+                    self.show_tag_menu();
+                }
+                MENU_CLEAR_WHITE => {
+                    self.image_source = ImageSource::Fill(Rgb::new(255, 255, 255));
+                    self.show_tag_menu();
+                }
+                MENU_FILL_BLACK => {
+                    self.image_source = ImageSource::Fill(Rgb::new(0, 0, 0));
+                    self.show_tag_menu();
+                }
+                MENU_FILL_RED => {
+                    self.image_source = ImageSource::Fill(Rgb::new(255, 0, 0));
+                    self.show_tag_menu();
+                }
+                MENU_COLOR_MODE => {
+                    self.quantizer = self.quantizer.next();
+                    sys::submenu_change_item_label(
+                        self.submenu,
+                        MENU_COLOR_MODE,
+                        Self::color_mode_label(self.quantizer),
+                    );
+                }
+                MENU_ACCENT_PULL => {
+                    self.accent_pull = self.accent_pull.next();
+                    sys::submenu_change_item_label(
+                        self.submenu,
+                        MENU_ACCENT_PULL,
+                        Self::accent_pull_label(self.accent_pull),
+                    );
+                }
+                MENU_ROTATION => {
+                    self.rotation = self.rotation.next();
+                    sys::submenu_change_item_label(
+                        self.submenu,
+                        MENU_ROTATION,
+                        Self::rotation_label(self.rotation),
+                    );
+                }
+                MENU_READER => {
+                    self.settings.reader = match self.settings.reader {
+                        ReaderKind::Internal => ReaderKind::Pn532,
+                        ReaderKind::Pn532 => ReaderKind::Internal,
+                    };
+                    self.settings.save();
+                    sys::submenu_change_item_label(
+                        self.submenu,
+                        MENU_READER,
+                        Self::reader_label(self.settings.reader),
+                    );
+                }
+                MENU_BROWSE_ROOT => {
+                    self.settings.browse_root = self.settings.browse_root.next();
+                    self.settings.save();
+                    sys::submenu_change_item_label(
+                        self.submenu,
+                        MENU_BROWSE_ROOT,
+                        Self::browse_root_label(self.settings.browse_root),
+                    );
+                }
+                MENU_EXPERIMENTAL => {
+                    self.settings.experimental_tags = !self.settings.experimental_tags;
+                    self.settings.save();
+                    sys::submenu_change_item_label(
+                        self.submenu,
+                        MENU_EXPERIMENTAL,
+                        Self::experimental_label(self.settings.experimental_tags),
+                    );
+                    self.experimental_menu = false;
+                    self.rebuild_tag_menu();
+                }
+                MENU_LEFT_HANDED => {
+                    self.settings.left_handed = !self.settings.left_handed;
+                    self.settings.save();
+                    input::set_left_handed(self.settings.left_handed);
+                    sys::submenu_change_item_label(
+                        self.submenu,
+                        MENU_LEFT_HANDED,
+                        Self::left_handed_label(self.settings.left_handed),
+                    );
+                }
+                MENU_RESUME => {
+                    self.resume_write();
+                }
+                MENU_OPEN_RECIPE => {
+                    self.open_recipe();
+                }
+                MENU_OPEN_TEMPLATE => {
+                    self.open_template();
+                }
+                MENU_TAG_INFO => {
+                    self.show_tag_info();
+                }
+                MENU_RUN_SCRIPT => {
+                    self.run_script();
+                }
+                MENU_STATS => {
+                    self.show_text(self.stats.summary());
+                }
+                MENU_ABOUT => {
+                    let text = alloc::format!("Tags written: {}", self.stats.tags_written);
+                    if let Ok(c_text) = CString::new(text) {
+                        self.show_message(c_str!("E-Ink NFC Writer"), c_text.as_ptr());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    unsafe fn on_tag_menu_select(&mut self, index: u32) {
+        unsafe {
+            match index {
+                TAG_MENU_SEARCH => {
+                    self.show_tag_search();
+                    return;
+                }
+                TAG_MENU_SIZE => {
+                    self.cycle_size_filter();
+                    return;
+                }
+                TAG_MENU_EXPERIMENTAL => {
+                    self.experimental_menu = true;
+                    self.rebuild_tag_menu();
+                    return;
+                }
+                _ => {}
+            }
+            if let Some(tag) = TagType::get(index as usize) {
+                self.selected_tag = Some(tag);
+                self.target_face = Face::Front;
+                self.caption = None;
+                self.overlay = None;
+                self.threshold = Threshold::DEFAULT;
+                match self.image_source {
+                    ImageSource::File => self.select_image(),
+                    ImageSource::Screen => self.capture_screen(tag),
+                    ImageSource::Fill(color) => self.fill_tag(tag, color),
+                    ImageSource::Text => self.show_text_input(),
+                    ImageSource::Template => self.start_template(tag),
+                    ImageSource::Folder => self.convert_folder(tag),
+                }
+            }
+        }
+    }
+
+    unsafe fn on_write_menu_select(&mut self, index: u32) {
+        unsafe {
+            match index {
+                WRITE_MENU_WRITE => {
+                    self.partial_window = None;
+                    self.start_align();
+                }
+                WRITE_MENU_PARTIAL => {
+                    self.write_changes();
+                }
+                WRITE_MENU_PLAY_SEQUENCE => {
+                    self.start_sequence();
+                }
+                WRITE_MENU_COLOR_REPORT => {
+                    self.show_color_report();
+                }
+                WRITE_MENU_SEQUENCE_DWELL => {
+                    let current = DWELL_PRESETS.iter().position(|&s| s == self.sequence_dwell);
+                    let next = current.map_or(0, |i| (i + 1) % DWELL_PRESETS.len());
+                    self.sequence_dwell = DWELL_PRESETS[next];
+                    let label = Self::sequence_dwell_label(self.sequence_dwell);
+                    let item = WRITE_MENU_SEQUENCE_DWELL;
+                    sys::submenu_change_item_label(self.write_submenu, item, label.as_ptr());
+                }
+                WRITE_MENU_SEQUENCE_LOOP => {
+                    self.sequence_loop = !self.sequence_loop;
+                    let label = Self::sequence_loop_label(self.sequence_loop);
+                    let item = WRITE_MENU_SEQUENCE_LOOP;
+                    sys::submenu_change_item_label(self.write_submenu, item, label);
+                }
+                WRITE_MENU_AUTH_KEY => {
+                    self.show_auth_key_input();
+                }
+                WRITE_MENU_SAVE_ENCODED => {
+                    self.save_encoded();
+                }
+                WRITE_MENU_PREVIEW_COMMANDS => {
+                    self.preview_commands();
+                }
+                WRITE_MENU_SIMULATE => {
+                    self.partial_window = None;
+                    self.simulate = true;
+                    self.write_to_tag();
+                }
+                WRITE_MENU_EXPORT_RECIPE => {
+                    self.export_recipe();
+                }
+                WRITE_MENU_SCREEN_SCALE => {
+                    self.screen_style.whole_pixels = !self.screen_style.whole_pixels;
+                    self.restyle_screen(WRITE_MENU_SCREEN_SCALE);
+                }
+                WRITE_MENU_SCREEN_INVERT => {
+                    self.screen_style.invert = !self.screen_style.invert;
+                    self.restyle_screen(WRITE_MENU_SCREEN_INVERT);
+                }
+                WRITE_MENU_CAPTION => {
+                    // Edit the image's caption, or start from the last one
+                    if let Some(caption) = &self.caption {
+                        self.caption_draft = caption.clone();
+                    }
+                    self.show_caption_input();
+                }
+                WRITE_MENU_REMEMBER => {
+                    self.toggle_remembered_options();
+                }
+                WRITE_MENU_OVERLAY => {
+                    self.toggle_overlay();
+                }
+                WRITE_MENU_THRESHOLD => {
+                    self.show_threshold();
+                }
+                WRITE_MENU_REAR_IMAGE => {
+                    self.target_face = Face::Rear;
+                    self.select_image();
+                }
+                WRITE_MENU_CANCEL => {
+                    self.image_data = None;
+                    self.rear_image = None;
+                    self.selected_tag = None;
+                    self.show_main_menu();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Decode the checkpoint's image again; writing then continues where it stopped
+    unsafe fn resume_write(&mut self) {
+        unsafe {
+            let Some(checkpoint) = &self.resume else {
+                return;
+            };
+            let (Some(tag), Ok(path)) = (checkpoint.tag(), CString::new(checkpoint.image_path.as_str())) else {
+                return;
+            };
+            if !image::sd_card_present() {
+                self.show_sd_card_removed();
+                return;
+            }
+
+            self.selected_tag = Some(tag);
+            self.target_face = Face::Front;
+            self.image_source = ImageSource::File;
+            self.start_decode(tag, DecodeSource::File(path));
+        }
+    }
+
+    /// Color mode (with the halftone screen angle), gamma, threshold, accent
+    /// pull, turn and match rule of `options` as `key=value` pairs joined by
+    /// `;`, the way `.eink` containers record them
+    fn conversion_note(&self, options: &DecodeOptions) -> String {
+        let mut note = alloc::format!("colors={}", options.quantizer.key());
+        if options.quantizer == QuantizerKind::Halftone {
+            note.push_str(&alloc::format!(";screen={}", self.settings.screen_angle.degrees()));
+        }
+        if self.settings.gamma != quantize::Gamma::LINEAR {
+            note.push_str(&alloc::format!(";gamma={}", self.settings.gamma));
+        }
+        if options.threshold != Threshold::DEFAULT {
+            note.push_str(&alloc::format!(";threshold={}", options.threshold));
+        }
+        if options.accent_pull != AccentPull::OFF {
+            note.push_str(&alloc::format!(";accent={}", options.accent_pull));
+        }
+        if options.rotation != Rotation::None {
+            note.push_str(&alloc::format!(";rotate={}", options.rotation.degrees()));
+        }
+        if self.settings.color_match != quantize::ColorMatch::Hue {
+            note.push_str(&alloc::format!(";match={}", self.settings.color_match.key()));
+        }
+        note
+    }
+
+    /// Write the packed tag buffer(s) to SD as `.eink` containers
+    ///
+    /// Files go next to the source image as `<name>_<part>.eink` (screen
+    /// captures go to the app data folder); a dual-face rear image gets a
+    /// `_rear` file too. The container records the tag type and color mode,
+    /// and only loads again for the same tag type. The menu item is
+    /// relabeled with the file name on success.
+    unsafe fn save_encoded(&mut self) {
+        unsafe {
+            let (Some(tag), Some(image)) = (self.selected_tag, &self.image_data) else {
+                return;
+            };
+
+            let base = match self.image_path.as_ref().map(|path| path.to_str()) {
+                Some(Ok(path)) => String::from(path.rsplit_once('.').map_or(path, |(stem, _)| stem)),
+                Some(Err(_)) => {
+                    self.show_message(c_str!("Error"), c_str!("Unsupported file name"));
+                    return;
+                }
+                None => {
+                    storage::make_data_dir();
+                    alloc::format!("{}/screen", storage::APP_DATA_DIR.to_str().unwrap_or(""))
+                }
+            };
+
+            // Note what produced the buffer; pre-packed input went through untouched
+            let colors = self.conversion_note(&self.decode_options(self.image_fit));
+            let transforms = match &self.image_path {
+                Some(path) if image::is_packed(path) => String::from("source=packed"),
+                Some(_) => colors.clone(),
+                None => String::from("source=screen"),
+            };
+            let save = |path: &str, transforms: &str, payload: &[u8]| {
+                let container = Container { part_number: tag.part_number, transforms, payload };
+                match CString::new(path) {
+                    Ok(path) => storage::write_file(&path, &container.encode()),
+                    Err(_) => false,
+                }
+            };
+
+            let front = alloc::format!("{}_{}.eink", base, tag.part_number);
+            let rear = alloc::format!("{}_{}_rear.eink", base, tag.part_number);
+            let mut saved = save(&front, &transforms, image.as_slice());
+            if let Some(rear_image) = &self.rear_image {
+                saved &= save(&rear, &alloc::format!("{};face=rear", colors), rear_image.as_slice());
+            }
+
+            if !saved {
+                self.show_message(c_str!("Error"), c_str!("Could not write file"));
+                return;
+            }
+            log_info!("Saved encoded data to {}", front);
+            // Stay in the write menu so the image can still be written
+            let name = front.rsplit('/').next().unwrap_or(&front);
+            if let Ok(c_label) = CString::new(alloc::format!("Saved {}", name)) {
+                sys::submenu_change_item_label(self.write_submenu, WRITE_MENU_SAVE_ENCODED, c_label.as_ptr());
+            }
+        }
+    }
+
+    /// List the commands a write would send, without touching a tag
+    ///
+    /// Protocols are set up exactly as in `write_to_tag`, so the listing
+    /// reflects the selected controller, auth key and rear face. A streamed
+    /// image has no packed frame; its protocol plans without one.
+    unsafe fn preview_commands(&mut self) {
+        unsafe {
+            let Some(tag) = self.selected_tag.filter(|tag| self.has_front_image(tag)) else {
+                return;
+            };
+            let front = self.image_data.as_ref().map_or(&[][..], AnyImage::as_slice);
+            let auth_key = self.settings.auth_key(tag);
+            let rear = self.rear_image.as_ref().filter(|_| tag.rear_face.is_some());
+            let plan = eink_protocol::handler(tag, auth_key)
+                .plan(front, rear.map(AnyImage::as_slice));
+
+            self.show_text(alloc::format!("{}\n{}", tag.part_number, plan));
+            self.plan_shown = true;
+        }
+    }
+
+    /// Show how many pixels of each color the front image has
+    unsafe fn show_color_report(&mut self) {
+        unsafe {
+            let (Some(tag), Some(image)) = (self.selected_tag, &self.image_data) else {
+                return;
+            };
+            let report = ColorReport::of(image, tag);
+            self.show_text(alloc::format!("{}\n{}", tag.part_number, report.details()));
+            self.plan_shown = true;
+        }
+    }
+
+    unsafe fn select_image(&mut self) {
+        unsafe {
+            let tag = match self.selected_tag {
+                Some(t) => t,
+                None => {
+                    self.show_main_menu();
+                    return;
+                }
+            };
+
+            // Re-check the card so a retry after ejecting it fails clearly
+            if !self.browse_root_present() {
+                self.show_sd_card_removed();
+                return;
+            }
+
+            // The browser filters on one extension, so list everything and
+            // let the loader reject what it can't read
+            if let Some(selected_path) = self.browse_file(c_str!("*")) {
+                if self.target_face == Face::Front {
+                    self.apply_remembered_options(&selected_path);
+                }
+                self.show_file_details(tag, selected_path);
+            }
+        }
+    }
+
+    /// Show what the header of the image at `path` says and how it will be
+    /// converted for `tag`, with a button to go ahead
+    ///
+    /// Packed buffers go straight to decoding; a file whose header can't be
+    /// read is reported without one.
+    unsafe fn show_file_details(&mut self, tag: &'static TagType, path: CString) {
+        unsafe {
+            if image::is_packed(&path) {
+                return self.start_decode(tag, DecodeSource::File(path));
+            }
+            let info = match image::image_info(&path) {
+                Ok(info) => info,
+                Err(error) => return self.show_image_error(error),
+            };
+            let Ok(c_text) = CString::new(self.file_details(tag, &path, &info)) else {
+                return self.start_decode(tag, DecodeSource::File(path));
+            };
+            self.details_path = Some(path);
+
+            sys::widget_reset(self.widget);
+            sys::widget_add_text_scroll_element(self.widget, 0, 0, 128, 50, c_text.as_ptr());
+            sys::widget_add_button_element(
+                self.widget,
+                sys::GuiButtonTypeRight,
+                c_str!("Convert"),
+                Some(details_button_callback),
+                self as *mut _ as *mut c_void,
+            );
+            self.current_view = VIEW_WIDGET;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_WIDGET);
+        }
+    }
+
+    /// File details text: name, header, size, and the rotation and fit the
+    /// conversion for `tag` will apply
+    fn file_details(&self, tag: &TagType, path: &CStr, info: &image::ImageInfo) -> String {
+        let (width, height) = info.size;
+        let mut text = batch::file_name(path);
+        text.push_str(&alloc::format!(
+            "\n{} {}x{}, {}-bit\n",
+            info.format,
+            width,
+            height,
+            info.bits_per_pixel
+        ));
+        if info.palette > 0 {
+            text.push_str(&alloc::format!("Palette: {} colors\n", info.palette));
+        }
+        if info.file_size < 1024 {
+            text.push_str(&alloc::format!("File: {} bytes\n", info.file_size));
+        } else {
+            text.push_str(&alloc::format!("File: {} KB\n", info.file_size.div_ceil(1024)));
+        }
+        if self.rotation != Rotation::None {
+            text.push_str(&alloc::format!("Rotated {} degrees\n", self.rotation.degrees()));
+        }
+        let target = self.rotation.source_size(tag);
+        let fit = self.remembered_fit.and_then(|fit| fit.for_size(info.size, target));
+        let scaling = match fit {
+            _ if info.size == target => String::from("Tag size, no scaling"),
+            Some(fit) => alloc::format!("Saved fit: {}", fit.key()),
+            None => alloc::format!("Not {}x{}: fit menu next", target.0, target.1),
+        };
+        text.push_str(&scaling);
+        text
+    }
+
+    /// Convert the image whose details are shown
+    unsafe fn confirm_file_details(&mut self) {
+        unsafe {
+            if let (Some(tag), Some(path)) = (self.selected_tag, self.details_path.take()) {
+                self.start_decode(tag, DecodeSource::File(path));
+            }
+        }
+    }
+
+    /// Pick any file in a folder and convert every image in that folder for
+    /// `tag` into the cache, behind the preview view
+    unsafe fn convert_folder(&mut self, tag: &'static TagType) {
+        unsafe {
+            if !self.browse_root_present() {
+                self.show_sd_card_removed();
+                return;
+            }
+            let picked = self.browse_file(c_str!("*"));
+            let Some(folder) = picked.and_then(|path| batch::folder_of(&path)) else {
+                return self.show_tag_menu();
+            };
+            let paths = batch::list_images(&folder);
+            if paths.is_empty() {
+                self.show_message(c_str!("Convert Folder"), c_str!("No images in folder"));
+                return;
+            }
+
+            let items = paths
+                .into_iter()
+                .map(|path| {
+                    let mut options = self.decode_options(Fit::Exact);
+                    let saved = ImageOptions::load(&path);
+                    if let Some(saved) = &saved {
+                        saved.apply(&mut options);
+                    }
+                    let note = self.conversion_note(&options);
+                    BatchItem { path, options, fit: saved.and_then(|saved| saved.fit), note }
+                })
+                .collect::<Vec<_>>();
+            log_info!("Converting {} images in {:?}", items.len(), folder);
+
+            self.preview.reset(0, 0);
+            self.current_view = VIEW_PREVIEW;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_PREVIEW);
+
+            let mut job = Box::new(BatchJob {
+                tag,
+                items,
+                tuning: self.settings.tuning.clone(),
+                preview: &mut self.preview,
+                view_dispatcher: self.view_dispatcher,
+                report: BatchReport::default(),
+            });
+            let context = &mut *job as *mut BatchJob as *mut c_void;
+            self.batch_job = Some(job);
+
+            self.worker = Worker::start(c"EinkBatch", batch_thread, context);
+            if self.worker.is_none() {
+                self.batch_job = None;
+                self.show_message(c_str!("Error"), c_str!("Out of memory"));
+            }
+        }
+    }
+
+    /// Show how the folder conversion went; Back returns to the main menu
+    unsafe fn finish_batch(&mut self) {
+        unsafe {
+            if let Some(worker) = self.worker.take() {
+                worker.join();
+            }
+            let Some(job) = self.batch_job.take() else {
+                return;
+            };
+            self.selected_tag = None;
+            self.show_text(job.report.summary());
+        }
+    }
+
+    /// Take the color mode, rotation, threshold, accent pull and fit saved
+    /// next to `path`, if any
+    ///
+    /// They replace the current choices as if picked in the main menu; the
+    /// fit waits until the image turns out not to be the tag's size.
+    unsafe fn apply_remembered_options(&mut self, path: &CStr) {
+        unsafe {
+            self.remembered_fit = None;
+            self.remembered_path = None;
+            let Some(options) = ImageOptions::load(path) else {
+                return;
+            };
+            log_info!("Applying saved options for {:?}", path);
+            if let Some(colors) = options.colors {
+                self.quantizer = colors;
+                let label = Self::color_mode_label(colors);
+                sys::submenu_change_item_label(self.submenu, MENU_COLOR_MODE, label);
+            }
+            if let Some(rotation) = options.rotation {
+                self.rotation = rotation;
+                let label = Self::rotation_label(rotation);
+                sys::submenu_change_item_label(self.submenu, MENU_ROTATION, label);
+            }
+            if let Some(threshold) = options.threshold {
+                self.threshold = threshold;
+            }
+            if let Some(pull) = options.accent_pull {
+                self.accent_pull = pull;
+                let label = Self::accent_pull_label(pull);
+                sys::submenu_change_item_label(self.submenu, MENU_ACCENT_PULL, label);
+            }
+            self.remembered_fit = options.fit;
+            self.remembered_path = Some(path.to_owned());
+        }
+    }
+
+    /// Save the front image's options next to it, or delete saved ones
+    unsafe fn toggle_remembered_options(&mut self) {
+        unsafe {
+            let Some(path) = self.image_path.clone() else {
+                return;
+            };
+            if self.remembered_path.as_ref() == Some(&path) {
+                ImageOptions::forget(&path);
+                self.remembered_path = None;
+            } else {
+                let options = ImageOptions {
+                    colors: Some(self.quantizer),
+                    rotation: Some(self.rotation),
+                    fit: Some(self.image_fit),
+                    threshold: Some(self.threshold),
+                    accent_pull: Some(self.accent_pull),
+                };
+                if !options.save(&path) {
+                    self.show_message(c_str!("Error"), c_str!("Could not write file"));
+                    return;
+                }
+                self.remembered_path = Some(path);
+            }
+            // Relabel the item in place
+            self.rebuild_write_menu();
+            sys::submenu_set_selected_item(self.write_submenu, WRITE_MENU_REMEMBER);
+        }
+    }
+
+    /// Pick an overlay for the front image, or take the current one off,
+    /// and decode the image again
+    ///
+    /// The overlay must be the size of the image as it is turned for the
+    /// tag; its key color (magenta unless `overlay_key` is set) shows the
+    /// image through.
+    unsafe fn toggle_overlay(&mut self) {
+        unsafe {
+            let Some(tag) = self.selected_tag else {
+                return;
+            };
+            if self.overlay.take().is_none() {
+                let Some(path) = self.browse_file(c_str!("*")) else {
+                    return;
+                };
+                let target = self.rotation.source_size(tag);
+                match image::image_size(&path) {
+                    Ok(size) if size == target => {}
+                    Ok(_) => {
+                        let message = alloc::format!("Overlay must be {}x{}", target.0, target.1);
+                        let message = CString::new(message).unwrap_or_default();
+                        self.show_message(c_str!("Wrong Size"), message.as_ptr());
+                        return;
+                    }
+                    Err(e) => {
+                        log_info!("Overlay unreadable: {:?}", e);
+                        self.show_message(c_str!("Error"), c_str!("Could not read overlay"));
+                        return;
+                    }
+                }
+                let key = self.settings.overlay_key.unwrap_or(image::OVERLAY_KEY);
+                self.overlay = Some(Overlay { path, key });
+            }
+            self.decode_front_again();
+        }
+    }
+
+    fn threshold_label(threshold: Threshold) -> CString {
+        CString::new(alloc::format!("Threshold: {}", threshold)).unwrap_or_default()
+    }
+
+    /// Open the threshold screen on a thumbnail of the front image file
+    unsafe fn show_threshold(&mut self) {
+        unsafe {
+            let (Some(tag), Some(path)) = (self.selected_tag, self.image_path.as_deref()) else {
+                return;
+            };
+            let size = (preview::PREVIEW_SIZE, preview::PREVIEW_SIZE);
+            let pixels = match image::load_thumbnail(path, size) {
+                Ok(pixels) => pixels,
+                Err(e) => {
+                    log_info!("Thumbnail failed: {:?}", e);
+                    self.show_message(c_str!("Error"), c_str!("Could not read image"));
+                    return;
+                }
+            };
+            self.threshold_view.reset(
+                pixels,
+                pack::for_tag(tag).palette(),
+                self.decode_options(self.image_fit),
+                self.view_dispatcher,
+                EVENT_THRESHOLD_DONE,
+            );
+            self.current_view = VIEW_THRESHOLD;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_THRESHOLD);
+        }
+    }
+
+    /// Decode the front image with the threshold picked on the screen, or
+    /// go back to the write menu if it was left as it was
+    unsafe fn finish_threshold(&mut self) {
+        unsafe {
+            match self.threshold_view.outcome() {
+                ThresholdOutcome::Apply(threshold) if threshold != self.threshold => {
+                    self.threshold = threshold;
+                    self.decode_front_again();
+                }
+                _ => self.show_write_menu(),
+            }
+        }
+    }
+
+    /// True if the **Browse** root can be read; only internal flash does
+    /// without the SD card
+    unsafe fn browse_root_present(&self) -> bool {
+        unsafe { !self.settings.browse_root.on_sd_card() || image::sd_card_present() }
+    }
+
+    /// Pick a file with the firmware file browser
+    ///
+    /// `extension` is the filter (".recipe", or "*" for every file). The
+    /// browser stays under the **Browse** root from the main menu. None if
+    /// the user backs out.
+    unsafe fn browse_file(&self, extension: *const core::ffi::c_char) -> Option<CString> {
+        unsafe {
+            let root = self.settings.browse_root.path();
+
+            // Open dialogs app
+            let dialogs = sys::furi_record_open(c_str!("dialogs")) as *mut sys::DialogsApp;
+
+            // Allocate path string
+            let path = sys::furi_string_alloc();
+            sys::furi_string_set_str(path, root.as_ptr());
+
+            let mut options: sys::DialogsFileBrowserOptions = core::mem::zeroed();
+            sys::dialog_file_browser_set_basic_options(&mut options, extension, null_mut());
+            options.base_path = root.as_ptr();
+            options.hide_dot_files = true;
+
+            // Show file browser
+            let selected = sys::dialog_file_browser_show(dialogs, path, path, &options);
+            let selected_path = CStr::from_ptr(sys::furi_string_get_cstr(path)).to_owned();
+
+            // Cleanup
+            sys::furi_string_free(path);
+            sys::furi_record_close(c_str!("dialogs"));
+
+            selected.then_some(selected_path)
+        }
+    }
+
+    /// Pick a `.recipe` file and decode its image for its tag type
+    ///
+    /// The recipe's color mode and rotation replace the current ones, as if
+    /// they had been picked in the main menu. The write menu follows as usual.
+    unsafe fn open_recipe(&mut self) {
+        unsafe {
+            if !self.browse_root_present() {
+                self.show_sd_card_removed();
+                return;
+            }
+            let Some(path) = self.browse_file(c_str!(".recipe")) else {
+                return;
+            };
+            let Some(recipe) = Recipe::load(&path) else {
+                self.show_message(c_str!("Error"), c_str!("Unreadable recipe"));
+                return;
+            };
+            let Some(image_path) = recipe.image_cstring() else {
+                self.show_message(c_str!("Error"), c_str!("Unsupported file name"));
+                return;
+            };
+            log_info!("Recipe {}: {} with {}", recipe.tag.part_number, recipe.image_path, recipe.colors.key());
+
+            self.quantizer = recipe.colors;
+            sys::submenu_change_item_label(self.submenu, MENU_COLOR_MODE, Self::color_mode_label(self.quantizer));
+            self.rotation = recipe.rotation;
+            sys::submenu_change_item_label(self.submenu, MENU_ROTATION, Self::rotation_label(self.rotation));
+            self.threshold = recipe.threshold;
+            self.accent_pull = recipe.accent_pull;
+            let label = Self::accent_pull_label(self.accent_pull);
+            sys::submenu_change_item_label(self.submenu, MENU_ACCENT_PULL, label);
+            self.selected_tag = Some(recipe.tag);
+            self.target_face = Face::Front;
+            self.caption = None;
+            self.overlay = None;
+            self.image_source = ImageSource::File;
+            self.start_decode(recipe.tag, DecodeSource::File(image_path));
+        }
+    }
+
+    /// Pick a `.template` file and start filling it in
+    ///
+    /// A template that names its tag type selects it; otherwise the tag menu
+    /// comes first, as for Text to Tag.
+    unsafe fn open_template(&mut self) {
+        unsafe {
+            if !self.browse_root_present() {
+                self.show_sd_card_removed();
+                return;
+            }
+            let Some(path) = self.browse_file(c_str!(".template")) else {
+                return;
+            };
+            let Some(template) = Template::load(&path) else {
+                self.show_message(c_str!("Error"), c_str!("Unreadable template"));
+                return;
+            };
+            log_info!("Template with {} field(s)", template.fields.len());
+
+            let tag = template.tag;
+            self.template = Some(template);
+            self.image_source = ImageSource::Template;
+            self.target_face = Face::Front;
+            match tag {
+                Some(tag) => {
+                    self.selected_tag = Some(tag);
+                    self.start_template(tag);
+                }
+                None => self.show_tag_menu(),
+            }
+        }
+    }
+
+    /// Ask for the open template's fields from the first one, for `tag`
+    unsafe fn start_template(&mut self, tag: &'static TagType) {
+        unsafe {
+            let Some(template) = &self.template else {
+                return;
+            };
+            if !template.fits(tag) {
+                self.show_message(c_str!("Template too big"), c_str!("Pick a larger tag"));
+                return;
+            }
+            self.template_answers.clear();
+            self.ask_template_field();
+        }
+    }
+
+    /// Ask for the next unanswered template field, or render the label once
+    /// all are in
+    unsafe fn ask_template_field(&mut self) {
+        unsafe {
+            let Some(template) = &self.template else {
+                return;
+            };
+            let Some(field) = template.fields.get(self.template_answers.len()) else {
+                self.render_template();
+                return;
+            };
+            self.field_buf[0] = 0;
+            sys::text_input_reset(self.text_input);
+            sys::text_input_set_header_text(self.text_input, field.prompt.as_ptr());
+            sys::text_input_set_result_callback(
+                self.text_input,
+                Some(template_input_callback),
+                self as *mut _ as *mut c_void,
+                self.field_buf.as_mut_ptr() as *mut core::ffi::c_char,
+                FIELD_LEN,
+                true,
+            );
+            self.current_view = VIEW_TEXT_INPUT;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_TEXT_INPUT);
+        }
+    }
+
+    /// Keep the typed answer and move on to the next field
+    unsafe fn on_template_field_done(&mut self) {
+        unsafe {
+            let answer = CStr::from_bytes_until_nul(&self.field_buf)
+                .ok()
+                .and_then(|s| s.to_str().ok())
+                .unwrap_or("");
+            self.template_answers.push(String::from(answer));
+            self.ask_template_field();
+        }
+    }
+
+    /// Render the filled-in template for the selected tag and go to the
+    /// write menu
+    ///
+    /// The logo is small, so it is decoded here on the GUI thread.
+    unsafe fn render_template(&mut self) {
+        unsafe {
+            let (Some(tag), Some(template)) = (self.selected_tag, &self.template) else {
+                return;
+            };
+            self.preview.reset(tag.width, tag.height);
+            let answers = &self.template_answers;
+            match template.render(answers, tag, self.quantizer, &mut self.preview) {
+                Ok(data) => {
+                    self.image_data = Some(data);
+                    self.rear_image = None;
+                    self.image_path = None;
+                    self.show_write_menu();
+                }
+                Err(ImageError::InvalidSize) => {
+                    self.show_message(c_str!("Text doesn't fit"), c_str!("Shorten a field"));
+                }
+                Err(ImageError::OpenFailed) => {
+                    self.show_message(c_str!("Error"), c_str!("Logo not found"));
+                }
+                Err(ImageError::SdCardRemoved) => self.show_sd_card_removed(),
+                Err(_) => {
+                    self.show_message(c_str!("Error"), c_str!("Unreadable logo"));
+                }
+            }
+        }
+    }
+
+    /// Save a recipe for the current tag, image, color mode and rotation
+    ///
+    /// It goes next to the image as `<name>_<part>.recipe` and refers to the
+    /// image by file name. The menu item is relabeled on success.
+    unsafe fn export_recipe(&mut self) {
+        unsafe {
+            let (Some(tag), Some(Ok(image_path))) =
+                (self.selected_tag, self.image_path.as_ref().map(|path| path.to_str()))
+            else {
+                return;
+            };
+            let stem = image_path.rsplit_once('.').map_or(image_path, |(stem, _)| stem);
+            let path = alloc::format!("{}_{}.recipe", stem, tag.part_number);
+            let recipe = Recipe {
+                tag,
+                image_path: String::from(image_path),
+                colors: self.quantizer,
+                rotation: self.rotation,
+                threshold: self.threshold,
+                accent_pull: self.accent_pull,
+            };
+
+            let saved = match CString::new(path.as_str()) {
+                Ok(c_path) => recipe.save(&c_path),
+                Err(_) => false,
+            };
+            if !saved {
+                self.show_message(c_str!("Error"), c_str!("Could not write file"));
+                return;
+            }
+            log_info!("Saved recipe to {}", path);
+            let name = path.rsplit('/').next().unwrap_or(&path);
+            if let Ok(c_label) = CString::new(alloc::format!("Saved {}", name)) {
+                sys::submenu_change_item_label(self.write_submenu, WRITE_MENU_EXPORT_RECIPE, c_label.as_ptr());
+            }
+        }
+    }
+
+    /// Capture the Flipper screen and render it for `tag`
+    ///
+    /// The app's view port is disabled while capturing so the GUI draws
+    /// whatever is underneath (usually the desktop).
+    unsafe fn capture_screen(&mut self, tag: &'static TagType) {
+        unsafe {
+            let capture = ScreenCapture::start();
+            self.start_decode(tag, DecodeSource::Screen(capture));
+        }
+    }
+
+    /// Whether the front image is the last screen capture, which the write
+    /// menu can then restyle
+    fn screen_capture_shown(&self) -> bool {
+        matches!(self.image_source, ImageSource::Screen)
+            && self.screen_frame.is_some()
+            && self.image_path.is_none()
+    }
+
+    fn screen_scale_label(style: ScreenStyle) -> *const core::ffi::c_char {
+        if style.whole_pixels {
+            c_str!("Screen Scale: Whole Pixels")
+        } else {
+            c_str!("Screen Scale: Fill")
+        }
+    }
+
+    fn screen_invert_label(style: ScreenStyle) -> *const core::ffi::c_char {
+        if style.invert {
+            c_str!("Invert: On")
+        } else {
+            c_str!("Invert: Off")
+        }
+    }
+
+    /// Render the kept screen capture again in the current style, leaving
+    /// the write menu on `item`
+    unsafe fn restyle_screen(&mut self, item: u32) {
+        unsafe {
+            let (Some(tag), Some(frame)) = (self.selected_tag, &self.screen_frame) else {
+                return;
+            };
+            self.preview.reset(tag.width, tag.height);
+            match image::load_screen(frame, tag, self.screen_style, &mut self.preview) {
+                Ok(data) => {
+                    self.image_data = Some(data);
+                    self.show_write_menu();
+                    sys::submenu_set_selected_item(self.write_submenu, item);
+                }
+                Err(error) => self.show_image_error(error),
+            }
+        }
+    }
+
+    /// Make a single-color image for `tag` and go straight to the write menu
+    ///
+    /// There is nothing to decode, so this runs on the GUI thread.
+    unsafe fn fill_tag(&mut self, tag: &'static TagType, color: Rgb) {
+        unsafe {
+            self.preview.reset(tag.width, tag.height);
+            match image::load_solid(tag, color, &mut self.preview) {
+                Ok(data) => {
+                    self.image_data = Some(data);
+                    self.rear_image = None;
+                    self.image_path = None;
+                    self.show_write_menu();
+                }
+                Err(_) => {
+                    self.show_message(c_str!("Error"), c_str!("Color not on this tag"));
+                }
+            }
+        }
+    }
+
+    /// Ask for the text to put on the tag, starting from the last one
+    unsafe fn show_text_input(&mut self) {
+        unsafe {
+            sys::text_input_reset(self.text_input);
+            sys::text_input_set_header_text(self.text_input, c_str!("Text for the tag"));
+            sys::text_input_set_result_callback(
+                self.text_input,
+                Some(label_input_callback),
+                self as *mut _ as *mut c_void,
+                self.label_buf.as_mut_ptr() as *mut core::ffi::c_char,
+                LABEL_LEN,
+                false,
+            );
+            self.current_view = VIEW_TEXT_INPUT;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_TEXT_INPUT);
+        }
+    }
+
+    /// Text menu label for the font scale
+    fn text_size_label(scale: Option<usize>) -> CString {
+        let label = match scale {
+            None => String::from("Size: Auto"),
+            Some(scale) => alloc::format!("Size: {}x", scale),
+        };
+        CString::new(label).unwrap_or_default()
+    }
+
+    fn text_kind_label(kind: TextKind) -> *const core::ffi::c_char {
+        match kind {
+            TextKind::Text => c_str!("Make: Text"),
+            TextKind::Qr => c_str!("Make: QR Code"),
+            TextKind::Barcode(Symbology::Code128) => c_str!("Make: Code 128"),
+            TextKind::Barcode(Symbology::Ean13) => c_str!("Make: EAN-13"),
+        }
+    }
+
+    fn text_align_label(align: TextAlign) -> *const core::ffi::c_char {
+        match align {
+            TextAlign::Left => c_str!("Align: Left"),
+            TextAlign::Center => c_str!("Align: Center"),
+            TextAlign::Right => c_str!("Align: Right"),
+        }
+    }
+
+    /// Offer what to make of the typed text, and its size and alignment
+    /// for plain text, before rendering it
+    unsafe fn show_text_menu(&mut self) {
+        unsafe {
+            let ctx = self as *mut _ as *mut c_void;
+            sys::submenu_reset(self.text_submenu);
+            let header = self.label_buf.as_ptr() as *const core::ffi::c_char;
+            sys::submenu_set_header(self.text_submenu, header);
+            sys::submenu_add_item(
+                self.text_submenu,
+                c_str!("Continue"),
+                TEXT_MENU_CONTINUE,
+                Some(text_menu_callback),
+                ctx,
+            );
+            sys::submenu_add_item(
+                self.text_submenu,
+                Self::text_kind_label(self.text_kind),
+                TEXT_MENU_KIND,
+                Some(text_menu_callback),
+                ctx,
+            );
+            if self.text_kind == TextKind::Text {
+                let size = Self::text_size_label(self.text_style.scale);
+                sys::submenu_add_item(
+                    self.text_submenu,
+                    size.as_ptr(),
+                    TEXT_MENU_SIZE,
+                    Some(text_menu_callback),
+                    ctx,
+                );
+                sys::submenu_add_item(
+                    self.text_submenu,
+                    Self::text_align_label(self.text_style.align),
+                    TEXT_MENU_ALIGN,
+                    Some(text_menu_callback),
+                    ctx,
+                );
+            }
+            sys::submenu_add_item(
+                self.text_submenu,
+                c_str!("Edit Text"),
+                TEXT_MENU_EDIT,
+                Some(text_menu_callback),
+                ctx,
+            );
+            self.current_view = VIEW_TEXT_MENU;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_TEXT_MENU);
+        }
+    }
+
+    unsafe fn on_text_menu_select(&mut self, index: u32) {
+        unsafe {
+            match index {
+                TEXT_MENU_CONTINUE => self.render_text(),
+                TEXT_MENU_KIND => {
+                    self.text_kind = match self.text_kind {
+                        TextKind::Text => TextKind::Qr,
+                        TextKind::Qr => TextKind::Barcode(Symbology::Code128),
+                        TextKind::Barcode(Symbology::Code128) => {
+                            TextKind::Barcode(Symbology::Ean13)
+                        }
+                        TextKind::Barcode(Symbology::Ean13) => TextKind::Text,
+                    };
+                    // Size and alignment only apply to text
+                    self.show_text_menu();
+                    sys::submenu_set_selected_item(self.text_submenu, TEXT_MENU_KIND);
+                }
+                TEXT_MENU_SIZE => {
+                    self.text_style.scale = match self.text_style.scale {
+                        None => Some(1),
+                        Some(image::MAX_TEXT_SCALE) => None,
+                        Some(scale) => Some(scale + 1),
+                    };
+                    let label = Self::text_size_label(self.text_style.scale);
+                    sys::submenu_change_item_label(
+                        self.text_submenu,
+                        TEXT_MENU_SIZE,
+                        label.as_ptr(),
+                    );
+                }
+                TEXT_MENU_ALIGN => {
+                    self.text_style.align = self.text_style.align.next();
+                    sys::submenu_change_item_label(
+                        self.text_submenu,
+                        TEXT_MENU_ALIGN,
+                        Self::text_align_label(self.text_style.align),
+                    );
+                }
+                TEXT_MENU_EDIT => self.show_text_input(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Render the typed text as chosen for the selected tag and go to the
+    /// write menu
+    unsafe fn render_text(&mut self) {
+        unsafe {
+            let Some(tag) = self.selected_tag else {
+                return;
+            };
+            let text = CStr::from_bytes_until_nul(&self.label_buf)
+                .ok()
+                .and_then(|s| s.to_str().ok())
+                .unwrap_or("");
+            self.preview.reset(tag.width, tag.height);
+            let result = match self.text_kind {
+                TextKind::Text => image::load_text(text, tag, self.text_style, &mut self.preview),
+                TextKind::Qr => compose::load_qr(text, tag, &mut self.preview),
+                TextKind::Barcode(symbology) => {
+                    compose::load_barcode(text, symbology, tag, &mut self.preview)
+                }
+            };
+            match result {
+                Ok(data) => {
+                    self.image_data = Some(data);
+                    self.rear_image = None;
+                    self.image_path = None;
+                    self.show_write_menu();
+                }
+                Err(error) => match self.text_kind {
+                    TextKind::Text => {
+                        self.show_message(
+                            c_str!("Text doesn't fit"),
+                            c_str!("Shorten it or pick Auto"),
+                        );
+                    }
+                    TextKind::Qr => {
+                        self.show_message(c_str!("QR doesn't fit"), c_str!("Shorten the text"));
+                    }
+                    TextKind::Barcode(symbology) => match (error, symbology) {
+                        (ImageError::InvalidFormat, Symbology::Ean13) => {
+                            self.show_message(
+                                c_str!("Not an EAN-13"),
+                                c_str!("Enter 12 or 13 digits"),
+                            );
+                        }
+                        (ImageError::InvalidFormat, Symbology::Code128) => {
+                            self.show_message(
+                                c_str!("Can't encode"),
+                                c_str!("Printable ASCII only"),
+                            );
+                        }
+                        _ => {
+                            self.show_message(
+                                c_str!("Barcode doesn't fit"),
+                                c_str!("Shorten the text"),
+                            );
+                        }
+                    },
+                },
+            }
+        }
+    }
+
+    /// Ask for the caption's text, starting from the one being edited
+    unsafe fn show_caption_input(&mut self) {
+        unsafe {
+            let text = self.caption_draft.text.as_bytes();
+            let len = text.len().min(CAPTION_LEN - 1);
+            self.caption_buf[..len].copy_from_slice(&text[..len]);
+            self.caption_buf[len] = 0;
+
+            sys::text_input_reset(self.text_input);
+            sys::text_input_set_header_text(self.text_input, c_str!("Caption"));
+            sys::text_input_set_result_callback(
+                self.text_input,
+                Some(caption_input_callback),
+                self as *mut _ as *mut c_void,
+                self.caption_buf.as_mut_ptr() as *mut core::ffi::c_char,
+                CAPTION_LEN,
+                false,
+            );
+            self.caption_input = true;
+            self.current_view = VIEW_TEXT_INPUT;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_TEXT_INPUT);
+        }
+    }
+
+    fn caption_position_label(anchor: Anchor) -> *const core::ffi::c_char {
+        match anchor {
+            Anchor::Center => c_str!("Position: Center"),
+            Anchor::TopLeft => c_str!("Position: Top Left"),
+            Anchor::Top => c_str!("Position: Top"),
+            Anchor::TopRight => c_str!("Position: Top Right"),
+            Anchor::Right => c_str!("Position: Right"),
+            Anchor::BottomRight => c_str!("Position: Bottom Right"),
+            Anchor::Bottom => c_str!("Position: Bottom"),
+            Anchor::BottomLeft => c_str!("Position: Bottom Left"),
+            Anchor::Left => c_str!("Position: Left"),
+        }
+    }
+
+    /// Caption menu label for palette entry `color` of `tag`
+    fn caption_color_label(tag: &TagType, color: u8) -> *const core::ffi::c_char {
+        let palette = pack::for_tag(tag).palette();
+        match palette.get(color as usize).map(|rgb| (rgb.r, rgb.g, rgb.b)) {
+            Some((0, 0, 0)) => c_str!("Color: Black"),
+            Some((255, 255, 255)) => c_str!("Color: White"),
+            Some((255, 0, 0)) => c_str!("Color: Red"),
+            Some((255, 255, 0)) => c_str!("Color: Yellow"),
+            _ => c_str!("Color: ?"),
+        }
+    }
+
+    /// Offer where and how to draw the typed caption before applying it
+    unsafe fn show_caption_menu(&mut self) {
+        unsafe {
+            let Some(tag) = self.selected_tag else {
+                return;
+            };
+            self.caption_draft.text = CStr::from_bytes_until_nul(&self.caption_buf)
+                .ok()
+                .and_then(|s| s.to_str().ok())
+                .map(String::from)
+                .unwrap_or_default();
+            // Another tag may have fewer colors than the last caption used
+            if self.caption_draft.color as usize >= pack::for_tag(tag).palette().len() {
+                self.caption_draft.color = image::BLACK;
+            }
+
+            let ctx = self as *mut _ as *mut c_void;
+            sys::submenu_reset(self.caption_submenu);
+            let header = self.caption_buf.as_ptr() as *const core::ffi::c_char;
+            sys::submenu_set_header(self.caption_submenu, header);
+            sys::submenu_add_item(
+                self.caption_submenu,
+                c_str!("Apply"),
+                CAPTION_MENU_APPLY,
+                Some(caption_menu_callback),
+                ctx,
+            );
+            sys::submenu_add_item(
+                self.caption_submenu,
+                Self::caption_position_label(self.caption_draft.anchor),
+                CAPTION_MENU_POSITION,
+                Some(caption_menu_callback),
+                ctx,
+            );
+            sys::submenu_add_item(
+                self.caption_submenu,
+                Self::caption_color_label(tag, self.caption_draft.color),
+                CAPTION_MENU_COLOR,
+                Some(caption_menu_callback),
+                ctx,
+            );
+            let size = Self::text_size_label(Some(self.caption_draft.scale));
+            sys::submenu_add_item(
+                self.caption_submenu,
+                size.as_ptr(),
+                CAPTION_MENU_SIZE,
+                Some(caption_menu_callback),
+                ctx,
+            );
+            sys::submenu_add_item(
+                self.caption_submenu,
+                c_str!("Edit Text"),
+                CAPTION_MENU_EDIT,
+                Some(caption_menu_callback),
+                ctx,
+            );
+            if self.caption.is_some() {
+                sys::submenu_add_item(
+                    self.caption_submenu,
+                    c_str!("Remove Caption"),
+                    CAPTION_MENU_REMOVE,
+                    Some(caption_menu_callback),
+                    ctx,
+                );
+            }
+            self.current_view = VIEW_CAPTION_MENU;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_CAPTION_MENU);
+        }
+    }
+
+    unsafe fn on_caption_menu_select(&mut self, index: u32) {
+        unsafe {
+            let Some(tag) = self.selected_tag else {
+                return;
+            };
+            match index {
+                CAPTION_MENU_APPLY => {
+                    let size = self.rotation.source_size(tag);
+                    if !self.caption_draft.fits(size) {
+                        self.show_message(
+                            c_str!("Caption doesn't fit"),
+                            c_str!("Shorten it or pick a smaller size"),
+                        );
+                        return;
+                    }
+                    self.caption = Some(self.caption_draft.clone());
+                    self.decode_front_again();
+                }
+                CAPTION_MENU_POSITION => {
+                    self.caption_draft.anchor = self.caption_draft.anchor.next();
+                    sys::submenu_change_item_label(
+                        self.caption_submenu,
+                        CAPTION_MENU_POSITION,
+                        Self::caption_position_label(self.caption_draft.anchor),
+                    );
+                }
+                CAPTION_MENU_COLOR => {
+                    let colors = pack::for_tag(tag).palette().len() as u8;
+                    self.caption_draft.color = (self.caption_draft.color + 1) % colors;
+                    sys::submenu_change_item_label(
+                        self.caption_submenu,
+                        CAPTION_MENU_COLOR,
+                        Self::caption_color_label(tag, self.caption_draft.color),
+                    );
+                }
+                CAPTION_MENU_SIZE => {
+                    let scale = self.caption_draft.scale % image::MAX_CAPTION_SCALE + 1;
+                    self.caption_draft.scale = scale;
+                    let label = Self::text_size_label(Some(self.caption_draft.scale));
+                    sys::submenu_change_item_label(
+                        self.caption_submenu,
+                        CAPTION_MENU_SIZE,
+                        label.as_ptr(),
+                    );
+                }
+                CAPTION_MENU_EDIT => self.show_caption_input(),
+                CAPTION_MENU_REMOVE => {
+                    self.caption = None;
+                    self.decode_front_again();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Decode the current front image again, fitted as before, to apply a
+    /// caption change
+    unsafe fn decode_front_again(&mut self) {
+        unsafe {
+            let (Some(tag), Some(path)) = (self.selected_tag, self.image_path.clone()) else {
+                return;
+            };
+            self.fit = self.image_fit;
+            self.target_face = Face::Front;
+            self.start_decode(tag, DecodeSource::File(path));
+        }
+    }
+
+    /// Decode an image on the worker thread behind the preview view
+    unsafe fn start_decode(&mut self, tag: &'static TagType, source: DecodeSource) {
+        unsafe {
+            // Screen captures are fitted to the panel as they are
+            let (width, height) = match source {
+                DecodeSource::File(_) => self.rotation.source_size(tag),
+                DecodeSource::Screen(_) => (tag.width, tag.height),
+            };
+            self.preview.reset(width, height);
+            match source {
+                DecodeSource::File(_) => {
+                    // Show the preview filling in while the image decodes
+                    self.current_view = VIEW_PREVIEW;
+                    sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_PREVIEW);
+                }
+                DecodeSource::Screen(_) => {
+                    // Hide the app so the capture sees the screen underneath
+                    self.current_view = sys::VIEW_NONE;
+                    sys::view_dispatcher_switch_to_view(self.view_dispatcher, sys::VIEW_NONE);
+                }
+            }
+
+            // A front image that will be streamed is packed as it is
+            // written, so only its preview is decoded now
+            let options = self.decode_options(self.fit);
+            let preview_only = match (&source, self.target_face) {
+                (DecodeSource::File(path), Face::Front) => self.streams(tag, path, options),
+                _ => false,
+            };
+
+            // Captioned images change with every edit, and an overlay file
+            // can change behind the stamp's back; not worth a slot
+            let plain = self.caption.is_none() && self.overlay.is_none();
+            let cache = match (&source, self.target_face) {
+                _ if preview_only => None,
+                (DecodeSource::File(path), face)
+                    if !image::is_packed(path) && (face == Face::Rear || plain) =>
+                {
+                    let note = self.conversion_note(&self.decode_options(self.fit));
+                    let conversion = cache_conversion(&note, self.fit, &self.settings.tuning);
+                    CacheEntry::new(path, tag, &conversion)
+                }
+                _ => None,
+            };
+
+            let mut job = Box::new(DecodeJob {
+                source,
+                face: self.target_face,
+                tag,
+                options,
+                preview_only,
+                caption: match self.target_face {
+                    Face::Front => self.caption.clone(),
+                    Face::Rear => None,
+                },
+                overlay: match self.target_face {
+                    Face::Front => self.overlay.clone(),
+                    Face::Rear => None,
+                },
+                cache,
+                screen_style: self.screen_style,
+                frame: None,
+                preview: &mut self.preview,
+                view_dispatcher: self.view_dispatcher,
+                result: None,
+            });
+            let context = &mut *job as *mut DecodeJob as *mut c_void;
+            self.decode_job = Some(job);
+
+            self.worker = Worker::start(c"EinkDecode", decode_thread, context);
+            if self.worker.is_none() {
+                self.decode_job = None;
+                self.show_message(c_str!("Error"), c_str!("Out of memory"));
+            }
+        }
+    }
+
+    /// Collect the decode result once the worker reports completion
+    unsafe fn finish_decode(&mut self) {
+        unsafe {
+            if let Some(worker) = self.worker.take() {
+                worker.join();
+            }
+
+            let Some(job) = self.decode_job.take() else {
+                return;
+            };
+            let DecodeJob { face, source, tag, options, frame, result, .. } = *job;
+            // A fit choice only holds for the image it was made for
+            self.fit = Fit::Exact;
+            // A sequence frame that didn't decode ends the sequence
+            let playing = self.sequence.is_some();
+            if !matches!(result, Some(Ok(_))) {
+                self.stop_sequence();
+            }
+
+            match result {
+                Some(Ok(data)) => {
+                    match face {
+                        Face::Front => {
+                            let path = match source {
+                                DecodeSource::File(path) => Some(path),
+                                DecodeSource::Screen(_) => None,
+                            };
+                            // A new front image starts a new pair; decoding
+                            // the same one again (caption) keeps the rear
+                            if path.is_none() || path != self.image_path {
+                                self.rear_image = None;
+                            }
+                            self.image_data = data;
+                            self.image_path = path;
+                            self.image_fit = options.fit;
+                            self.screen_frame = frame;
+                            // A saved fit is for this image, not the rear one
+                            self.remembered_fit = None;
+                        }
+                        Face::Rear => self.rear_image = data,
+                    }
+                    if playing {
+                        self.write_to_tag();
+                    } else {
+                        self.show_write_menu();
+                    }
+                }
+                Some(Err(ImageError::Cancelled)) => match face {
+                    // The last frame written is still there to write again
+                    Face::Front if playing => self.show_write_menu(),
+                    Face::Front => self.show_tag_menu(),
+                    Face::Rear => self.show_write_menu(),
+                },
+                Some(Err(ImageError::SdCardRemoved)) => {
+                    self.show_sd_card_removed();
+                }
+                Some(Err(ImageError::WrongTag)) => {
+                    self.show_message(c_str!("Wrong Tag"), c_str!("File is for another tag"));
+                }
+                Some(Err(ImageError::SizeMismatch { width, height })) => match source {
+                    DecodeSource::File(path) => {
+                        let target = self.rotation.source_size(tag);
+                        let remembered = self.remembered_fit.take();
+                        match remembered.and_then(|fit| fit.for_size((width, height), target)) {
+                            Some(fit) => {
+                                self.fit = fit;
+                                self.start_decode(tag, DecodeSource::File(path));
+                            }
+                            None => self.show_fit_menu(tag, path, (width, height)),
+                        }
+                    }
+                    DecodeSource::Screen(_) => {
+                        self.show_image_error(ImageError::SizeMismatch { width, height });
+                    }
+                },
+                Some(Err(error)) => self.show_image_error(error),
+                None => {
+                    self.show_message(c_str!("Error"), c_str!("Failed to load image"));
+                }
+            }
+        }
+    }
+
+    /// Offer ways to fit an image of `size` to `tag`
+    ///
+    /// Images larger than the tag in either direction can be shrunk, or
+    /// cropped around an anchor the menu cycles through in place. Smaller
+    /// ones can be padded out to the tag's size with a background color, or
+    /// blown up by a whole factor if at least 2x fits, which the menu names
+    /// so the user confirms the result size.
+    unsafe fn show_fit_menu(&mut self, tag: &TagType, path: CString, size: (usize, usize)) {
+        unsafe {
+            let target = self.rotation.source_size(tag);
+            let larger = size.0 > target.0 || size.1 > target.1;
+            let factor = resample::upscale_factor(size, target).filter(|_| !larger);
+            if self.pad_background == Background::Yellow && tag.image_format != ImageFormat::Bwry {
+                self.pad_background = Background::White;
+            }
+
+            let ctx = self as *mut _ as *mut c_void;
+            sys::submenu_reset(self.fit_submenu);
+            let header = alloc::format!("{}x{} for {}x{} tag", size.0, size.1, target.0, target.1);
+            if let Ok(c_header) = CString::new(header) {
+                sys::submenu_set_header(self.fit_submenu, c_header.as_ptr());
+            }
+            // A portrait image for a landscape tag (or the other way round)
+            // only needs turning; offered first, picking it confirms
+            if size != target && size == (target.1, target.0) {
+                sys::submenu_add_item(
+                    self.fit_submenu,
+                    c_str!("Rotate Clockwise"),
+                    FIT_MENU_ROTATE_CW,
+                    Some(fit_menu_callback),
+                    ctx,
+                );
+                sys::submenu_add_item(
+                    self.fit_submenu,
+                    c_str!("Rotate Counter-CW"),
+                    FIT_MENU_ROTATE_CCW,
+                    Some(fit_menu_callback),
+                    ctx,
+                );
+            }
+            if larger {
+                sys::submenu_add_item(
+                    self.fit_submenu,
+                    c_str!("Shrink to Fit"),
+                    FIT_MENU_SHRINK,
+                    Some(fit_menu_callback),
+                    ctx,
+                );
+                sys::submenu_add_item(
+                    self.fit_submenu,
+                    c_str!("Shrink Smoothly"),
+                    FIT_MENU_SHRINK_SMOOTH,
+                    Some(fit_menu_callback),
+                    ctx,
+                );
+                sys::submenu_add_item(
+                    self.fit_submenu,
+                    c_str!("Crop"),
+                    FIT_MENU_CROP,
+                    Some(fit_menu_callback),
+                    ctx,
+                );
+                sys::submenu_add_item(
+                    self.fit_submenu,
+                    Self::crop_anchor_label(self.crop_anchor),
+                    FIT_MENU_CROP_ANCHOR,
+                    Some(fit_menu_callback),
+                    ctx,
+                );
+            } else {
+                let label = alloc::format!("Pad to {}x{}", target.0, target.1);
+                if let Ok(c_label) = CString::new(label) {
+                    sys::submenu_add_item(
+                        self.fit_submenu,
+                        c_label.as_ptr(),
+                        FIT_MENU_PAD,
+                        Some(fit_menu_callback),
+                        ctx,
+                    );
+                }
+                sys::submenu_add_item(
+                    self.fit_submenu,
+                    Self::pad_background_label(self.pad_background),
+                    FIT_MENU_PAD_COLOR,
+                    Some(fit_menu_callback),
+                    ctx,
+                );
+            }
+            if let Some(factor) = factor {
+                let (width, height) = (size.0 * factor, size.1 * factor);
+                let label = alloc::format!("Upscale x{} to {}x{}", factor, width, height);
+                if let Ok(c_label) = CString::new(label) {
+                    sys::submenu_add_item(
+                        self.fit_submenu,
+                        c_label.as_ptr(),
+                        FIT_MENU_UPSCALE,
+                        Some(fit_menu_callback),
+                        ctx,
+                    );
+                }
+            }
+            self.fit_path = Some(path);
+            self.fit_size = size;
+            self.current_view = VIEW_FIT_MENU;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_FIT_MENU);
+        }
+    }
+
+    /// Decode the waiting image again, fitted as chosen
+    unsafe fn on_fit_menu_select(&mut self, index: u32) {
+        unsafe {
+            let Some(tag) = self.selected_tag else {
+                return;
+            };
+            let target = self.rotation.source_size(tag);
+            let fit = match index {
+                FIT_MENU_SHRINK => Fit::Shrink(Filter::Nearest),
+                FIT_MENU_SHRINK_SMOOTH => Fit::Shrink(Filter::Box),
+                FIT_MENU_CROP => Fit::Crop(self.crop_anchor),
+                FIT_MENU_CROP_ANCHOR => {
+                    self.crop_anchor = self.crop_anchor.next();
+                    sys::submenu_change_item_label(
+                        self.fit_submenu,
+                        FIT_MENU_CROP_ANCHOR,
+                        Self::crop_anchor_label(self.crop_anchor),
+                    );
+                    return;
+                }
+                FIT_MENU_PAD => Fit::Pad(self.pad_background),
+                FIT_MENU_PAD_COLOR => {
+                    let yellow = tag.image_format == ImageFormat::Bwry;
+                    self.pad_background = self.pad_background.next(yellow);
+                    sys::submenu_change_item_label(
+                        self.fit_submenu,
+                        FIT_MENU_PAD_COLOR,
+                        Self::pad_background_label(self.pad_background),
+                    );
+                    return;
+                }
+                FIT_MENU_UPSCALE => match resample::upscale_factor(self.fit_size, target) {
+                    Some(factor) => Fit::Upscale(factor),
+                    None => return,
+                },
+                FIT_MENU_ROTATE_CW | FIT_MENU_ROTATE_CCW => {
+                    // Turned a quarter, the image is the tag's size; the
+                    // rotation stays picked like one from the main menu
+                    self.rotation = match index {
+                        FIT_MENU_ROTATE_CW => self.rotation.next(),
+                        _ => self.rotation.previous(),
+                    };
+                    let label = Self::rotation_label(self.rotation);
+                    sys::submenu_change_item_label(self.submenu, MENU_ROTATION, label);
+                    Fit::Exact
+                }
+                _ => return,
+            };
+            let Some(path) = self.fit_path.take() else {
+                return;
+            };
+            self.fit = fit;
+            self.start_decode(tag, DecodeSource::File(path));
+        }
+    }
+
+    /// Tell the user the SD card is missing
+    unsafe fn show_sd_card_removed(&mut self) {
+        unsafe {
+            self.show_message(c_str!("SD card removed"), c_str!("Insert card and retry"));
+        }
+    }
+
+    /// Say what is wrong with an image and what to do about it
+    unsafe fn show_image_error(&mut self, error: ImageError) {
+        unsafe {
+            let title = CString::new(alloc::format!("{}", error)).unwrap_or_default();
+            let advice = CString::new(error.advice()).unwrap_or_default();
+            self.show_message(title.as_ptr(), advice.as_ptr());
+        }
+    }
+
+    unsafe fn on_custom_event(&mut self, event: u32) -> bool {
+        unsafe {
+            match event {
+                EVENT_DECODE_DONE => {
+                    self.finish_decode();
+                    true
+                }
+                EVENT_ALIGN_DONE => {
+                    self.finish_align();
+                    true
+                }
+                EVENT_SEQUENCE_NEXT => {
+                    self.next_sequence_frame();
+                    true
+                }
+                EVENT_THRESHOLD_DONE => {
+                    self.finish_threshold();
+                    true
+                }
+                EVENT_BATCH_DONE => {
+                    self.finish_batch();
+                    true
+                }
+                EVENT_WRITE_DONE => {
+                    self.finish_write();
+                    true
+                }
+                _ => false,
+            }
+        }
+    }
+
+    /// Show the live alignment meter until the tag sits well, then write
+    unsafe fn start_align(&mut self) {
+        unsafe {
+            let tag = match self.selected_tag {
+                Some(tag)
+                    if self.has_front_image(tag)
+                        && (tag.rear_face.is_none() || self.rear_image.is_some()) =>
+                {
+                    tag
+                }
+                // Let the write report what is missing
+                _ => return self.write_to_tag(),
+            };
+            if self.uses_pn532(tag) {
+                // The meter probes with the built-in reader only
+                return self.write_to_tag();
+            }
+
+            self.align.reset(tag.protocol.nfc_protocol(), self.view_dispatcher);
+            self.current_view = VIEW_ALIGN;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_ALIGN);
+
+            let context = &mut self.align as *mut Align as *mut c_void;
+            self.worker = Worker::start(c"EinkAlign", align_thread, context);
+            if self.worker.is_none() {
+                // No probe thread; write without the helper
+                self.write_to_tag();
+            }
+        }
+    }
+
+    /// Write or go back once the alignment probe has stopped
+    unsafe fn finish_align(&mut self) {
+        unsafe {
+            if let Some(worker) = self.worker.take() {
+                worker.join();
+            }
+
+            match self.align.outcome() {
+                AlignOutcome::Write => self.write_to_tag(),
+                AlignOutcome::Cancel => {
+                    self.stop_sequence();
+                    self.show_write_menu();
+                }
+            }
+        }
+    }
+
+    /// How image files are converted, with the current choices and `fit`
+    fn decode_options(&self, fit: Fit) -> DecodeOptions {
+        DecodeOptions {
+            quantizer: self.quantizer,
+            rotation: self.rotation,
+            fit,
+            threshold: self.threshold,
+            accent_pull: self.accent_pull,
+        }
+    }
+
+    /// Options the front image file is streamed with, if it can be
+    fn stream_options(&self, tag: &TagType) -> Option<DecodeOptions> {
+        let path = self.image_path.as_deref()?;
+        let options = self.decode_options(self.image_fit);
+        self.streams(tag, path, options).then_some(options)
+    }
+
+    /// True if the image file at `path` is written to `tag` through a
+    /// `FileStream` rather than as a packed frame
+    ///
+    /// Streaming is a setting, and only plain image files without a caption
+    /// or overlay on tags `FileStream::supports` qualify.
+    fn streams(&self, tag: &TagType, path: &CStr, options: DecodeOptions) -> bool {
+        self.settings.stream_writes
+            && tag.protocol == Protocol::IsodepBwry
+            && self.caption.is_none()
+            && self.overlay.is_none()
+            && !image::is_packed(path)
+            && FileStream::supports(tag, options)
+    }
+
+    /// True if there is a front image to write to `tag`
+    fn has_front_image(&self, tag: &TagType) -> bool {
+        self.image_data.is_some() || self.stream_options(tag).is_some()
+    }
+
+    /// True if `tag` is a single-panel, row-major GenB tag whose
+    /// controller can refresh part of the panel
+    fn partial_capable(tag: &TagType) -> bool {
+        let controller = tag.controller.unwrap_or(controller::Controller::Ssd1680);
+        tag.protocol == Protocol::IsodepGenb
+            && tag.rear_face.is_none()
+            && tag.scan_order == ScanOrder::RowMajor
+            && !controller.profile().partial_refresh.is_empty()
+    }
+
+    /// Write only the part of the image that differs from the last write
+    ///
+    /// The changed bytes are found by diffing against the buffer saved by
+    /// the last full or partial write to this tag type, so a price or a
+    /// counter goes out as a small window and a partial refresh.
+    unsafe fn write_changes(&mut self) {
+        unsafe {
+            let (Some(tag), Some(image)) = (self.selected_tag, &self.image_data) else {
+                return;
+            };
+            let window = match image::load_cached(LAST_WRITE_PATH, tag, LAST_WRITE_NOTE) {
+                Ok(base) => Window::of_changes(base.as_slice(), image.as_slice(), tag.geometry()),
+                Err(e) => {
+                    log_info!("Last write unavailable: {:?}", e);
+                    self.partial_base = None;
+                    self.show_message(c_str!("Error"), c_str!("Last write not found"));
+                    return;
+                }
+            };
+            let Some(window) = window else {
+                self.show_message(c_str!("No Changes"), c_str!("Tag already shows this"));
+                return;
+            };
+            log_info!("Changed window: {:?}", window);
+            self.partial_window = Some(window);
+            self.start_align();
+        }
+    }
+
+    /// Keep the buffer just written to a partial-refresh tag for the next
+    /// Write Changes Only
+    unsafe fn save_last_write(&mut self, tag: &'static TagType) {
+        unsafe {
+            let Some(image) = self.image_data.as_ref().filter(|_| Self::partial_capable(tag)) else {
+                return;
+            };
+            let container = Container {
+                part_number: tag.part_number,
+                transforms: LAST_WRITE_NOTE,
+                payload: image.as_slice(),
+            };
+            storage::make_data_dir();
+            let saved = storage::write_file(LAST_WRITE_PATH, &container.encode());
+            self.partial_base = saved.then_some(tag);
+        }
+    }
+
+    fn sequence_dwell_label(seconds: u32) -> CString {
+        CString::new(alloc::format!("Dwell: {}s", seconds)).unwrap_or_default()
+    }
+
+    fn sequence_loop_label(looping: bool) -> *const core::ffi::c_char {
+        if looping {
+            c_str!("Loop: On")
+        } else {
+            c_str!("Loop: Off")
+        }
+    }
+
+    /// True if the front image file is a numbered frame the selected tag
+    /// can play as a sequence
+    ///
+    /// Dual-panel tags are left out; every frame would need a rear image.
+    fn sequence_playable(&self) -> bool {
+        let single_panel = self.selected_tag.is_some_and(|tag| tag.rear_face.is_none());
+        let numbered = self.image_path.as_deref().and_then(|path| Sequence::parse(path, 0, false));
+        single_panel && numbered.is_some()
+    }
+
+    /// Write the front image, then each following frame after the dwell
+    unsafe fn start_sequence(&mut self) {
+        unsafe {
+            let Some(path) = self.image_path.as_deref() else {
+                return;
+            };
+            self.sequence = Sequence::parse(path, self.sequence_dwell, self.sequence_loop);
+            self.partial_window = None;
+            // Only the first frame waits for the tag to sit well; it stays put
+            self.start_align();
+        }
+    }
+
+    /// Count the frame just written and arm the timer for the next one
+    unsafe fn continue_sequence(&mut self) {
+        unsafe {
+            let context = self.view_dispatcher as *mut c_void;
+            let Some(sequence) = self.sequence.as_mut() else {
+                return;
+            };
+            sequence.written += 1;
+            let title = alloc::format!("Frame {} written", sequence.frame());
+            let title = CString::new(title).unwrap_or_default();
+            if sequence.next_frame().is_none() {
+                return self.end_sequence();
+            }
+            if !sequence.schedule(sequence_timer_callback, context) {
+                self.stop_sequence();
+                self.show_message(title.as_ptr(), c_str!("Sequence stopped"));
+                return;
+            }
+            let message = alloc::format!("Next in {}s, Back stops", sequence.dwell);
+            let message = CString::new(message).unwrap_or_default();
+            self.show_message(title.as_ptr(), message.as_ptr());
+        }
+    }
+
+    /// Decode the next frame once the dwell is over; it is written as soon
+    /// as it is ready
+    unsafe fn next_sequence_frame(&mut self) {
+        unsafe {
+            let (Some(tag), Some(sequence)) = (self.selected_tag, self.sequence.as_mut()) else {
+                return;
+            };
+            // The file may have gone during the dwell
+            let Some((number, path)) = sequence.next_frame() else {
+                return self.end_sequence();
+            };
+            sequence.set_frame(number);
+            if !image::sd_card_present() {
+                self.stop_sequence();
+                self.show_sd_card_removed();
+                return;
+            }
+            log_info!("Sequence frame {}: {:?}", number, path);
+            // Frames are fitted like the one the sequence started from
+            self.fit = self.image_fit;
+            self.target_face = Face::Front;
+            self.start_decode(tag, DecodeSource::File(path));
+        }
+    }
+
+    /// Report a sequence that ran out of frames
+    unsafe fn end_sequence(&mut self) {
+        unsafe {
+            let Some(sequence) = self.sequence.take() else {
+                return;
+            };
+            let message = alloc::format!("{} frames written", sequence.written);
+            let message = CString::new(message).unwrap_or_default();
+            self.show_message(c_str!("Sequence Done"), message.as_ptr());
+        }
+    }
+
+    /// Drop the sequence and any pending frame
+    fn stop_sequence(&mut self) {
+        if let Some(sequence) = self.sequence.take() {
+            log_info!("Sequence stopped after {} frame(s)", sequence.written);
+        }
+    }
+
+    /// True if writes to `tag` go through the external PN532
+    fn uses_pn532(&self, tag: &TagType) -> bool {
+        self.settings.reader == ReaderKind::Pn532 && tag.protocol.is_isodep()
+    }
+
+    unsafe fn write_to_tag(&mut self) {
+        unsafe {
+            let tag = match self.selected_tag {
+                Some(t) => t,
+                None => {
+                    self.show_message(c_str!("Error"), c_str!("No tag type selected"));
+                    return;
+                }
+            };
+
+            if !self.has_front_image(tag) {
+                self.show_message(c_str!("No Image"), c_str!("Select an image first"));
+                return;
+            }
+
+            // An image selected for streaming was only previewed, so the
+            // file is packed as packets go out; a frame already decoded is
+            // written as it is
+            let mut stream = None;
+            if self.image_data.is_none()
+                && let (Some(options), Some(path)) = (self.stream_options(tag), &self.image_path)
+            {
+                match FileStream::open(path, tag, options) {
+                    Ok(opened) => stream = Some(opened),
+                    Err(e) => {
+                        log_info!("Image can't be streamed: {:?}", e);
+                        self.show_message(c_str!("Error"), c_str!("Could not read image"));
+                        return;
+                    }
+                }
+            }
+            if tag.rear_face.is_some() && self.rear_image.is_none() {
+                self.show_message(c_str!("No Rear Image"), c_str!("Select the rear image"));
+                return;
+            }
+
+            // Show writing status, with the expected duration once there is history
+            let mut proto = eink_protocol::handler(tag, self.settings.auth_key(tag));
+            let simulate = core::mem::take(&mut self.simulate) && tag.protocol.is_isodep();
+            let title = match self.stats.estimate_ms(tag) {
+                _ if simulate => String::from("Simulating..."),
+                Some(ms) => alloc::format!("Writing... ~{}s", ms.div_ceil(1000)),
+                None => String::from("Writing..."),
+            };
+            let title = CString::new(title).unwrap_or_default();
+            self.show_message(title.as_ptr(), proto.status().as_ptr());
+
+            // IsoDep tags go through a transport: the mock when simulating,
+            // the external PN532 when selected, otherwise the built-in
+            // poller, checked against the type the tag was written as before
+            let check = !core::mem::take(&mut self.skip_tag_check);
+            let mut transport: Option<CheckedTransport> = None;
+            if tag.protocol.is_isodep() {
+                let opened = if simulate {
+                    Ok(Box::new(MockTransport::default()) as Box<dyn Transport>)
+                } else if self.uses_pn532(tag) {
+                    Pn532::open().map(|reader| Box::new(reader) as Box<dyn Transport>)
+                } else {
+                    PollerTransport::new().map(|poller| Box::new(poller) as Box<dyn Transport>)
+                };
+                match opened {
+                    Ok(opened) => transport = Some(CheckedTransport::new(opened, tag, check)),
+                    Err(e) => {
+                        self.stop_sequence();
+                        self.stats.record_failure(tag, e);
+                        self.show_error_with_hint(e);
+                        return;
+                    }
+                }
+            }
+
+            // Uploads of an image file can be resumed if they are interrupted
+            if tag.protocol == Protocol::IsodepEsl
+                && !simulate
+                && let Some(AnyImage::Bwr(image)) = &self.image_data
+                && let Some(path) = self.image_path.as_ref().and_then(|path| path.to_str().ok())
+            {
+                let checksum = checkpoint::checksum(image.as_slice());
+                let mut checkpoint = Checkpoint::new(tag.part_number, path, checksum);
+                let saved = self.resume.as_ref();
+                let saved = saved.filter(|saved| saved.resumes(tag.part_number, checksum));
+                if let Some(saved) = saved {
+                    checkpoint.uid = saved.uid.clone();
+                    checkpoint.packets_acked = saved.packets_acked;
+                }
+                proto.resume(checkpoint);
+            }
+
+            let partial_window = self.partial_window.take();
+            let rear_bytes = self.rear_image.as_ref().map_or(0, |rear| rear.as_slice().len());
+            let front_bytes = match (&stream, &self.image_data, partial_window) {
+                (Some(stream), _, _) => stream.size(),
+                (None, _, Some(window)) => 2 * window.plane_bytes(),
+                (None, Some(img), None) => img.as_slice().len(),
+                (None, None, None) => 0,
+            };
+
+            // The images go with the write and come back when it ends
+            let mut job = Box::new(WriteJob {
+                tag,
+                proto,
+                front: self.image_data.take(),
+                rear: self.rear_image.take(),
+                stream,
+                partial_window,
+                transport,
+                simulate,
+                bytes: front_bytes + rear_bytes,
+                view_dispatcher: self.view_dispatcher,
+                elapsed: 0,
+                temperature: None,
+                result: None,
+            });
+            let context = &mut *job as *mut WriteJob as *mut c_void;
+            self.write_job = Some(job);
+
+            self.worker = Worker::start(c"EinkWrite", write_thread, context);
+            if self.worker.is_none() {
+                if let Some(job) = self.write_job.take() {
+                    self.image_data = job.front;
+                    self.rear_image = job.rear;
+                }
+                self.stop_sequence();
+                self.show_message(c_str!("Error"), c_str!("Out of memory"));
+            }
+        }
+    }
+
+    /// Show how the write went once the worker reports completion
+    unsafe fn finish_write(&mut self) {
+        unsafe {
+            if let Some(worker) = self.worker.take() {
+                worker.join();
+            }
+            // A Back that came too late must not stop Tag Info or a script
+            protocol_common::clear_cancel();
+            let Some(job) = self.write_job.take() else {
+                return;
+            };
+            let WriteJob {
+                tag,
+                front,
+                rear,
+                partial_window,
+                transport,
+                simulate,
+                bytes,
+                elapsed,
+                temperature,
+                result,
+                ..
+            } = *job;
+            self.image_data = front;
+            self.rear_image = rear;
+
+            let Some((result, retries)) = result else {
+                // The worker thread always sets it before sending the event
+                self.stop_sequence();
+                return;
+            };
+
+            if simulate {
+                // Nothing reached a tag: stats, known tags and checkpoint stay as they were
+                match result {
+                    Ok(()) => {
+                        let (secs, tenths) = (elapsed / 1000, elapsed % 1000 / 100);
+                        let line = alloc::format!("Ran in {}.{} s, no tag", secs, tenths);
+                        let line = CString::new(line).unwrap_or_default();
+                        self.show_message(c_str!("Simulated"), line.as_ptr());
+                    }
+                    Err(e) => self.show_nfc_error(e),
+                }
+                return;
+            }
+
+            match result {
+                Ok(()) => {
+                    self.stats.record_write(tag, bytes, elapsed);
+                    self.save_last_write(tag);
+                    if let Some(transport) = &transport {
+                        transport.learn();
+                    }
+                    if self.sequence.is_some() {
+                        self.continue_sequence();
+                    } else if let Some(celsius) = temperature {
+                        // Refreshes run slow and pale in the cold
+                        let line = alloc::format!("Written, panel at {} C", celsius);
+                        let line = CString::new(line).unwrap_or_default();
+                        self.show_message(c_str!("Success!"), line.as_ptr());
+                    } else {
+                        self.show_message(c_str!("Success!"), c_str!("Image written to tag"));
+                    }
+                    self.show_retry_map(&retries);
+                }
+                Err(protocol_common::NfcError::NoTag) => {
+                    // Nobody held a tag up: no failure is counted, and Retry
+                    // sends the same write, changed area and all
+                    self.stop_sequence();
+                    self.partial_window = partial_window;
+                    self.show_no_tag();
+                }
+                Err(protocol_common::NfcError::Cancelled) => {
+                    // Back was pressed, so it doesn't count against the tag
+                    self.stop_sequence();
+                    self.show_message(c_str!("Cancelled"), c_str!("Write stopped"));
+                }
+                Err(protocol_common::NfcError::WrongTag) => {
+                    self.stop_sequence();
+                    let suspect = transport.as_ref().and_then(CheckedTransport::suspect);
+                    if let Some((known, by)) = suspect {
+                        self.show_tag_mismatch(tag, known, by);
+                    }
+                }
+                Err(e) => {
+                    self.stop_sequence();
+                    if self.stats.record_failure(tag, e) {
+                        // This tag type keeps failing; point at the usual fix
+                        self.show_error_with_hint(e);
+                    } else {
+                        self.show_nfc_error(e);
+                        self.show_retry_map(&retries);
+                    }
+                }
+            }
+
+            // The upload saved or cleared its checkpoint
+            if tag.protocol == Protocol::IsodepEsl {
+                self.refresh_resume();
+            }
+        }
+    }
+
+    /// Say that no tag turned up before the tag timeout, with buttons to
+    /// give up or wait again
+    unsafe fn show_no_tag(&mut self) {
+        unsafe {
+            self.show_message(c_str!("No tag found"), c_str!("Hold the tag to the back"));
+            self.no_tag_prompt = true;
+            sys::widget_add_button_element(
+                self.widget,
+                sys::GuiButtonTypeLeft,
+                c_str!("Cancel"),
+                Some(no_tag_button_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::widget_add_button_element(
+                self.widget,
+                sys::GuiButtonTypeRight,
+                c_str!("Retry"),
+                Some(no_tag_button_callback),
+                self as *mut _ as *mut c_void,
+            );
+        }
+    }
+
+    /// Answer the no-tag prompt: write again or go back to the write menu
+    unsafe fn resolve_no_tag(&mut self, retry: bool) {
+        unsafe {
+            self.no_tag_prompt = false;
+            if retry {
+                self.write_to_tag();
+            } else {
+                self.partial_window = None;
+                self.show_write_menu();
+            }
+        }
+    }
+
+    /// Warn that the tag about to be written was last written as `known`,
+    /// not the selected `tag`, with buttons to write anyway or switch
+    unsafe fn show_tag_mismatch(&mut self, tag: &TagType, known: &'static TagType, by: MatchedBy) {
+        unsafe {
+            // Types of one size differ in colors, so name them in full
+            let (known_size, size) = match (panel_size(known), panel_size(tag)) {
+                (known_size, size) if known_size != size => (known_size, size),
+                _ => (String::from(known.name), String::from(tag.name)),
+            };
+            // Historical bytes only tell the batch, not this tag
+            let written = match by {
+                MatchedBy::Uid => "It was last written as",
+                MatchedBy::Ats => "Tags like this were written as",
+            };
+            let text = alloc::format!(
+                "This looks like a {} panel, not {}.\n{} {}.",
+                known_size,
+                size,
+                written,
+                known.part_number
+            );
+            let Ok(c_text) = CString::new(text) else {
+                return;
+            };
+            self.mismatch_tag = Some(known);
+
+            sys::widget_reset(self.widget);
+            sys::widget_add_text_scroll_element(self.widget, 0, 0, 128, 50, c_text.as_ptr());
+            sys::widget_add_button_element(
+                self.widget,
+                sys::GuiButtonTypeLeft,
+                c_str!("Anyway"),
+                Some(mismatch_button_callback),
+                self as *mut _ as *mut c_void,
+            );
+            sys::widget_add_button_element(
+                self.widget,
+                sys::GuiButtonTypeRight,
+                c_str!("Switch"),
+                Some(mismatch_button_callback),
+                self as *mut _ as *mut c_void,
+            );
+            self.current_view = VIEW_WIDGET;
+            sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_WIDGET);
+        }
+    }
 
-            // Show writing status
-            let status_msg = match tag.protocol {
-                Protocol::IsodepBwry => c_str!("Writing BWRY..."),
-                Protocol::IsodepGenb => c_str!("Writing BWR..."),
+    /// Answer the tag mismatch warning: write to the tag as the selected
+    /// type after all, or select the type it was recognised as and convert
+    /// the image again for it
+    unsafe fn resolve_tag_mismatch(&mut self, switch: bool) {
+        unsafe {
+            let Some(known) = self.mismatch_tag.take() else {
+                return;
             };
-            self.show_message(c_str!("Writing..."), status_msg);
+            if !switch {
+                self.skip_tag_check = true;
+                return self.write_to_tag();
+            }
+            self.selected_tag = Some(known);
+            self.rear_image = None;
+            if self.image_path.is_some() {
+                self.decode_front_again();
+            } else {
+                self.image_data = None;
+                self.show_tag_menu();
+            }
+        }
+    }
+
+    /// Add a per-packet resend sparkline under the result message
+    ///
+    /// Each bar covers 1/64 of the transfer, so clusters show where in the
+    /// image the tag struggled. Nothing is drawn for a clean write.
+    unsafe fn show_retry_map(&mut self, retries: &RetryMap) {
+        unsafe {
+            let total = retries.total();
+            if total == 0 {
+                return;
+            }
+            for (packet, count) in retries.hot_packets() {
+                log_info!("Packet {}: {} failed attempt(s)", packet, count);
+            }
 
-            // Get image data and dispatch to appropriate protocol
-            let img = self.image_data.as_ref().unwrap();
+            let label = alloc::format!("Resends: {}", total);
+            if let Ok(c_label) = CString::new(label) {
+                sys::widget_add_string_element(
+                    self.widget,
+                    64,
+                    44,
+                    sys::AlignCenter,
+                    sys::AlignCenter,
+                    sys::FontSecondary,
+                    c_label.as_ptr(),
+                );
+            }
 
-            let result = match (tag.protocol, img) {
-                (Protocol::IsodepBwry, AnyImage::Bwry(image)) => {
-                    let mut proto = protocol_bwry::BwryProtocol::new();
-                    proto.write_image(image.as_slice())
+            // Bars grow up from the bottom edge, scaled to the busiest bucket
+            let buckets = retries.buckets(RETRY_BARS);
+            let peak = buckets.iter().copied().max().unwrap_or(1).max(1);
+            for (i, &count) in buckets.iter().enumerate() {
+                if count == 0 {
+                    continue;
                 }
-                (Protocol::IsodepGenb, AnyImage::Bwr(image)) => {
-                    let mut proto = protocol_genb::GenbProtocol::new();
-                    proto.write_image(image.as_slice())
+                let height = (count * RETRY_BAR_HEIGHT / peak).max(1);
+                sys::widget_add_rect_element(
+                    self.widget,
+                    (i * 2) as u8,
+                    (64 - height) as u8,
+                    2,
+                    height as u8,
+                    0,
+                    true,
+                );
+            }
+        }
+    }
+
+    /// Error screen for a failed NFC operation
+    ///
+    /// A transmit failure is named in the title, leaving the message line
+    /// for the step, packet and status word or link error code it failed
+    /// with.
+    unsafe fn show_nfc_error(&mut self, error: protocol_common::NfcError) {
+        unsafe {
+            match error {
+                protocol_common::NfcError::TransmitFailed(failure) => {
+                    let detail = CString::new(alloc::format!("{}", failure)).unwrap_or_default();
+                    self.show_message(Self::nfc_error_message(error), detail.as_ptr());
                 }
-                _ => {
-                    // This should never happen due to type safety
-                    self.show_message(c_str!("Error"), c_str!("Format mismatch"));
+                error => self.show_message(c_str!("Error"), Self::nfc_error_message(error)),
+            }
+        }
+    }
+
+    /// Error screen with a remediation hint underneath
+    unsafe fn show_error_with_hint(&mut self, error: protocol_common::NfcError) {
+        unsafe {
+            self.show_nfc_error(error);
+            sys::widget_add_string_element(
+                self.widget,
+                64,
+                50,
+                sys::AlignCenter,
+                sys::AlignCenter,
+                sys::FontSecondary,
+                c_str!("Keeps failing:"),
+            );
+            sys::widget_add_string_element(
+                self.widget,
+                64,
+                60,
+                sys::AlignCenter,
+                sys::AlignCenter,
+                sys::FontSecondary,
+                error.remedy().as_ptr(),
+            );
+        }
+    }
+
+    /// Short user-facing text for an NFC error
+    fn nfc_error_message(error: protocol_common::NfcError) -> *const core::ffi::c_char {
+        match error {
+            protocol_common::NfcError::DetectFailed => c_str!("Detection failed"),
+            protocol_common::NfcError::TransmitFailed(_) => c_str!("Transmit failed"),
+            protocol_common::NfcError::AllocFailed => c_str!("Alloc failed"),
+            protocol_common::NfcError::SizeMismatch => c_str!("Image size mismatch"),
+            protocol_common::NfcError::ReaderUnavailable => c_str!("PN532 not responding"),
+            protocol_common::NfcError::Busy => c_str!("NFC in use"),
+            protocol_common::NfcError::ImageReadFailed => c_str!("Image read failed"),
+            protocol_common::NfcError::WrongTag => c_str!("Wrong tag type"),
+            protocol_common::NfcError::VerifyFailed => c_str!("Verify failed"),
+            protocol_common::NfcError::Cancelled => c_str!("Write cancelled"),
+            protocol_common::NfcError::NoTag => c_str!("No tag found"),
+            protocol_common::NfcError::RefreshTimeout => c_str!("Refresh timed out"),
+        }
+    }
+
+    /// Read what a tag reports about itself and show it
+    unsafe fn show_tag_info(&mut self) {
+        unsafe {
+            self.show_message(c_str!("Tag Info"), c_str!("Hold tag to Flipper"));
+
+            let mut proto = protocol_info::InfoProtocol::new();
+            let info = match proto.read_info() {
+                Ok(info) => info,
+                Err(e) => {
+                    self.show_nfc_error(e);
                     return;
                 }
             };
 
-            match result {
-                Ok(()) => {
-                    self.show_message(c_str!("Success!"), c_str!("Image written to tag"));
+            let mut text = alloc::format!("UID: {}\n", hex_bytes(&info.uid));
+            if !info.historical.is_empty() {
+                text.push_str(&alloc::format!("ATS hist: {}\n", hex_bytes(&info.historical)));
+            }
+            match identify::known_as(&info.uid, &info.historical) {
+                Some((known, MatchedBy::Uid)) => {
+                    text.push_str(&alloc::format!("Written as: {}\n", known.name));
+                }
+                Some((known, MatchedBy::Ats)) => {
+                    text.push_str(&alloc::format!("Batch written as: {}\n", known.name));
+                }
+                None => {}
+            }
+            if info.bridge {
+                text.push_str("Bridge: GoodDisplay (INIT ok)\n");
+            } else {
+                text.push_str("Bridge: none (INIT rejected)\n");
+            }
+            if let Some(regs) = &info.registers {
+                text.push_str(&alloc::format!("Regs: {}\n", hex_bytes(regs)));
+            }
+            if let Some(status) = info.status {
+                text.push_str(&alloc::format!("Status: {:02X}\n", status));
+            }
+            match info.controller_status {
+                Some(status) => {
+                    // SSD16xx: bit 5 HV ready, bit 4 VCI ok, bits 1-0 chip ID
+                    let id = status & 0x03;
+                    text.push_str(&alloc::format!("Controller: {:02X} (ID {})\n", status, id));
                 }
+                None if info.bridge => text.push_str("Controller: no answer\n"),
+                None => {}
+            }
+
+            self.show_text(text);
+        }
+    }
+
+    /// Pick a `.apdu` script and send it to the next tag held to the reader
+    ///
+    /// Goes through the PN532 when it is the selected reader. The transcript
+    /// is shown like Tag Info; the log has every byte.
+    unsafe fn run_script(&mut self) {
+        unsafe {
+            if !self.browse_root_present() {
+                self.show_sd_card_removed();
+                return;
+            }
+            let Some(path) = self.browse_file(c_str!(".apdu")) else {
+                return;
+            };
+            let script = match ApduScript::load(&path) {
+                Ok(script) => script,
                 Err(e) => {
-                    let msg = match e {
-                        protocol_common::NfcError::DetectFailed => c_str!("Detection failed"),
-                        protocol_common::NfcError::TransmitFailed => c_str!("Transmit failed"),
-                        protocol_common::NfcError::AllocFailed => c_str!("Alloc failed"),
-                    };
-                    self.show_message(c_str!("Error"), msg);
+                    let message = CString::new(alloc::format!("{}", e)).unwrap_or_default();
+                    self.show_message(c_str!("Error"), message.as_ptr());
+                    return;
                 }
+            };
+            log_info!("APDU script: {} commands", script.commands());
+
+            self.show_message(c_str!("APDU Script"), c_str!("Hold tag to Flipper"));
+            let opened = if self.settings.reader == ReaderKind::Pn532 {
+                Pn532::open().map(|reader| Box::new(reader) as Box<dyn Transport>)
+            } else {
+                PollerTransport::new().map(|poller| Box::new(poller) as Box<dyn Transport>)
+            };
+            match opened.and_then(|mut transport| script.run(transport.as_mut())) {
+                Ok(transcript) => self.show_text(transcript),
+                Err(e) => self.show_nfc_error(e),
+            }
+        }
+    }
+
+    /// Show multi-line text in a scrollable widget
+    unsafe fn show_text(&mut self, text: String) {
+        unsafe {
+            if let Ok(c_text) = CString::new(text) {
+                sys::widget_reset(self.widget);
+                sys::widget_add_text_scroll_element(self.widget, 0, 0, 128, 64, c_text.as_ptr());
+                self.current_view = VIEW_WIDGET;
+                sys::view_dispatcher_switch_to_view(self.view_dispatcher, VIEW_WIDGET);
             }
         }
     }
 }
 
+/// Log the SDK API version the app was built against and the running firmware
+///
+/// The FAP loader resolves every import before `main` runs, so a firmware
+/// missing one of our NFC symbols refuses to start the app rather than
+/// letting it probe; this line is what to compare in a bug report.
+unsafe fn log_firmware_version() {
+    unsafe {
+        let version = sys::furi_hal_version_get_firmware_version();
+        let firmware = if version.is_null() {
+            c"unknown"
+        } else {
+            CStr::from_ptr(sys::version_get_version(version))
+        };
+        log_info!(
+            "Built for API {}.{}, running firmware {}",
+            sys::API_VERSION >> 16,
+            sys::API_VERSION & 0xFFFF,
+            firmware.to_str().unwrap_or("?")
+        );
+    }
+}
+
 /// Main menu item callback
 unsafe extern "C" fn menu_callback(context: *mut c_void, index: u32) {
     unsafe {
@@ -463,6 +4091,48 @@ unsafe extern "C" fn menu_callback(context: *mut c_void, index: u32) {
     }
 }
 
+/// Convert button on the file details screen
+unsafe extern "C" fn details_button_callback(
+    result: sys::GuiButtonType,
+    kind: sys::InputType,
+    context: *mut c_void,
+) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        if result == sys::GuiButtonTypeRight && kind == sys::InputTypeShort {
+            app.confirm_file_details();
+        }
+    }
+}
+
+/// Tag mismatch warning button callback
+unsafe extern "C" fn mismatch_button_callback(
+    result: sys::GuiButtonType,
+    kind: sys::InputType,
+    context: *mut c_void,
+) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        if kind == sys::InputTypeShort {
+            app.resolve_tag_mismatch(result == sys::GuiButtonTypeRight);
+        }
+    }
+}
+
+/// No-tag prompt button callback
+unsafe extern "C" fn no_tag_button_callback(
+    result: sys::GuiButtonType,
+    kind: sys::InputType,
+    context: *mut c_void,
+) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        if kind == sys::InputTypeShort {
+            app.resolve_no_tag(result == sys::GuiButtonTypeRight);
+        }
+    }
+}
+
 /// Tag menu item callback
 unsafe extern "C" fn tag_menu_callback(context: *mut c_void, index: u32) {
     unsafe {
@@ -471,6 +4141,81 @@ unsafe extern "C" fn tag_menu_callback(context: *mut c_void, index: u32) {
     }
 }
 
+/// Text input result callback (tag search)
+unsafe extern "C" fn text_input_callback(context: *mut c_void) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.on_tag_search_done();
+    }
+}
+
+/// Text input result callback (Text to Tag)
+unsafe extern "C" fn label_input_callback(context: *mut c_void) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.show_text_menu();
+    }
+}
+
+/// Text input result callback (template fields)
+unsafe extern "C" fn template_input_callback(context: *mut c_void) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.on_template_field_done();
+    }
+}
+
+/// Text input result callback (caption)
+unsafe extern "C" fn caption_input_callback(context: *mut c_void) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.caption_input = false;
+        app.show_caption_menu();
+    }
+}
+
+/// Caption menu item callback
+unsafe extern "C" fn caption_menu_callback(context: *mut c_void, index: u32) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.on_caption_menu_select(index);
+    }
+}
+
+/// Text menu item callback
+unsafe extern "C" fn text_menu_callback(context: *mut c_void, index: u32) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.on_text_menu_select(index);
+    }
+}
+
+/// Alignment probe running on the worker thread
+unsafe extern "C" fn align_thread(context: *mut c_void) -> i32 {
+    unsafe {
+        let align = &*(context as *const Align);
+        align.run();
+        sys::view_dispatcher_send_custom_event(align.view_dispatcher(), EVENT_ALIGN_DONE);
+        0
+    }
+}
+
+/// Byte input result callback (auth key)
+unsafe extern "C" fn byte_input_callback(context: *mut c_void) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.on_auth_key_done();
+    }
+}
+
+/// Fit menu item callback
+unsafe extern "C" fn fit_menu_callback(context: *mut c_void, index: u32) {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.on_fit_menu_select(index);
+    }
+}
+
 /// Write menu item callback
 unsafe extern "C" fn write_menu_callback(context: *mut c_void, index: u32) {
     unsafe {
@@ -479,6 +4224,168 @@ unsafe extern "C" fn write_menu_callback(context: *mut c_void, index: u32) {
     }
 }
 
+/// Worker thread entry point for image decoding
+unsafe extern "C" fn decode_thread(context: *mut c_void) -> i32 {
+    unsafe {
+        let job = &mut *(context as *mut DecodeJob);
+        let result = match &mut job.source {
+            DecodeSource::File(path) if job.preview_only => {
+                image::preview_file(path, job.tag, job.options, &mut *job.preview).map(|()| None)
+            }
+            DecodeSource::File(path) => match job.cache.as_ref().and_then(|c| c.load(job.tag)) {
+                Some(image) => {
+                    log_info!("Using cached conversion of {:?}", path);
+                    Ok(Some(image))
+                }
+                None => {
+                    let (caption, overlay) = (job.caption.as_ref(), job.overlay.as_ref());
+                    let preview = &mut *job.preview;
+                    let result =
+                        image::load_file(path, job.tag, job.options, caption, overlay, preview);
+                    if let (Ok(image), Some(cache)) = (&result, &job.cache)
+                        && !cache.store(job.tag, image)
+                    {
+                        log_info!("Could not cache the conversion");
+                    }
+                    result.map(Some)
+                }
+            },
+            DecodeSource::Screen(capture) => {
+                sys::furi_delay_ms(SCREEN_SETTLE_MS);
+                match capture.stop() {
+                    Some(frame) => {
+                        let style = job.screen_style;
+                        let result = image::load_screen(&frame, job.tag, style, &mut *job.preview);
+                        job.frame = Some(Box::new(frame));
+                        result.map(Some)
+                    }
+                    None => Err(ImageError::ReadFailed),
+                }
+            }
+        };
+        job.result = Some(result);
+        sys::view_dispatcher_send_custom_event(job.view_dispatcher, EVENT_DECODE_DONE);
+        0
+    }
+}
+
+/// Worker thread entry point for a tag write
+unsafe extern "C" fn write_thread(context: *mut c_void) -> i32 {
+    unsafe {
+        let job = &mut *(context as *mut WriteJob);
+        let started = sys::furi_get_tick();
+        job.result = Some(job.run());
+        job.elapsed = sys::furi_get_tick().wrapping_sub(started);
+        sys::view_dispatcher_send_custom_event(job.view_dispatcher, EVENT_WRITE_DONE);
+        0
+    }
+}
+
+/// Panel size at the start of a tag's name, e.g. `2.13in`; the whole name
+/// if it does not start with one
+fn panel_size(tag: &TagType) -> String {
+    let first = tag.name.split(' ').next().unwrap_or(tag.name);
+    match first.strip_suffix("inch") {
+        Some(size) => alloc::format!("{}in", size),
+        None => String::from(tag.name),
+    }
+}
+
+/// Cache stamp input for an image converted as `note` describes and fitted
+/// with `fit`; decoding and folder conversion must agree on it to share
+/// slots
+fn cache_conversion(note: &str, fit: Fit, tuning: &Tuning) -> String {
+    alloc::format!("{};fit={:?};tuning={:?}", note, fit, tuning)
+}
+
+/// Folder conversion worker: decode and cache each image in turn
+///
+/// Images already cached are only checked. An image of another size than
+/// the tag is decoded again with its remembered fit, if it has one. Back
+/// or a removed card stops the run; the rest count as skipped.
+unsafe extern "C" fn batch_thread(context: *mut c_void) -> i32 {
+    unsafe {
+        let job = &mut *(context as *mut BatchJob);
+        let preview = &mut *job.preview;
+        let count = job.items.len();
+        for (index, item) in job.items.iter().enumerate() {
+            if preview.cancelled() {
+                job.report.skipped = count - index;
+                break;
+            }
+            let target = item.options.rotation.source_size(job.tag);
+            preview.next_image(target.0, target.1, (index + 1, count));
+
+            let mut options = item.options;
+            let mut result = batch_convert(job, item, options, preview);
+            if let Err(BatchError::Image(ImageError::SizeMismatch { width, height })) = result
+                && let Some(fit) = item.fit.and_then(|fit| fit.for_size((width, height), target))
+            {
+                options.fit = fit;
+                preview.next_image(target.0, target.1, (index + 1, count));
+                result = batch_convert(job, item, options, preview);
+            }
+            match result {
+                Ok(true) => job.report.converted += 1,
+                Ok(false) => job.report.current += 1,
+                Err(BatchError::Image(ImageError::Cancelled)) => {
+                    job.report.skipped = count - index;
+                    break;
+                }
+                Err(error) => {
+                    job.report.failures.push((batch::file_name(&item.path), error));
+                    if matches!(error, BatchError::Image(ImageError::SdCardRemoved)) {
+                        job.report.skipped = count - index - 1;
+                        break;
+                    }
+                }
+            }
+        }
+        sys::view_dispatcher_send_custom_event(job.view_dispatcher, EVENT_BATCH_DONE);
+        0
+    }
+}
+
+/// Make sure `item` converted with `options` is cached; true if it had to
+/// be decoded, false if the cached conversion was current
+unsafe fn batch_convert(
+    job: &BatchJob,
+    item: &BatchItem,
+    options: DecodeOptions,
+    preview: &mut Preview,
+) -> Result<bool, BatchError> {
+    unsafe {
+        let conversion = cache_conversion(&item.note, options.fit, &job.tuning);
+        let Some(cache) = CacheEntry::new(&item.path, job.tag, &conversion) else {
+            return Err(BatchError::Image(ImageError::OpenFailed));
+        };
+        if cache.load(job.tag).is_some() {
+            return Ok(false);
+        }
+        let image = image::load_file(&item.path, job.tag, options, None, None, preview)?;
+        if !cache.store(job.tag, &image) {
+            return Err(BatchError::Store);
+        }
+        Ok(true)
+    }
+}
+
+/// Sequence dwell timer (timer thread): the next frame is due
+unsafe extern "C" fn sequence_timer_callback(context: *mut c_void) {
+    unsafe {
+        let view_dispatcher = context as *mut sys::ViewDispatcher;
+        sys::view_dispatcher_send_custom_event(view_dispatcher, EVENT_SEQUENCE_NEXT);
+    }
+}
+
+/// Custom event callback (worker completion)
+unsafe extern "C" fn custom_event_callback(context: *mut c_void, event: u32) -> bool {
+    unsafe {
+        let app = &mut *(context as *mut App);
+        app.on_custom_event(event)
+    }
+}
+
 /// Navigation callback (back button)
 unsafe extern "C" fn navigation_callback(context: *mut c_void) -> bool {
     unsafe {
@@ -490,17 +4397,85 @@ unsafe extern "C" fn navigation_callback(context: *mut c_void) -> bool {
                 sys::view_dispatcher_stop(app.view_dispatcher);
             }
             VIEW_TAG_MENU => {
-                // On tag menu, go back to main menu
-                app.show_main_menu();
+                if app.experimental_menu {
+                    // Experimental list goes back to the main tag list
+                    app.close_experimental_menu();
+                } else {
+                    // On tag menu, go back to main menu
+                    app.show_main_menu();
+                }
+            }
+            VIEW_TEXT_INPUT if app.caption_input => {
+                // Keep the image as it is
+                app.caption_input = false;
+                app.show_write_menu();
+            }
+            VIEW_TEXT_INPUT => {
+                // Abandon the search
+                app.show_tag_menu();
+            }
+            VIEW_BYTE_INPUT => {
+                // Keep the stored key
+                app.show_write_menu();
             }
             VIEW_WRITE_MENU => {
-                // On write menu, go back to tag menu and clear image
+                // On write menu, go back to tag menu and clear images
                 app.image_data = None;
+                app.rear_image = None;
                 app.show_tag_menu();
             }
+            VIEW_TEXT_MENU => {
+                // Keep the text for another tag
+                app.show_tag_menu();
+            }
+            VIEW_CAPTION_MENU => {
+                // Keep the image as it is
+                app.show_write_menu();
+            }
+            VIEW_WIDGET if app.write_job.is_some() => {
+                // The write stops at its next command; its result replaces this screen
+                protocol_common::request_cancel();
+                app.show_message(c_str!("Stopping..."), c_str!("Finishing the last command"));
+            }
+            VIEW_WIDGET if app.details_path.is_some() => {
+                // Drop the image, back to where it was picked
+                app.details_path = None;
+                match app.target_face {
+                    Face::Front => app.show_tag_menu(),
+                    Face::Rear => app.show_write_menu(),
+                }
+            }
+            VIEW_FIT_MENU => {
+                // Drop the image, back to where it was picked
+                app.fit_path = None;
+                match app.target_face {
+                    Face::Front => app.show_tag_menu(),
+                    Face::Rear => app.show_write_menu(),
+                }
+            }
+            VIEW_WIDGET if app.no_tag_prompt => {
+                // Same as Cancel
+                app.resolve_no_tag(false);
+            }
+            VIEW_WIDGET if app.mismatch_tag.is_some() => {
+                // Keep the image; the tag type can be changed from here
+                app.mismatch_tag = None;
+                app.show_write_menu();
+            }
+            VIEW_WIDGET if app.sequence.is_some() => {
+                // Stop during the dwell; the frame on the tag stays loaded
+                app.stop_sequence();
+                app.show_write_menu();
+            }
+            VIEW_WIDGET if app.plan_shown => {
+                // Command preview and color report keep the image for writing
+                app.plan_shown = false;
+                app.show_write_menu();
+            }
             _ => {
                 // On other views (widget), go back to main menu
                 app.image_data = None;
+                app.rear_image = None;
                 app.selected_tag = None;
                 app.show_main_menu();
             }
@@ -0,0 +1,219 @@
+//! Netpbm (PBM/PGM/PPM) decoding
+//!
+//! Both the plain (ASCII, P1-P3) and raw (binary, P4-P6) variants are read.
+//! Samples are scaled from the header's maxval to 8 bits; PBM bits are 1 for
+//! black. The formats have no palette or row padding, so scripts on the
+//! desktop side can write them with a few lines of code.
+
+use flipperzero_sys as sys;
+
+use crate::image::{ImageError, ImageResult, RowReader};
+use crate::quantize::Rgb;
+
+/// Bytes fetched from the file per read
+const BUFFER_SIZE: usize = 256;
+
+/// Which of the three Netpbm formats the file holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    /// PBM: 1 bit per pixel
+    Bitmap,
+    /// PGM: one gray sample per pixel
+    Graymap,
+    /// PPM: red, green and blue samples per pixel
+    Pixmap,
+}
+
+/// Streaming reader over an open Netpbm file
+///
+/// The file handle stays owned by the caller, which closes it.
+pub struct NetpbmReader {
+    file: *mut sys::File,
+    buf: [u8; BUFFER_SIZE],
+    pos: usize,
+    len: usize,
+    kind: Kind,
+    /// ASCII samples (P1-P3) rather than binary
+    plain: bool,
+    maxval: u32,
+    width: usize,
+    height: usize,
+}
+
+impl NetpbmReader {
+    /// Parse the header, leaving the reader at the first pixel
+    pub unsafe fn new(file: *mut sys::File) -> ImageResult<Self> {
+        unsafe {
+            let mut reader = Self {
+                file,
+                buf: [0; BUFFER_SIZE],
+                pos: 0,
+                len: 0,
+                kind: Kind::Bitmap,
+                plain: false,
+                maxval: 1,
+                width: 0,
+                height: 0,
+            };
+
+            if reader.byte()? != b'P' {
+                return Err(ImageError::InvalidFormat);
+            }
+            (reader.kind, reader.plain) = match reader.byte()? {
+                b'1' => (Kind::Bitmap, true),
+                b'2' => (Kind::Graymap, true),
+                b'3' => (Kind::Pixmap, true),
+                b'4' => (Kind::Bitmap, false),
+                b'5' => (Kind::Graymap, false),
+                b'6' => (Kind::Pixmap, false),
+                _ => return Err(ImageError::InvalidFormat),
+            };
+
+            reader.width = reader.number()? as usize;
+            reader.height = reader.number()? as usize;
+            if reader.kind != Kind::Bitmap {
+                reader.maxval = reader.number()?;
+            }
+            if reader.width == 0 || reader.height == 0 || !(1..=0xFFFF).contains(&reader.maxval) {
+                return Err(ImageError::InvalidFormat);
+            }
+
+            // `number` consumed the single whitespace byte that ends the header
+            Ok(reader)
+        }
+    }
+
+    /// Read one sample and scale it to 0..=255
+    unsafe fn sample(&mut self) -> ImageResult<u8> {
+        unsafe {
+            let value = if self.plain {
+                self.number()?
+            } else if self.maxval < 256 {
+                self.byte()? as u32
+            } else {
+                // Two bytes per sample, most significant first
+                u16::from_be_bytes([self.byte()?, self.byte()?]) as u32
+            };
+            let value = value.min(self.maxval);
+            Ok(((value * 255 + self.maxval / 2) / self.maxval) as u8)
+        }
+    }
+
+    /// Parse an ASCII decimal number, skipping whitespace and comments first
+    ///
+    /// The byte that ends the number is consumed.
+    unsafe fn number(&mut self) -> ImageResult<u32> {
+        unsafe {
+            let mut byte = self.skip_space()?;
+            if !byte.is_ascii_digit() {
+                return Err(ImageError::InvalidFormat);
+            }
+            let mut value: u32 = 0;
+            while byte.is_ascii_digit() {
+                value = value
+                    .checked_mul(10)
+                    .and_then(|v| v.checked_add((byte - b'0') as u32))
+                    .ok_or(ImageError::InvalidFormat)?;
+                byte = match self.byte() {
+                    Ok(b) => b,
+                    // The last sample of a plain file may end the file
+                    Err(ImageError::Truncated) => break,
+                    Err(e) => return Err(e),
+                };
+            }
+            Ok(value)
+        }
+    }
+
+    /// First byte that is neither whitespace nor part of a `#` comment
+    unsafe fn skip_space(&mut self) -> ImageResult<u8> {
+        unsafe {
+            loop {
+                match self.byte()? {
+                    b'#' => while self.byte()? != b'\n' {},
+                    byte if byte.is_ascii_whitespace() => {}
+                    byte => return Ok(byte),
+                }
+            }
+        }
+    }
+
+    /// Next byte of the file; running out is `Truncated`
+    unsafe fn byte(&mut self) -> ImageResult<u8> {
+        unsafe {
+            if self.pos == self.len {
+                self.len = sys::storage_file_read(self.file, self.buf.as_mut_ptr() as *mut _, BUFFER_SIZE);
+                self.pos = 0;
+                if self.len == 0 {
+                    return Err(ImageError::Truncated);
+                }
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            Ok(byte)
+        }
+    }
+}
+
+impl RowReader for NetpbmReader {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn format_name(&self) -> &'static str {
+        match self.kind {
+            Kind::Bitmap => "PBM",
+            Kind::Graymap => "PGM",
+            Kind::Pixmap => "PPM",
+        }
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        // Samples take as many bits as the largest value needs
+        let sample = (u32::BITS - self.maxval.leading_zeros()) as usize;
+        match self.kind {
+            Kind::Bitmap => 1,
+            Kind::Graymap => sample,
+            Kind::Pixmap => 3 * sample,
+        }
+    }
+
+    unsafe fn read_row(&mut self, row: &mut [Rgb]) -> ImageResult<()> {
+        unsafe {
+            match (self.kind, self.plain) {
+                (Kind::Bitmap, true) => {
+                    for pixel in row.iter_mut() {
+                        *pixel = match self.skip_space()? {
+                            b'0' => Rgb::new(255, 255, 255),
+                            b'1' => Rgb::new(0, 0, 0),
+                            _ => return Err(ImageError::InvalidFormat),
+                        };
+                    }
+                }
+                (Kind::Bitmap, false) => {
+                    // Rows are packed MSB first and padded to a whole byte
+                    let mut bits = 0u8;
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        if x % 8 == 0 {
+                            bits = self.byte()?;
+                        }
+                        let black = bits & (0x80 >> (x % 8)) != 0;
+                        *pixel = if black { Rgb::new(0, 0, 0) } else { Rgb::new(255, 255, 255) };
+                    }
+                }
+                (Kind::Graymap, _) => {
+                    for pixel in row.iter_mut() {
+                        let gray = self.sample()?;
+                        *pixel = Rgb::new(gray, gray, gray);
+                    }
+                }
+                (Kind::Pixmap, _) => {
+                    for pixel in row.iter_mut() {
+                        *pixel = Rgb::new(self.sample()?, self.sample()?, self.sample()?);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
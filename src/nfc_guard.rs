@@ -0,0 +1,55 @@
+//! Exclusive ownership of the NFC hardware
+//!
+//! Writes, Tag Info and the alignment probe each allocate their `Nfc`
+//! instance through `NfcGuard`. Only one guard exists at a time, so a scene
+//! or worker thread that starts a poller while another is still running gets
+//! `NfcError::Busy` instead of a second poller fighting over the HAL.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use flipperzero_sys as sys;
+
+use crate::protocol_common::{log_error, NfcError, NfcResult};
+
+/// Set while an `NfcGuard` is alive
+static OWNED: AtomicBool = AtomicBool::new(false);
+
+/// The app's only `Nfc` instance, freed (and the slot released) on drop
+///
+/// Pollers allocated on `nfc()` must be freed before the guard is dropped.
+pub struct NfcGuard {
+    nfc: *mut sys::Nfc,
+}
+
+impl NfcGuard {
+    /// Claim the NFC hardware, failing if another part of the app holds it
+    pub fn acquire() -> NfcResult<Self> {
+        if OWNED
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            log_error!("NFC already in use");
+            return Err(NfcError::Busy);
+        }
+
+        let nfc = unsafe { sys::nfc_alloc() };
+        if nfc.is_null() {
+            OWNED.store(false, Ordering::Release);
+            return Err(NfcError::AllocFailed);
+        }
+        Ok(Self { nfc })
+    }
+
+    /// Raw instance for `nfc_poller_alloc`
+    pub fn nfc(&self) -> *mut sys::Nfc {
+        self.nfc
+    }
+}
+
+impl Drop for NfcGuard {
+    fn drop(&mut self) {
+        unsafe {
+            sys::nfc_free(self.nfc);
+        }
+        OWNED.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,277 @@
+//! Frame buffer packing for e-ink panel formats
+//!
+//! A packer turns rows of palette indices (from a quantizer) into the byte
+//! layout a panel controller expects. The packer is chosen from the TagType,
+//! so loaders only deal with pixels and never with bit layouts. That
+//! includes the tag's `ScanOrder`: a row's bytes may land in a column of
+//! the buffer, or right to left, rather than side by side.
+//!
+//! Widths need not fill whole bytes (122, 250): each row's last byte is
+//! padded with white, so the bits past the panel edge are the same in every
+//! buffer whatever the controller does with them.
+
+use alloc::boxed::Box;
+
+use crate::image::WHITE;
+use crate::quantize::{self, Rgb};
+use crate::tag_type::{ImageFormat, ScanOrder, TagType};
+
+/// Packs rows of palette indices into a frame buffer
+pub trait Packer {
+    /// Palette the color indices passed to `pack_row` refer to
+    fn palette(&self) -> &'static [Rgb];
+
+    /// Packed buffer size in bytes
+    fn buffer_size(&self) -> usize;
+
+    /// Pack one row of palette indices into row `y` of `data`
+    fn pack_row(&self, data: &mut [u8], y: usize, colors: &[u8]);
+
+    /// Read row `y` of `data` back into palette indices, the inverse of
+    /// `pack_row`
+    fn unpack_row(&self, data: &[u8], y: usize, colors: &mut [u8]);
+}
+
+/// Select the packer for a tag's image format and native geometry
+///
+/// Rows passed to the packer are in the panel's RAM order; see
+/// `image::Orienter` for the mapping from image rows.
+pub fn for_tag(tag: &TagType) -> Box<dyn Packer> {
+    let (width, height) = tag.native_size();
+    match tag.image_format {
+        ImageFormat::Bwry => Box::new(BwryPacker::new(width, height, tag.scan_order)),
+        ImageFormat::Bwr => Box::new(BwrPacker::new(width, height, tag.scan_order)),
+        ImageFormat::Mono => Box::new(MonoPacker::new(width, height, tag.scan_order)),
+        ImageFormat::Acep => Box::new(AcepPacker::new(width, height, tag.scan_order)),
+    }
+}
+
+/// Bytes per packed row at `bits` bits per pixel
+fn row_stride(width: usize, bits: usize) -> usize {
+    (width * bits).div_ceil(8)
+}
+
+/// Every pixel slot of a packed row of `stride` bytes at `bits` bits per
+/// pixel, with its color: `colors` up to `width`, white past it
+fn padded_row(
+    colors: &[u8],
+    width: usize,
+    stride: usize,
+    bits: usize,
+) -> impl Iterator<Item = (usize, u8)> + '_ {
+    let slots = stride * 8 / bits;
+    let row = colors.iter().copied().take(width);
+    row.chain(core::iter::repeat(WHITE)).take(slots).enumerate()
+}
+
+/// Where each byte of a packed row lands in one plane of the buffer
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    /// Bytes per row
+    stride: usize,
+    rows: usize,
+    order: ScanOrder,
+}
+
+impl Layout {
+    fn new(width: usize, height: usize, bits: usize, order: ScanOrder) -> Self {
+        Self { stride: row_stride(width, bits), rows: height, order }
+    }
+
+    /// Plane size in bytes
+    fn size(&self) -> usize {
+        self.stride * self.rows
+    }
+
+    /// Buffer index of byte `col` of row `row`
+    fn index(&self, row: usize, col: usize) -> usize {
+        match self.order {
+            ScanOrder::RowMajor => row * self.stride + col,
+            ScanOrder::ColumnMajor => col * self.rows + row,
+            ScanOrder::Serpentine if row % 2 == 1 => row * self.stride + self.stride - 1 - col,
+            ScanOrder::Serpentine => row * self.stride + col,
+        }
+    }
+}
+
+/// 2bpp BWRY: 4 pixels per byte, MSB first, color code = palette index
+pub struct BwryPacker {
+    width: usize,
+    layout: Layout,
+}
+
+impl BwryPacker {
+    pub fn new(width: usize, height: usize, order: ScanOrder) -> Self {
+        Self { width, layout: Layout::new(width, height, 2, order) }
+    }
+}
+
+impl Packer for BwryPacker {
+    fn palette(&self) -> &'static [Rgb] {
+        quantize::BWRY_PALETTE
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn pack_row(&self, data: &mut [u8], y: usize, colors: &[u8]) {
+        let layout = self.layout;
+        for col in 0..layout.stride {
+            data[layout.index(y, col)] = 0;
+        }
+        for (x, color) in padded_row(colors, self.width, layout.stride, 2) {
+            data[layout.index(y, x / 4)] |= (color & 0x03) << (6 - 2 * (x % 4));
+        }
+    }
+
+    fn unpack_row(&self, data: &[u8], y: usize, colors: &mut [u8]) {
+        let layout = self.layout;
+        for (x, color) in colors.iter_mut().take(self.width).enumerate() {
+            *color = (data[layout.index(y, x / 4)] >> (6 - 2 * (x % 4))) & 0x03;
+        }
+    }
+}
+
+/// Dual-plane BWR: 1bpp B/W plane (white=1) followed by 1bpp red plane (red=1)
+///
+/// Both planes follow the scan order on their own.
+pub struct BwrPacker {
+    width: usize,
+    layout: Layout,
+}
+
+impl BwrPacker {
+    pub fn new(width: usize, height: usize, order: ScanOrder) -> Self {
+        Self { width, layout: Layout::new(width, height, 1, order) }
+    }
+
+    /// Size of one plane in bytes
+    pub fn plane_size(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+impl Packer for BwrPacker {
+    fn palette(&self) -> &'static [Rgb] {
+        quantize::BWR_PALETTE
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.plane_size() * 2
+    }
+
+    fn pack_row(&self, data: &mut [u8], y: usize, colors: &[u8]) {
+        let layout = self.layout;
+        let (bw_plane, red_plane) = data.split_at_mut(self.plane_size());
+        for col in 0..layout.stride {
+            bw_plane[layout.index(y, col)] = 0;
+            red_plane[layout.index(y, col)] = 0;
+        }
+        for (x, color) in padded_row(colors, self.width, layout.stride, 1) {
+            let (byte, mask) = (layout.index(y, x / 8), 0x80 >> (x % 8));
+            if color == quantize::BWR_WHITE {
+                bw_plane[byte] |= mask;
+            }
+            if color == quantize::BWR_RED {
+                red_plane[byte] |= mask;
+            }
+        }
+    }
+
+    fn unpack_row(&self, data: &[u8], y: usize, colors: &mut [u8]) {
+        let layout = self.layout;
+        let (bw_plane, red_plane) = data.split_at(self.plane_size());
+        for (x, color) in colors.iter_mut().take(self.width).enumerate() {
+            let (byte, mask) = (layout.index(y, x / 8), 0x80 >> (x % 8));
+            *color = if red_plane[byte] & mask != 0 {
+                quantize::BWR_RED
+            } else if bw_plane[byte] & mask != 0 {
+                quantize::BWR_WHITE
+            } else {
+                0
+            };
+        }
+    }
+}
+
+/// 1bpp B/W: 8 pixels per byte, MSB first, white=1
+pub struct MonoPacker {
+    width: usize,
+    layout: Layout,
+}
+
+impl MonoPacker {
+    pub fn new(width: usize, height: usize, order: ScanOrder) -> Self {
+        Self { width, layout: Layout::new(width, height, 1, order) }
+    }
+}
+
+impl Packer for MonoPacker {
+    fn palette(&self) -> &'static [Rgb] {
+        quantize::BW_PALETTE
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn pack_row(&self, data: &mut [u8], y: usize, colors: &[u8]) {
+        let layout = self.layout;
+        for col in 0..layout.stride {
+            data[layout.index(y, col)] = 0;
+        }
+        for (x, color) in padded_row(colors, self.width, layout.stride, 1) {
+            if color != 0 {
+                data[layout.index(y, x / 8)] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+
+    fn unpack_row(&self, data: &[u8], y: usize, colors: &mut [u8]) {
+        let layout = self.layout;
+        for (x, color) in colors.iter_mut().take(self.width).enumerate() {
+            *color = (data[layout.index(y, x / 8)] >> (7 - x % 8)) & 0x01;
+        }
+    }
+}
+
+/// 4bpp ACeP: 2 pixels per byte, high nibble first, color code = palette
+/// index
+pub struct AcepPacker {
+    width: usize,
+    layout: Layout,
+}
+
+impl AcepPacker {
+    pub fn new(width: usize, height: usize, order: ScanOrder) -> Self {
+        Self { width, layout: Layout::new(width, height, 4, order) }
+    }
+}
+
+impl Packer for AcepPacker {
+    fn palette(&self) -> &'static [Rgb] {
+        quantize::ACEP_PALETTE
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn pack_row(&self, data: &mut [u8], y: usize, colors: &[u8]) {
+        let layout = self.layout;
+        for col in 0..layout.stride {
+            data[layout.index(y, col)] = 0;
+        }
+        for (x, color) in padded_row(colors, self.width, layout.stride, 4) {
+            data[layout.index(y, x / 2)] |= (color & 0x0F) << (4 - 4 * (x % 2));
+        }
+    }
+
+    fn unpack_row(&self, data: &[u8], y: usize, colors: &mut [u8]) {
+        let layout = self.layout;
+        for (x, color) in colors.iter_mut().take(self.width).enumerate() {
+            *color = (data[layout.index(y, x / 2)] >> (4 - 4 * (x % 2))) & 0x0F;
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! External PN532 reader on the GPIO UART
+//!
+//! Bench setups with a bigger antenna can drive tags through a PN532 module
+//! wired to the Flipper's USART (pin 13 TX, pin 14 RX, 3V3, GND) in HSU mode
+//! at 115200 baud. The PN532 activates the tag itself (including RATS for
+//! ISO 14443-4) and carries each APDU with InDataExchange, so the IsoDep
+//! protocol state machines run unchanged on top of it as a `Transport`.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use flipperzero_sys as sys;
+
+use crate::iso_dep;
+use crate::protocol_common::{self, log_error, log_info, NfcError, NfcResult, TagWait};
+use crate::transport::Transport;
+
+/// UART speed of the PN532 HSU interface
+const BAUD_RATE: u32 = 115200;
+
+/// Bytes buffered between the UART interrupt and the reader
+const RX_BUFFER_SIZE: usize = 512;
+
+/// Time allowed for the ACK frame (ms)
+const ACK_TIMEOUT_MS: u32 = 50;
+
+/// Time allowed for a response frame, including the tag's answer (ms)
+const RESPONSE_TIMEOUT_MS: u32 = 1000;
+
+/// Delay between activation attempts while waiting for a tag (ms)
+const ACTIVATE_RETRY_MS: u32 = 100;
+
+/// Frame direction bytes (TFI)
+const HOST_TO_PN532: u8 = 0xD4;
+const PN532_TO_HOST: u8 = 0xD5;
+
+/// PN532 command codes
+mod commands {
+    pub const SAM_CONFIGURATION: u8 = 0x14;
+    pub const RF_CONFIGURATION: u8 = 0x32;
+    pub const IN_DATA_EXCHANGE: u8 = 0x40;
+    pub const IN_LIST_PASSIVE_TARGET: u8 = 0x4A;
+    pub const IN_RELEASE: u8 = 0x52;
+
+    /// Long preamble that wakes the PN532 from power-down in HSU mode
+    pub const WAKE_UP: &[u8] = &[
+        0x55, 0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// SAMConfiguration: normal mode, 1 s virtual card timeout, use IRQ
+    pub const SAM_NORMAL: &[u8] = &[0x01, 0x14, 0x01];
+
+    /// RFConfiguration item 5 (MaxRetries): ATR 0xFF, PSL 1, passive activation 2
+    ///
+    /// Limits InListPassiveTarget so it returns when no tag is present.
+    pub const MAX_RETRIES: &[u8] = &[0x05, 0xFF, 0x01, 0x02];
+
+    /// InListPassiveTarget: one target, 106 kbps type A
+    pub const LIST_TYPE_A: &[u8] = &[0x01, 0x00];
+
+    /// Logical number of the activated target
+    pub const TARGET: u8 = 0x01;
+
+    /// InDataExchange target bit: more data of the same command follows
+    pub const MORE_INFORMATION: u8 = 0x40;
+}
+
+/// Most data bytes one InDataExchange carries in a normal information
+/// frame (255 bytes less TFI, command code and target)
+const MAX_EXCHANGE_DATA: usize = 252;
+
+/// Open PN532 connection
+pub struct Pn532 {
+    serial: *mut sys::FuriHalSerialHandle,
+    rx: *mut sys::FuriStreamBuffer,
+    expansion: *mut sys::Expansion,
+    /// NFCID1 of the last activated target
+    uid: Vec<u8>,
+    /// Historical bytes of its ATS
+    historical: Vec<u8>,
+}
+
+impl Pn532 {
+    /// Take over the USART and check that a PN532 answers
+    ///
+    /// Expansion module support is disabled while the reader is open since
+    /// it listens on the same UART.
+    pub unsafe fn open() -> NfcResult<Self> {
+        unsafe {
+            let expansion = sys::furi_record_open(c"expansion".as_ptr()) as *mut sys::Expansion;
+            sys::expansion_disable(expansion);
+
+            let serial = sys::furi_hal_serial_control_acquire(sys::FuriHalSerialIdUsart);
+            if serial.is_null() {
+                log_error!("PN532: USART is busy");
+                sys::expansion_enable(expansion);
+                sys::furi_record_close(c"expansion".as_ptr());
+                return Err(NfcError::ReaderUnavailable);
+            }
+            sys::furi_hal_serial_init(serial, BAUD_RATE);
+
+            let rx = sys::furi_stream_buffer_alloc(RX_BUFFER_SIZE, 1);
+            if rx.is_null() {
+                sys::furi_hal_serial_deinit(serial);
+                sys::furi_hal_serial_control_release(serial);
+                sys::expansion_enable(expansion);
+                sys::furi_record_close(c"expansion".as_ptr());
+                return Err(NfcError::AllocFailed);
+            }
+            sys::furi_hal_serial_async_rx_start(serial, Some(rx_callback), rx as *mut c_void, false);
+            let mut reader =
+                Self { serial, rx, expansion, uid: Vec::new(), historical: Vec::new() };
+
+            sys::furi_hal_serial_tx(serial, commands::WAKE_UP.as_ptr(), commands::WAKE_UP.len());
+            sys::furi_hal_serial_tx_wait_complete(serial);
+            if reader.command(commands::SAM_CONFIGURATION, commands::SAM_NORMAL).is_none() {
+                log_error!("PN532: no answer to SAMConfiguration");
+                return Err(NfcError::ReaderUnavailable);
+            }
+            if reader.command(commands::RF_CONFIGURATION, commands::MAX_RETRIES).is_none() {
+                return Err(NfcError::ReaderUnavailable);
+            }
+            log_info!("PN532 ready");
+            Ok(reader)
+        }
+    }
+
+    /// Try to activate one ISO 14443A tag; false if none answered
+    unsafe fn list_target(&mut self) -> NfcResult<bool> {
+        unsafe {
+            match self.command(commands::IN_LIST_PASSIVE_TARGET, commands::LIST_TYPE_A) {
+                Some(resp) => {
+                    // NbTg Tg SENS_RES(2) SEL_RES NFCIDLength NFCID1... ATS...
+                    if resp.first().is_some_and(|&targets| targets > 0) {
+                        let uid_len = resp.get(5).copied().unwrap_or(0) as usize;
+                        self.uid = resp.get(6..6 + uid_len).map(<[u8]>::to_vec).unwrap_or_default();
+                        let ats = resp.get(6 + uid_len..).unwrap_or_default();
+                        self.historical = iso_dep::historical_bytes(ats);
+                        Ok(true)
+                    } else {
+                        Ok(false)
+                    }
+                }
+                None => Err(NfcError::ReaderUnavailable),
+            }
+        }
+    }
+
+    /// InDataExchange with the block `tx`; `target` carries the MI bit for
+    /// all but the last link of a chain, which the PN532 turns into ISO-DEP
+    /// chaining with the tag
+    unsafe fn data_exchange(&mut self, tx: &[u8], rx: &mut Vec<u8>, target: u8) -> bool {
+        unsafe {
+            let mut params = Vec::with_capacity(tx.len() + 1);
+            params.push(target);
+            params.extend_from_slice(tx);
+
+            let resp = match self.command(commands::IN_DATA_EXCHANGE, &params) {
+                Some(resp) => resp,
+                None => return false,
+            };
+            // Status byte: low 6 bits are the error code
+            match resp.split_first() {
+                Some((&status, data)) if status & 0x3F == 0 => {
+                    rx.clear();
+                    rx.extend_from_slice(data);
+                    true
+                }
+                Some((&status, _)) => {
+                    log_error!("PN532: InDataExchange status {:02X}", status);
+                    protocol_common::note_link_error(u32::from(status & 0x3F));
+                    false
+                }
+                None => false,
+            }
+        }
+    }
+
+    /// Send `cmd` and return the response parameters
+    unsafe fn command(&mut self, cmd: u8, params: &[u8]) -> Option<Vec<u8>> {
+        unsafe {
+            // Drop anything left over from an earlier timeout
+            sys::furi_stream_buffer_reset(self.rx);
+
+            let frame = build_frame(cmd, params);
+            sys::furi_hal_serial_tx(self.serial, frame.as_ptr(), frame.len());
+            sys::furi_hal_serial_tx_wait_complete(self.serial);
+
+            // ACK is an empty frame
+            if !self.read_frame(ACK_TIMEOUT_MS)?.is_empty() {
+                log_error!("PN532: expected ACK for {:02X}", cmd);
+                return None;
+            }
+
+            let body = self.read_frame(RESPONSE_TIMEOUT_MS)?;
+            if body.len() < 2 || body[0] != PN532_TO_HOST || body[1] != cmd.wrapping_add(1) {
+                log_error!("PN532: unexpected response to {:02X}", cmd);
+                return None;
+            }
+            Some(body[2..].to_vec())
+        }
+    }
+
+    /// Read one frame and return its body (TFI onwards); empty for ACK
+    unsafe fn read_frame(&mut self, timeout_ms: u32) -> Option<Vec<u8>> {
+        unsafe {
+            let deadline = sys::furi_get_tick().wrapping_add(sys::furi_ms_to_ticks(timeout_ms));
+
+            // Preamble and start code: any zeros, then 00 FF
+            let mut prev = 0xFF;
+            loop {
+                let byte = self.read_byte(deadline)?;
+                if prev == 0x00 && byte == 0xFF {
+                    break;
+                }
+                prev = byte;
+            }
+
+            let len = self.read_byte(deadline)?;
+            let lcs = self.read_byte(deadline)?;
+            if len.wrapping_add(lcs) != 0 {
+                log_error!("PN532: bad length checksum");
+                return None;
+            }
+            if len == 0 {
+                // ACK: 00 00 FF 00 FF 00
+                let _postamble = self.read_byte(deadline)?;
+                return Some(Vec::new());
+            }
+
+            let mut body = Vec::with_capacity(len as usize);
+            for _ in 0..len {
+                body.push(self.read_byte(deadline)?);
+            }
+            let dcs = self.read_byte(deadline)?;
+            let _postamble = self.read_byte(deadline)?;
+            let sum = body.iter().fold(dcs, |acc, b| acc.wrapping_add(*b));
+            if sum != 0 {
+                log_error!("PN532: bad data checksum");
+                return None;
+            }
+            Some(body)
+        }
+    }
+
+    /// Next received byte, or None once `deadline` (ticks) has passed
+    unsafe fn read_byte(&mut self, deadline: u32) -> Option<u8> {
+        unsafe {
+            let now = sys::furi_get_tick();
+            let remaining = deadline.wrapping_sub(now);
+            if remaining > i32::MAX as u32 {
+                return None;
+            }
+            let mut byte = 0u8;
+            let received = sys::furi_stream_buffer_receive(
+                self.rx,
+                &mut byte as *mut u8 as *mut c_void,
+                1,
+                remaining,
+            );
+            (received == 1).then_some(byte)
+        }
+    }
+}
+
+impl Transport for Pn532 {
+    unsafe fn activate(&mut self) -> NfcResult<()> {
+        unsafe {
+            // Keep waiting like the built-in poller does
+            let wait = TagWait::start();
+            while !self.list_target()? {
+                wait.check()?;
+                sys::furi_delay_ms(ACTIVATE_RETRY_MS);
+            }
+            log_info!("PN532: tag activated");
+            Ok(())
+        }
+    }
+
+    fn exchange(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool {
+        unsafe { self.data_exchange(tx, rx, commands::TARGET) }
+    }
+
+    fn exchange_chained(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool {
+        let target = commands::TARGET | commands::MORE_INFORMATION;
+        unsafe { self.data_exchange(tx, rx, target) }
+    }
+
+    fn max_block(&self) -> usize {
+        MAX_EXCHANGE_DATA
+    }
+
+    /// The PN532 answers WTX requests inside InDataExchange
+    fn held_ms(&self) -> u32 {
+        0
+    }
+
+    unsafe fn deactivate(&mut self) {
+        unsafe {
+            self.command(commands::IN_RELEASE, &[0x00]);
+        }
+    }
+
+    fn uid(&self) -> Vec<u8> {
+        self.uid.clone()
+    }
+
+    fn historical_bytes(&self) -> Vec<u8> {
+        self.historical.clone()
+    }
+}
+
+impl Drop for Pn532 {
+    fn drop(&mut self) {
+        unsafe {
+            sys::furi_hal_serial_async_rx_stop(self.serial);
+            sys::furi_hal_serial_deinit(self.serial);
+            sys::furi_hal_serial_control_release(self.serial);
+            sys::furi_stream_buffer_free(self.rx);
+            sys::expansion_enable(self.expansion);
+            sys::furi_record_close(c"expansion".as_ptr());
+        }
+    }
+}
+
+/// Normal information frame: 00 00 FF LEN LCS D4 CMD PARAMS... DCS 00
+fn build_frame(cmd: u8, params: &[u8]) -> Vec<u8> {
+    let len = (params.len() + 2) as u8;
+    let mut frame = Vec::with_capacity(params.len() + 9);
+    frame.extend_from_slice(&[0x00, 0x00, 0xFF, len, len.wrapping_neg(), HOST_TO_PN532, cmd]);
+    frame.extend_from_slice(params);
+    let sum = params
+        .iter()
+        .fold(HOST_TO_PN532.wrapping_add(cmd), |acc, b| acc.wrapping_add(*b));
+    frame.push(sum.wrapping_neg());
+    frame.push(0x00);
+    frame
+}
+
+/// UART receive interrupt: move bytes into the stream buffer
+unsafe extern "C" fn rx_callback(
+    handle: *mut sys::FuriHalSerialHandle,
+    event: sys::FuriHalSerialRxEvent,
+    context: *mut c_void,
+) {
+    unsafe {
+        let rx = context as *mut sys::FuriStreamBuffer;
+        if event.0 & sys::FuriHalSerialRxEventData.0 != 0 {
+            while sys::furi_hal_serial_async_rx_available(handle) {
+                let byte = sys::furi_hal_serial_async_rx(handle);
+                sys::furi_stream_buffer_send(rx, &byte as *const u8 as *const c_void, 1, 0);
+            }
+        }
+    }
+}
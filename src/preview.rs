@@ -0,0 +1,254 @@
+//! Progressive image preview shown while an image is decoded
+//!
+//! The loader reports each finished row to the preview, which downsamples it
+//! into a 64x64 thumbnail and publishes it to the view model one band of rows
+//! at a time. The GUI thread redraws from the model, so the thumbnail fills
+//! in while decoding continues on the worker thread. Back requests
+//! cancellation, which the loader observes at the next row. A folder
+//! conversion shows each image in turn, numbered.
+
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+use flipperzero_sys as sys;
+
+use crate::image::DecodeObserver;
+use crate::input;
+
+/// Thumbnail edge length in screen pixels
+pub const PREVIEW_SIZE: usize = 64;
+
+/// Bytes per 1bpp thumbnail bitmap
+const PREVIEW_BYTES: usize = PREVIEW_SIZE * PREVIEW_SIZE / 8;
+
+/// Decoded rows between model updates
+const BAND_ROWS: usize = 8;
+
+/// Palette index used for white by every panel palette
+const WHITE: u8 = 1;
+
+/// Spinner frames drawn next to the progress text
+const SPINNER: [&core::ffi::CStr; 4] = [c"|", c"/", c"-", c"\\"];
+
+/// Palette indices shrunk to the thumbnail, as two 1bpp bitmaps
+#[derive(Clone, Copy)]
+pub struct Thumbnail {
+    /// Pixels drawn solid (black)
+    dark: [u8; PREVIEW_BYTES],
+    /// Pixels drawn checkered (red/yellow accents)
+    accent: [u8; PREVIEW_BYTES],
+}
+
+impl Thumbnail {
+    pub const EMPTY: Thumbnail = Thumbnail { dark: [0; PREVIEW_BYTES], accent: [0; PREVIEW_BYTES] };
+
+    /// Show palette index `color` at (`px`, `py`)
+    pub fn set(&mut self, px: usize, py: usize, color: u8) {
+        let bit = py * PREVIEW_SIZE + px;
+        let mask = 1 << (bit % 8);
+        self.dark[bit / 8] &= !mask;
+        self.accent[bit / 8] &= !mask;
+        if color == 0 {
+            self.dark[bit / 8] |= mask;
+        } else if color != WHITE {
+            self.accent[bit / 8] |= mask;
+        }
+    }
+
+    /// Draw framed in the top left corner of the screen
+    pub unsafe fn draw(&self, canvas: *mut sys::Canvas) {
+        unsafe {
+            sys::canvas_draw_frame(canvas, 0, 0, PREVIEW_SIZE, PREVIEW_SIZE);
+            for py in 0..PREVIEW_SIZE {
+                for px in 0..PREVIEW_SIZE {
+                    let bit = py * PREVIEW_SIZE + px;
+                    let mask = 1 << (bit % 8);
+                    let dark = self.dark[bit / 8] & mask != 0;
+                    let accent = self.accent[bit / 8] & mask != 0 && (px + py) % 2 == 0;
+                    if dark || accent {
+                        sys::canvas_draw_dot(canvas, px as i32, py as i32);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// View model shared with the GUI thread
+struct PreviewModel {
+    thumbnail: Thumbnail,
+    rows_done: u16,
+    total_rows: u16,
+    /// Number of the image and size of the batch it is part of; (0, 0)
+    /// for a single image
+    position: (u16, u16),
+    frame: u8,
+}
+
+/// Preview view plus staging state for the decode in progress
+pub struct Preview {
+    view: *mut sys::View,
+    cancel: AtomicBool,
+    width: usize,
+    height: usize,
+    rows_done: usize,
+    position: (usize, usize),
+    thumbnail: Thumbnail,
+}
+
+impl Preview {
+    pub fn new() -> Self {
+        Self {
+            view: core::ptr::null_mut(),
+            cancel: AtomicBool::new(false),
+            width: 0,
+            height: 0,
+            rows_done: 0,
+            position: (0, 0),
+            thumbnail: Thumbnail::EMPTY,
+        }
+    }
+
+    /// Allocate the view; `self` must stay at a fixed address while it exists
+    pub unsafe fn alloc(&mut self) -> bool {
+        unsafe {
+            self.view = sys::view_alloc();
+            if self.view.is_null() {
+                return false;
+            }
+            sys::view_allocate_model(
+                self.view,
+                sys::ViewModelTypeLocking,
+                core::mem::size_of::<PreviewModel>(),
+            );
+            sys::view_set_context(self.view, self as *mut _ as *mut c_void);
+            sys::view_set_draw_callback(self.view, Some(draw_callback));
+            sys::view_set_input_callback(self.view, Some(input_callback));
+            true
+        }
+    }
+
+    pub unsafe fn free(&mut self) {
+        unsafe {
+            if !self.view.is_null() {
+                sys::view_free(self.view);
+                self.view = core::ptr::null_mut();
+            }
+        }
+    }
+
+    pub fn view(&self) -> *mut sys::View {
+        self.view
+    }
+
+    /// Clear the thumbnail and cancel flag before decoding a `width` x `height` image
+    pub unsafe fn reset(&mut self, width: usize, height: usize) {
+        self.cancel.store(false, Ordering::Relaxed);
+        unsafe { self.next_image(width, height, (0, 0)) };
+    }
+
+    /// Clear the thumbnail for image `position.0` of a batch of
+    /// `position.1`, keeping a cancel request from the image before
+    pub unsafe fn next_image(&mut self, width: usize, height: usize, position: (usize, usize)) {
+        self.width = width;
+        self.height = height;
+        self.rows_done = 0;
+        self.position = position;
+        self.thumbnail = Thumbnail::EMPTY;
+        unsafe { self.publish() };
+    }
+
+    /// Whether Back was pressed during the last decode
+    pub fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    /// Copy the staged thumbnail and progress into the view model
+    unsafe fn publish(&mut self) {
+        unsafe {
+            let model = sys::view_get_model(self.view) as *mut PreviewModel;
+            (*model).thumbnail = self.thumbnail;
+            (*model).rows_done = self.rows_done as u16;
+            (*model).total_rows = self.height as u16;
+            (*model).position = (self.position.0 as u16, self.position.1 as u16);
+            (*model).frame = (*model).frame.wrapping_add(1);
+            sys::view_commit_model(self.view, true);
+        }
+    }
+}
+
+impl DecodeObserver for Preview {
+    fn on_row(&mut self, y: usize, colors: &[u8]) -> bool {
+        if self.height > 0 && self.width > 0 {
+            // Thumbnail rows whose source row is `y`
+            let mut py = (y * PREVIEW_SIZE).div_ceil(self.height);
+            while py < PREVIEW_SIZE && py * self.height / PREVIEW_SIZE == y {
+                for px in 0..PREVIEW_SIZE {
+                    self.thumbnail.set(px, py, colors[px * self.width / PREVIEW_SIZE]);
+                }
+                py += 1;
+            }
+        }
+
+        self.rows_done += 1;
+        if self.rows_done % BAND_ROWS == 0 || self.rows_done == self.height {
+            unsafe { self.publish() };
+        }
+
+        !self.cancelled()
+    }
+}
+
+/// Draw the thumbnail, progress and spinner
+unsafe extern "C" fn draw_callback(canvas: *mut sys::Canvas, model: *mut c_void) {
+    unsafe {
+        let model = &*(model as *const PreviewModel);
+        model.thumbnail.draw(canvas);
+
+        sys::canvas_set_font(canvas, sys::FontPrimary);
+        sys::canvas_draw_str(canvas, 68, 12, c"Processing".as_ptr());
+
+        sys::canvas_set_font(canvas, sys::FontSecondary);
+        let (number, count) = model.position;
+        if count > 0 {
+            let text = alloc::format!("image {}/{}", number, count);
+            if let Ok(c_text) = alloc::ffi::CString::new(text) {
+                sys::canvas_draw_str(canvas, 68, 22, c_text.as_ptr());
+            }
+        } else {
+            sys::canvas_draw_str(canvas, 68, 22, c"image...".as_ptr());
+        }
+        let percent = if model.total_rows > 0 {
+            model.rows_done as u32 * 100 / model.total_rows as u32
+        } else {
+            0
+        };
+        let text = alloc::format!("{}%", percent);
+        if let Ok(c_text) = alloc::ffi::CString::new(text) {
+            sys::canvas_draw_str(canvas, 68, 38, c_text.as_ptr());
+        }
+        let spinner = SPINNER[(model.frame as usize) % SPINNER.len()];
+        sys::canvas_draw_str(canvas, 110, 38, spinner.as_ptr());
+        let keys = alloc::format!("{}: cancel", input::label(sys::InputKeyBack).to_str().unwrap_or(""));
+        if let Ok(c_keys) = alloc::ffi::CString::new(keys) {
+            sys::canvas_draw_str(canvas, 68, 60, c_keys.as_ptr());
+        }
+    }
+}
+
+/// Back requests cancellation; the loader stops at the next row
+///
+/// OK is swallowed too, so a left-handed Back (which arrives as OK) never
+/// reaches the view dispatcher's navigation.
+unsafe extern "C" fn input_callback(event: *mut sys::InputEvent, context: *mut c_void) -> bool {
+    unsafe {
+        let preview = &*(context as *const Preview);
+        let key = input::remap((*event).key);
+        if key == sys::InputKeyBack {
+            if (*event).type_ == sys::InputTypeShort {
+                preview.cancel.store(true, Ordering::Relaxed);
+            }
+            return true;
+        }
+        key == sys::InputKeyOk
+    }
+}
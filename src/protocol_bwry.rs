@@ -3,14 +3,16 @@
 //! Protocol reverse-engineered from the official Android app.
 //! See research_docs/PROTOCOL_IsoDep_BWRY.md for detailed documentation.
 
-use core::cell::UnsafeCell;
-use core::ptr::null_mut;
-use flipperzero_sys as sys;
+extern crate alloc;
 
-use crate::protocol_common::{
-    self, commands as common_commands, log_error, log_info,
-    NfcError, NfcResult, CHUNK_SIZE, IMAGE_DATA_SIZE, NUM_PACKETS,
-};
+use alloc::string::String;
+use core::ffi::CStr;
+
+use crate::eink_protocol::{self, EinkProtocol, Front, Payload};
+use crate::engine::{Engine, Ready, Steps};
+use crate::protocol_common::{log_error, AuthKey, ImageStream, NfcError, NfcResult, RetryMap};
+use crate::transport::Transport;
+use crate::tag_type::{TagType, TimingProfile};
 
 /// BWRY-specific command sequences
 pub mod commands {
@@ -45,118 +47,25 @@ pub mod commands {
     pub const REG_07_VAL: &[u8] = &[0xA5];
 }
 
-/// State machine states for the poller callback
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum PollerState {
-    WaitingForTag,
-    Init,
-    Gpio0,
-    Gpio1,
-    DisplayInit,
-    RegE0Select,
-    RegE0Write,
-    RegE6Select,
-    RegE6Write,
-    RegA5Select,
-    RegA5Write,
-    StartTx,
-    SendData(usize), // packet index
-    Refresh,
-    WaitRefresh,
-    PollStatus,
-    Cleanup02Select,
-    Cleanup02Write,
-    Cleanup07Select,
-    Cleanup07Write,
-    Done,
-    Error(NfcError),
-}
-
-/// Context passed to the NFC poller callback
-struct PollerContext {
-    state: PollerState,
-    image_data: *const u8,
-    tx_buf: *mut sys::BitBuffer,
-    rx_buf: *mut sys::BitBuffer,
-}
-
 /// Protocol handler for BWRY (4-color) NFC e-ink displays
 pub struct BwryProtocol {
-    nfc: *mut sys::Nfc,
-    poller: *mut sys::NfcPoller,
-    context: UnsafeCell<PollerContext>,
-    result: NfcResult<()>,
+    /// Packed frame size for the selected tag (10,000 bytes at 200x200)
+    image_size: usize,
+    auth_key: AuthKey,
+    /// Delays from the tag's entry
+    timing: TimingProfile,
+    /// Image packets that needed resending
+    retries: RetryMap,
 }
 
 impl BwryProtocol {
-    /// Create a new protocol handler
-    pub fn new() -> Self {
+    /// Create a new protocol handler sized for `tag`, unlocking it with `auth_key`
+    pub fn new(tag: &TagType, auth_key: AuthKey) -> Self {
         Self {
-            nfc: null_mut(),
-            poller: null_mut(),
-            context: UnsafeCell::new(PollerContext {
-                state: PollerState::WaitingForTag,
-                image_data: null_mut(),
-                tx_buf: null_mut(),
-                rx_buf: null_mut(),
-            }),
-            result: Ok(()),
-        }
-    }
-
-    /// Initialize NFC hardware
-    fn init_nfc(&mut self) -> NfcResult<()> {
-        unsafe {
-            // Allocate NFC instance
-            self.nfc = sys::nfc_alloc();
-            if self.nfc.is_null() {
-                return Err(NfcError::AllocFailed);
-            }
-
-            // Allocate poller for ISO14443-4A protocol
-            self.poller = sys::nfc_poller_alloc(self.nfc, sys::NfcProtocolIso14443_4a);
-            if self.poller.is_null() {
-                sys::nfc_free(self.nfc);
-                self.nfc = null_mut();
-                return Err(NfcError::AllocFailed);
-            }
-
-            // Initialize context buffers
-            let ctx = &mut *self.context.get();
-            ctx.tx_buf = sys::bit_buffer_alloc(512);
-            ctx.rx_buf = sys::bit_buffer_alloc(512);
-
-            if ctx.tx_buf.is_null() || ctx.rx_buf.is_null() {
-                self.cleanup();
-                return Err(NfcError::AllocFailed);
-            }
-
-            Ok(())
-        }
-    }
-
-    /// Clean up NFC resources
-    pub fn cleanup(&mut self) {
-        unsafe {
-            let ctx = &mut *self.context.get();
-
-            if !ctx.tx_buf.is_null() {
-                sys::bit_buffer_free(ctx.tx_buf);
-                ctx.tx_buf = null_mut();
-            }
-            if !ctx.rx_buf.is_null() {
-                sys::bit_buffer_free(ctx.rx_buf);
-                ctx.rx_buf = null_mut();
-            }
-
-            if !self.poller.is_null() {
-                sys::nfc_poller_free(self.poller);
-                self.poller = null_mut();
-            }
-            if !self.nfc.is_null() {
-                sys::nfc_free(self.nfc);
-                self.nfc = null_mut();
-            }
+            image_size: tag.image_data_size(),
+            auth_key,
+            timing: tag.timing,
+            retries: RetryMap::default(),
         }
     }
 
@@ -165,300 +74,159 @@ impl BwryProtocol {
     /// This executes the full BWRY protocol sequence:
     /// 1. Initialize communication
     /// 2. Configure display registers (E0, E6, A5)
-    /// 3. Transfer image data in 64-byte chunks
+    /// 3. Transfer image data in `CHUNK_SIZE` packets, chained where the
+    ///    tag's frames are smaller (`TagType::image_data_size` bytes)
     /// 4. Trigger display refresh
     /// 5. Wait for refresh to complete
     /// 6. Cleanup registers
-    pub fn write_image(&mut self, image_data: &[u8; IMAGE_DATA_SIZE]) -> NfcResult<()> {
-        // Initialize NFC
-        self.init_nfc()?;
+    ///
+    /// APDUs go through `transport` (built-in poller or external PN532).
+    pub fn write_image(&mut self, image_data: &[u8], transport: &mut dyn Transport) -> NfcResult<()> {
+        let mut image = image_data;
+        self.write_stream(&mut image, transport)
+    }
 
-        unsafe {
-            // Set up context
-            let ctx = &mut *self.context.get();
-            ctx.state = PollerState::WaitingForTag;
-            ctx.image_data = image_data.as_ptr();
+    /// Write an image whose packed bytes are produced as packets go out
+    ///
+    /// Same sequence as `write_image`; a stream that fails mid-write ends it
+    /// with `ImageReadFailed`.
+    pub fn write_stream(
+        &mut self,
+        image: &mut dyn ImageStream,
+        transport: &mut dyn Transport,
+    ) -> NfcResult<()> {
+        if image.size() != self.image_size {
+            log_error!("BWRY: image is {} bytes, tag expects {}", image.size(), self.image_size);
+            return Err(NfcError::SizeMismatch);
+        }
 
-            // Start poller with callback
-            sys::nfc_poller_start(
-                self.poller,
-                Some(Self::poller_callback),
-                self.context.get() as *mut core::ffi::c_void,
-            );
+        let steps = self.steps();
+        let mut engine = Engine::new(&steps);
+        let result = engine.run(transport, image);
+        self.retries = engine.take_retries();
+        result
+    }
 
-            // Wait for completion by polling the state
-            // The callback will run on the NFC thread and update the state
-            loop {
-                sys::furi_delay_ms(100);
-                let state = (*self.context.get()).state;
-                match state {
-                    PollerState::Done => {
-                        self.result = Ok(());
-                        break;
-                    }
-                    PollerState::Error(e) => {
-                        self.result = Err(e);
-                        break;
-                    }
-                    _ => continue,
-                }
-            }
+    /// The BWRY sequence, with the tag's delays
+    fn steps(&self) -> Steps {
+        let timing = self.timing;
+        let mut steps = Steps::default();
+        steps.bridge(&self.auth_key, timing.gpio_ms);
+        steps.command("DISP INIT", commands::DISPLAY_INIT, timing.setup_ms);
+        steps.register(commands::REG_E0, commands::REG_E0_VAL, 0);
+        steps.register(commands::REG_E6, commands::REG_E6_VAL, 0);
+        steps.register(commands::REG_A5, commands::REG_A5_VAL, timing.setup_ms);
+        steps.command("START TX", commands::START_TX, 0);
+        // Brief delay after the final packet before the refresh
+        steps.buffer("  Data", 0, self.image_size, timing.data_ms);
+        steps.command("REFRESH", commands::REFRESH, 0);
+        steps.wait_refresh(timing.refresh_ms);
+        // STATUS_BYTE: 0x00 = busy, non-zero = ready
+        steps.poll_until(Ready::Not(0x00), timing.poll_ms, timing.max_polls);
+        steps.cleanup();
+        steps.register(commands::REG_02, commands::REG_02_VAL, timing.cleanup_ms);
+        steps.register(commands::REG_07, commands::REG_07_VAL, 0);
+        steps
+    }
+}
 
-            // Stop poller
-            sys::nfc_poller_stop(self.poller);
+impl EinkProtocol for BwryProtocol {
+    fn status(&self) -> &'static CStr {
+        c"Writing BWRY..."
+    }
+
+    fn write(
+        &mut self,
+        payload: Payload<'_>,
+        transport: Option<&mut dyn Transport>,
+    ) -> NfcResult<()> {
+        let transport = eink_protocol::isodep_transport(transport)?;
+        match payload.front {
+            Front::Packed(image) => self.write_image(image, transport),
+            Front::Streamed(stream) => self.write_stream(stream, transport),
         }
+    }
 
-        // Clean up and return result
-        self.cleanup();
-        self.result
+    fn plan(&mut self, _front: &[u8], _rear: Option<&[u8]>) -> String {
+        self.steps().plan()
     }
 
-    /// NFC poller callback - implements the BWRY protocol state machine
-    unsafe extern "C" fn poller_callback(
-        event: sys::NfcGenericEvent,
-        context: *mut core::ffi::c_void,
-    ) -> sys::NfcCommand {
-        unsafe {
-            let ctx = &mut *(context as *mut PollerContext);
+    fn take_retries(&mut self) -> RetryMap {
+        core::mem::take(&mut self.retries)
+    }
+}
 
-            // Check event data
-            let event_data = event.event_data as *const sys::Iso14443_4aPollerEvent;
-            if event_data.is_null() {
-                return sys::NfcCommandContinue;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
 
-            let event_type = (*event_data).type_;
+    use crate::protocol_common::{commands as common, CHUNK_SIZE, DEFAULT_AUTH_KEY};
+    use crate::transport::MockTransport;
 
-            // Handle WaitingForTag state specially - keep polling on errors
-            if ctx.state == PollerState::WaitingForTag {
-                if event_type == sys::Iso14443_4aPollerEventTypeReady {
-                    // Tag detected! Transition to Init state
-                    log_info!("Tag detected! Starting BWRY protocol...");
-                    ctx.state = PollerState::Init;
-                    // Fall through to process Init state
-                } else {
-                    // Not ready yet (error or other event), keep polling
-                    return sys::NfcCommandContinue;
-                }
-            } else if event_type != sys::Iso14443_4aPollerEventTypeReady {
-                // For non-waiting states, errors are fatal
-                if event_type == sys::Iso14443_4aPollerEventTypeError {
-                    ctx.state = PollerState::Error(NfcError::DetectFailed);
-                    return sys::NfcCommandStop;
-                }
-                return sys::NfcCommandContinue;
-            }
+    /// Two full packets and a short one
+    const FRAME: usize = 600;
 
-            // Get the ISO14443-4A poller instance
-            let poller = event.instance as *mut sys::Iso14443_4aPoller;
+    fn protocol(max_polls: u32) -> BwryProtocol {
+        BwryProtocol {
+            image_size: FRAME,
+            auth_key: DEFAULT_AUTH_KEY,
+            timing: TimingProfile { max_polls, ..TimingProfile::NONE },
+            retries: RetryMap::default(),
+        }
+    }
 
-            // Process state machine
-            match ctx.state {
-                PollerState::WaitingForTag => {
-                    // Should not reach here, but just in case
-                    return sys::NfcCommandContinue;
-                }
-                PollerState::Init => {
-                    log_info!("State: Init - sending auth command");
-                    if protocol_common::send_command(poller, ctx.tx_buf, ctx.rx_buf, common_commands::INIT) {
-                        ctx.state = PollerState::Gpio0;
-                    } else {
-                        log_error!("Init command failed!");
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::Gpio0 => {
-                    if protocol_common::send_command(poller, ctx.tx_buf, ctx.rx_buf, common_commands::GPIO_0) {
-                        sys::furi_delay_ms(50);
-                        ctx.state = PollerState::Gpio1;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::Gpio1 => {
-                    if protocol_common::send_command(poller, ctx.tx_buf, ctx.rx_buf, common_commands::GPIO_1) {
-                        sys::furi_delay_ms(200); // BWRY uses 200ms delay
-                        ctx.state = PollerState::DisplayInit;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::DisplayInit => {
-                    if protocol_common::send_command(poller, ctx.tx_buf, ctx.rx_buf, commands::DISPLAY_INIT) {
-                        sys::furi_delay_ms(100);
-                        ctx.state = PollerState::RegE0Select;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::RegE0Select => {
-                    if protocol_common::send_select_register(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_E0) {
-                        ctx.state = PollerState::RegE0Write;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::RegE0Write => {
-                    if protocol_common::send_write_data(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_E0_VAL) {
-                        ctx.state = PollerState::RegE6Select;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::RegE6Select => {
-                    if protocol_common::send_select_register(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_E6) {
-                        ctx.state = PollerState::RegE6Write;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::RegE6Write => {
-                    if protocol_common::send_write_data(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_E6_VAL) {
-                        ctx.state = PollerState::RegA5Select;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::RegA5Select => {
-                    if protocol_common::send_select_register(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_A5) {
-                        ctx.state = PollerState::RegA5Write;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::RegA5Write => {
-                    if protocol_common::send_write_data(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_A5_VAL) {
-                        sys::furi_delay_ms(100);
-                        ctx.state = PollerState::StartTx;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::StartTx => {
-                    if protocol_common::send_command(poller, ctx.tx_buf, ctx.rx_buf, commands::START_TX) {
-                        ctx.state = PollerState::SendData(0);
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::SendData(packet_idx) => {
-                    let offset = packet_idx * CHUNK_SIZE;
-                    let remaining = IMAGE_DATA_SIZE - offset;
-                    let chunk_len = core::cmp::min(CHUNK_SIZE, remaining);
+    /// Select `reg`, then write `value` to it
+    fn register(reg: u8, value: u8) -> [Vec<u8>; 2] {
+        [vec![0x74, 0x99, 0x00, 0x0D, 0x01, reg], vec![0x74, 0x9A, 0x00, 0x0E, 0x01, value]]
+    }
 
-                    if protocol_common::send_image_packet_raw(
-                        poller, ctx.tx_buf, ctx.rx_buf,
-                        ctx.image_data, offset, chunk_len
-                    ) {
-                        if packet_idx + 1 >= NUM_PACKETS {
-                            // Brief delay after final packet before refresh
-                            sys::furi_delay_ms(50);
-                            ctx.state = PollerState::Refresh;
-                        } else {
-                            ctx.state = PollerState::SendData(packet_idx + 1);
-                        }
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::Refresh => {
-                    if protocol_common::send_command(poller, ctx.tx_buf, ctx.rx_buf, commands::REFRESH) {
-                        ctx.state = PollerState::WaitRefresh;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::WaitRefresh => {
-                    // Wait for initial refresh (10 seconds for BWRY)
-                    sys::furi_delay_ms(10000);
-                    ctx.state = PollerState::PollStatus;
-                }
-                PollerState::PollStatus => {
-                    // Poll busy status
-                    if protocol_common::send_command(poller, ctx.tx_buf, ctx.rx_buf, common_commands::READ_STATUS) {
-                        // Response format: [STATUS_BYTE, SW1, SW2]
-                        // STATUS_BYTE: 0x00 = busy, non-zero = ready (BWRY)
-                        let rx_size = sys::bit_buffer_get_size_bytes(ctx.rx_buf);
-                        if rx_size >= 3 {
-                            let status_byte = sys::bit_buffer_get_byte(ctx.rx_buf, 0);
-                            log_info!("Status poll: byte={:02X}", status_byte);
-                            if status_byte != 0x00 {
-                                log_info!("Display ready!");
-                                ctx.state = PollerState::Cleanup02Select;
-                            } else {
-                                // Still busy, wait and poll again (400ms for BWRY)
-                                sys::furi_delay_ms(400);
-                                // Stay in PollStatus state
-                            }
-                        } else {
-                            // Unexpected response length, assume ready
-                            log_info!("Unexpected status response len={}, assuming ready", rx_size);
-                            ctx.state = PollerState::Cleanup02Select;
-                        }
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::Cleanup02Select => {
-                    if protocol_common::send_select_register(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_02) {
-                        ctx.state = PollerState::Cleanup02Write;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::Cleanup02Write => {
-                    if protocol_common::send_write_data(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_02_VAL) {
-                        sys::furi_delay_ms(200);
-                        ctx.state = PollerState::Cleanup07Select;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::Cleanup07Select => {
-                    if protocol_common::send_select_register(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_07) {
-                        ctx.state = PollerState::Cleanup07Write;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::Cleanup07Write => {
-                    if protocol_common::send_write_data(poller, ctx.tx_buf, ctx.rx_buf, commands::REG_07_VAL) {
-                        ctx.state = PollerState::Done;
-                        return sys::NfcCommandStop;
-                    } else {
-                        ctx.state = PollerState::Error(NfcError::TransmitFailed);
-                        return sys::NfcCommandStop;
-                    }
-                }
-                PollerState::Done | PollerState::Error(_) => {
-                    return sys::NfcCommandStop;
-                }
-            }
+    fn packet(chunk: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x74, 0x9A, 0x00, 0x0E, chunk.len() as u8];
+        packet.extend_from_slice(chunk);
+        packet
+    }
 
-            sys::NfcCommandContinue
-        }
+    #[test]
+    fn sends_the_bwry_sequence() {
+        let image: Vec<u8> = (0..FRAME).map(|i| i as u8).collect();
+        let mut mock = MockTransport::recording();
+        assert_eq!(protocol(3).write_image(&image, &mut mock), Ok(()));
+
+        let mut expected = vec![
+            common::init(&DEFAULT_AUTH_KEY).to_vec(),
+            common::GPIO_0.to_vec(),
+            common::GPIO_1.to_vec(),
+            commands::DISPLAY_INIT.to_vec(),
+        ];
+        expected.extend(register(commands::REG_E0, 0x02));
+        expected.extend(register(commands::REG_E6, 0x5D));
+        expected.extend(register(commands::REG_A5, 0x00));
+        expected.push(commands::START_TX.to_vec());
+        // Packets are longer than the mock's blocks, so this also checks chaining
+        expected.extend(image.chunks(CHUNK_SIZE).map(packet));
+        expected.push(commands::REFRESH.to_vec());
+        expected.push(common::READ_STATUS.to_vec());
+        expected.extend(register(commands::REG_02, 0x00));
+        expected.extend(register(commands::REG_07, 0xA5));
+        assert_eq!(mock.sent(), expected.as_slice());
     }
-}
 
-impl Drop for BwryProtocol {
-    fn drop(&mut self) {
-        self.cleanup();
+    #[test]
+    fn gives_up_on_a_panel_that_stays_busy() {
+        let mut mock = MockTransport::recording();
+        // INIT to REFRESH: bridge, DISP INIT, three registers, START TX,
+        // three packets, REFRESH
+        for _ in 0..3 + 1 + 6 + 1 + 3 + 1 {
+            mock.reply(&[0x90, 0x00]);
+        }
+        for _ in 0..3 {
+            mock.reply(&[0x00, 0x90, 0x00]);
+        }
+        let result = protocol(3).write_image(&[0u8; FRAME], &mut mock);
+        assert_eq!(result, Err(NfcError::RefreshTimeout));
+        let polls = mock.sent().iter().filter(|cmd| cmd.as_slice() == common::READ_STATUS);
+        assert_eq!(polls.count(), 3);
     }
 }
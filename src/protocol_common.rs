@@ -5,25 +5,35 @@
 
 extern crate alloc;
 
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::ffi::CStr;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use flipperzero_sys as sys;
 
-/// Log tag for debugging
-pub const TAG: &CStr = c"EINK_NFC";
+use crate::transport::Transport;
 
-/// Helper macro for debug logging
+/// Helper macro for debug logging (mirrored into `log_ring`)
+///
+/// Lines are dropped when the calling module's `LogModule` is switched off.
 macro_rules! log_info {
     ($($arg:tt)*) => {{
-        let msg = alloc::format!($($arg)*);
-        if let Ok(c_msg) = alloc::ffi::CString::new(msg) {
-            #[allow(unused_unsafe)]
-            unsafe {
-                flipperzero_sys::furi_log_print_format(
-                    flipperzero_sys::FuriLogLevelInfo,
-                    $crate::protocol_common::TAG.as_ptr(),
-                    c_msg.as_ptr(),
-                );
+        let module = $crate::log_ring::LogModule::of(module_path!());
+        if $crate::log_ring::enabled(module) {
+            let msg = alloc::format!($($arg)*);
+            $crate::log_ring::push('I', &msg);
+            if let Ok(c_msg) = alloc::ffi::CString::new(msg) {
+                #[allow(unused_unsafe)]
+                unsafe {
+                    flipperzero_sys::furi_log_print_format(
+                        flipperzero_sys::FuriLogLevelInfo,
+                        $crate::log_ring::tag(),
+                        c_msg.as_ptr(),
+                    );
+                }
             }
         }
     }};
@@ -32,12 +42,13 @@ macro_rules! log_info {
 macro_rules! log_error {
     ($($arg:tt)*) => {{
         let msg = alloc::format!($($arg)*);
+        $crate::log_ring::push('E', &msg);
         if let Ok(c_msg) = alloc::ffi::CString::new(msg) {
             #[allow(unused_unsafe)]
             unsafe {
                 flipperzero_sys::furi_log_print_format(
                     flipperzero_sys::FuriLogLevelError,
-                    $crate::protocol_common::TAG.as_ptr(),
+                    $crate::log_ring::tag(),
                     c_msg.as_ptr(),
                 );
             }
@@ -48,46 +59,312 @@ macro_rules! log_error {
 pub(crate) use log_error;
 pub(crate) use log_info;
 
-/// Display dimensions (shared by 1.54" displays)
-pub const DISPLAY_WIDTH: usize = 200;
-pub const DISPLAY_HEIGHT: usize = 200;
+/// Chunk size for data transfer
+///
+/// The 250-byte chunks of the original Android app. With its 5-byte header a
+/// packet is longer than most tags' frame size; `send_command` sends it as
+/// a chain of I-blocks.
+pub const CHUNK_SIZE: usize = 250;
 
-/// Total image data size (200 * 200 * 2 bits / 8 = 10,000 bytes for BWRY)
-/// BWR uses same size: 5,000 B/W + 5,000 Red = 10,000 bytes
-pub const IMAGE_DATA_SIZE: usize = 10_000;
+/// Resends allowed per image packet before a write fails
+pub const MAX_PACKET_RETRIES: u8 = 2;
 
-/// Chunk size for data transfer
+/// Resends needed per image packet during one write
 ///
-/// Note: The original Android app uses 250-byte chunks.
-/// Reduced to 64 bytes here due to Flipper Zero's ISO 14443-4 frame size limits.
-pub const CHUNK_SIZE: usize = 64;
+/// Packets are numbered in send order across planes and faces. Clusters at
+/// particular packet numbers usually point at frame-size or timing limits of
+/// a tag.
+#[derive(Debug, Clone, Default)]
+pub struct RetryMap {
+    /// Failed attempts per packet (trailing packets without failures omitted)
+    counts: Vec<u8>,
+    /// Packets delivered so far; also the number of the packet in flight
+    sent: usize,
+}
+
+impl RetryMap {
+    /// Note that the packet in flight was delivered
+    pub fn packet_sent(&mut self) {
+        self.sent += 1;
+    }
+
+    /// Note a failed attempt at the packet in flight; true if it may be resent
+    ///
+    /// Callers keep their state on true, so the next step resends the packet.
+    pub fn retry(&mut self) -> bool {
+        if self.counts.len() <= self.sent {
+            self.counts.resize(self.sent + 1, 0);
+        }
+        let count = &mut self.counts[self.sent];
+        *count = count.saturating_add(1);
+        log_error!("Packet {} failed (attempt {})", self.sent, *count);
+        *count <= MAX_PACKET_RETRIES
+    }
 
-/// Number of data packets for single-buffer transfer (BWRY)
-pub const NUM_PACKETS: usize = (IMAGE_DATA_SIZE + CHUNK_SIZE - 1) / CHUNK_SIZE;
+    /// Total failed attempts
+    pub fn total(&self) -> u32 {
+        self.counts.iter().map(|&c| c as u32).sum()
+    }
+
+    /// Packets that failed at least once, with their failure counts
+    pub fn hot_packets(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        self.counts.iter().copied().enumerate().filter(|&(_, c)| c > 0)
+    }
 
-/// Number of data packets per buffer for dual-buffer transfer (BWR)
-pub const NUM_PACKETS_PER_BUFFER: usize = (5_000 + CHUNK_SIZE - 1) / CHUNK_SIZE;
+    /// Failures folded into `width` equal spans of the transfer, for a sparkline
+    pub fn buckets(&self, width: usize) -> Vec<u32> {
+        let packets = self.sent.max(self.counts.len()).max(1);
+        let mut buckets = vec![0u32; width];
+        for (packet, count) in self.hot_packets() {
+            buckets[packet * width / packets] += count as u32;
+        }
+        buckets
+    }
+}
 
 /// NFC operation errors
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NfcError {
     /// Tag detection failed
     DetectFailed,
-    /// Command transmission failed
-    TransmitFailed,
+    /// Command transmission failed, and where
+    TransmitFailed(Failure),
     /// Allocation failed
     AllocFailed,
+    /// Image buffer does not match the panel geometry
+    SizeMismatch,
+    /// External reader did not answer
+    ReaderUnavailable,
+    /// Another part of the app is using the NFC hardware
+    Busy,
+    /// A streamed image could not be read or decoded mid-write
+    ImageReadFailed,
+    /// The tag was last written as another tag type than the one selected
+    WrongTag,
+    /// Image data read back from the controller differs from what was sent
+    VerifyFailed,
+    /// Back was pressed during the write
+    Cancelled,
+    /// No tag came into the field before the tag timeout
+    NoTag,
+    /// The panel still reported busy at the last status poll
+    RefreshTimeout,
+}
+
+impl NfcError {
+    /// What usually fixes this error, shown when a tag keeps failing
+    pub fn remedy(self) -> &'static CStr {
+        match self {
+            NfcError::DetectFailed => c"Check antenna alignment",
+            NfcError::TransmitFailed(_) => c"Hold tag still, flat on back",
+            NfcError::AllocFailed => c"Restart the app",
+            NfcError::SizeMismatch => c"Reload image for this tag",
+            NfcError::ReaderUnavailable => c"Check PN532 wiring/power",
+            NfcError::Busy => c"Wait, then try again",
+            NfcError::ImageReadFailed => c"Check the SD card",
+            NfcError::WrongTag => c"Pick the tag type it is",
+            NfcError::VerifyFailed => c"Hold tag still, write again",
+            NfcError::Cancelled => c"Write again when ready",
+            NfcError::NoTag => c"Hold the tag to the back",
+            NfcError::RefreshTimeout => c"Power-cycle the tag, retry",
+        }
+    }
 }
 
 pub type NfcResult<T> = Result<T, NfcError>;
 
+/// No link error noted since the last command
+const NO_LINK_ERROR: u32 = u32::MAX;
+
+/// Error code of the last frame the reader or tag rejected
+static LINK_ERROR: AtomicU32 = AtomicU32::new(NO_LINK_ERROR);
+
+/// Note the error code of a frame that failed below the APDU layer: an
+/// ISO 14443 or ISO 15693 poller error, an ISO 15693 response error code or
+/// a PN532 status byte
+pub fn note_link_error(code: u32) {
+    LINK_ERROR.store(code, Ordering::Relaxed);
+}
+
+/// Forget the link error of an earlier command before sending the next
+pub fn clear_link_error() {
+    LINK_ERROR.store(NO_LINK_ERROR, Ordering::Relaxed);
+}
+
+/// Where a write failed, for the error screen
+///
+/// A failed command gives either a link error code, when its frame got no
+/// valid answer, or the status word the tag rejected it with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Failure {
+    /// Step or state the write was in
+    pub step: &'static str,
+    /// Packet, page or message of the step, counting from 0
+    pub packet: Option<usize>,
+    /// SW1 SW2 of the tag's answer
+    pub sw: Option<(u8, u8)>,
+    /// Link error code noted with `note_link_error`
+    pub code: Option<u32>,
+}
+
+impl Failure {
+    /// Failure in `step`
+    pub const fn at(step: &'static str) -> Self {
+        Self { step, packet: None, sw: None, code: None }
+    }
+
+    /// Same failure at `packet` of the step
+    pub fn packet(self, packet: usize) -> Self {
+        Self { packet: Some(packet), ..self }
+    }
+
+    /// Same failure with the link error code of the last command, if its
+    /// frame failed
+    pub fn link(self) -> Self {
+        let code = LINK_ERROR.load(Ordering::Relaxed);
+        Self { code: (code != NO_LINK_ERROR).then_some(code), ..self }
+    }
+
+    /// Same failure with the link error code of the last command, or else
+    /// the status word at the end of its APDU answer `rx`
+    pub fn answer(self, rx: &[u8]) -> Self {
+        let failure = self.link();
+        match rx {
+            [.., sw1, sw2] if failure.code.is_none() => {
+                Self { sw: Some((*sw1, *sw2)), ..failure }
+            }
+            _ => failure,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.step)?;
+        if let Some(packet) = self.packet {
+            write!(f, " #{}", packet)?;
+        }
+        match (self.sw, self.code) {
+            (Some((sw1, sw2)), _) => write!(f, ", SW {:02X}{:02X}", sw1, sw2),
+            (None, Some(code)) => write!(f, ", code {:02X}", code),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// How often a wait for a tag or for the poller looks for a cancel request
+pub const CANCEL_POLL_MS: u32 = 50;
+
+/// Set from the GUI thread to stop the write in progress
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+/// Ask the write in progress to stop before its next command
+pub fn request_cancel() {
+    CANCEL.store(true, Ordering::Relaxed);
+}
+
+/// Forget a cancel request left over from an earlier write
+pub fn clear_cancel() {
+    CANCEL.store(false, Ordering::Relaxed);
+}
+
+/// True once the write in progress has been asked to stop
+///
+/// Protocols check this between commands, never in the middle of one, so
+/// the tag is left between two complete APDUs.
+pub fn cancel_requested() -> bool {
+    CANCEL.load(Ordering::Relaxed)
+}
+
+/// Seconds a write waits for a tag unless the settings say otherwise
+pub const DEFAULT_TAG_TIMEOUT_S: u32 = 30;
+
+/// How long a write waits for a tag, in ms; 0 waits until Back
+static TAG_TIMEOUT_MS: AtomicU32 = AtomicU32::new(DEFAULT_TAG_TIMEOUT_S * 1000);
+
+/// Wait at most `seconds` for a tag from now on; 0 waits until Back
+pub fn set_tag_timeout(seconds: u32) {
+    TAG_TIMEOUT_MS.store(seconds.saturating_mul(1000), Ordering::Relaxed);
+}
+
+/// A wait for a tag to come into the field
+///
+/// Readers check it between attempts; it ends the wait on Back or once the
+/// tag timeout has passed. Time spent with a tag is not counted: a write
+/// that has found its tag runs as long as it needs.
+pub struct TagWait {
+    started: u32,
+}
+
+impl TagWait {
+    pub fn start() -> Self {
+        Self { started: unsafe { sys::furi_get_tick() } }
+    }
+
+    /// `Cancelled` after Back, `NoTag` once the timeout has passed
+    pub fn check(&self) -> NfcResult<()> {
+        if cancel_requested() {
+            return Err(NfcError::Cancelled);
+        }
+        let timeout = TAG_TIMEOUT_MS.load(Ordering::Relaxed);
+        let waited = unsafe { sys::furi_get_tick() }.wrapping_sub(self.started);
+        if timeout > 0 && waited >= timeout {
+            log_info!("No tag after {} ms", waited);
+            return Err(NfcError::NoTag);
+        }
+        Ok(())
+    }
+}
+
+/// Packed image bytes that a protocol reads a packet at a time
+///
+/// Lets an image be sent without the whole packed frame in memory (see
+/// `image::FileStream`). Reads move forward through the image, except that
+/// the latest one may be repeated when its packet is resent.
+pub trait ImageStream {
+    /// Packed size in bytes
+    fn size(&self) -> usize;
+
+    /// Copy bytes `offset..offset + buf.len()` into `buf`; false if they
+    /// could not be produced
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> bool;
+}
+
+impl ImageStream for &[u8] {
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> bool {
+        match self.get(offset..offset + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Key sent with the INIT (74 B1) command
+pub type AuthKey = [u8; 8];
+
+/// Factory auth key: 00 11 22 33 44 55 66 77
+pub const DEFAULT_AUTH_KEY: AuthKey = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+
 /// Shared APDU command sequences
 pub mod commands {
-    /// Authentication/Init: 74 B1 00 00 08 00 11 22 33 44 55 66 77
-    pub const INIT: &[u8] = &[
-        0x74, 0xB1, 0x00, 0x00, 0x08,
-        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
-    ];
+    use super::AuthKey;
+
+    /// Authentication/Init: 74 B1 00 00 08 KEY[8]
+    ///
+    /// Factory tags use `DEFAULT_AUTH_KEY`; locked tags need their own key.
+    pub fn init(key: &AuthKey) -> [u8; 13] {
+        let mut cmd = [0u8; 13];
+        cmd[..5].copy_from_slice(&[0x74, 0xB1, 0x00, 0x00, 0x08]);
+        cmd[5..].copy_from_slice(key);
+        cmd
+    }
 
     /// GPIO/Power control step 0: 74 97 00 08 00
     pub const GPIO_0: &[u8] = &[0x74, 0x97, 0x00, 0x08, 0x00];
@@ -97,118 +374,230 @@ pub mod commands {
 
     /// Read busy status: 74 9B 00 0F 01
     pub const READ_STATUS: &[u8] = &[0x74, 0x9B, 0x00, 0x0F, 0x01];
+
+    /// Read `len` bytes from the selected register: 74 9B 00 0F LEN
+    ///
+    /// READ_STATUS is the one-byte form with no register selected.
+    pub fn read_data(len: u8) -> [u8; 5] {
+        [0x74, 0x9B, 0x00, 0x0F, len]
+    }
 }
 
-/// Helper: Send a raw command and check for success
-///
-/// Returns true if the command was sent successfully and the response
-/// indicates success (SW1=0x90, SW2=0x00).
-pub unsafe fn send_command(
-    poller: *mut sys::Iso14443_4aPoller,
-    tx_buf: *mut sys::BitBuffer,
-    rx_buf: *mut sys::BitBuffer,
-    cmd: &[u8],
-) -> bool {
-    unsafe {
-        // Log command (first 6 bytes max for brevity)
-        let cmd_preview: Vec<u8> = cmd.iter().take(6).copied().collect();
-        log_info!("TX: {:02X?} (len={})", cmd_preview, cmd.len());
-
-        sys::bit_buffer_reset(tx_buf);
-        sys::bit_buffer_reset(rx_buf);
-        sys::bit_buffer_copy_bytes(tx_buf, cmd.as_ptr(), cmd.len());
-
-        let error = sys::iso14443_4a_poller_send_block(poller, tx_buf, rx_buf);
-        if error != sys::Iso14443_4aErrorNone {
-            // Error codes: 0=None, 1=NotPresent, 2=Protocol, 3=Timeout
-            log_error!("NFC send error code: {}", error.0);
-            return false;
+/// Bytes as space-separated hex pairs
+pub fn hex_bytes(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
         }
+        out.push_str(&format!("{:02X}", byte));
+    }
+    out
+}
 
-        // Log response
-        let rx_size = sys::bit_buffer_get_size_bytes(rx_buf);
-        if rx_size > 0 {
-            let mut rx_bytes = Vec::new();
-            for i in 0..core::cmp::min(rx_size, 8) {
-                rx_bytes.push(sys::bit_buffer_get_byte(rx_buf, i));
-            }
-            log_info!("RX: {:02X?} (len={})", rx_bytes, rx_size);
+/// Command listing for "Preview Commands"; nothing is sent
+///
+/// Each protocol's `plan` walks the same tables, commands and delays as its
+/// write (for BWRY and GenB, the very `engine::Steps` it sends) and adds one
+/// line per step, so a changed sequence can be checked on the Flipper
+/// before it reaches a tag.
+#[derive(Debug, Default)]
+pub struct Plan {
+    text: String,
+    /// Frames sent, counting one round of each poll
+    frames: usize,
+    /// Fixed delays, not counting poll intervals
+    wait_ms: u32,
+}
+
+impl Plan {
+    /// A heading that sends nothing
+    pub fn note(&mut self, line: &str) {
+        self.line(line, 0);
+    }
+
+    /// One frame described by `line`, then `delay_ms`
+    pub fn send(&mut self, line: &str, delay_ms: u32) {
+        self.frames += 1;
+        self.line(line, delay_ms);
+    }
+
+    /// One command frame, shown by its 5-byte header
+    pub fn command(&mut self, name: &str, cmd: &[u8], delay_ms: u32) {
+        let header = &cmd[..cmd.len().min(5)];
+        self.send(&format!("{} {}", name, hex_bytes(header)), delay_ms);
+    }
+
+    /// Select `reg` and, unless `data` is empty, write it
+    pub fn register(&mut self, reg: u8, data: &[u8], delay_ms: u32) {
+        if data.is_empty() {
+            self.send(&format!("REG {:02X}", reg), delay_ms);
         } else {
-            log_info!("RX: empty");
+            self.frames += 1;
+            self.send(&format!("REG {:02X}: {}", reg, hex_bytes(data)), delay_ms);
         }
+    }
 
-        // Check for success response (0x90 0x00) at the END of response
-        // APDU response format is [DATA...] [SW1] [SW2]
-        if rx_size >= 2 {
-            let sw1 = sys::bit_buffer_get_byte(rx_buf, rx_size - 2);
-            let sw2 = sys::bit_buffer_get_byte(rx_buf, rx_size - 1);
-            let success = sw1 == 0x90 && sw2 == 0x00;
-            if !success {
-                log_error!("Bad response: SW1={:02X} SW2={:02X}", sw1, sw2);
-            }
-            success
-        } else {
-            true // Some commands may have minimal response
+    /// `bytes` of data in `chunk`-byte frames, then `delay_ms`
+    pub fn packets(&mut self, name: &str, bytes: usize, chunk: usize, delay_ms: u32) {
+        let count = bytes.div_ceil(chunk);
+        self.frames += count;
+        self.line(&format!("{} x{} ({} B)", name, count, bytes), delay_ms);
+    }
+
+    /// The frame described by `line`, sent `count` times
+    pub fn repeat(&mut self, line: &str, count: usize) {
+        self.frames += count;
+        self.line(&format!("{} x{}", line, count), 0);
+    }
+
+    /// `ms` more wait after the line just added
+    pub fn delay(&mut self, ms: u32) {
+        if self.text.ends_with('\n') {
+            self.text.pop();
+        }
+        self.text.push_str(&format!(" +{}ms\n", ms));
+    }
+
+    /// A fixed wait
+    pub fn wait(&mut self, ms: u32) {
+        self.wait_ms += ms;
+        self.text.push_str(&format!("wait {} ms\n", ms));
+    }
+
+    /// `count` rounds of a status read, repeated every `interval_ms` until `until`
+    pub fn poll(&mut self, name: &str, count: usize, interval_ms: u32, until: &str) {
+        self.frames += count;
+        let rounds = if count > 1 { format!(" x{}", count) } else { String::new() };
+        self.text.push_str(&format!("poll {}{} /{}ms until {}\n", name, rounds, interval_ms, until));
+    }
+
+    fn line(&mut self, line: &str, delay_ms: u32) {
+        self.text.push_str(line);
+        if delay_ms > 0 {
+            self.text.push_str(&format!(" +{}ms", delay_ms));
         }
+        self.text.push('\n');
+    }
+
+    /// The listing followed by a totals line
+    pub fn finish(mut self) -> String {
+        self.text.push_str(&format!("Total: {}+ frames, {} ms waits", self.frames, self.wait_ms));
+        self.text
     }
 }
 
-/// Helper: Send a select register command (74 99 00 0D 01 REG)
-pub unsafe fn send_select_register(
-    poller: *mut sys::Iso14443_4aPoller,
-    tx_buf: *mut sys::BitBuffer,
-    rx_buf: *mut sys::BitBuffer,
-    reg: u8,
-) -> bool {
-    unsafe {
-        let cmd = [0x74, 0x99, 0x00, 0x0D, 0x01, reg];
-        send_command(poller, tx_buf, rx_buf, &cmd)
+/// Helper: Send a raw command and leave the tag's response in `rx`
+///
+/// Returns false only if the transport failed; the status word is not
+/// checked. A command longer than the transport's `max_block` goes out in
+/// chained blocks; only the last one is answered with a response APDU.
+pub fn exchange_command(transport: &mut dyn Transport, rx: &mut Vec<u8>, cmd: &[u8]) -> bool {
+    // Log command (first 6 bytes max for brevity)
+    let cmd_preview: Vec<u8> = cmd.iter().take(6).copied().collect();
+    log_info!("TX: {:02X?} (len={})", cmd_preview, cmd.len());
+    clear_link_error();
+
+    let block = transport.max_block().max(1);
+    let mut rest = cmd;
+    while rest.len() > block {
+        let (link, tail) = rest.split_at(block);
+        if !transport.exchange_chained(link, rx) {
+            let sent = cmd.len() - rest.len();
+            log_error!("Chained block failed after {} of {} bytes", sent, cmd.len());
+            return false;
+        }
+        rest = tail;
+    }
+
+    if !transport.exchange(rest, rx) {
+        return false;
     }
+
+    // Log response
+    if rx.is_empty() {
+        log_info!("RX: empty");
+    } else {
+        log_info!("RX: {:02X?} (len={})", &rx[..rx.len().min(8)], rx.len());
+    }
+    true
 }
 
-/// Helper: Send a write data command (74 9A 00 0E LEN DATA...)
-pub unsafe fn send_write_data(
-    poller: *mut sys::Iso14443_4aPoller,
-    tx_buf: *mut sys::BitBuffer,
-    rx_buf: *mut sys::BitBuffer,
-    data: &[u8],
-) -> bool {
-    unsafe {
-        let mut cmd = [0u8; 260];
-        cmd[0] = 0x74;
-        cmd[1] = 0x9A;
-        cmd[2] = 0x00;
-        cmd[3] = 0x0E;
-        cmd[4] = data.len() as u8;
-        cmd[5..5 + data.len()].copy_from_slice(data);
-        send_command(poller, tx_buf, rx_buf, &cmd[..5 + data.len()])
+/// Helper: Send a raw command and check for success
+///
+/// Returns true if the command was sent successfully and the response
+/// indicates success (SW1=0x90, SW2=0x00); see `exchange_command`.
+pub fn send_command(transport: &mut dyn Transport, rx: &mut Vec<u8>, cmd: &[u8]) -> bool {
+    if !exchange_command(transport, rx, cmd) {
+        return false;
+    }
+
+    // Check for success response (0x90 0x00) at the END of response
+    // APDU response format is [DATA...] [SW1] [SW2]
+    match rx.as_slice() {
+        [.., 0x90, 0x00] => true,
+        [.., sw1, sw2] => {
+            log_error!("Bad response: SW1={:02X} SW2={:02X}", sw1, sw2);
+            false
+        }
+        _ => true, // Some commands may have minimal response
     }
 }
 
-/// Helper: Send an image data packet from a buffer
+/// Helper: Wait before the first status poll after a refresh command
 ///
-/// `image_data` - pointer to the full image buffer
-/// `offset` - byte offset into the buffer
-/// `chunk_len` - number of bytes to send in this packet
-pub unsafe fn send_image_packet_raw(
-    poller: *mut sys::Iso14443_4aPoller,
-    tx_buf: *mut sys::BitBuffer,
-    rx_buf: *mut sys::BitBuffer,
-    image_data: *const u8,
-    offset: usize,
-    chunk_len: usize,
-) -> bool {
-    unsafe {
-        let mut packet = [0u8; 128]; // 5 header + up to 64 data + margin
-        packet[0] = 0x74;
-        packet[1] = 0x9A;
-        packet[2] = 0x00;
-        packet[3] = 0x0E;
-        packet[4] = chunk_len as u8;
+/// A tag that held its reply to the command with WTX requests was refreshing
+/// meanwhile and has finished, so the fixed `head_start_ms` is only spent on
+/// tags that answered at once.
+pub unsafe fn wait_refresh(transport: &dyn Transport, head_start_ms: u32) {
+    let held_ms = transport.held_ms();
+    if held_ms > 0 {
+        log_info!("Refresh held for {} ms, polling status now", held_ms);
+    } else {
+        unsafe { sys::furi_delay_ms(head_start_ms) };
+    }
+}
+
+/// Helper: Send a select register command (74 99 00 0D 01 REG)
+pub fn send_select_register(transport: &mut dyn Transport, rx: &mut Vec<u8>, reg: u8) -> bool {
+    let cmd = [0x74, 0x99, 0x00, 0x0D, 0x01, reg];
+    send_command(transport, rx, &cmd)
+}
+
+/// Helper: Send a write data command (74 9A 00 0E LEN DATA...)
+pub fn send_write_data(transport: &mut dyn Transport, rx: &mut Vec<u8>, data: &[u8]) -> bool {
+    let mut cmd = [0u8; 260];
+    cmd[0] = 0x74;
+    cmd[1] = 0x9A;
+    cmd[2] = 0x00;
+    cmd[3] = 0x0E;
+    cmd[4] = data.len() as u8;
+    cmd[5..5 + data.len()].copy_from_slice(data);
+    send_command(transport, rx, &cmd[..5 + data.len()])
+}
 
-        let src = image_data.add(offset);
-        core::ptr::copy_nonoverlapping(src, packet[5..].as_mut_ptr(), chunk_len);
+/// Helper: Select `reg` and, unless `data` is empty, write `data` to it
+pub fn send_register(
+    transport: &mut dyn Transport,
+    rx: &mut Vec<u8>,
+    reg: u8,
+    data: &[u8],
+) -> bool {
+    send_select_register(transport, rx, reg)
+        && (data.is_empty() || send_write_data(transport, rx, data))
+}
 
-        send_command(poller, tx_buf, rx_buf, &packet[..5 + chunk_len])
+/// Helper: Read `len` bytes from the selected register (74 9B 00 0F LEN)
+///
+/// Returns the data without the status word, or None if the read was
+/// rejected.
+pub fn send_read_data(
+    transport: &mut dyn Transport,
+    rx: &mut Vec<u8>,
+    len: u8,
+) -> Option<Vec<u8>> {
+    if !send_command(transport, rx, &commands::read_data(len)) {
+        return None;
     }
+    Some(rx[..rx.len().saturating_sub(2)].to_vec())
 }
@@ -0,0 +1,423 @@
+//! Electronic shelf label (ESL) protocol implementation
+//!
+//! A label applet is sent a compressed image file over ISO-DEP: the applet
+//! is selected, the file is written with UPDATE BINARY at increasing
+//! offsets, and a refresh command makes the label decode and display it.
+//!
+//! The image file is a small header followed by each color plane compressed
+//! with PackBits run-length encoding.
+//!
+//! This format was defined for this app, not taken from a commercial label;
+//! `research_docs/PROTOCOL_ESL.md` describes it for label applets to
+//! implement.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::ptr::null_mut;
+use flipperzero_sys as sys;
+
+use crate::eink_protocol::{self, EinkProtocol, Payload};
+use crate::protocol_common::{
+    self, commands as common_commands, log_error, log_info,
+    Failure, NfcError, NfcResult, Plan, RetryMap, CHUNK_SIZE,
+};
+use crate::checkpoint::{Checkpoint, CHECKPOINT_INTERVAL};
+use crate::tag_type::TimingProfile;
+use crate::transport::Transport;
+use crate::controller::Geometry;
+
+/// ESL applet command sequences
+pub mod commands {
+    /// SELECT label applet by AID: 00 A4 04 00 07 A0 00 00 04 45 53 4C
+    pub const SELECT: &[u8] = &[
+        0x00, 0xA4, 0x04, 0x00, 0x07,
+        0xA0, 0x00, 0x00, 0x04, 0x45, 0x53, 0x4C,
+    ];
+
+    /// UPDATE BINARY header: 00 D6 OFF_HI OFF_LO LEN DATA...
+    pub const UPDATE_BINARY: [u8; 2] = [0x00, 0xD6];
+
+    /// Decode and display the uploaded image file: 80 10 00 00 00
+    pub const REFRESH: &[u8] = &[0x80, 0x10, 0x00, 0x00, 0x00];
+}
+
+/// Image file header describing the compressed payload
+pub mod header {
+    /// File magic "EL"
+    pub const MAGIC: [u8; 2] = [0x45, 0x4C];
+
+    /// Header format version
+    pub const VERSION: u8 = 0x01;
+
+    /// Compression: PackBits run-length encoding per plane
+    pub const COMPRESSION_PACKBITS: u8 = 0x01;
+
+    /// Header length in bytes:
+    /// MAGIC(2) VERSION(1) PLANES(1) WIDTH(2) HEIGHT(2) COMPRESSION(1) LENGTH(2) CRC16(2)
+    pub const SIZE: usize = 13;
+}
+
+/// Compress `input` with PackBits run-length encoding
+///
+/// Control byte `n`: 0..=127 copies the next n+1 literal bytes, 129..=255
+/// repeats the next byte 257-n times.
+pub fn packbits(input: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < input.len() {
+        // Measure run starting at i
+        let mut run = 1;
+        while i + run < input.len() && run < 128 && input[i + run] == input[i] {
+            run += 1;
+        }
+
+        if run >= 2 {
+            out.push((257 - run) as u8);
+            out.push(input[i]);
+            i += run;
+        } else {
+            // Literal span until the next run of two or more
+            let start = i;
+            while i < input.len() && i - start < 128 {
+                if i + 1 < input.len() && input[i] == input[i + 1] {
+                    break;
+                }
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&input[start..i]);
+        }
+    }
+}
+
+/// CRC-16/CCITT-FALSE over `data`
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Build the label image file from the BWR dual-plane buffer
+pub fn encode_file(image_data: &[u8], geometry: Geometry) -> Vec<u8> {
+    let plane_size = geometry.plane_size();
+
+    let mut payload = Vec::new();
+    packbits(&image_data[..plane_size], &mut payload);
+    packbits(&image_data[plane_size..], &mut payload);
+
+    let len = (payload.len() as u16).to_be_bytes();
+    let crc = crc16(&payload).to_be_bytes();
+    let width = (geometry.width as u16).to_be_bytes();
+    let height = (geometry.height as u16).to_be_bytes();
+
+    let mut file = Vec::with_capacity(header::SIZE + payload.len());
+    file.extend_from_slice(&header::MAGIC);
+    file.push(header::VERSION);
+    file.push(2); // B/W plane + red plane
+    file.extend_from_slice(&width);
+    file.extend_from_slice(&height);
+    file.push(header::COMPRESSION_PACKBITS);
+    file.extend_from_slice(&len);
+    file.extend_from_slice(&crc);
+    file.extend_from_slice(&payload);
+    file
+}
+
+/// State machine states, advanced by `step`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PollerState {
+    Select,
+    SendFile(usize), // packet index
+    Refresh,
+    WaitRefresh,
+    PollStatus(u32), // polls so far
+    Done,
+    Error(NfcError),
+}
+
+/// State shared between the protocol and its `step` function
+struct PollerContext {
+    state: PollerState,
+    file_data: *const u8,
+    file_len: usize,
+    /// The label's answer to the last command
+    rx: Vec<u8>,
+    /// Image packets that needed resending
+    retries: RetryMap,
+    /// Progress saved to the SD card so an interrupted upload can resume
+    checkpoint: Option<Checkpoint>,
+    /// First packet to send (non-zero when resuming)
+    resume_from: usize,
+    timing: TimingProfile,
+}
+
+/// Protocol handler for NFC electronic shelf labels
+pub struct EslProtocol {
+    context: UnsafeCell<PollerContext>,
+    geometry: Geometry,
+    result: NfcResult<()>,
+}
+
+impl EslProtocol {
+    /// Create a new protocol handler for a `geometry` label
+    pub fn new(geometry: Geometry) -> Self {
+        Self {
+            context: UnsafeCell::new(PollerContext {
+                state: PollerState::Select,
+                file_data: null_mut(),
+                file_len: 0,
+                rx: Vec::new(),
+                retries: RetryMap::default(),
+                checkpoint: None,
+                resume_from: 0,
+                timing: TimingProfile::ESL,
+            }),
+            geometry,
+            result: Ok(()),
+        }
+    }
+
+    /// Wait as `timing` says instead of the usual label delays
+    pub fn with_timing(mut self, timing: TimingProfile) -> Self {
+        self.context.get_mut().timing = timing;
+        self
+    }
+
+    /// Write image data to the label
+    ///
+    /// This executes the ESL upload sequence:
+    /// 1. Compress the BWR planes into a label image file
+    /// 2. Select the label applet
+    /// 3. Write the file with UPDATE BINARY in `CHUNK_SIZE` (250-byte) chunks,
+    ///    each chained over several I-blocks if the tag's frames are smaller
+    /// 4. Trigger decode and refresh
+    /// 5. Poll busy status until complete
+    ///
+    /// APDUs go through `transport` (built-in poller or external PN532).
+    pub fn write_image(&mut self, image_data: &[u8], transport: &mut dyn Transport) -> NfcResult<()> {
+        if image_data.len() != self.geometry.plane_size() * 2 {
+            log_error!("ESL: image is {} bytes, tag expects {}", image_data.len(), self.geometry.plane_size() * 2);
+            return Err(NfcError::SizeMismatch);
+        }
+
+        let file = encode_file(image_data, self.geometry);
+        log_info!("ESL file: {} bytes (from {})", file.len(), image_data.len());
+
+        // UPDATE BINARY offsets are 15 bits
+        if file.len() > 0x7FFF {
+            log_error!("ESL: file is {} bytes, past the last UPDATE BINARY offset", file.len());
+            return Err(NfcError::SizeMismatch);
+        }
+
+        unsafe {
+            // Set up context
+            let ctx = &mut *self.context.get();
+            ctx.file_data = file.as_ptr();
+            ctx.file_len = file.len();
+        }
+
+        // Run the state machine over the transport
+        self.result = self.run(transport);
+        self.result
+    }
+
+    /// Activate the tag, step the state machine until it stops, release the tag
+    fn run(&mut self, transport: &mut dyn Transport) -> NfcResult<()> {
+        unsafe {
+            let ctx = &mut *self.context.get();
+            transport.activate()?;
+            log_info!("Tag ready, starting ESL sequence");
+            ctx.state = PollerState::Select;
+            ctx.retries = RetryMap::default();
+            ctx.resume_from = 0;
+
+            // Offsets are absolute, so packets the tag already acked can be skipped
+            let uid = transport.uid();
+            if let Some(checkpoint) = ctx.checkpoint.as_mut() {
+                let acked = checkpoint.packets_acked;
+                if acked > 0 && acked * CHUNK_SIZE < ctx.file_len && checkpoint.uid == uid {
+                    log_info!("Resuming ESL upload at packet {}", acked);
+                    ctx.resume_from = acked;
+                } else {
+                    checkpoint.packets_acked = 0;
+                }
+                checkpoint.uid = uid;
+            }
+
+            while Self::step(ctx, transport) != sys::NfcCommandStop {}
+            transport.deactivate();
+
+            if let Some(checkpoint) = ctx.checkpoint.as_ref() {
+                match ctx.state {
+                    PollerState::Error(_) => {
+                        checkpoint.save();
+                    }
+                    _ => Checkpoint::clear(),
+                }
+            }
+
+            match ctx.state {
+                PollerState::Error(e) => Err(e),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Send one UPDATE BINARY chunk of the image file
+    unsafe fn send_file_chunk(
+        transport: &mut dyn Transport,
+        ctx: &mut PollerContext,
+        offset: usize,
+        chunk_len: usize,
+    ) -> bool {
+        unsafe {
+            let mut packet = [0u8; 5 + CHUNK_SIZE];
+            packet[..2].copy_from_slice(&commands::UPDATE_BINARY);
+            packet[2] = (offset >> 8) as u8;
+            packet[3] = offset as u8;
+            packet[4] = chunk_len as u8;
+            let src = core::slice::from_raw_parts(ctx.file_data.add(offset), chunk_len);
+            packet[5..5 + chunk_len].copy_from_slice(src);
+
+            protocol_common::send_command(transport, &mut ctx.rx, &packet[..5 + chunk_len])
+        }
+    }
+
+    /// Run one step of the ESL state machine over `transport`
+    unsafe fn step(ctx: &mut PollerContext, transport: &mut dyn Transport) -> sys::NfcCommand {
+        unsafe {
+            // A cancel stops the upload, which saves its checkpoint; once the
+            // refresh is sent the label shows the image anyway
+            if matches!(ctx.state, PollerState::Select | PollerState::SendFile(_))
+                && protocol_common::cancel_requested()
+            {
+                log_info!("ESL upload cancelled");
+                ctx.state = PollerState::Error(NfcError::Cancelled);
+                return sys::NfcCommandStop;
+            }
+
+            // Process state machine
+            match ctx.state {
+                PollerState::Select => {
+                    if protocol_common::send_command(transport, &mut ctx.rx, commands::SELECT) {
+                        ctx.state = PollerState::SendFile(ctx.resume_from);
+                    } else {
+                        log_error!("ESL applet select failed!");
+                        let failure = Failure::at("Select").answer(&ctx.rx);
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::SendFile(packet_idx) => {
+                    let offset = packet_idx * CHUNK_SIZE;
+                    let chunk_len = core::cmp::min(CHUNK_SIZE, ctx.file_len - offset);
+
+                    if Self::send_file_chunk(transport, ctx, offset, chunk_len) {
+                        ctx.retries.packet_sent();
+                        if let Some(checkpoint) = ctx.checkpoint.as_mut() {
+                            checkpoint.packets_acked = packet_idx + 1;
+                            if checkpoint.packets_acked % CHECKPOINT_INTERVAL == 0 {
+                                checkpoint.save();
+                            }
+                        }
+                        if offset + chunk_len >= ctx.file_len {
+                            ctx.state = PollerState::Refresh;
+                        } else {
+                            ctx.state = PollerState::SendFile(packet_idx + 1);
+                        }
+                    } else if !ctx.retries.retry() {
+                        let failure = Failure::at("SendFile").packet(packet_idx).answer(&ctx.rx);
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::Refresh => {
+                    if protocol_common::send_command(transport, &mut ctx.rx, commands::REFRESH) {
+                        ctx.state = PollerState::WaitRefresh;
+                    } else {
+                        log_error!("ESL rejected image file (header/CRC?)");
+                        let failure = Failure::at("Refresh").answer(&ctx.rx);
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::WaitRefresh => {
+                    // Labels decode before refreshing; give them a head start
+                    protocol_common::wait_refresh(transport, ctx.timing.refresh_ms);
+                    ctx.state = PollerState::PollStatus(0);
+                }
+                PollerState::PollStatus(polls) => {
+                    if protocol_common::send_command(transport, &mut ctx.rx, common_commands::READ_STATUS) {
+                        // Response format: [STATUS_BYTE, SW1, SW2]; 0x00 = busy
+                        if ctx.rx.len() >= 3 && ctx.rx[0] == 0x00 {
+                            if polls + 1 >= ctx.timing.max_polls {
+                                log_error!("Label still busy after {} status polls", polls + 1);
+                                ctx.state = PollerState::Error(NfcError::RefreshTimeout);
+                                return sys::NfcCommandStop;
+                            }
+                            sys::furi_delay_ms(ctx.timing.poll_ms);
+                            ctx.state = PollerState::PollStatus(polls + 1);
+                        } else {
+                            log_info!("Display ready!");
+                            ctx.state = PollerState::Done;
+                            return sys::NfcCommandStop;
+                        }
+                    } else {
+                        let failure = Failure::at("PollStatus").answer(&ctx.rx);
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::Done | PollerState::Error(_) => {
+                    return sys::NfcCommandStop;
+                }
+            }
+
+            sys::NfcCommandContinue
+        }
+    }
+}
+
+impl EinkProtocol for EslProtocol {
+    fn status(&self) -> &'static CStr {
+        c"Writing label..."
+    }
+
+    fn write(
+        &mut self,
+        mut payload: Payload<'_>,
+        transport: Option<&mut dyn Transport>,
+    ) -> NfcResult<()> {
+        let transport = eink_protocol::isodep_transport(transport)?;
+        let front = payload.front.bytes()?;
+        self.write_image(&front, transport)
+    }
+
+    fn plan(&mut self, front: &[u8], _rear: Option<&[u8]>) -> String {
+        let file = encode_file(front, self.geometry);
+        let timing = self.context.get_mut().timing;
+        let mut plan = Plan::default();
+        plan.command("SELECT", commands::SELECT, 0);
+        plan.packets("UPDATE BIN", file.len(), CHUNK_SIZE, 0);
+        plan.command("REFRESH", commands::REFRESH, 0);
+        plan.wait(timing.refresh_ms);
+        plan.poll("STATUS", 1, timing.poll_ms, "not 00");
+        plan.finish()
+    }
+
+    fn resume(&mut self, checkpoint: Checkpoint) {
+        self.context.get_mut().checkpoint = Some(checkpoint);
+    }
+
+    fn take_retries(&mut self) -> RetryMap {
+        core::mem::take(&mut self.context.get_mut().retries)
+    }
+}
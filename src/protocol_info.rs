@@ -0,0 +1,207 @@
+//! Tag information query for IsoDep e-ink bridges
+//!
+//! Reads what a tag reveals about itself without touching the panel: the
+//! ISO 14443-4A UID, whether the GoodDisplay bridge accepts the INIT auth,
+//! the bridge's register window through the `74 9B` read path (controller
+//! model and firmware bytes on bridges that expose them), the busy status
+//! byte and the panel controller's own status register. The result is shown
+//! on the "Tag Info" screen to help pick a TagType and to triage tags that
+//! show nothing after a write.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use flipperzero_sys as sys;
+
+use crate::protocol_common::{
+    self, commands as common_commands, log_info,
+    NfcResult, DEFAULT_AUTH_KEY,
+};
+use crate::transport::{PollerTransport, Transport};
+
+/// Bridge read commands
+pub mod commands {
+    /// Read register window: 74 9B 00 ADDR LEN
+    pub const READ_REGS: [u8; 2] = [0x74, 0x9B];
+
+    /// First register of the info window
+    pub const INFO_ADDR: u8 = 0x00;
+
+    /// Registers below the status byte (0x0F)
+    pub const INFO_LEN: u8 = 0x0F;
+
+    /// SSD16xx Status Bit Read: HV ready, VCI detect, busy, chip ID
+    pub const CONTROLLER_STATUS_REG: u8 = 0x2F;
+}
+
+/// Wait after each GPIO step, as in the GenB write
+const GPIO_DELAY_MS: u32 = 50;
+
+/// What a tag reported about itself
+#[derive(Debug, Clone, Default)]
+pub struct TagInfo {
+    /// ISO 14443-4A UID
+    pub uid: Vec<u8>,
+    /// Historical bytes of the ATS
+    pub historical: Vec<u8>,
+    /// True if the bridge accepted the INIT auth (GenB/BWRY family)
+    pub bridge: bool,
+    /// Register window read through `74 9B`, if the bridge answered
+    pub registers: Option<Vec<u8>>,
+    /// Busy status byte (READ_STATUS), if the bridge answered
+    pub status: Option<u8>,
+    /// Controller status register, read through the bridge once the panel
+    /// is powered; None if the controller did not answer (non-SSD16xx)
+    pub controller_status: Option<u8>,
+}
+
+/// State machine states, advanced by `step`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PollerState {
+    Init,
+    ReadRegisters,
+    ReadStatus,
+    ReadController,
+    Done,
+}
+
+/// State shared between the protocol and its `step` function
+struct PollerContext {
+    state: PollerState,
+    info: TagInfo,
+    /// The tag's answer to the last command
+    rx: Vec<u8>,
+}
+
+/// Protocol handler for the tag information query
+pub struct InfoProtocol {
+    context: UnsafeCell<PollerContext>,
+}
+
+impl InfoProtocol {
+    /// Create a new protocol handler
+    pub fn new() -> Self {
+        Self {
+            context: UnsafeCell::new(PollerContext {
+                state: PollerState::Init,
+                info: TagInfo::default(),
+                rx: Vec::new(),
+            }),
+        }
+    }
+
+    /// Wait for a tag and read its information
+    ///
+    /// This executes the query sequence:
+    /// 1. Detect the tag and record its UID
+    /// 2. Send INIT with the factory key; tags that reject it are reported as
+    ///    non-bridge tags (or bridges locked with another key)
+    /// 3. Read the register window with `74 9B 00 00 0F`
+    /// 4. Read the busy status byte
+    /// 5. Power the panel (GPIO steps, as before a write) and read the
+    ///    controller's status register
+    ///
+    /// Always uses the built-in reader, which is the only one that reports
+    /// the UID.
+    pub fn read_info(&mut self) -> NfcResult<TagInfo> {
+        let mut transport = PollerTransport::new()?;
+
+        let result;
+        unsafe {
+            // Set up context
+            let ctx = &mut *self.context.get();
+            ctx.info = TagInfo::default();
+
+            result = transport.activate();
+            if result.is_ok() {
+                log_info!("Tag detected! Reading tag info...");
+                ctx.info.uid = transport.uid();
+                ctx.info.historical = transport.historical_bytes();
+                ctx.state = PollerState::Init;
+                while Self::step(ctx, &mut transport) != sys::NfcCommandStop {}
+                transport.deactivate();
+            }
+        }
+
+        let info = core::mem::take(&mut self.context.get_mut().info);
+        result.map(|()| info)
+    }
+
+    /// Response payload without the trailing SW1 SW2
+    fn response_data(rx: &[u8]) -> Vec<u8> {
+        rx[..rx.len().saturating_sub(2)].to_vec()
+    }
+
+    /// Power the panel and read the controller's status register; None if
+    /// any step is rejected
+    unsafe fn read_controller_status(
+        ctx: &mut PollerContext,
+        transport: &mut dyn Transport,
+    ) -> Option<u8> {
+        unsafe {
+            let rx = &mut ctx.rx;
+            for gpio in [common_commands::GPIO_0, common_commands::GPIO_1] {
+                if !protocol_common::send_command(transport, rx, gpio) {
+                    return None;
+                }
+                sys::furi_delay_ms(GPIO_DELAY_MS);
+            }
+            let reg = commands::CONTROLLER_STATUS_REG;
+            if !protocol_common::send_select_register(transport, rx, reg) {
+                return None;
+            }
+            protocol_common::send_read_data(transport, rx, 1)?.first().copied()
+        }
+    }
+
+    /// Run one step of the info query over `transport`
+    unsafe fn step(ctx: &mut PollerContext, transport: &mut dyn Transport) -> sys::NfcCommand {
+        unsafe {
+            // Process state machine
+            match ctx.state {
+                PollerState::Init => {
+                    if protocol_common::send_command(transport, &mut ctx.rx, &common_commands::init(&DEFAULT_AUTH_KEY)) {
+                        ctx.info.bridge = true;
+                        ctx.state = PollerState::ReadRegisters;
+                    } else {
+                        // Not a GoodDisplay bridge; the UID is all we get
+                        log_info!("INIT rejected - not a bridge tag");
+                        ctx.state = PollerState::Done;
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::ReadRegisters => {
+                    let cmd = [
+                        commands::READ_REGS[0],
+                        commands::READ_REGS[1],
+                        0x00,
+                        commands::INFO_ADDR,
+                        commands::INFO_LEN,
+                    ];
+                    // Bridges without the window reject the read; that is not an error
+                    if protocol_common::send_command(transport, &mut ctx.rx, &cmd) {
+                        ctx.info.registers = Some(Self::response_data(&ctx.rx));
+                    }
+                    ctx.state = PollerState::ReadStatus;
+                }
+                PollerState::ReadStatus => {
+                    if protocol_common::send_command(transport, &mut ctx.rx, common_commands::READ_STATUS) {
+                        ctx.info.status = Self::response_data(&ctx.rx).first().copied();
+                    }
+                    ctx.state = PollerState::ReadController;
+                }
+                PollerState::ReadController => {
+                    ctx.info.controller_status = Self::read_controller_status(ctx, transport);
+                    ctx.state = PollerState::Done;
+                    return sys::NfcCommandStop;
+                }
+                PollerState::Done => {
+                    return sys::NfcCommandStop;
+                }
+            }
+
+            sys::NfcCommandContinue
+        }
+    }
+}
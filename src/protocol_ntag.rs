@@ -0,0 +1,514 @@
+//! NTAG21x page-write protocol implementation for e-ink price tags
+//!
+//! These tags expose an NTAG21x-style memory instead of ISO-DEP. The image is
+//! uploaded in windows of 4-byte pages (WRITE, A2h); after each window the
+//! reader posts a command to a control page and the tag MCU clears it once the
+//! window has been copied to the panel controller.
+//!
+//! Commands are issued through the ISO14443-3A poller (the layer the
+//! MfUltralight poller is built on), so the card is not re-read or verified
+//! against an MfUltralight dump before writing.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use flipperzero_sys as sys;
+
+use crate::eink_protocol::{EinkProtocol, Payload};
+use crate::nfc_guard::NfcGuard;
+use crate::protocol_common::{
+    self, log_error, log_info, Failure, NfcError, NfcResult, Plan, TagWait, CANCEL_POLL_MS,
+};
+use crate::transport::Transport;
+
+/// NTAG21x command set
+pub mod commands {
+    /// READ: 30 PAGE -> 16 bytes (4 pages)
+    pub const READ: u8 = 0x30;
+
+    /// WRITE: A2 PAGE D0 D1 D2 D3 -> 4-bit ACK
+    pub const WRITE: u8 = 0xA2;
+
+    /// 4-bit acknowledge value
+    pub const ACK: u8 = 0x0A;
+
+    /// Page size in bytes
+    pub const PAGE_SIZE: usize = 4;
+
+    /// Control page command: a data window is ready (D1 = seq, D2..D3 = length LE)
+    pub const CTRL_CHUNK: u8 = 0x01;
+
+    /// Control page command: all data delivered, refresh the panel
+    pub const CTRL_REFRESH: u8 = 0x02;
+
+    /// Control page command byte once the MCU has consumed the request
+    pub const CTRL_IDLE: u8 = 0x00;
+}
+
+/// Frame waiting time for NTAG commands (carrier cycles)
+const FWT_FC: u32 = 60_000;
+
+/// Page window layout and handshake settings
+#[derive(Debug, Clone, Copy)]
+pub struct NtagConfig {
+    /// First user page of the data window
+    pub first_page: u8,
+    /// Number of pages written per window
+    pub window_pages: u8,
+    /// Page used to post commands to the tag MCU
+    pub ctrl_page: u8,
+    /// Delay between control page polls (ms)
+    pub poll_interval_ms: u32,
+    /// Number of polls before a window is considered lost
+    pub max_polls: u32,
+}
+
+impl NtagConfig {
+    /// NTAG213 layout: pages 4..35 as a 128-byte window, control page 36
+    pub const DEFAULT: NtagConfig = NtagConfig {
+        first_page: 4,
+        window_pages: 32,
+        ctrl_page: 36,
+        poll_interval_ms: 20,
+        max_polls: 250,
+    };
+
+    /// Bytes carried per window
+    fn window_size(&self) -> usize {
+        self.window_pages as usize * commands::PAGE_SIZE
+    }
+}
+
+/// State machine states for the poller callback
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PollerState {
+    WaitingForTag,
+    WritePage(usize, u8),       // window index, page within window
+    PostChunk(usize),           // window index
+    WaitChunk(usize, u32),      // window index, polls so far
+    PostRefresh,
+    WaitRefresh(u32),           // polls so far
+    Done,
+    Error(NfcError),
+}
+
+/// Context passed to the NFC poller callback
+struct PollerContext {
+    state: PollerState,
+    config: NtagConfig,
+    image_data: *const u8,
+    image_size: usize,
+    tx_buf: *mut sys::BitBuffer,
+    rx_buf: *mut sys::BitBuffer,
+    /// Released by the callback once it stops the poller
+    done: *mut sys::FuriSemaphore,
+    /// Set by the callback once the tag has turned up, which ends the tag
+    /// timeout
+    found: AtomicBool,
+}
+
+/// Protocol handler for NTAG-based e-ink price tags
+pub struct NtagProtocol {
+    nfc: Option<NfcGuard>,
+    poller: *mut sys::NfcPoller,
+    context: UnsafeCell<PollerContext>,
+    result: NfcResult<()>,
+}
+
+impl NtagProtocol {
+    /// Create a new protocol handler
+    pub fn new(config: NtagConfig) -> Self {
+        Self {
+            nfc: None,
+            poller: null_mut(),
+            context: UnsafeCell::new(PollerContext {
+                state: PollerState::WaitingForTag,
+                config,
+                image_data: null_mut(),
+                image_size: 0,
+                tx_buf: null_mut(),
+                rx_buf: null_mut(),
+                done: null_mut(),
+                found: AtomicBool::new(false),
+            }),
+            result: Ok(()),
+        }
+    }
+
+    /// Initialize NFC hardware
+    fn init_nfc(&mut self) -> NfcResult<()> {
+        unsafe {
+            // Claim the NFC hardware
+            let nfc = self.nfc.insert(NfcGuard::acquire()?).nfc();
+
+            // Allocate poller for ISO14443-3A
+            self.poller = sys::nfc_poller_alloc(nfc, sys::NfcProtocolIso14443_3a);
+            if self.poller.is_null() {
+                self.nfc = None;
+                return Err(NfcError::AllocFailed);
+            }
+
+            // Initialize context buffers
+            let ctx = &mut *self.context.get();
+            ctx.tx_buf = sys::bit_buffer_alloc(32);
+            ctx.rx_buf = sys::bit_buffer_alloc(32);
+            ctx.done = sys::furi_semaphore_alloc(1, 0);
+
+            if ctx.tx_buf.is_null() || ctx.rx_buf.is_null() || ctx.done.is_null() {
+                self.cleanup();
+                return Err(NfcError::AllocFailed);
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Clean up NFC resources
+    pub fn cleanup(&mut self) {
+        unsafe {
+            let ctx = &mut *self.context.get();
+
+            if !ctx.tx_buf.is_null() {
+                sys::bit_buffer_free(ctx.tx_buf);
+                ctx.tx_buf = null_mut();
+            }
+            if !ctx.rx_buf.is_null() {
+                sys::bit_buffer_free(ctx.rx_buf);
+                ctx.rx_buf = null_mut();
+            }
+            if !ctx.done.is_null() {
+                sys::furi_semaphore_free(ctx.done);
+                ctx.done = null_mut();
+            }
+
+            if !self.poller.is_null() {
+                sys::nfc_poller_free(self.poller);
+                self.poller = null_mut();
+            }
+            // Release the hardware once the poller is gone
+            self.nfc = None;
+        }
+    }
+
+    /// Write image data to the tag
+    ///
+    /// This executes the page-window upload:
+    /// 1. Write `window_pages` pages of data starting at `first_page`
+    /// 2. Post CTRL_CHUNK to the control page and wait until the MCU clears it
+    /// 3. Repeat until the whole buffer has been delivered
+    /// 4. Post CTRL_REFRESH and wait until the panel refresh has finished
+    pub fn write_image(&mut self, image_data: &[u8]) -> NfcResult<()> {
+        // Initialize NFC
+        self.init_nfc()?;
+
+        unsafe {
+            // Set up context
+            let ctx = &mut *self.context.get();
+            ctx.state = PollerState::WaitingForTag;
+            ctx.image_data = image_data.as_ptr();
+            ctx.image_size = image_data.len();
+            ctx.found.store(false, Ordering::Relaxed);
+            let (done, found) = (ctx.done, &ctx.found as *const AtomicBool);
+
+            // Start poller with callback
+            sys::nfc_poller_start(
+                self.poller,
+                Some(Self::poller_callback),
+                self.context.get() as *mut core::ffi::c_void,
+            );
+
+            // Wait for the callback to stop the poller, for a cancel, or for
+            // the tag timeout while no tag has turned up
+            let (wait, timeout) = (TagWait::start(), sys::furi_ms_to_ticks(CANCEL_POLL_MS));
+            let mut stopped = None;
+            while sys::furi_semaphore_acquire(done, timeout) != sys::FuriStatusOk {
+                match wait.check() {
+                    Err(NfcError::NoTag) if (*found).load(Ordering::Relaxed) => {}
+                    Err(e) => {
+                        stopped = Some(e);
+                        break;
+                    }
+                    Ok(()) => {}
+                }
+            }
+
+            // Stop poller; the state the callback left is only read after that
+            sys::nfc_poller_stop(self.poller);
+            self.result = match (*self.context.get()).state {
+                PollerState::Done => Ok(()),
+                PollerState::Error(e) => Err(e),
+                _ => Err(stopped.unwrap_or(NfcError::TransmitFailed(Failure::at("Poller")))),
+            };
+        }
+
+        // Clean up and return result
+        self.cleanup();
+        self.result
+    }
+
+    /// Write one 4-byte page and check for the 4-bit ACK
+    unsafe fn write_page(
+        poller: *mut sys::Iso14443_3aPoller,
+        tx_buf: *mut sys::BitBuffer,
+        rx_buf: *mut sys::BitBuffer,
+        page: u8,
+        data: &[u8; commands::PAGE_SIZE],
+    ) -> bool {
+        unsafe {
+            sys::bit_buffer_reset(tx_buf);
+            sys::bit_buffer_reset(rx_buf);
+            sys::bit_buffer_append_byte(tx_buf, commands::WRITE);
+            sys::bit_buffer_append_byte(tx_buf, page);
+            sys::bit_buffer_append_bytes(tx_buf, data.as_ptr(), data.len());
+            protocol_common::clear_link_error();
+
+            // The ACK is a bare 4-bit frame, which the poller reports as a CRC error
+            let error = sys::iso14443_3a_poller_send_standard_frame(poller, tx_buf, rx_buf, FWT_FC);
+            if error != sys::Iso14443_3aErrorWrongCrc {
+                log_error!("NTAG write page {} error code: {}", page, error.0);
+                protocol_common::note_link_error(error.0 as u32);
+                return false;
+            }
+            if sys::bit_buffer_get_size(rx_buf) != 4 {
+                log_error!("NTAG write page {}: unexpected response size", page);
+                return false;
+            }
+            let ack = sys::bit_buffer_get_byte(rx_buf, 0) & 0x0F;
+            if ack != commands::ACK {
+                log_error!("NTAG write page {}: NAK {:X}", page, ack);
+                protocol_common::note_link_error(u32::from(ack));
+                return false;
+            }
+            true
+        }
+    }
+
+    /// Read the control page and return its command byte
+    unsafe fn read_ctrl(
+        poller: *mut sys::Iso14443_3aPoller,
+        tx_buf: *mut sys::BitBuffer,
+        rx_buf: *mut sys::BitBuffer,
+        page: u8,
+    ) -> Option<u8> {
+        unsafe {
+            sys::bit_buffer_reset(tx_buf);
+            sys::bit_buffer_reset(rx_buf);
+            sys::bit_buffer_append_byte(tx_buf, commands::READ);
+            sys::bit_buffer_append_byte(tx_buf, page);
+            protocol_common::clear_link_error();
+
+            let error = sys::iso14443_3a_poller_send_standard_frame(poller, tx_buf, rx_buf, FWT_FC);
+            if error != sys::Iso14443_3aErrorNone {
+                log_error!("NTAG read page {} error code: {}", page, error.0);
+                protocol_common::note_link_error(error.0 as u32);
+                return None;
+            }
+            if sys::bit_buffer_get_size_bytes(rx_buf) < commands::PAGE_SIZE {
+                return None;
+            }
+            Some(sys::bit_buffer_get_byte(rx_buf, 0))
+        }
+    }
+
+    /// NFC poller callback - runs the state machine and wakes `write_image`
+    /// once it stops the poller
+    unsafe extern "C" fn poller_callback(
+        event: sys::NfcGenericEvent,
+        context: *mut core::ffi::c_void,
+    ) -> sys::NfcCommand {
+        unsafe {
+            let ctx = &mut *(context as *mut PollerContext);
+            let command = Self::step(ctx, event);
+            if command == sys::NfcCommandStop {
+                sys::furi_semaphore_release(ctx.done);
+            }
+            command
+        }
+    }
+
+    /// One step of the page-window upload state machine
+    unsafe fn step(ctx: &mut PollerContext, event: sys::NfcGenericEvent) -> sys::NfcCommand {
+        unsafe {
+            // Check event data
+            let event_data = event.event_data as *const sys::Iso14443_3aPollerEvent;
+            if event_data.is_null() {
+                return sys::NfcCommandContinue;
+            }
+
+            let event_type = (*event_data).type_;
+
+            // Handle WaitingForTag state specially - keep polling on errors
+            if ctx.state == PollerState::WaitingForTag {
+                if event_type == sys::Iso14443_3aPollerEventTypeReady {
+                    log_info!("Tag detected! Starting NTAG page upload...");
+                    ctx.found.store(true, Ordering::Relaxed);
+                    ctx.state = PollerState::WritePage(0, 0);
+                } else {
+                    return sys::NfcCommandContinue;
+                }
+            } else if event_type != sys::Iso14443_3aPollerEventTypeReady {
+                if event_type == sys::Iso14443_3aPollerEventTypeError {
+                    ctx.state = PollerState::Error(NfcError::DetectFailed);
+                    return sys::NfcCommandStop;
+                }
+                return sys::NfcCommandContinue;
+            }
+
+            // Get the ISO14443-3A poller instance
+            let poller = event.instance as *mut sys::Iso14443_3aPoller;
+            let window_size = ctx.config.window_size();
+            let num_windows = ctx.image_size.div_ceil(window_size);
+
+            // A cancel stops the transfer between commands
+            if protocol_common::cancel_requested() {
+                ctx.state = PollerState::Error(NfcError::Cancelled);
+                return sys::NfcCommandStop;
+            }
+
+            // Process state machine
+            match ctx.state {
+                PollerState::WaitingForTag => {
+                    return sys::NfcCommandContinue;
+                }
+                PollerState::WritePage(window, page) => {
+                    let window_len = core::cmp::min(window_size, ctx.image_size - window * window_size);
+                    let page_offset = page as usize * commands::PAGE_SIZE;
+
+                    // Pad the final page of a short window with zeros
+                    let mut data = [0u8; commands::PAGE_SIZE];
+                    let page_len = core::cmp::min(commands::PAGE_SIZE, window_len - page_offset);
+                    let src = ctx.image_data.add(window * window_size + page_offset);
+                    core::ptr::copy_nonoverlapping(src, data.as_mut_ptr(), page_len);
+
+                    if Self::write_page(poller, ctx.tx_buf, ctx.rx_buf, ctx.config.first_page + page, &data) {
+                        if page_offset + commands::PAGE_SIZE >= window_len {
+                            ctx.state = PollerState::PostChunk(window);
+                        } else {
+                            ctx.state = PollerState::WritePage(window, page + 1);
+                        }
+                    } else {
+                        let failure = Failure::at("WritePage").packet(window).link();
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::PostChunk(window) => {
+                    let window_len = core::cmp::min(window_size, ctx.image_size - window * window_size) as u16;
+                    let len = window_len.to_le_bytes();
+                    let ctrl = [commands::CTRL_CHUNK, window as u8, len[0], len[1]];
+                    if Self::write_page(poller, ctx.tx_buf, ctx.rx_buf, ctx.config.ctrl_page, &ctrl) {
+                        ctx.state = PollerState::WaitChunk(window, 0);
+                    } else {
+                        let failure = Failure::at("PostChunk").packet(window).link();
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::WaitChunk(window, polls) => {
+                    match Self::read_ctrl(poller, ctx.tx_buf, ctx.rx_buf, ctx.config.ctrl_page) {
+                        Some(commands::CTRL_IDLE) => {
+                            if window + 1 >= num_windows {
+                                ctx.state = PollerState::PostRefresh;
+                            } else {
+                                ctx.state = PollerState::WritePage(window + 1, 0);
+                            }
+                        }
+                        Some(_) if polls + 1 < ctx.config.max_polls => {
+                            sys::furi_delay_ms(ctx.config.poll_interval_ms);
+                            ctx.state = PollerState::WaitChunk(window, polls + 1);
+                        }
+                        Some(cmd) => {
+                            log_error!("Window {} not consumed (ctrl={:02X})", window, cmd);
+                            let failure = Failure::at("WaitChunk").packet(window);
+                            ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                            return sys::NfcCommandStop;
+                        }
+                        None => {
+                            let failure = Failure::at("WaitChunk").packet(window).link();
+                            ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                            return sys::NfcCommandStop;
+                        }
+                    }
+                }
+                PollerState::PostRefresh => {
+                    let ctrl = [commands::CTRL_REFRESH, 0x00, 0x00, 0x00];
+                    if Self::write_page(poller, ctx.tx_buf, ctx.rx_buf, ctx.config.ctrl_page, &ctrl) {
+                        ctx.state = PollerState::WaitRefresh(0);
+                    } else {
+                        let failure = Failure::at("PostRefresh").link();
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::WaitRefresh(polls) => {
+                    match Self::read_ctrl(poller, ctx.tx_buf, ctx.rx_buf, ctx.config.ctrl_page) {
+                        Some(commands::CTRL_IDLE) => {
+                            log_info!("Display ready!");
+                            ctx.state = PollerState::Done;
+                            return sys::NfcCommandStop;
+                        }
+                        Some(_) if polls + 1 < ctx.config.max_polls => {
+                            sys::furi_delay_ms(ctx.config.poll_interval_ms);
+                            ctx.state = PollerState::WaitRefresh(polls + 1);
+                        }
+                        _ => {
+                            log_error!("Refresh not acknowledged by tag MCU");
+                            let failure = Failure::at("WaitRefresh").link();
+                            ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                            return sys::NfcCommandStop;
+                        }
+                    }
+                }
+                PollerState::Done | PollerState::Error(_) => {
+                    return sys::NfcCommandStop;
+                }
+            }
+
+            sys::NfcCommandContinue
+        }
+    }
+}
+
+impl EinkProtocol for NtagProtocol {
+    fn status(&self) -> &'static CStr {
+        c"Writing pages..."
+    }
+
+    fn write(
+        &mut self,
+        mut payload: Payload<'_>,
+        _transport: Option<&mut dyn Transport>,
+    ) -> NfcResult<()> {
+        let front = payload.front.bytes()?;
+        self.write_image(&front)
+    }
+
+    fn plan(&mut self, front: &[u8], _rear: Option<&[u8]>) -> String {
+        let image_size = front.len();
+        let config = self.context.get_mut().config;
+        let window_size = config.window_size();
+        let windows = image_size.div_ceil(window_size);
+        let ctrl = config.ctrl_page;
+        let mut plan = Plan::default();
+        plan.note(&format!("{} windows of {} B:", windows, window_size));
+        let last_page = config.first_page + config.window_pages - 1;
+        let pages = format!("  WRITE p{}-{}", config.first_page, last_page);
+        plan.packets(&pages, image_size, commands::PAGE_SIZE, 0);
+        plan.repeat(&format!("  WRITE p{}: {:02X}", ctrl, commands::CTRL_CHUNK), windows);
+        plan.poll(&format!("  READ p{}", ctrl), windows, config.poll_interval_ms, "00");
+        plan.send(&format!("WRITE p{}: {:02X}", ctrl, commands::CTRL_REFRESH), 0);
+        plan.poll(&format!("READ p{}", ctrl), 1, config.poll_interval_ms, "00");
+        plan.finish()
+    }
+}
+
+impl Drop for NtagProtocol {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
@@ -0,0 +1,465 @@
+//! ST25DV mailbox protocol implementation for DIY e-paper frames
+//!
+//! Several DIY e-paper frames pair a panel and a small MCU with an ST25DV
+//! dynamic tag (ISO 15693). The reader pushes the image through the ST25DV
+//! fast-transfer mailbox one message at a time and waits for the MCU to
+//! drain each message before writing the next one.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use flipperzero_sys as sys;
+
+use crate::eink_protocol::{EinkProtocol, Payload};
+use crate::nfc_guard::NfcGuard;
+use crate::protocol_common::{
+    self, log_error, log_info, Failure, NfcError, NfcResult, Plan, TagWait, CANCEL_POLL_MS,
+};
+use crate::transport::Transport;
+
+/// ST25DV custom command set (ISO 15693, manufacturer code 0x02)
+pub mod commands {
+    /// Request flags: high data rate, no addressing
+    pub const FLAGS: u8 = 0x02;
+
+    /// ST Microelectronics IC manufacturer code
+    pub const MFG_CODE: u8 = 0x02;
+
+    /// Write Message: FLAGS AA 02 LEN-1 DATA...
+    pub const WRITE_MESSAGE: u8 = 0xAA;
+
+    /// Read Dynamic Configuration: FLAGS AD 02 PTR
+    pub const READ_DYN_CONFIG: u8 = 0xAD;
+
+    /// Write Dynamic Configuration: FLAGS AE 02 PTR VALUE
+    pub const WRITE_DYN_CONFIG: u8 = 0xAE;
+
+    /// Dynamic register MB_CTRL_Dyn
+    pub const MB_CTRL_DYN: u8 = 0x0D;
+
+    /// MB_CTRL_Dyn: mailbox enabled
+    pub const MB_EN: u8 = 0x01;
+
+    /// MB_CTRL_Dyn: message put by RF, not yet read by the MCU
+    pub const RF_PUT_MSG: u8 = 0x04;
+
+    /// Largest message the ST25DV mailbox can hold
+    pub const MAILBOX_MAX: usize = 256;
+}
+
+/// Frame waiting time for ST25DV custom commands (carrier cycles)
+const FWT_FC: u32 = 4202 * 8;
+
+/// Mailbox transfer settings
+#[derive(Debug, Clone, Copy)]
+pub struct MailboxConfig {
+    /// Bytes per mailbox message (1..=256)
+    pub message_size: usize,
+    /// Delay between MB_CTRL_Dyn polls while the MCU drains a message (ms)
+    pub poll_interval_ms: u32,
+    /// Number of polls before a message is considered lost
+    pub max_polls: u32,
+}
+
+impl MailboxConfig {
+    /// Full-size messages, 20ms handshake polling, 2s per message
+    pub const DEFAULT: MailboxConfig = MailboxConfig {
+        message_size: commands::MAILBOX_MAX,
+        poll_interval_ms: 20,
+        max_polls: 100,
+    };
+}
+
+/// State machine states for the poller callback
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PollerState {
+    WaitingForTag,
+    EnableMailbox,
+    WriteMessage(usize),           // message index
+    WaitConsumed(usize, u32),      // message index, polls so far
+    Done,
+    Error(NfcError),
+}
+
+/// Context passed to the NFC poller callback
+struct PollerContext {
+    state: PollerState,
+    config: MailboxConfig,
+    image_data: *const u8,
+    image_size: usize,
+    tx_buf: *mut sys::BitBuffer,
+    rx_buf: *mut sys::BitBuffer,
+    /// Released by the callback once it stops the poller
+    done: *mut sys::FuriSemaphore,
+    /// Set by the callback once the tag has turned up, which ends the tag
+    /// timeout
+    found: AtomicBool,
+}
+
+/// Protocol handler for ST25DV mailbox e-paper frames
+pub struct St25dvProtocol {
+    nfc: Option<NfcGuard>,
+    poller: *mut sys::NfcPoller,
+    context: UnsafeCell<PollerContext>,
+    result: NfcResult<()>,
+}
+
+impl St25dvProtocol {
+    /// Create a new protocol handler
+    pub fn new(config: MailboxConfig) -> Self {
+        Self {
+            nfc: None,
+            poller: null_mut(),
+            context: UnsafeCell::new(PollerContext {
+                state: PollerState::WaitingForTag,
+                config,
+                image_data: null_mut(),
+                image_size: 0,
+                tx_buf: null_mut(),
+                rx_buf: null_mut(),
+                done: null_mut(),
+                found: AtomicBool::new(false),
+            }),
+            result: Ok(()),
+        }
+    }
+
+    /// Initialize NFC hardware
+    fn init_nfc(&mut self) -> NfcResult<()> {
+        unsafe {
+            // Claim the NFC hardware
+            let nfc = self.nfc.insert(NfcGuard::acquire()?).nfc();
+
+            // Allocate poller for ISO15693-3
+            self.poller = sys::nfc_poller_alloc(nfc, sys::NfcProtocolIso15693_3);
+            if self.poller.is_null() {
+                self.nfc = None;
+                return Err(NfcError::AllocFailed);
+            }
+
+            // Initialize context buffers
+            let ctx = &mut *self.context.get();
+            ctx.tx_buf = sys::bit_buffer_alloc(commands::MAILBOX_MAX + 8);
+            ctx.rx_buf = sys::bit_buffer_alloc(commands::MAILBOX_MAX + 8);
+            ctx.done = sys::furi_semaphore_alloc(1, 0);
+
+            if ctx.tx_buf.is_null() || ctx.rx_buf.is_null() || ctx.done.is_null() {
+                self.cleanup();
+                return Err(NfcError::AllocFailed);
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Clean up NFC resources
+    pub fn cleanup(&mut self) {
+        unsafe {
+            let ctx = &mut *self.context.get();
+
+            if !ctx.tx_buf.is_null() {
+                sys::bit_buffer_free(ctx.tx_buf);
+                ctx.tx_buf = null_mut();
+            }
+            if !ctx.rx_buf.is_null() {
+                sys::bit_buffer_free(ctx.rx_buf);
+                ctx.rx_buf = null_mut();
+            }
+            if !ctx.done.is_null() {
+                sys::furi_semaphore_free(ctx.done);
+                ctx.done = null_mut();
+            }
+
+            if !self.poller.is_null() {
+                sys::nfc_poller_free(self.poller);
+                self.poller = null_mut();
+            }
+            // Release the hardware once the poller is gone
+            self.nfc = None;
+        }
+    }
+
+    /// Write image data to the frame
+    ///
+    /// This executes the mailbox transfer:
+    /// 1. Enable the mailbox (MB_CTRL_Dyn.MB_EN)
+    /// 2. Write one `message_size` chunk as a mailbox message
+    /// 3. Poll MB_CTRL_Dyn until the MCU has read the message
+    /// 4. Repeat until the whole buffer has been delivered
+    ///
+    /// The frame MCU is responsible for reassembling the buffer and
+    /// refreshing its panel.
+    pub fn write_image(&mut self, image_data: &[u8]) -> NfcResult<()> {
+        // Initialize NFC
+        self.init_nfc()?;
+
+        unsafe {
+            // Set up context
+            let ctx = &mut *self.context.get();
+            ctx.state = PollerState::WaitingForTag;
+            ctx.image_data = image_data.as_ptr();
+            ctx.image_size = image_data.len();
+            ctx.found.store(false, Ordering::Relaxed);
+            let (done, found) = (ctx.done, &ctx.found as *const AtomicBool);
+            ctx.config.message_size = ctx.config.message_size.clamp(1, commands::MAILBOX_MAX);
+
+            // Start poller with callback
+            sys::nfc_poller_start(
+                self.poller,
+                Some(Self::poller_callback),
+                self.context.get() as *mut core::ffi::c_void,
+            );
+
+            // Wait for the callback to stop the poller, for a cancel, or for
+            // the tag timeout while no tag has turned up
+            let (wait, timeout) = (TagWait::start(), sys::furi_ms_to_ticks(CANCEL_POLL_MS));
+            let mut stopped = None;
+            while sys::furi_semaphore_acquire(done, timeout) != sys::FuriStatusOk {
+                match wait.check() {
+                    Err(NfcError::NoTag) if (*found).load(Ordering::Relaxed) => {}
+                    Err(e) => {
+                        stopped = Some(e);
+                        break;
+                    }
+                    Ok(()) => {}
+                }
+            }
+
+            // Stop poller; the state the callback left is only read after that
+            sys::nfc_poller_stop(self.poller);
+            self.result = match (*self.context.get()).state {
+                PollerState::Done => Ok(()),
+                PollerState::Error(e) => Err(e),
+                _ => Err(stopped.unwrap_or(NfcError::TransmitFailed(Failure::at("Poller")))),
+            };
+        }
+
+        // Clean up and return result
+        self.cleanup();
+        self.result
+    }
+
+    /// Number of mailbox messages needed for the full buffer
+    fn num_messages(ctx: &PollerContext) -> usize {
+        ctx.image_size.div_ceil(ctx.config.message_size)
+    }
+
+    /// Send an ST25DV custom command and check the response flags
+    ///
+    /// Returns the response (without the leading flags byte) in `rx_buf`.
+    unsafe fn send_custom(
+        poller: *mut sys::Iso15693_3Poller,
+        tx_buf: *mut sys::BitBuffer,
+        rx_buf: *mut sys::BitBuffer,
+        cmd: u8,
+        params: &[u8],
+    ) -> bool {
+        unsafe {
+            sys::bit_buffer_reset(tx_buf);
+            sys::bit_buffer_reset(rx_buf);
+            sys::bit_buffer_append_byte(tx_buf, commands::FLAGS);
+            sys::bit_buffer_append_byte(tx_buf, cmd);
+            sys::bit_buffer_append_byte(tx_buf, commands::MFG_CODE);
+            sys::bit_buffer_append_bytes(tx_buf, params.as_ptr(), params.len());
+            protocol_common::clear_link_error();
+
+            let error = sys::iso15693_3_poller_send_frame(poller, tx_buf, rx_buf, FWT_FC);
+            if error != sys::Iso15693_3ErrorNone {
+                log_error!("ST25DV cmd {:02X} send error code: {}", cmd, error.0);
+                protocol_common::note_link_error(error.0 as u32);
+                return false;
+            }
+
+            // Response format is [FLAGS] [DATA...]; bit 0 of FLAGS signals an error
+            let rx_size = sys::bit_buffer_get_size_bytes(rx_buf);
+            if rx_size == 0 {
+                log_error!("ST25DV cmd {:02X}: empty response", cmd);
+                return false;
+            }
+            let flags = sys::bit_buffer_get_byte(rx_buf, 0);
+            if flags & 0x01 != 0 {
+                let code = if rx_size > 1 { sys::bit_buffer_get_byte(rx_buf, 1) } else { 0 };
+                log_error!("ST25DV cmd {:02X}: error flags={:02X} code={:02X}", cmd, flags, code);
+                protocol_common::note_link_error(u32::from(code));
+                return false;
+            }
+            true
+        }
+    }
+
+    /// NFC poller callback - runs the state machine and wakes `write_image`
+    /// once it stops the poller
+    unsafe extern "C" fn poller_callback(
+        event: sys::NfcGenericEvent,
+        context: *mut core::ffi::c_void,
+    ) -> sys::NfcCommand {
+        unsafe {
+            let ctx = &mut *(context as *mut PollerContext);
+            let command = Self::step(ctx, event);
+            if command == sys::NfcCommandStop {
+                sys::furi_semaphore_release(ctx.done);
+            }
+            command
+        }
+    }
+
+    /// One step of the mailbox transfer state machine
+    unsafe fn step(ctx: &mut PollerContext, event: sys::NfcGenericEvent) -> sys::NfcCommand {
+        unsafe {
+            // Check event data
+            let event_data = event.event_data as *const sys::Iso15693_3PollerEvent;
+            if event_data.is_null() {
+                return sys::NfcCommandContinue;
+            }
+
+            let event_type = (*event_data).type_;
+
+            // Handle WaitingForTag state specially - keep polling on errors
+            if ctx.state == PollerState::WaitingForTag {
+                if event_type == sys::Iso15693_3PollerEventTypeReady {
+                    log_info!("Tag detected! Starting ST25DV mailbox transfer...");
+                    ctx.found.store(true, Ordering::Relaxed);
+                    ctx.state = PollerState::EnableMailbox;
+                } else {
+                    return sys::NfcCommandContinue;
+                }
+            } else if event_type != sys::Iso15693_3PollerEventTypeReady {
+                if event_type == sys::Iso15693_3PollerEventTypeError {
+                    ctx.state = PollerState::Error(NfcError::DetectFailed);
+                    return sys::NfcCommandStop;
+                }
+                return sys::NfcCommandContinue;
+            }
+
+            // Get the ISO15693-3 poller instance
+            let poller = event.instance as *mut sys::Iso15693_3Poller;
+
+            // A cancel stops the transfer between commands
+            if protocol_common::cancel_requested() {
+                ctx.state = PollerState::Error(NfcError::Cancelled);
+                return sys::NfcCommandStop;
+            }
+
+            // Process state machine
+            match ctx.state {
+                PollerState::WaitingForTag => {
+                    return sys::NfcCommandContinue;
+                }
+                PollerState::EnableMailbox => {
+                    let params = [commands::MB_CTRL_DYN, commands::MB_EN];
+                    if Self::send_custom(poller, ctx.tx_buf, ctx.rx_buf, commands::WRITE_DYN_CONFIG, &params) {
+                        ctx.state = PollerState::WriteMessage(0);
+                    } else {
+                        log_error!("Mailbox enable failed (is MB_MODE set on the tag?)");
+                        let failure = Failure::at("EnableMailbox").link();
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::WriteMessage(msg_idx) => {
+                    let offset = msg_idx * ctx.config.message_size;
+                    let chunk_len = core::cmp::min(ctx.config.message_size, ctx.image_size - offset);
+
+                    // Message length is encoded as LEN-1
+                    let mut params = [0u8; commands::MAILBOX_MAX + 1];
+                    params[0] = (chunk_len - 1) as u8;
+                    let src = core::slice::from_raw_parts(ctx.image_data.add(offset), chunk_len);
+                    params[1..1 + chunk_len].copy_from_slice(src);
+
+                    if Self::send_custom(
+                        poller, ctx.tx_buf, ctx.rx_buf,
+                        commands::WRITE_MESSAGE, &params[..1 + chunk_len]
+                    ) {
+                        ctx.state = PollerState::WaitConsumed(msg_idx, 0);
+                    } else {
+                        let failure = Failure::at("WriteMessage").packet(msg_idx).link();
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+                }
+                PollerState::WaitConsumed(msg_idx, polls) => {
+                    let params = [commands::MB_CTRL_DYN];
+                    if !Self::send_custom(poller, ctx.tx_buf, ctx.rx_buf, commands::READ_DYN_CONFIG, &params) {
+                        let failure = Failure::at("WaitConsumed").packet(msg_idx).link();
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    }
+
+                    // Response format: [FLAGS, MB_CTRL_DYN]
+                    let mb_ctrl = if sys::bit_buffer_get_size_bytes(ctx.rx_buf) >= 2 {
+                        sys::bit_buffer_get_byte(ctx.rx_buf, 1)
+                    } else {
+                        0
+                    };
+
+                    if mb_ctrl & commands::RF_PUT_MSG == 0 {
+                        // MCU has read the message
+                        if msg_idx + 1 >= Self::num_messages(ctx) {
+                            log_info!("All {} mailbox messages delivered", msg_idx + 1);
+                            ctx.state = PollerState::Done;
+                            return sys::NfcCommandStop;
+                        }
+                        ctx.state = PollerState::WriteMessage(msg_idx + 1);
+                    } else if polls + 1 >= ctx.config.max_polls {
+                        log_error!("Mailbox message {} not consumed (MB_CTRL={:02X})", msg_idx, mb_ctrl);
+                        let failure = Failure::at("WaitConsumed").packet(msg_idx);
+                        ctx.state = PollerState::Error(NfcError::TransmitFailed(failure));
+                        return sys::NfcCommandStop;
+                    } else {
+                        sys::furi_delay_ms(ctx.config.poll_interval_ms);
+                        ctx.state = PollerState::WaitConsumed(msg_idx, polls + 1);
+                    }
+                }
+                PollerState::Done | PollerState::Error(_) => {
+                    return sys::NfcCommandStop;
+                }
+            }
+
+            sys::NfcCommandContinue
+        }
+    }
+}
+
+impl EinkProtocol for St25dvProtocol {
+    fn status(&self) -> &'static CStr {
+        c"Writing mailbox..."
+    }
+
+    fn write(
+        &mut self,
+        mut payload: Payload<'_>,
+        _transport: Option<&mut dyn Transport>,
+    ) -> NfcResult<()> {
+        let front = payload.front.bytes()?;
+        self.write_image(&front)
+    }
+
+    fn plan(&mut self, front: &[u8], _rear: Option<&[u8]>) -> String {
+        let image_size = front.len();
+        let config = self.context.get_mut().config;
+        let message_size = config.message_size.clamp(1, commands::MAILBOX_MAX);
+        let messages = image_size.div_ceil(message_size);
+        let mut plan = Plan::default();
+        plan.send(&format!("WR DYN {:02X}: {:02X}", commands::MB_CTRL_DYN, commands::MB_EN), 0);
+        plan.note(&format!("{} messages of {} B:", messages, message_size));
+        plan.packets("  WRITE MSG", image_size, message_size, 0);
+        plan.poll(
+            &format!("  RD DYN {:02X}", commands::MB_CTRL_DYN),
+            messages,
+            config.poll_interval_ms,
+            "read",
+        );
+        plan.finish()
+    }
+}
+
+impl Drop for St25dvProtocol {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
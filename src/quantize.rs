@@ -0,0 +1,1013 @@
+//! Color quantization for e-ink palettes
+//!
+//! Maps source RGB pixels to the index of a panel color. Quantizers are
+//! selected at runtime and fed one row at a time, so error-diffusion variants
+//! can carry state between pixels and rows without the packing code knowing.
+//! Besides the pixel-level dithers there is a clustered-dot halftone, which
+//! prints photos as a screen of round dots like a newspaper.
+//!
+//! Pixels are matched by hue and saturation rather than raw RGB distance, so
+//! a dark red stays red and a pale yellow stays yellow; the settings file can
+//! switch this to CIELAB distance instead. A gamma curve from the settings
+//! file can be applied to source pixels before any of them are matched
+//! against the palette. The file can also give the colors a panel batch
+//! really shows, and move the thresholds of the hue rule (see `Tuning`).
+//! Each image can have its own black/white `Threshold` and `AccentPull` on
+//! top.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU8, Ordering};
+
+/// 8-bit RGB color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+/// BWRY panel palette, indexed by 2-bit color code
+/// 0=Black, 1=White, 2=Yellow, 3=Red
+pub const BWRY_PALETTE: &[Rgb] = &[
+    Rgb::new(0, 0, 0),
+    Rgb::new(255, 255, 255),
+    Rgb::new(255, 255, 0),
+    Rgb::new(255, 0, 0),
+];
+
+/// BWR panel palette
+/// 0=Black, 1=White, 2=Red
+pub const BWR_PALETTE: &[Rgb] = &[
+    Rgb::new(0, 0, 0),
+    Rgb::new(255, 255, 255),
+    Rgb::new(255, 0, 0),
+];
+
+/// BWR palette index for white
+pub const BWR_WHITE: u8 = 1;
+/// BWR palette index for red
+pub const BWR_RED: u8 = 2;
+
+/// Black/white palette for 1bpp panels
+/// 0=Black, 1=White
+pub const BW_PALETTE: &[Rgb] = &[Rgb::new(0, 0, 0), Rgb::new(255, 255, 255)];
+
+/// ACeP 7-color palette, indexed by 4-bit controller color code
+/// 0=Black, 1=White, 2=Green, 3=Blue, 4=Red, 5=Yellow, 6=Orange
+pub const ACEP_PALETTE: &[Rgb] = &[
+    Rgb::new(0, 0, 0),
+    Rgb::new(255, 255, 255),
+    Rgb::new(0, 255, 0),
+    Rgb::new(0, 0, 255),
+    Rgb::new(255, 0, 0),
+    Rgb::new(255, 255, 0),
+    Rgb::new(255, 128, 0),
+];
+
+/// Maps RGB pixels of one image to palette indices
+///
+/// Pixels are fed left to right; `next_row` is called after each row.
+pub trait Quantizer {
+    /// Map the pixel at column `x` of the current row to a palette index
+    fn quantize(&mut self, x: usize, color: Rgb) -> u8;
+
+    /// Advance to the next row
+    fn next_row(&mut self) {}
+}
+
+/// Quantizer selection at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizerKind {
+    /// Closest palette color, no dithering
+    Nearest,
+    /// Floyd-Steinberg error diffusion
+    FloydSteinberg,
+    /// Ordered dithering with an 8x8 Bayer matrix
+    Bayer,
+    /// Ordered dithering with a 16x16 blue-noise mask
+    BlueNoise,
+    /// Clustered-dot halftone screen at the configured `ScreenAngle`
+    Halftone,
+}
+
+impl QuantizerKind {
+    /// Create a quantizer for `palette` and rows of `width` pixels
+    ///
+    /// Palette colors are replaced by their tuned values (see `set_tuning`),
+    /// and the configured gamma curve (see `set_gamma`) is applied in front.
+    pub fn build(self, palette: &[Rgb], width: usize) -> Box<dyn Quantizer> {
+        let palette = tuned_palette(palette);
+        let quantizer: Box<dyn Quantizer> = match self {
+            QuantizerKind::Nearest => Box::new(Nearest::new(palette)),
+            QuantizerKind::FloydSteinberg => Box::new(FloydSteinberg::new(palette, width)),
+            QuantizerKind::Bayer => Box::new(Bayer::new(palette)),
+            QuantizerKind::BlueNoise => Box::new(BlueNoise::new(palette)),
+            QuantizerKind::Halftone => Box::new(Halftone::new(palette, screen_angle())),
+        };
+        match gamma() {
+            Gamma::LINEAR => quantizer,
+            gamma => Box::new(ToneCurve::new(gamma.table(), quantizer)),
+        }
+    }
+
+    /// Mode for a `key` name, as written by `key`
+    pub fn from_key(key: &str) -> Option<Self> {
+        [
+            QuantizerKind::Nearest,
+            QuantizerKind::FloydSteinberg,
+            QuantizerKind::Bayer,
+            QuantizerKind::BlueNoise,
+            QuantizerKind::Halftone,
+        ]
+        .into_iter()
+        .find(|kind| kind.key() == key)
+    }
+
+    /// Short name for files describing how an image was produced
+    pub fn key(self) -> &'static str {
+        match self {
+            QuantizerKind::Nearest => "nearest",
+            QuantizerKind::FloydSteinberg => "floyd_steinberg",
+            QuantizerKind::Bayer => "bayer",
+            QuantizerKind::BlueNoise => "blue_noise",
+            QuantizerKind::Halftone => "halftone",
+        }
+    }
+
+    /// The mode after this one, for cycling through them in a menu
+    pub fn next(self) -> Self {
+        match self {
+            QuantizerKind::Nearest => QuantizerKind::FloydSteinberg,
+            QuantizerKind::FloydSteinberg => QuantizerKind::Bayer,
+            QuantizerKind::Bayer => QuantizerKind::BlueNoise,
+            QuantizerKind::BlueNoise => QuantizerKind::Halftone,
+            QuantizerKind::Halftone => QuantizerKind::Nearest,
+        }
+    }
+}
+
+/// Rule for matching pixels against the palette
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMatch {
+    /// Colored pixels by hue, the rest by brightness
+    #[default]
+    Hue,
+    /// Smallest CIELAB distance
+    Lab,
+}
+
+impl ColorMatch {
+    /// Value stored in the settings file
+    pub fn key(self) -> &'static str {
+        match self {
+            ColorMatch::Hue => "hue",
+            ColorMatch::Lab => "lab",
+        }
+    }
+
+    /// Rule for a `key` name, as written by `key`
+    pub fn from_key(key: &str) -> Option<Self> {
+        [ColorMatch::Hue, ColorMatch::Lab].into_iter().find(|rule| rule.key() == key)
+    }
+}
+
+static COLOR_MATCH: AtomicU8 = AtomicU8::new(ColorMatch::Hue as u8);
+
+/// Set the palette matching rule for later decodes
+pub fn set_color_match(rule: ColorMatch) {
+    COLOR_MATCH.store(rule as u8, Ordering::Relaxed);
+}
+
+fn color_match() -> ColorMatch {
+    match COLOR_MATCH.load(Ordering::Relaxed) {
+        rule if rule == ColorMatch::Lab as u8 => ColorMatch::Lab,
+        _ => ColorMatch::Hue,
+    }
+}
+
+/// Exponent of the tone curve applied before palette matching, in hundredths
+///
+/// Each channel `c` becomes `255 * (c / 255) ^ gamma`. Above 1.00 midtones
+/// darken (2.20 undoes sRGB encoding, so distances are judged on light
+/// intensity); below 1.00 they lighten. Black and white stay put.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gamma(pub u16);
+
+impl Gamma {
+    /// No curve
+    pub const LINEAR: Gamma = Gamma(100);
+
+    /// Accepted range, 0.25 to 4.00
+    const MIN: u16 = 25;
+    const MAX: u16 = 400;
+
+    /// Parse a decimal exponent such as `2.2`, clamped to the accepted range
+    pub fn parse(value: &str) -> Option<Self> {
+        let gamma: f32 = value.trim().parse().ok()?;
+        if gamma.is_nan() || gamma <= 0.0 {
+            return None;
+        }
+        Some(Gamma(((gamma * 100.0 + 0.5) as u16).clamp(Self::MIN, Self::MAX)))
+    }
+
+    /// Curve lookup table indexed by channel value
+    fn table(self) -> [u8; 256] {
+        let exponent = self.0 as f32 / 100.0;
+        let mut table = [0u8; 256];
+        for (value, out) in table.iter_mut().enumerate().skip(1) {
+            let level = exp2(exponent * log2(value as f32 / 255.0));
+            *out = (level * 255.0 + 0.5) as u8;
+        }
+        table
+    }
+}
+
+impl Default for Gamma {
+    fn default() -> Self {
+        Gamma::LINEAR
+    }
+}
+
+impl core::fmt::Display for Gamma {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{:02}", self.0 / 100, self.0 % 100)
+    }
+}
+
+/// Gamma used by quantizers built from now on
+static GAMMA: AtomicU16 = AtomicU16::new(Gamma::LINEAR.0);
+
+/// Set the gamma curve for later decodes
+pub fn set_gamma(gamma: Gamma) {
+    GAMMA.store(gamma.0, Ordering::Relaxed);
+}
+
+fn gamma() -> Gamma {
+    Gamma(GAMMA.load(Ordering::Relaxed))
+}
+
+/// Direction of the rows of dots in the halftone mode, from horizontal
+///
+/// 45 degrees hides the screen best, and is what print uses for black; the
+/// others are there for images whose own lines beat against it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScreenAngle {
+    Deg0,
+    Deg15,
+    #[default]
+    Deg45,
+    Deg75,
+}
+
+impl ScreenAngle {
+    const ALL: [ScreenAngle; 4] =
+        [ScreenAngle::Deg0, ScreenAngle::Deg15, ScreenAngle::Deg45, ScreenAngle::Deg75];
+
+    pub fn degrees(self) -> u8 {
+        match self {
+            ScreenAngle::Deg0 => 0,
+            ScreenAngle::Deg15 => 15,
+            ScreenAngle::Deg45 => 45,
+            ScreenAngle::Deg75 => 75,
+        }
+    }
+
+    /// Angle for a value written by `degrees`
+    pub fn from_degrees(value: &str) -> Option<Self> {
+        let degrees: u8 = value.trim().parse().ok()?;
+        Self::ALL.into_iter().find(|angle| angle.degrees() == degrees)
+    }
+
+    /// Cosine and sine of the angle
+    fn unit(self) -> (f32, f32) {
+        use core::f32::consts::FRAC_1_SQRT_2;
+        match self {
+            ScreenAngle::Deg0 => (1.0, 0.0),
+            ScreenAngle::Deg15 => (0.965_925_8, 0.258_819_04),
+            ScreenAngle::Deg45 => (FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+            ScreenAngle::Deg75 => (0.258_819_04, 0.965_925_8),
+        }
+    }
+}
+
+static SCREEN_ANGLE: AtomicU8 = AtomicU8::new(ScreenAngle::Deg45 as u8);
+
+/// Set the halftone screen angle for later decodes
+pub fn set_screen_angle(angle: ScreenAngle) {
+    SCREEN_ANGLE.store(angle as u8, Ordering::Relaxed);
+}
+
+fn screen_angle() -> ScreenAngle {
+    let stored = SCREEN_ANGLE.load(Ordering::Relaxed);
+    ScreenAngle::ALL.into_iter().find(|angle| *angle as u8 == stored).unwrap_or_default()
+}
+
+/// Gray level (0-255) that divides dark pixels from light ones
+///
+/// Channels are stretched so this level lands on mid-gray before the
+/// palette is matched: raising it turns more of the image black, lowering
+/// it more white. Black and white stay put, and the dithering modes work
+/// on the shifted tones. Unlike gamma it is chosen per image, on the
+/// threshold screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Threshold(pub u8);
+
+impl Threshold {
+    /// No shift
+    pub const DEFAULT: Threshold = Threshold(128);
+
+    /// Accepted range, which keeps some of both tones in any image
+    const MIN: u8 = 16;
+    const MAX: u8 = 240;
+    /// Change per key press
+    const STEP: u8 = 8;
+
+    /// One step lower, so fewer pixels come out black
+    pub fn lower(self) -> Self {
+        Threshold(self.0.saturating_sub(Self::STEP).max(Self::MIN))
+    }
+
+    /// One step higher, so more pixels come out black
+    pub fn higher(self) -> Self {
+        Threshold(self.0.saturating_add(Self::STEP).min(Self::MAX))
+    }
+
+    /// Parse a level written by `Display`, clamped to the accepted range
+    pub fn parse(value: &str) -> Option<Self> {
+        let level: u8 = value.trim().parse().ok()?;
+        Some(Threshold(level.clamp(Self::MIN, Self::MAX)))
+    }
+
+    /// Piecewise linear stretch taking the level to 128, by channel value
+    fn table(self) -> [u8; 256] {
+        let level = self.0 as u32;
+        let mut table = [0u8; 256];
+        for (value, out) in table.iter_mut().enumerate() {
+            let value = value as u32;
+            *out = if value < level {
+                value * 128 / level
+            } else {
+                128 + (value - level) * 127 / (255 - level)
+            } as u8;
+        }
+        table
+    }
+
+    /// `quantizer` with this threshold applied to the pixels fed to it
+    pub fn apply(self, quantizer: Box<dyn Quantizer>) -> Box<dyn Quantizer> {
+        if self == Self::DEFAULT {
+            quantizer
+        } else {
+            Box::new(ToneCurve::new(self.table(), quantizer))
+        }
+    }
+}
+
+impl Default for Threshold {
+    fn default() -> Self {
+        Threshold::DEFAULT
+    }
+}
+
+impl core::fmt::Display for Threshold {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Base-2 logarithm of a positive, normal `x`
+///
+/// `core` has no float math; the mantissa in [1, 2) goes through the atanh
+/// series, which is good to about 1e-7 there.
+fn log2(x: f32) -> f32 {
+    let bits = x.to_bits();
+    let exponent = ((bits >> 23) & 0xFF) as i32 - 127;
+    let mantissa = f32::from_bits((bits & 0x007F_FFFF) | 0x3F80_0000);
+    let z = (mantissa - 1.0) / (mantissa + 1.0);
+    let (mut term, mut ln) = (z, 0.0);
+    for k in 0..6 {
+        ln += term / (2 * k + 1) as f32;
+        term *= z * z;
+    }
+    exponent as f32 + 2.0 * ln * core::f32::consts::LOG2_E
+}
+
+/// Cosine of `turns` full turns
+///
+/// Folded into [-pi, pi) around half a turn, where the Taylor series to
+/// x^14 is good to about 5e-6.
+fn cos_turns(turns: f32) -> f32 {
+    let mut whole = turns as i32;
+    if whole as f32 > turns {
+        whole -= 1;
+    }
+    // cos(2 pi t) = -cos(2 pi (t - 1/2))
+    let x = (turns - whole as f32 - 0.5) * 2.0 * core::f32::consts::PI;
+    let (mut term, mut sum) = (1.0, 1.0);
+    for k in 1..8 {
+        term *= -x * x / ((2 * k - 1) * (2 * k)) as f32;
+        sum += term;
+    }
+    -sum
+}
+
+/// 2 raised to `y`, for `y` in the normal range
+fn exp2(y: f32) -> f32 {
+    let mut whole = y as i32;
+    if whole as f32 > y {
+        whole -= 1;
+    }
+    // 2^frac = e^(frac * ln 2), with frac in [0, 1)
+    let t = (y - whole as f32) * core::f32::consts::LN_2;
+    let (mut term, mut sum) = (1.0, 1.0);
+    for k in 1..10 {
+        term *= t / k as f32;
+        sum += term;
+    }
+    if whole < -126 {
+        return 0.0;
+    }
+    sum * f32::from_bits(((whole + 127) as u32) << 23)
+}
+
+/// How hard colored pixels are pushed toward the panel's accent colors
+///
+/// Some panels draw red weakly, and pinks or muted reds that should print
+/// red come out white or black. Each step adds half to the saturation of
+/// every pixel around its own brightness before matching, so more of them
+/// clear the hue rule's bar (or land nearer red in CIELAB) and error
+/// diffusion spreads more accent. Grays stay as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccentPull(pub u8);
+
+impl AccentPull {
+    pub const OFF: AccentPull = AccentPull(0);
+
+    /// Strongest step, which moves pixels two and a half times as far from gray
+    const MAX: u8 = 3;
+
+    /// The step after this one, back to off after the strongest
+    pub fn next(self) -> Self {
+        AccentPull(if self.0 >= Self::MAX { 0 } else { self.0 + 1 })
+    }
+
+    /// Parse a step written by `Display`, clamped to the strongest
+    pub fn parse(value: &str) -> Option<Self> {
+        let step: u8 = value.trim().parse().ok()?;
+        Some(AccentPull(step.min(Self::MAX)))
+    }
+
+    /// `quantizer` with the pull applied to the pixels fed to it
+    pub fn apply(self, quantizer: Box<dyn Quantizer>) -> Box<dyn Quantizer> {
+        if self == Self::OFF {
+            quantizer
+        } else {
+            Box::new(SaturationBoost { step: self.0 as i32, inner: quantizer })
+        }
+    }
+}
+
+impl core::fmt::Display for AccentPull {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Moves each pixel away from the gray of its brightness before handing it on
+struct SaturationBoost {
+    /// Half-steps added to the distance from gray
+    step: i32,
+    inner: Box<dyn Quantizer>,
+}
+
+impl Quantizer for SaturationBoost {
+    fn quantize(&mut self, x: usize, color: Rgb) -> u8 {
+        let gray = luma(color);
+        let boost = |c: u8| (gray + (c as i32 - gray) * (2 + self.step) / 2).clamp(0, 255) as u8;
+        self.inner.quantize(x, Rgb::new(boost(color.r), boost(color.g), boost(color.b)))
+    }
+
+    fn next_row(&mut self) {
+        self.inner.next_row();
+    }
+}
+
+/// Passes each pixel through a lookup table (a gamma curve or threshold
+/// stretch) before handing it on
+pub struct ToneCurve {
+    table: [u8; 256],
+    inner: Box<dyn Quantizer>,
+}
+
+impl ToneCurve {
+    pub fn new(table: [u8; 256], inner: Box<dyn Quantizer>) -> Self {
+        Self { table, inner }
+    }
+}
+
+impl Quantizer for ToneCurve {
+    fn quantize(&mut self, x: usize, color: Rgb) -> u8 {
+        let curve = |c: u8| self.table[c as usize];
+        let color = Rgb::new(curve(color.r), curve(color.g), curve(color.b));
+        self.inner.quantize(x, color)
+    }
+
+    fn next_row(&mut self) {
+        self.inner.next_row();
+    }
+}
+
+/// Calculate squared distance between two RGB colors
+fn color_distance_sq(a: Rgb, b: Rgb) -> u32 {
+    let dr = a.r as i32 - b.r as i32;
+    let dg = a.g as i32 - b.g as i32;
+    let db = a.b as i32 - b.b as i32;
+    (dr * dr + dg * dg + db * db) as u32
+}
+
+/// Index of the palette entry closest to `color` (first wins on ties)
+fn nearest_rgb_index(palette: &[Rgb], color: Rgb) -> u8 {
+    let mut best = 0;
+    let mut best_dist = u32::MAX;
+    for (idx, entry) in palette.iter().enumerate() {
+        let dist = color_distance_sq(color, *entry);
+        if dist < best_dist {
+            best = idx;
+            best_dist = dist;
+        }
+    }
+    best as u8
+}
+
+/// Panel colors whose shade can be tuned, by settings name
+pub const NAMED_COLORS: [(&str, Rgb); 7] = [
+    ("black", Rgb::new(0, 0, 0)),
+    ("white", Rgb::new(255, 255, 255)),
+    ("red", Rgb::new(255, 0, 0)),
+    ("yellow", Rgb::new(255, 255, 0)),
+    ("green", Rgb::new(0, 255, 0)),
+    ("blue", Rgb::new(0, 0, 255)),
+    ("orange", Rgb::new(255, 128, 0)),
+];
+
+/// How pixels are judged against a panel
+///
+/// Panel batches differ: one shows a brick red, another an orange-ish one.
+/// Giving the measured shades makes both matching and error diffusion aim
+/// at what the panel really draws.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tuning {
+    /// Shade shown for each of `NAMED_COLORS` (`None` keeps the nominal one)
+    pub colors: [Option<Rgb>; NAMED_COLORS.len()],
+    /// Saturation (0-255) from which a pixel counts as colored
+    pub min_saturation: u8,
+    /// Brightness (0-255) below which a pixel is dark whatever its hue
+    pub min_value: u8,
+    /// Widest hue gap (degrees) between a colored pixel and its palette color
+    pub max_hue_gap: u8,
+}
+
+impl Tuning {
+    /// Nominal shades, about 25% saturation, brightness 48, hue within 45 degrees
+    pub const DEFAULT: Tuning = Tuning {
+        colors: [None; NAMED_COLORS.len()],
+        min_saturation: 64,
+        min_value: 48,
+        max_hue_gap: 45,
+    };
+
+    /// Parse an `r,g,b` triple of decimal channel values
+    pub fn parse_color(value: &str) -> Option<Rgb> {
+        let mut channels = value.split(',').map(|c| c.trim().parse::<u8>().ok());
+        let (r, g, b) = (channels.next()??, channels.next()??, channels.next()??);
+        channels.next().is_none().then_some(Rgb::new(r, g, b))
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Tuned shade per entry of `NAMED_COLORS`, `TUNED_SET | 0xRRGGBB` or 0 when not set
+static TUNED_COLORS: [AtomicU32; NAMED_COLORS.len()] =
+    [const { AtomicU32::new(0) }; NAMED_COLORS.len()];
+const TUNED_SET: u32 = 1 << 24;
+static MIN_SATURATION: AtomicU8 = AtomicU8::new(Tuning::DEFAULT.min_saturation);
+static MIN_VALUE: AtomicU8 = AtomicU8::new(Tuning::DEFAULT.min_value);
+static MAX_HUE_GAP: AtomicU8 = AtomicU8::new(Tuning::DEFAULT.max_hue_gap);
+
+/// Set the panel shades and match thresholds for later decodes
+pub fn set_tuning(tuning: &Tuning) {
+    for (slot, color) in TUNED_COLORS.iter().zip(tuning.colors) {
+        let packed = color.map_or(0, |c| {
+            TUNED_SET | ((c.r as u32) << 16) | ((c.g as u32) << 8) | c.b as u32
+        });
+        slot.store(packed, Ordering::Relaxed);
+    }
+    MIN_SATURATION.store(tuning.min_saturation, Ordering::Relaxed);
+    MIN_VALUE.store(tuning.min_value, Ordering::Relaxed);
+    MAX_HUE_GAP.store(tuning.max_hue_gap, Ordering::Relaxed);
+}
+
+/// `palette` with every named color replaced by its tuned shade
+fn tuned_palette(palette: &[Rgb]) -> Vec<Rgb> {
+    palette
+        .iter()
+        .map(|entry| {
+            let slot = NAMED_COLORS.iter().position(|(_, nominal)| nominal == entry);
+            match slot.map(|idx| TUNED_COLORS[idx].load(Ordering::Relaxed)) {
+                Some(packed) if packed & TUNED_SET != 0 => {
+                    Rgb::new((packed >> 16) as u8, (packed >> 8) as u8, packed as u8)
+                }
+                _ => *entry,
+            }
+        })
+        .collect()
+}
+
+/// Hue (degrees), saturation and value (0-255) of `color`
+///
+/// Grays have saturation 0 and hue 0.
+fn hsv(color: Rgb) -> (i32, i32, i32) {
+    let (r, g, b) = (color.r as i32, color.g as i32, color.b as i32);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta == 0 {
+        return (0, 0, max);
+    }
+    let hue = if max == r {
+        60 * (g - b) / delta
+    } else if max == g {
+        120 + 60 * (b - r) / delta
+    } else {
+        240 + 60 * (r - g) / delta
+    };
+    ((hue + 360) % 360, delta * 255 / max, max)
+}
+
+/// Distance between two hues around the color wheel
+fn hue_gap(a: i32, b: i32) -> i32 {
+    let gap = (a - b).abs() % 360;
+    gap.min(360 - gap)
+}
+
+/// Perceived brightness (Rec. 601 luma, 0-255)
+fn luma(color: Rgb) -> i32 {
+    (299 * color.r as i32 + 587 * color.g as i32 + 114 * color.b as i32) / 1000
+}
+
+/// Replace `best` with (`idx`, `dist`) if it is strictly closer
+fn keep_closer(best: &mut Option<(usize, i32)>, idx: usize, dist: i32) {
+    if best.is_none_or(|(_, best_dist)| dist < best_dist) {
+        *best = Some((idx, dist));
+    }
+}
+
+/// Index of the palette entry `color` is drawn with, by the configured rule
+fn nearest_index(palette: &[Rgb], color: Rgb) -> u8 {
+    match color_match() {
+        ColorMatch::Hue => hue_index(palette, color),
+        ColorMatch::Lab => lab_index(palette, color),
+    }
+}
+
+/// Palette index by hue and saturation
+///
+/// Colored pixels (saturated enough and not too dark) take the colored
+/// palette entry nearest in hue; the rest are shades of gray and take the
+/// neutral entry nearest in brightness. Plain RGB distance sent dark reds
+/// to black and pale yellows to white, which ruined logos. Entries count as
+/// colored by the same saturation threshold, so a tuned off-white is still
+/// white. If the palette has no entry of the right kind, RGB distance
+/// decides after all.
+fn hue_index(palette: &[Rgb], color: Rgb) -> u8 {
+    let min_saturation = MIN_SATURATION.load(Ordering::Relaxed) as i32;
+    let min_value = MIN_VALUE.load(Ordering::Relaxed) as i32;
+    let max_hue_gap = MAX_HUE_GAP.load(Ordering::Relaxed) as i32;
+    let (hue, saturation, value) = hsv(color);
+    let mut best: Option<(usize, i32)> = None;
+
+    if saturation >= min_saturation && value >= min_value {
+        for (idx, entry) in palette.iter().enumerate() {
+            let (entry_hue, entry_saturation, _) = hsv(*entry);
+            let gap = hue_gap(hue, entry_hue);
+            if entry_saturation >= min_saturation && gap <= max_hue_gap {
+                keep_closer(&mut best, idx, gap);
+            }
+        }
+    }
+    if best.is_none() {
+        let brightness = luma(color);
+        for (idx, entry) in palette.iter().enumerate() {
+            if hsv(*entry).1 < min_saturation {
+                keep_closer(&mut best, idx, (brightness - luma(*entry)).abs());
+            }
+        }
+    }
+
+    best.map_or_else(|| nearest_rgb_index(palette, color), |(idx, _)| idx as u8)
+}
+
+/// sRGB channel value `c` to linear light in 1/4096 units, at `[c / 16][c % 16]`
+const SRGB_TO_LINEAR: [[u16; 16]; 16] = [
+    [0, 1, 2, 4, 5, 6, 7, 9, 10, 11, 12, 14, 15, 16, 18, 20],
+    [21, 23, 25, 27, 29, 31, 33, 35, 37, 40, 42, 45, 48, 50, 53, 56],
+    [59, 62, 66, 69, 72, 76, 79, 83, 87, 91, 95, 99, 103, 107, 112, 116],
+    [121, 126, 131, 136, 141, 146, 151, 156, 162, 168, 173, 179, 185, 191, 197, 204],
+    [210, 217, 223, 230, 237, 244, 251, 258, 265, 273, 280, 288, 296, 304, 312, 320],
+    [329, 337, 346, 354, 363, 372, 381, 390, 400, 409, 419, 429, 438, 448, 458, 469],
+    [479, 490, 500, 511, 522, 533, 544, 556, 567, 579, 590, 602, 614, 626, 639, 651],
+    [664, 676, 689, 702, 715, 729, 742, 756, 769, 783, 797, 811, 826, 840, 855, 869],
+    [884, 899, 914, 930, 945, 961, 976, 992, 1008, 1025, 1041, 1058, 1074, 1091, 1108, 1125],
+    [1142, 1160, 1177, 1195, 1213, 1231, 1249, 1268, 1286, 1305, 1324, 1343, 1362, 1381, 1400, 1420],
+    [1440, 1460, 1480, 1500, 1521, 1541, 1562, 1583, 1604, 1625, 1647, 1668, 1690, 1712, 1734, 1756],
+    [1778, 1801, 1824, 1846, 1869, 1893, 1916, 1940, 1963, 1987, 2011, 2035, 2060, 2084, 2109, 2134],
+    [2159, 2184, 2210, 2235, 2261, 2287, 2313, 2339, 2366, 2392, 2419, 2446, 2473, 2501, 2528, 2556],
+    [2584, 2612, 2640, 2668, 2697, 2725, 2754, 2783, 2813, 2842, 2872, 2902, 2931, 2962, 2992, 3022],
+    [3053, 3084, 3115, 3146, 3178, 3209, 3241, 3273, 3305, 3338, 3370, 3403, 3436, 3469, 3502, 3535],
+    [3569, 3603, 3637, 3671, 3705, 3740, 3775, 3810, 3845, 3880, 3916, 3951, 3987, 4023, 4060, 4096],
+];
+
+/// CIELAB color (D65 white), each component in 1/16 units
+#[derive(Debug, Clone, Copy)]
+struct Lab {
+    l: i32,
+    a: i32,
+    b: i32,
+}
+
+/// Floor of the cube root of `x` (at most 2^36)
+fn cbrt(x: u64) -> i32 {
+    let (mut lo, mut hi) = (0u64, 4096u64);
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        if mid * mid * mid <= x {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    lo as i32
+}
+
+/// The CIELAB companding function, input and output in 1/4096 units
+///
+/// Cube root above (6/29)^3, the matching straight line below it.
+fn lab_f(t: i32) -> i32 {
+    if t > 36 {
+        cbrt((t as u64) << 24)
+    } else {
+        t * 7787 / 1000 + 565
+    }
+}
+
+/// Convert an sRGB color to CIELAB
+///
+/// The XYZ matrix rows are pre-divided by the white point and scaled so
+/// each sums to 4096, which keeps white at exactly L* 100.
+fn lab(color: Rgb) -> Lab {
+    let linear = |c: u8| SRGB_TO_LINEAR[c as usize / 16][c as usize % 16] as i32;
+    let (r, g, b) = (linear(color.r), linear(color.g), linear(color.b));
+    let fx = lab_f((1777 * r + 1541 * g + 778 * b) >> 12);
+    let fy = lab_f((871 * r + 2929 * g + 296 * b) >> 12);
+    let fz = lab_f((73 * r + 448 * g + 3575 * b) >> 12);
+    Lab {
+        l: (116 * fy - 16 * 4096) >> 8,
+        a: (500 * (fx - fy)) >> 8,
+        b: (200 * (fy - fz)) >> 8,
+    }
+}
+
+/// Palette index with the smallest CIELAB distance (first wins on ties)
+///
+/// Distances follow perceived difference, so on photos shadows stay black
+/// instead of turning red, while real reds still come out red.
+fn lab_index(palette: &[Rgb], color: Rgb) -> u8 {
+    let target = lab(color);
+    let mut best: Option<(usize, i32)> = None;
+    for (idx, entry) in palette.iter().enumerate() {
+        let entry = lab(*entry);
+        let (dl, da, db) = (target.l - entry.l, target.a - entry.a, target.b - entry.b);
+        keep_closer(&mut best, idx, dl * dl + da * da + db * db);
+    }
+    best.map_or(0, |(idx, _)| idx as u8)
+}
+
+/// Nearest palette index after adding `offset` to every channel of `color`
+fn nudged_index(palette: &[Rgb], color: Rgb, offset: i16) -> u8 {
+    let nudge = |c: u8| (c as i16 + offset).clamp(0, 255) as u8;
+    nearest_index(palette, Rgb::new(nudge(color.r), nudge(color.g), nudge(color.b)))
+}
+
+/// Nearest-color quantizer
+pub struct Nearest {
+    palette: Vec<Rgb>,
+}
+
+impl Nearest {
+    pub fn new(palette: Vec<Rgb>) -> Self {
+        Self { palette }
+    }
+}
+
+impl Quantizer for Nearest {
+    fn quantize(&mut self, _x: usize, color: Rgb) -> u8 {
+        nearest_index(&self.palette, color)
+    }
+}
+
+/// Floyd-Steinberg error-diffusion quantizer
+///
+/// Keeps the accumulated error for the current and next row, with one
+/// guard column on each side so edge pixels need no bounds checks.
+pub struct FloydSteinberg {
+    palette: Vec<Rgb>,
+    cur: Vec<[i16; 3]>,
+    next: Vec<[i16; 3]>,
+}
+
+impl FloydSteinberg {
+    pub fn new(palette: Vec<Rgb>, width: usize) -> Self {
+        Self {
+            palette,
+            cur: vec![[0; 3]; width + 2],
+            next: vec![[0; 3]; width + 2],
+        }
+    }
+}
+
+impl Quantizer for FloydSteinberg {
+    fn quantize(&mut self, x: usize, color: Rgb) -> u8 {
+        let err = self.cur[x + 1];
+        let adjusted = [
+            (color.r as i16 + err[0]).clamp(0, 255),
+            (color.g as i16 + err[1]).clamp(0, 255),
+            (color.b as i16 + err[2]).clamp(0, 255),
+        ];
+
+        let idx = nearest_index(
+            &self.palette,
+            Rgb::new(adjusted[0] as u8, adjusted[1] as u8, adjusted[2] as u8),
+        );
+        let chosen = self.palette[idx as usize];
+        let chosen = [chosen.r as i16, chosen.g as i16, chosen.b as i16];
+
+        // Distribute error: 7/16 right, 3/16 down-left, 5/16 down, 1/16 down-right
+        for c in 0..3 {
+            let e = adjusted[c] - chosen[c];
+            self.cur[x + 2][c] += e * 7 / 16;
+            self.next[x][c] += e * 3 / 16;
+            self.next[x + 1][c] += e * 5 / 16;
+            self.next[x + 2][c] += e / 16;
+        }
+
+        idx
+    }
+
+    fn next_row(&mut self) {
+        core::mem::swap(&mut self.cur, &mut self.next);
+        self.next.fill([0; 3]);
+    }
+}
+
+/// 8x8 Bayer threshold matrix (values 0 to 63)
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Ordered-dither quantizer
+///
+/// Each pixel is nudged by its cell in the Bayer matrix (from -126 to +126
+/// on every channel) before the nearest palette color is picked. No error
+/// is carried, so there is no row buffer and the pattern stays regular,
+/// which keeps text edges crisp.
+pub struct Bayer {
+    palette: Vec<Rgb>,
+    y: usize,
+}
+
+impl Bayer {
+    pub fn new(palette: Vec<Rgb>) -> Self {
+        Self { palette, y: 0 }
+    }
+}
+
+impl Quantizer for Bayer {
+    fn quantize(&mut self, x: usize, color: Rgb) -> u8 {
+        let offset = BAYER_8X8[self.y % 8][x % 8] as i16 * 4 - 126;
+        nudged_index(&self.palette, color, offset)
+    }
+
+    fn next_row(&mut self) {
+        self.y += 1;
+    }
+}
+
+/// 16x16 blue-noise threshold mask (each value 0 to 255 appears once)
+///
+/// Generated offline with void-and-cluster (Ulichney, Gaussian sigma 1.5)
+/// on a torus, so tiles join without seams.
+const BLUE_NOISE_16X16: [[u8; 16]; 16] = [
+    [234, 50, 188, 19, 58, 171, 121, 47, 163, 1, 247, 104, 22, 132, 14, 65],
+    [209, 8, 118, 97, 240, 205, 23, 228, 138, 64, 123, 170, 72, 224, 99, 149],
+    [85, 139, 229, 165, 78, 146, 111, 84, 176, 216, 30, 231, 153, 201, 42, 180],
+    [25, 62, 195, 29, 43, 185, 7, 249, 41, 100, 191, 48, 87, 5, 128, 243],
+    [221, 152, 101, 253, 130, 220, 59, 200, 156, 12, 136, 112, 255, 174, 69, 109],
+    [46, 189, 0, 73, 172, 90, 142, 116, 80, 237, 210, 61, 147, 33, 206, 160],
+    [81, 124, 217, 113, 208, 15, 241, 27, 168, 45, 178, 20, 193, 96, 225, 18],
+    [242, 164, 60, 35, 157, 53, 181, 68, 223, 105, 125, 83, 236, 131, 55, 141],
+    [197, 10, 227, 134, 246, 95, 126, 198, 148, 3, 244, 161, 71, 9, 182, 106],
+    [40, 93, 179, 75, 192, 6, 218, 36, 91, 57, 202, 34, 215, 155, 233, 74],
+    [252, 120, 150, 24, 110, 63, 166, 119, 232, 183, 133, 103, 49, 117, 31, 167],
+    [16, 212, 51, 238, 207, 137, 254, 21, 76, 151, 13, 250, 190, 88, 203, 135],
+    [102, 184, 82, 169, 38, 89, 187, 52, 204, 98, 173, 67, 129, 4, 222, 56],
+    [230, 144, 2, 127, 226, 11, 154, 114, 239, 39, 219, 28, 235, 145, 175, 77],
+    [196, 37, 248, 70, 107, 199, 66, 177, 17, 143, 115, 159, 86, 44, 108, 26],
+    [122, 92, 158, 214, 140, 32, 245, 94, 213, 79, 194, 54, 211, 186, 251, 162],
+];
+
+/// Blue-noise ordered-dither quantizer
+///
+/// Works like `Bayer`, one table lookup per pixel, but the mask has no
+/// low-frequency structure: gradients come out as fine grain rather than
+/// a visible crosshatch.
+pub struct BlueNoise {
+    palette: Vec<Rgb>,
+    y: usize,
+}
+
+impl BlueNoise {
+    pub fn new(palette: Vec<Rgb>) -> Self {
+        Self { palette, y: 0 }
+    }
+}
+
+impl Quantizer for BlueNoise {
+    fn quantize(&mut self, x: usize, color: Rgb) -> u8 {
+        let offset = BLUE_NOISE_16X16[self.y % 16][x % 16] as i16 - 127;
+        nudged_index(&self.palette, color, offset)
+    }
+
+    fn next_row(&mut self) {
+        self.y += 1;
+    }
+}
+
+/// Distance between neighboring dot centers of the halftone screen (px)
+const HALFTONE_PERIOD: f32 = 6.0;
+
+/// Clustered-dot halftone quantizer
+///
+/// The panel is covered by a grid of cells, turned by the screen angle, and
+/// each pixel is nudged by how close it sits to the center of its cell
+/// (from -126 at the center to +126 at the corners) before the nearest
+/// palette color is picked. Darker areas grow a bigger dot in each cell
+/// rather than scattering single pixels, so skin tones and soft shadows
+/// come out as smooth shading that survives the panel's blur and ghosting.
+/// Colored areas keep their hue, as with `Bayer`.
+pub struct Halftone {
+    palette: Vec<Rgb>,
+    y: usize,
+    /// Cosine and sine of the screen angle
+    unit: (f32, f32),
+}
+
+impl Halftone {
+    pub fn new(palette: Vec<Rgb>, angle: ScreenAngle) -> Self {
+        Self { palette, y: 0, unit: angle.unit() }
+    }
+}
+
+impl Quantizer for Halftone {
+    fn quantize(&mut self, x: usize, color: Rgb) -> u8 {
+        let (cos, sin) = self.unit;
+        let (x, y) = (x as f32, self.y as f32);
+        // Position in cells along and across the screen
+        let u = (x * cos + y * sin) / HALFTONE_PERIOD;
+        let v = (y * cos - x * sin) / HALFTONE_PERIOD;
+        // Round dot spot function: 1 at a cell's center, -1 at its corners
+        let spot = (cos_turns(u) + cos_turns(v)) / 2.0;
+        nudged_index(&self.palette, color, (-spot * 126.0) as i16)
+    }
+
+    fn next_row(&mut self) {
+        self.y += 1;
+    }
+}
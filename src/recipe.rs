@@ -0,0 +1,122 @@
+//! Shareable tag recipes
+//!
+//! A recipe (`.recipe`) is a `key=value` file naming what it takes to
+//! reproduce a label on another Flipper: the tag type by part number, the
+//! image, the color mode and the rotation, and the black/white threshold and
+//! accent pull if they were moved. Opening one selects the tag, sets the
+//! color mode and rotation and decodes the image, leaving one tap on "Write
+//! to Tag".
+//!
+//! The image is referenced, not embedded. A bare file name is looked up
+//! next to the recipe, so a recipe and its image travel as a pair of files;
+//! paths starting with `/` are used as they are. Unknown keys are ignored,
+//! which leaves room for settings that later versions add.
+//!
+//! ```text
+//! tag=DMPL0154FN1
+//! image=badge.bmp
+//! colors=bayer
+//! rotate=90
+//! ```
+
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::string::String;
+use core::ffi::CStr;
+
+use crate::image::Rotation;
+use crate::quantize::{AccentPull, QuantizerKind, Threshold};
+use crate::storage;
+use crate::tag_type::TagType;
+
+/// Everything needed to reproduce one label
+#[derive(Debug, Clone)]
+pub struct Recipe {
+    /// Tag type, by `TagType::part_number`
+    pub tag: &'static TagType,
+    /// Image file, absolute
+    pub image_path: String,
+    /// Color mode the image is decoded with
+    pub colors: QuantizerKind,
+    /// Clockwise turn applied before writing
+    pub rotation: Rotation,
+    pub threshold: Threshold,
+    pub accent_pull: AccentPull,
+}
+
+/// Directory part of `path`, without the trailing `/`
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// Absolute path of a file named inside the file at `path`
+///
+/// A bare name is next to it; one starting with `/` is used as it is.
+pub fn resolve(path: &CStr, name: &str) -> Option<String> {
+    if name.starts_with('/') {
+        Some(String::from(name))
+    } else {
+        Some(alloc::format!("{}/{}", parent(path.to_str().ok()?), name))
+    }
+}
+
+impl Recipe {
+    /// Read the recipe at `path`
+    ///
+    /// None if the file is unreadable, names a tag type this build doesn't
+    /// know or has no image. A missing or unknown color mode reads as
+    /// nearest color, a missing or unknown rotation as none, a missing
+    /// threshold as the default and a missing accent pull as off.
+    pub unsafe fn load(path: &CStr) -> Option<Self> {
+        unsafe {
+            let entries = storage::read_kv(path);
+            let get = |key: &str| entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+
+            let part_number = get("tag")?;
+            let tag = TagType::ALL.iter().find(|tag| tag.part_number == part_number)?;
+            let image = get("image").filter(|image| !image.is_empty())?;
+            let image_path = resolve(path, image)?;
+            let colors = get("colors").and_then(QuantizerKind::from_key).unwrap_or(QuantizerKind::Nearest);
+            let rotation = get("rotate").and_then(Rotation::from_degrees).unwrap_or_default();
+            let threshold = get("threshold").and_then(Threshold::parse).unwrap_or_default();
+            let accent_pull = get("accent").and_then(AccentPull::parse).unwrap_or_default();
+
+            Some(Self { tag, image_path, colors, rotation, threshold, accent_pull })
+        }
+    }
+
+    /// Write the recipe to `path`
+    ///
+    /// An image in the recipe's own directory is stored by file name only,
+    /// so the pair can be copied anywhere together.
+    pub unsafe fn save(&self, path: &CStr) -> bool {
+        unsafe {
+            let Ok(path_str) = path.to_str() else {
+                return false;
+            };
+            let image = match self.image_path.rsplit_once('/') {
+                Some((dir, name)) if dir == parent(path_str) => name,
+                _ => self.image_path.as_str(),
+            };
+            let mut entries = alloc::vec![
+                ("tag", String::from(self.tag.part_number)),
+                ("image", String::from(image)),
+                ("colors", String::from(self.colors.key())),
+                ("rotate", alloc::format!("{}", self.rotation.degrees())),
+            ];
+            if self.threshold != Threshold::DEFAULT {
+                entries.push(("threshold", alloc::format!("{}", self.threshold)));
+            }
+            if self.accent_pull != AccentPull::OFF {
+                entries.push(("accent", alloc::format!("{}", self.accent_pull)));
+            }
+            storage::write_kv(path, &entries)
+        }
+    }
+
+    /// Image path for the decoder
+    pub fn image_cstring(&self) -> Option<CString> {
+        CString::new(self.image_path.as_str()).ok()
+    }
+}
@@ -0,0 +1,118 @@
+//! Color usage of a converted image
+//!
+//! Counted from the packed buffer after decoding, so it reflects exactly
+//! what will be written. The write menu shows the split as its header and
+//! flags an accent color (red, yellow) that came out unused or covering
+//! most of the panel: the usual sign of a photo matched with the wrong
+//! color mode or thresholds, caught before a write of ten seconds or more.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::image::{AnyImage, BLACK, WHITE};
+use crate::pack;
+use crate::quantize::NAMED_COLORS;
+use crate::tag_type::TagType;
+
+/// Share of the panel above which an accent color is called dominant (%)
+const DOMINANT_PERCENT: u32 = 50;
+
+/// Pixels per palette color of one image
+#[derive(Debug, Clone)]
+pub struct ColorReport {
+    /// Name per palette index, from `NAMED_COLORS`
+    names: Vec<&'static str>,
+    counts: Vec<u32>,
+    total: u32,
+}
+
+/// What stands out about an accent color
+#[derive(Debug, Clone, Copy)]
+pub enum ColorWarning {
+    Unused(&'static str),
+    Dominant(&'static str, u32),
+}
+
+impl ColorReport {
+    /// Count the colors of `image`, packed for `tag`
+    pub fn of(image: &AnyImage, tag: &TagType) -> Self {
+        let packer = pack::for_tag(tag);
+        let names = packer
+            .palette()
+            .iter()
+            .map(|color| {
+                let named = NAMED_COLORS.iter().find(|(_, nominal)| nominal == color);
+                named.map_or("?", |(name, _)| *name)
+            })
+            .collect::<Vec<_>>();
+        let mut counts = vec![0u32; names.len()];
+        let (width, height) = tag.native_size();
+        let mut colors = vec![0u8; width];
+        for y in 0..height {
+            packer.unpack_row(image.as_slice(), y, &mut colors);
+            for &color in &colors {
+                if let Some(count) = counts.get_mut(color as usize) {
+                    *count += 1;
+                }
+            }
+        }
+        Self { names, counts, total: (width * height) as u32 }
+    }
+
+    /// Share of palette color `index`, rounded; at least 1 if it is used
+    fn percent(&self, index: usize) -> u32 {
+        let count = self.counts[index] as u64;
+        if count == 0 || self.total == 0 {
+            return 0;
+        }
+        ((count * 100 + self.total as u64 / 2) / self.total as u64).max(1) as u32
+    }
+
+    /// One line for the menu header, e.g. `B52% W40% R8%`
+    pub fn summary(&self) -> String {
+        let shares = self.names.iter().enumerate().map(|(index, name)| {
+            let initial = name.chars().next().unwrap_or('?').to_ascii_uppercase();
+            alloc::format!("{}{}%", initial, self.percent(index))
+        });
+        shares.collect::<Vec<_>>().join(" ")
+    }
+
+    /// The first accent color that is unused or dominant, if any
+    pub fn warning(&self) -> Option<ColorWarning> {
+        let accents = (0..self.names.len()).filter(|&i| i != BLACK as usize && i != WHITE as usize);
+        for index in accents {
+            let name = self.names[index];
+            if self.counts[index] == 0 {
+                return Some(ColorWarning::Unused(name));
+            }
+            let percent = self.percent(index);
+            if percent > DOMINANT_PERCENT {
+                return Some(ColorWarning::Dominant(name, percent));
+            }
+        }
+        None
+    }
+
+    /// Full report, one color per line, then the warning
+    pub fn details(&self) -> String {
+        let mut text = String::new();
+        for (index, name) in self.names.iter().enumerate() {
+            let count = self.counts[index];
+            text.push_str(&alloc::format!("{}: {}% ({} px)\n", name, self.percent(index), count));
+        }
+        match self.warning() {
+            Some(ColorWarning::Unused(name)) => {
+                let hint = "check the color mode or Accent Pull";
+                text.push_str(&alloc::format!("No {} pixels: {}", name, hint));
+            }
+            Some(ColorWarning::Dominant(name, percent)) => {
+                text.push_str(&alloc::format!("{} covers {}%: check the thresholds", name, percent));
+            }
+            None => {}
+        }
+        text
+    }
+}
@@ -0,0 +1,438 @@
+//! Streaming fit of source rows to the tag's size
+//!
+//! Files that are not the tag's size can be fitted instead of refused: the
+//! image is scaled, aspect kept, to the largest size that fits the panel and
+//! centered on white, like Screen to Tag. Small images (icons) can instead
+//! be blown up by a whole factor, which keeps their pixels square and sharp,
+//! and large ones cropped to the tag's size around a chosen anchor; small
+//! ones can also sit unscaled on a background of the user's color. Rows are
+//! taken as the loader reads them and each finished target row is handed on
+//! at once, so a 640x480 BMP needs one source row and one target row of
+//! memory, not a whole frame.
+//!
+//! Rows may arrive bottom to top (most BMPs); target rows then come out
+//! bottom to top as well, so the quantizer sees a steady direction.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::image::ImageResult;
+use crate::protocol_common::log_info;
+use crate::quantize::Rgb;
+
+/// Color around a fitted image, unless padding picks another
+const BACKGROUND: Rgb = Rgb::new(255, 255, 255);
+
+/// What to do with a source that is not the tag's size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fit {
+    /// Refuse it (`ImageError::SizeMismatch`)
+    #[default]
+    Exact,
+    /// Scale it down to fit, aspect kept
+    Shrink(Filter),
+    /// Repeat every pixel this many times each way, centered
+    Upscale(usize),
+    /// Cut out the tag's size at `Anchor`, unscaled
+    Crop(Anchor),
+    /// Center it unscaled on a `Background` of the tag's size
+    Pad(Background),
+}
+
+impl Fit {
+    /// Name stored in files, e.g. `crop:top-left`; read back by `from_key`
+    pub fn key(self) -> String {
+        match self {
+            Fit::Exact => String::from("exact"),
+            Fit::Shrink(Filter::Nearest) => String::from("shrink"),
+            Fit::Shrink(Filter::Box) => String::from("smooth"),
+            Fit::Upscale(factor) => alloc::format!("upscale:{}", factor),
+            Fit::Crop(anchor) => alloc::format!("crop:{}", anchor.key()),
+            Fit::Pad(background) => alloc::format!("pad:{}", background.key()),
+        }
+    }
+
+    /// Fit for a name written by `key`
+    pub fn from_key(key: &str) -> Option<Self> {
+        let (kind, arg) = key.split_once(':').unwrap_or((key, ""));
+        match kind {
+            "exact" => Some(Fit::Exact),
+            "shrink" => Some(Fit::Shrink(Filter::Nearest)),
+            "smooth" => Some(Fit::Shrink(Filter::Box)),
+            "upscale" => arg.parse().ok().filter(|&factor| factor >= 2).map(Fit::Upscale),
+            "crop" => Anchor::from_key(arg).map(Fit::Crop),
+            "pad" => Background::from_key(arg).map(Fit::Pad),
+            _ => None,
+        }
+    }
+
+    /// This fit for a `source` image on a `target` panel, if the fit menu
+    /// would offer it
+    ///
+    /// Shrinking and cropping need a larger source, padding and upscaling
+    /// a smaller one; the upscale factor is worked out afresh.
+    pub fn for_size(self, source: (usize, usize), target: (usize, usize)) -> Option<Self> {
+        let larger = source.0 > target.0 || source.1 > target.1;
+        match self {
+            Fit::Exact => None,
+            Fit::Shrink(_) | Fit::Crop(_) => larger.then_some(self),
+            Fit::Pad(_) => (!larger).then_some(self),
+            Fit::Upscale(_) => {
+                upscale_factor(source, target).filter(|_| !larger).map(Fit::Upscale)
+            }
+        }
+    }
+}
+
+/// Color a padded image sits on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Background {
+    #[default]
+    White,
+    Black,
+    Red,
+    Yellow,
+}
+
+impl Background {
+    const ALL: [Background; 4] =
+        [Background::White, Background::Black, Background::Red, Background::Yellow];
+
+    /// Name stored in files
+    fn key(self) -> &'static str {
+        match self {
+            Background::White => "white",
+            Background::Black => "black",
+            Background::Red => "red",
+            Background::Yellow => "yellow",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|background| background.key() == key)
+    }
+
+    /// The color after this one; yellow only on panels that can show it
+    pub fn next(self, yellow: bool) -> Self {
+        match self {
+            Background::White => Background::Black,
+            Background::Black => Background::Red,
+            Background::Red if yellow => Background::Yellow,
+            Background::Red | Background::Yellow => Background::White,
+        }
+    }
+
+    fn rgb(self) -> Rgb {
+        match self {
+            Background::White => BACKGROUND,
+            Background::Black => Rgb::new(0, 0, 0),
+            Background::Red => Rgb::new(255, 0, 0),
+            Background::Yellow => Rgb::new(255, 255, 0),
+        }
+    }
+}
+
+/// Part of an oversized image a crop keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Anchor {
+    #[default]
+    Center,
+    TopLeft,
+    Top,
+    TopRight,
+    Right,
+    BottomRight,
+    Bottom,
+    BottomLeft,
+    Left,
+}
+
+impl Anchor {
+    /// Name stored in files
+    fn key(self) -> &'static str {
+        match self {
+            Anchor::Center => "center",
+            Anchor::TopLeft => "top-left",
+            Anchor::Top => "top",
+            Anchor::TopRight => "top-right",
+            Anchor::Right => "right",
+            Anchor::BottomRight => "bottom-right",
+            Anchor::Bottom => "bottom",
+            Anchor::BottomLeft => "bottom-left",
+            Anchor::Left => "left",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        // `next` visits every anchor once before coming back to the center
+        let mut anchor = Anchor::Center;
+        loop {
+            if anchor.key() == key {
+                return Some(anchor);
+            }
+            anchor = anchor.next();
+            if anchor == Anchor::Center {
+                return None;
+            }
+        }
+    }
+
+    /// The anchor after this one: center, then clockwise from top-left
+    pub fn next(self) -> Self {
+        match self {
+            Anchor::Center => Anchor::TopLeft,
+            Anchor::TopLeft => Anchor::Top,
+            Anchor::Top => Anchor::TopRight,
+            Anchor::TopRight => Anchor::Right,
+            Anchor::Right => Anchor::BottomRight,
+            Anchor::BottomRight => Anchor::Bottom,
+            Anchor::Bottom => Anchor::BottomLeft,
+            Anchor::BottomLeft => Anchor::Left,
+            Anchor::Left => Anchor::Center,
+        }
+    }
+
+    /// Horizontal and vertical position, 0 (left/top) to 2 (right/bottom)
+    pub fn position(self) -> (usize, usize) {
+        match self {
+            Anchor::TopLeft => (0, 0),
+            Anchor::Top => (1, 0),
+            Anchor::TopRight => (2, 0),
+            Anchor::Left => (0, 1),
+            Anchor::Center => (1, 1),
+            Anchor::Right => (2, 1),
+            Anchor::BottomLeft => (0, 2),
+            Anchor::Bottom => (1, 2),
+            Anchor::BottomRight => (2, 2),
+        }
+    }
+}
+
+/// Largest whole factor that blows `source` up within `target`, if at least 2
+pub fn upscale_factor(source: (usize, usize), target: (usize, usize)) -> Option<usize> {
+    let factor = (target.0 / source.0.max(1)).min(target.1 / source.1.max(1));
+    (factor >= 2).then_some(factor)
+}
+
+/// How source pixels become target pixels when scaling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// Each target pixel copies the source pixel it lands on
+    Nearest,
+    /// Each target pixel averages the source pixels it covers
+    Box,
+}
+
+/// A rectangle of pixels, in the source or the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// A `width` x `height` image centered in `target`
+fn centered(width: usize, height: usize, target: (usize, usize)) -> Rect {
+    let (width, height) = (width.min(target.0), height.min(target.1));
+    Rect { left: (target.0 - width) / 2, top: (target.1 - height) / 2, width, height }
+}
+
+/// Largest rectangle of `source`'s aspect centered in `target`
+fn fitted(source: (usize, usize), target: (usize, usize)) -> Rect {
+    let ((sw, sh), (tw, th)) = (source, target);
+    if tw * sh <= th * sw {
+        centered(tw, (tw * sh / sw).max(1), target)
+    } else {
+        centered((th * sw / sh).max(1), th, target)
+    }
+}
+
+/// The part of a `source` image a crop at `anchor` to `target` keeps
+fn crop_window(source: (usize, usize), target: (usize, usize), anchor: Anchor) -> Rect {
+    let (width, height) = (source.0.min(target.0), source.1.min(target.1));
+    let (ax, ay) = anchor.position();
+    Rect { left: (source.0 - width) * ax / 2, top: (source.1 - height) * ay / 2, width, height }
+}
+
+/// Turns source rows into target rows
+pub struct Resampler {
+    /// Part of the source that is drawn
+    window: Rect,
+    target: (usize, usize),
+    /// Where the window lands in the target
+    place: Rect,
+    background: Rgb,
+    filter: Filter,
+    /// Rows arrive bottom to top
+    descending: bool,
+    /// Target rows handed on so far, in arrival order
+    emitted: usize,
+    row: Vec<Rgb>,
+    sums: Vec<[u32; 3]>,
+    counts: Vec<u32>,
+}
+
+impl Resampler {
+    /// Fit a `source`-sized image to `target` as `fit` says
+    ///
+    /// `Fit::Exact` passes rows through, so callers check the size first.
+    pub fn new(source: (usize, usize), target: (usize, usize), fit: Fit, descending: bool) -> Self {
+        let whole = Rect { left: 0, top: 0, width: source.0, height: source.1 };
+        let (window, place, filter) = match fit {
+            Fit::Exact => {
+                let place = Rect { left: 0, top: 0, width: target.0, height: target.1 };
+                (whole, place, Filter::Nearest)
+            }
+            Fit::Shrink(filter) => (whole, fitted(source, target), filter),
+            Fit::Upscale(factor) => {
+                (whole, centered(source.0 * factor, source.1 * factor, target), Filter::Nearest)
+            }
+            Fit::Crop(anchor) => {
+                let window = crop_window(source, target, anchor);
+                (window, centered(window.width, window.height, target), Filter::Nearest)
+            }
+            Fit::Pad(_) => (whole, centered(source.0, source.1, target), Filter::Nearest),
+        };
+        let background = match fit {
+            Fit::Pad(background) => background.rgb(),
+            _ => BACKGROUND,
+        };
+        // Averaging needs at least one source pixel per target pixel
+        let filter = if place.width > window.width || place.height > window.height {
+            Filter::Nearest
+        } else {
+            filter
+        };
+        if source != target {
+            log_info!(
+                "Fitting {}x{} from {},{} into {}x{} at {},{} ({:?})",
+                window.width,
+                window.height,
+                window.left,
+                window.top,
+                place.width,
+                place.height,
+                place.left,
+                place.top,
+                filter
+            );
+        }
+        Self {
+            window,
+            target,
+            place,
+            background,
+            filter,
+            descending,
+            emitted: 0,
+            row: vec![background; target.0],
+            sums: vec![[0; 3]; place.width],
+            counts: vec![0; place.width],
+        }
+    }
+
+    /// Convert between target row and arrival order (its own inverse)
+    fn arrival(&self, n: usize) -> usize {
+        if self.descending { self.target.1 - 1 - n } else { n }
+    }
+
+    /// Hand on background rows until `count` rows have gone out
+    fn pad_to(
+        &mut self,
+        count: usize,
+        emit: &mut dyn FnMut(usize, &[Rgb]) -> ImageResult<()>,
+    ) -> ImageResult<()> {
+        if self.emitted < count {
+            self.row.fill(self.background);
+        }
+        while self.emitted < count {
+            emit(self.arrival(self.emitted), &self.row)?;
+            self.emitted += 1;
+        }
+        Ok(())
+    }
+
+    /// Hand on target row `y`, whose image pixels are already in `row`
+    fn emit_row(
+        &mut self,
+        y: usize,
+        emit: &mut dyn FnMut(usize, &[Rgb]) -> ImageResult<()>,
+    ) -> ImageResult<()> {
+        emit(y, &self.row)?;
+        self.emitted += 1;
+        Ok(())
+    }
+
+    /// Take source row `sy` (counted from the top)
+    ///
+    /// `emit` gets every target row the row completes, with its y.
+    pub fn push(
+        &mut self,
+        sy: usize,
+        pixels: &[Rgb],
+        emit: &mut dyn FnMut(usize, &[Rgb]) -> ImageResult<()>,
+    ) -> ImageResult<()> {
+        let Rect { left, top, width, height } = self.place;
+        let window = self.window;
+        if sy < window.top || sy >= window.top + window.height {
+            return Ok(());
+        }
+        // From here on, coordinates are within the window
+        let sy = sy - window.top;
+        let pixels = &pixels[window.left..window.left + window.width];
+        let (sw, sh) = (window.width, window.height);
+        match self.filter {
+            Filter::Nearest => {
+                // Image rows i whose source row is sy: i * sh / height == sy
+                let (first, end) = ((sy * height).div_ceil(sh), ((sy + 1) * height).div_ceil(sh));
+                for n in 0..end - first {
+                    let y = top + if self.descending { end - 1 - n } else { first + n };
+                    self.pad_to(self.arrival(y), emit)?;
+                    for (x, dst) in self.row[left..left + width].iter_mut().enumerate() {
+                        *dst = pixels[x * sw / width];
+                    }
+                    self.emit_row(y, emit)?;
+                }
+            }
+            Filter::Box => {
+                for (sx, px) in pixels[..sw].iter().enumerate() {
+                    let x = sx * width / sw;
+                    self.sums[x][0] += px.r as u32;
+                    self.sums[x][1] += px.g as u32;
+                    self.sums[x][2] += px.b as u32;
+                    self.counts[x] += 1;
+                }
+                // Average once the last source row of image row i is in
+                let i = sy * height / sh;
+                let next = if self.descending {
+                    sy.checked_sub(1)
+                } else {
+                    Some(sy + 1).filter(|&n| n < sh)
+                };
+                if next.is_none_or(|n| n * height / sh != i) {
+                    let y = top + i;
+                    self.pad_to(self.arrival(y), emit)?;
+                    for (x, dst) in self.row[left..left + width].iter_mut().enumerate() {
+                        let (sum, count) = (self.sums[x], self.counts[x]);
+                        let avg = |c: u32| ((c + count / 2) / count) as u8;
+                        *dst = Rgb::new(avg(sum[0]), avg(sum[1]), avg(sum[2]));
+                    }
+                    self.sums.fill([0; 3]);
+                    self.counts.fill(0);
+                    self.emit_row(y, emit)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand on the background rows after the last image row
+    pub fn finish(
+        &mut self,
+        emit: &mut dyn FnMut(usize, &[Rgb]) -> ImageResult<()>,
+    ) -> ImageResult<()> {
+        self.pad_to(self.target.1, emit)
+    }
+}
@@ -0,0 +1,112 @@
+//! Flipper screen capture
+//!
+//! The GUI hands every committed frame to registered framebuffer callbacks.
+//! A capture keeps the most recent frame while registered; the app hides its
+//! own view port in between so the frame shows whatever is underneath.
+//!
+//! Frames are 128x64 1bpp in the display's page layout: each byte holds 8
+//! vertically stacked pixels (LSB on top), one page of 128 bytes per 8 rows.
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+use flipperzero_sys as sys;
+
+/// Flipper display width in pixels
+pub const SCREEN_WIDTH: usize = 128;
+/// Flipper display height in pixels
+pub const SCREEN_HEIGHT: usize = 64;
+
+/// Framebuffer size in bytes
+const FRAME_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT / 8;
+
+/// One captured 128x64 frame
+#[derive(Clone)]
+pub struct Frame {
+    data: [u8; FRAME_SIZE],
+}
+
+impl Frame {
+    /// True if the pixel at (`x`, `y`) is lit (dark on the LCD)
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.data[(y / 8) * SCREEN_WIDTH + x] & (1 << (y % 8)) != 0
+    }
+}
+
+/// Framebuffer capture registered with the GUI
+pub struct ScreenCapture {
+    gui: *mut sys::Gui,
+    frame: UnsafeCell<Frame>,
+    captured: AtomicBool,
+}
+
+impl ScreenCapture {
+    /// Register a framebuffer callback and start keeping frames
+    ///
+    /// Boxed so the callback context stays put until `stop`.
+    pub unsafe fn start() -> Box<Self> {
+        unsafe {
+            let capture = Box::new(Self {
+                gui: sys::furi_record_open(c"gui".as_ptr()) as *mut sys::Gui,
+                frame: UnsafeCell::new(Frame { data: [0; FRAME_SIZE] }),
+                captured: AtomicBool::new(false),
+            });
+            sys::gui_add_framebuffer_callback(
+                capture.gui,
+                Some(framebuffer_callback),
+                capture.context(),
+            );
+            capture
+        }
+    }
+
+    fn context(&self) -> *mut c_void {
+        self as *const Self as *mut c_void
+    }
+
+    /// Unregister and return the last committed frame, if any arrived
+    pub unsafe fn stop(&mut self) -> Option<Frame> {
+        unsafe {
+            if self.gui.is_null() {
+                return None;
+            }
+            // Removal takes the GUI lock, so no callback is running afterwards
+            sys::gui_remove_framebuffer_callback(self.gui, Some(framebuffer_callback), self.context());
+            sys::furi_record_close(c"gui".as_ptr());
+            self.gui = core::ptr::null_mut();
+
+            if self.captured.load(Ordering::Acquire) {
+                Some((*self.frame.get()).clone())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+impl Drop for ScreenCapture {
+    fn drop(&mut self) {
+        unsafe {
+            self.stop();
+        }
+    }
+}
+
+/// GUI framebuffer callback: keep a copy of the latest frame
+unsafe extern "C" fn framebuffer_callback(
+    data: *const u8,
+    size: usize,
+    _orientation: sys::CanvasOrientation,
+    context: *mut c_void,
+) {
+    unsafe {
+        let capture = &*(context as *const ScreenCapture);
+        if data.is_null() || size < FRAME_SIZE {
+            return;
+        }
+        let frame = &mut *capture.frame.get();
+        core::ptr::copy_nonoverlapping(data, frame.data.as_mut_ptr(), FRAME_SIZE);
+        capture.captured.store(true, Ordering::Release);
+    }
+}
@@ -0,0 +1,147 @@
+//! Numbered image sequences
+//!
+//! "Play Sequence" writes `frame_001.bmp`, `frame_002.bmp`, ... to the tag
+//! one after another, waiting a dwell time on each: a slow animation for a
+//! demo, or a long run of full refreshes to stress a panel. The frame number
+//! is the run of digits ending the file name's stem; later frames keep its
+//! width, so `frame_001` is followed by `frame_002` and `frame9` by
+//! `frame10`. Playback ends at the first missing number, or starts over
+//! from the selected frame when looping.
+
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::string::String;
+use core::ffi::{c_void, CStr};
+
+use flipperzero_sys as sys;
+
+use crate::storage;
+
+/// Dwell presets the write menu cycles through (s)
+pub const DWELL_PRESETS: [u32; 5] = [2, 5, 10, 30, 60];
+
+/// Called on the timer thread once the dwell is over
+pub type DwellCallback = unsafe extern "C" fn(context: *mut c_void);
+
+/// One-shot FuriTimer; freed when dropped
+struct DwellTimer {
+    timer: *mut sys::FuriTimer,
+}
+
+impl DwellTimer {
+    unsafe fn new(callback: DwellCallback, context: *mut c_void) -> Option<Self> {
+        unsafe {
+            let timer = sys::furi_timer_alloc(Some(callback), sys::FuriTimerTypeOnce, context);
+            (!timer.is_null()).then_some(Self { timer })
+        }
+    }
+
+    /// (Re)arm the timer to fire once after `ms`
+    unsafe fn start(&self, ms: u32) -> bool {
+        unsafe { sys::furi_timer_start(self.timer, sys::furi_ms_to_ticks(ms)) == sys::FuriStatusOk }
+    }
+}
+
+impl Drop for DwellTimer {
+    fn drop(&mut self) {
+        // Stops a pending timer; must not run on the timer thread
+        unsafe { sys::furi_timer_free(self.timer) };
+    }
+}
+
+/// A sequence being played
+pub struct Sequence {
+    /// Path up to the frame number
+    prefix: String,
+    /// Zero-padded width of the frame number
+    digits: usize,
+    /// Rest of the file name after the number (the extension)
+    suffix: String,
+    /// Frame the sequence was started from
+    first: u32,
+    /// Frame on the tag, or being decoded
+    frame: u32,
+    /// Frames written so far
+    pub written: u32,
+    /// Wait after each frame (s)
+    pub dwell: u32,
+    /// Start over at the first frame instead of stopping
+    pub looping: bool,
+    timer: Option<DwellTimer>,
+}
+
+impl Sequence {
+    /// Sequence starting at `path`, if its file name ends in a number
+    pub fn parse(path: &CStr, dwell: u32, looping: bool) -> Option<Self> {
+        let path = path.to_str().ok()?;
+        let name_start = path.rfind('/').map_or(0, |slash| slash + 1);
+        let stem_end = match path[name_start..].rfind('.') {
+            Some(0) | None => path.len(),
+            Some(dot) => name_start + dot,
+        };
+        let stem = &path[name_start..stem_end];
+        let digits = stem.bytes().rev().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        let number_start = stem_end - digits;
+        let frame = path[number_start..stem_end].parse().ok()?;
+        Some(Self {
+            prefix: String::from(&path[..number_start]),
+            digits,
+            suffix: String::from(&path[stem_end..]),
+            first: frame,
+            frame,
+            written: 0,
+            dwell,
+            looping,
+            timer: None,
+        })
+    }
+
+    /// Path of frame `number`
+    fn path_of(&self, number: u32) -> Option<CString> {
+        let path = alloc::format!("{}{:03$}{}", self.prefix, number, self.suffix, self.digits);
+        CString::new(path).ok()
+    }
+
+    /// Number of the frame on the tag
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// The frame after the current one, or the first when looping past the
+    /// end; None when the sequence is over
+    pub unsafe fn next_frame(&self) -> Option<(u32, CString)> {
+        unsafe {
+            let next = self.frame.checked_add(1).and_then(|n| Some((n, self.path_of(n)?)));
+            match next {
+                Some((number, path)) if storage::file_stamp(&path).is_some() => {
+                    Some((number, path))
+                }
+                _ if self.looping => Some((self.first, self.path_of(self.first)?)),
+                _ => None,
+            }
+        }
+    }
+
+    /// Move on to frame `number`
+    pub fn set_frame(&mut self, number: u32) {
+        self.frame = number;
+    }
+
+    /// Call `callback(context)` once the dwell time is over
+    ///
+    /// The callback runs on the timer thread. Dropping the sequence cancels
+    /// a pending call.
+    pub unsafe fn schedule(&mut self, callback: DwellCallback, context: *mut c_void) -> bool {
+        unsafe {
+            if self.timer.is_none() {
+                self.timer = DwellTimer::new(callback, context);
+            }
+            let ms = self.dwell.saturating_mul(1000);
+            self.timer.as_ref().is_some_and(|timer| timer.start(ms))
+        }
+    }
+}
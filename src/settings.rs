@@ -0,0 +1,373 @@
+//! Persistent user settings
+//!
+//! Stored as `key=value` lines in the app data directory (see `storage`).
+//! Values that match their defaults are not written, so the file only holds
+//! what the user actually changed.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use crate::log_ring::{self, LogModule};
+use crate::protocol_common::{self, AuthKey, DEFAULT_AUTH_KEY, DEFAULT_TAG_TIMEOUT_S};
+use crate::quantize::{self, ColorMatch, Gamma, Rgb, ScreenAngle, Tuning, NAMED_COLORS};
+use crate::storage;
+use crate::tag_type::TagType;
+
+/// Settings file path
+const SETTINGS_PATH: &CStr = c"/ext/apps_data/eink_nfc_img/settings.txt";
+
+/// NFC front end used for writes
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReaderKind {
+    /// The Flipper's own NFC hardware
+    #[default]
+    Internal,
+    /// External PN532 on the GPIO UART (IsoDep tags)
+    Pn532,
+}
+
+impl ReaderKind {
+    /// Value stored in the settings file
+    fn key(self) -> &'static str {
+        match self {
+            ReaderKind::Internal => "internal",
+            ReaderKind::Pn532 => "pn532",
+        }
+    }
+}
+
+/// Where the file browser opens
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BrowseRoot {
+    /// The whole SD card
+    #[default]
+    SdCard,
+    /// The Flipper's internal flash
+    Internal,
+    /// Sample images installed with the app (`storage::SAMPLES_DIR`)
+    Samples,
+}
+
+impl BrowseRoot {
+    /// Every root, in menu order
+    const ALL: [BrowseRoot; 3] = [BrowseRoot::SdCard, BrowseRoot::Internal, BrowseRoot::Samples];
+
+    /// Value stored in the settings file
+    fn key(self) -> &'static str {
+        match self {
+            BrowseRoot::SdCard => "ext",
+            BrowseRoot::Internal => "int",
+            BrowseRoot::Samples => "samples",
+        }
+    }
+
+    /// Root stored as `key`
+    fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|root| root.key() == key)
+    }
+
+    /// Folder the browser starts in, and can't leave
+    pub fn path(self) -> &'static CStr {
+        match self {
+            BrowseRoot::SdCard => c"/ext",
+            BrowseRoot::Internal => c"/int",
+            BrowseRoot::Samples => storage::SAMPLES_DIR,
+        }
+    }
+
+    /// True if browsing needs the SD card
+    pub fn on_sd_card(self) -> bool {
+        self != BrowseRoot::Internal
+    }
+
+    /// The root after this one, wrapping around
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|root| *root == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// User settings
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Reader that carries writes
+    pub reader: ReaderKind,
+    /// List tag types whose register tables are still unverified
+    pub experimental_tags: bool,
+    /// Swap OK/Back and Up/Down in the app's own views (see `input`)
+    pub left_handed: bool,
+    /// Where files are picked from
+    pub browse_root: BrowseRoot,
+    /// Tone curve applied before palette matching (file only, e.g. `gamma=2.2`)
+    pub gamma: Gamma,
+    /// Palette matching rule (file only, e.g. `color_match=lab`)
+    pub color_match: ColorMatch,
+    /// Panel shades and hue rule thresholds (file only, see `load_tuning`)
+    pub tuning: Tuning,
+    /// Halftone screen angle (file only, `halftone_angle=0`, 15, 45 or 75)
+    pub screen_angle: ScreenAngle,
+    /// Pack image files while writing instead of ahead of it (file only,
+    /// `stream_writes=1`; see `image::FileStream`)
+    pub stream_writes: bool,
+    /// See-through color of overlays (file only, `overlay_key=r,g,b`;
+    /// `image::OVERLAY_KEY` if unset)
+    pub overlay_key: Option<Rgb>,
+    /// Seconds a write waits for a tag before offering to retry (file only,
+    /// `tag_timeout=30`; 0 waits until Back)
+    pub tag_timeout_s: u32,
+    /// Debug: furi log tag (empty for the default)
+    pub log_tag: String,
+    /// Debug: modules whose info lines are dropped
+    pub log_muted: Vec<LogModule>,
+    /// INIT auth key per tag type, indexed like `TagType::ALL`
+    auth_keys: Vec<AuthKey>,
+}
+
+/// File key for a tag's auth key
+fn auth_key_name(tag: &TagType) -> String {
+    alloc::format!("auth_key.{}", tag.part_number.replace(' ', "_"))
+}
+
+/// File key for a module's log switch
+fn log_key_name(module: LogModule) -> String {
+    alloc::format!("log.{}", module.key())
+}
+
+/// File key for a tuned panel shade
+fn color_key_name(name: &str) -> String {
+    alloc::format!("color.{}", name)
+}
+
+/// Read `color.<name>=r,g,b` shades and `match.*` thresholds
+///
+/// Malformed values keep their default, like every other key.
+fn load_tuning(entries: &[(String, String)]) -> Tuning {
+    let find = |name: &str| entries.iter().find(|(k, _)| k == name).map(|(_, v)| v.trim());
+    let threshold =
+        |name: &str, default: u8| find(name).and_then(|v| v.parse().ok()).unwrap_or(default);
+    let mut colors = Tuning::DEFAULT.colors;
+    for ((name, _), slot) in NAMED_COLORS.iter().zip(colors.iter_mut()) {
+        *slot = find(&color_key_name(name)).and_then(Tuning::parse_color);
+    }
+    Tuning {
+        colors,
+        min_saturation: threshold("match.saturation", Tuning::DEFAULT.min_saturation),
+        min_value: threshold("match.brightness", Tuning::DEFAULT.min_value),
+        max_hue_gap: threshold("match.hue_gap", Tuning::DEFAULT.max_hue_gap),
+    }
+}
+
+/// Parse 16 hex digits into an auth key
+fn parse_key(value: &str) -> Option<AuthKey> {
+    let value = value.trim();
+    if value.len() != 2 * DEFAULT_AUTH_KEY.len() {
+        return None;
+    }
+    let mut key = [0u8; DEFAULT_AUTH_KEY.len()];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(value.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+impl Settings {
+    /// Load settings from the SD card (defaults if missing)
+    pub unsafe fn load() -> Self {
+        unsafe {
+            let entries = storage::read_kv(SETTINGS_PATH);
+            let auth_keys = TagType::ALL
+                .iter()
+                .map(|tag| {
+                    let name = auth_key_name(tag);
+                    entries
+                        .iter()
+                        .find(|(k, _)| *k == name)
+                        .and_then(|(_, v)| parse_key(v))
+                        .unwrap_or(DEFAULT_AUTH_KEY)
+                })
+                .collect();
+            let reader = match entries.iter().find(|(k, _)| k == "reader") {
+                Some((_, v)) if v == ReaderKind::Pn532.key() => ReaderKind::Pn532,
+                _ => ReaderKind::Internal,
+            };
+            let experimental_tags = entries.iter().any(|(k, v)| k == "experimental_tags" && v == "1");
+            let left_handed = entries.iter().any(|(k, v)| k == "left_handed" && v == "1");
+            let browse_root = entries
+                .iter()
+                .find(|(k, _)| k == "browse")
+                .and_then(|(_, v)| BrowseRoot::from_key(v))
+                .unwrap_or_default();
+            let gamma = entries
+                .iter()
+                .find(|(k, _)| k == "gamma")
+                .and_then(|(_, v)| Gamma::parse(v))
+                .unwrap_or_default();
+            let color_match = entries
+                .iter()
+                .find(|(k, _)| k == "color_match")
+                .and_then(|(_, v)| ColorMatch::from_key(v.trim()))
+                .unwrap_or_default();
+            let tuning = load_tuning(&entries);
+            let screen_angle = entries
+                .iter()
+                .find(|(k, _)| k == "halftone_angle")
+                .and_then(|(_, v)| ScreenAngle::from_degrees(v))
+                .unwrap_or_default();
+            let stream_writes = entries.iter().any(|(k, v)| k == "stream_writes" && v == "1");
+            let overlay_key = entries
+                .iter()
+                .find(|(k, _)| k == "overlay_key")
+                .and_then(|(_, v)| Tuning::parse_color(v));
+            let tag_timeout_s = entries
+                .iter()
+                .find(|(k, _)| k == "tag_timeout")
+                .and_then(|(_, v)| v.trim().parse().ok())
+                .unwrap_or(DEFAULT_TAG_TIMEOUT_S);
+            let log_tag = entries
+                .iter()
+                .find(|(k, _)| k == "log_tag")
+                .map(|(_, v)| String::from(v.trim()))
+                .unwrap_or_default();
+            let log_muted = LogModule::ALL
+                .into_iter()
+                .filter(|module| {
+                    let name = log_key_name(*module);
+                    entries.iter().any(|(k, v)| *k == name && v == "0")
+                })
+                .collect();
+            Self {
+                reader,
+                experimental_tags,
+                left_handed,
+                browse_root,
+                gamma,
+                color_match,
+                tuning,
+                screen_angle,
+                stream_writes,
+                overlay_key,
+                tag_timeout_s,
+                log_tag,
+                log_muted,
+                auth_keys,
+            }
+        }
+    }
+
+    /// Save settings to the SD card
+    pub unsafe fn save(&self) -> bool {
+        unsafe {
+            let mut entries: Vec<(String, String)> = Vec::new();
+            if self.reader != ReaderKind::Internal {
+                entries.push((String::from("reader"), String::from(self.reader.key())));
+            }
+            if self.experimental_tags {
+                entries.push((String::from("experimental_tags"), String::from("1")));
+            }
+            if self.left_handed {
+                entries.push((String::from("left_handed"), String::from("1")));
+            }
+            if self.browse_root != BrowseRoot::SdCard {
+                entries.push((String::from("browse"), String::from(self.browse_root.key())));
+            }
+            if self.gamma != Gamma::LINEAR {
+                entries.push((String::from("gamma"), alloc::format!("{}", self.gamma)));
+            }
+            if self.color_match != ColorMatch::Hue {
+                entries.push((String::from("color_match"), String::from(self.color_match.key())));
+            }
+            for ((name, _), color) in NAMED_COLORS.iter().zip(self.tuning.colors) {
+                if let Some(c) = color {
+                    let value = alloc::format!("{},{},{}", c.r, c.g, c.b);
+                    entries.push((color_key_name(name), value));
+                }
+            }
+            let thresholds = [
+                ("match.saturation", self.tuning.min_saturation, Tuning::DEFAULT.min_saturation),
+                ("match.brightness", self.tuning.min_value, Tuning::DEFAULT.min_value),
+                ("match.hue_gap", self.tuning.max_hue_gap, Tuning::DEFAULT.max_hue_gap),
+            ];
+            for (key, value, default) in thresholds {
+                if value != default {
+                    entries.push((String::from(key), alloc::format!("{}", value)));
+                }
+            }
+            if self.screen_angle != ScreenAngle::default() {
+                let value = alloc::format!("{}", self.screen_angle.degrees());
+                entries.push((String::from("halftone_angle"), value));
+            }
+            if self.stream_writes {
+                entries.push((String::from("stream_writes"), String::from("1")));
+            }
+            if let Some(key) = self.overlay_key {
+                let value = alloc::format!("{},{},{}", key.r, key.g, key.b);
+                entries.push((String::from("overlay_key"), value));
+            }
+            if self.tag_timeout_s != DEFAULT_TAG_TIMEOUT_S {
+                let value = alloc::format!("{}", self.tag_timeout_s);
+                entries.push((String::from("tag_timeout"), value));
+            }
+            if !self.log_tag.is_empty() {
+                entries.push((String::from("log_tag"), self.log_tag.clone()));
+            }
+            for module in &self.log_muted {
+                entries.push((log_key_name(*module), String::from("0")));
+            }
+            for (tag, key) in TagType::ALL.iter().zip(self.auth_keys.iter()) {
+                if *key != DEFAULT_AUTH_KEY {
+                    let hex: String = key.iter().map(|b| alloc::format!("{:02X}", b)).collect();
+                    entries.push((auth_key_name(tag), hex));
+                }
+            }
+            storage::write_kv(SETTINGS_PATH, &entries)
+        }
+    }
+
+    /// Apply the debug log tag and module switches
+    pub unsafe fn apply_logging(&self) {
+        unsafe {
+            log_ring::set_tag(&self.log_tag);
+        }
+        for module in LogModule::ALL {
+            log_ring::set_enabled(module, !self.log_muted.contains(&module));
+        }
+    }
+
+    /// Hand the color settings to the quantizers
+    pub fn apply_colors(&self) {
+        quantize::set_gamma(self.gamma);
+        quantize::set_color_match(self.color_match);
+        quantize::set_tuning(&self.tuning);
+        quantize::set_screen_angle(self.screen_angle);
+    }
+
+    /// Hand the tag timeout to the readers
+    pub fn apply_tag_timeout(&self) {
+        protocol_common::set_tag_timeout(self.tag_timeout_s);
+    }
+
+    /// Index of `tag` in `TagType::ALL`
+    fn tag_index(tag: &TagType) -> Option<usize> {
+        TagType::ALL.iter().position(|t| t.part_number == tag.part_number)
+    }
+
+    /// INIT auth key for `tag`
+    pub fn auth_key(&self, tag: &TagType) -> AuthKey {
+        Self::tag_index(tag)
+            .and_then(|idx| self.auth_keys.get(idx).copied())
+            .unwrap_or(DEFAULT_AUTH_KEY)
+    }
+
+    /// Change the INIT auth key for `tag`
+    pub fn set_auth_key(&mut self, tag: &TagType, key: AuthKey) {
+        if let Some(idx) = Self::tag_index(tag) {
+            if self.auth_keys.len() < TagType::ALL.len() {
+                self.auth_keys.resize(TagType::ALL.len(), DEFAULT_AUTH_KEY);
+            }
+            self.auth_keys[idx] = key;
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Per-image conversion options
+//!
+//! "Remember Options" in the write menu saves how an image file was
+//! converted to a sidecar next to it, named after the image plus
+//! `.eink.cfg` (`badge.bmp.eink.cfg`). Selecting the image again applies
+//! the color mode, rotation, threshold and accent pull before decoding and,
+//! if it is not the tag's size, the fit instead of asking. It is a
+//! `key=value` file like a recipe; missing or unknown values leave the
+//! current choice.
+//!
+//! ```text
+//! colors=floyd_steinberg
+//! rotate=90
+//! fit=crop:top-left
+//! threshold=104
+//! ```
+
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use crate::image::{DecodeOptions, Rotation};
+use crate::quantize::{AccentPull, QuantizerKind, Threshold};
+use crate::resample::Fit;
+use crate::storage;
+
+/// Appended to the image's file name
+const SUFFIX: &str = ".eink.cfg";
+
+/// Conversion choices remembered for one image
+#[derive(Debug, Clone, Copy)]
+pub struct ImageOptions {
+    pub colors: Option<QuantizerKind>,
+    pub rotation: Option<Rotation>,
+    /// How an image of another size than the tag was fitted
+    pub fit: Option<Fit>,
+    pub threshold: Option<Threshold>,
+    pub accent_pull: Option<AccentPull>,
+}
+
+/// Sidecar path for the image at `image`
+fn sidecar_path(image: &CStr) -> Option<CString> {
+    CString::new(alloc::format!("{}{}", image.to_str().ok()?, SUFFIX)).ok()
+}
+
+impl ImageOptions {
+    /// Options saved for the image at `image`, if it has a sidecar
+    pub unsafe fn load(image: &CStr) -> Option<Self> {
+        unsafe {
+            let entries = storage::read_kv(&sidecar_path(image)?);
+            if entries.is_empty() {
+                return None;
+            }
+            let get = |key: &str| entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+            Some(Self {
+                colors: get("colors").and_then(QuantizerKind::from_key),
+                rotation: get("rotate").and_then(Rotation::from_degrees),
+                fit: get("fit").and_then(Fit::from_key),
+                threshold: get("threshold").and_then(Threshold::parse),
+                accent_pull: get("accent").and_then(AccentPull::parse),
+            })
+        }
+    }
+
+    /// Replace the choices in `options` that were saved; the fit is left
+    /// alone, since it only holds once the image is not the tag's size
+    pub fn apply(&self, options: &mut DecodeOptions) {
+        options.quantizer = self.colors.unwrap_or(options.quantizer);
+        options.rotation = self.rotation.unwrap_or(options.rotation);
+        options.threshold = self.threshold.unwrap_or(options.threshold);
+        options.accent_pull = self.accent_pull.unwrap_or(options.accent_pull);
+    }
+
+    /// Write the sidecar for the image at `image`
+    pub unsafe fn save(&self, image: &CStr) -> bool {
+        unsafe {
+            let Some(path) = sidecar_path(image) else {
+                return false;
+            };
+            let mut entries = Vec::new();
+            if let Some(colors) = self.colors {
+                entries.push(("colors", String::from(colors.key())));
+            }
+            if let Some(rotation) = self.rotation {
+                entries.push(("rotate", alloc::format!("{}", rotation.degrees())));
+            }
+            if let Some(fit) = self.fit.filter(|fit| *fit != Fit::Exact) {
+                entries.push(("fit", fit.key()));
+            }
+            if let Some(threshold) = self.threshold.filter(|t| *t != Threshold::DEFAULT) {
+                entries.push(("threshold", alloc::format!("{}", threshold)));
+            }
+            if let Some(pull) = self.accent_pull.filter(|pull| *pull != AccentPull::OFF) {
+                entries.push(("accent", alloc::format!("{}", pull)));
+            }
+            storage::write_kv(&path, &entries)
+        }
+    }
+
+    /// Delete the sidecar for the image at `image`
+    pub unsafe fn forget(image: &CStr) {
+        unsafe {
+            if let Some(path) = sidecar_path(image) {
+                storage::remove(&path);
+            }
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Lifetime usage statistics
+//!
+//! Persisted in the app data directory. The About screen shows the total
+//! number of tags written; the Statistics screen breaks writes, failures and
+//! average write time down per tag type. The write screen shows a duration
+//! estimate calibrated from the same history.
+
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::mem::discriminant;
+use flipperzero_sys as sys;
+
+use crate::protocol_common::{Failure, NfcError};
+use crate::storage;
+use crate::tag_type::TagType;
+
+/// Stats file path
+const STATS_PATH: &CStr = c"/ext/apps_data/eink_nfc_img/stats.txt";
+
+/// Failure kinds tracked: error, file key, screen label
+///
+/// Errors are matched by variant; where a transmit failed is not kept.
+const FAILURE_KINDS: [(NfcError, &str, &str); 9] = [
+    (NfcError::DetectFailed, "fail.detect", "Detect"),
+    (NfcError::TransmitFailed(Failure::at("")), "fail.transmit", "Transmit"),
+    (NfcError::AllocFailed, "fail.alloc", "Alloc"),
+    (NfcError::SizeMismatch, "fail.size", "Size"),
+    (NfcError::ReaderUnavailable, "fail.reader", "Reader"),
+    (NfcError::Busy, "fail.busy", "Busy"),
+    (NfcError::ImageReadFailed, "fail.image", "Image"),
+    (NfcError::VerifyFailed, "fail.verify", "Verify"),
+    (NfcError::RefreshTimeout, "fail.refresh", "Refresh"),
+];
+
+/// Number of recent attempts kept per tag type
+const RECENT_WINDOW: u8 = 10;
+
+/// Recent attempts needed before the failure rate is judged
+const ALERT_MIN_ATTEMPTS: u8 = 4;
+
+/// Recent failure rate (percent) at which remedies are suggested
+const ALERT_FAILURE_PERCENT: u32 = 50;
+
+/// Each write moves the time estimate 1/N of the way to its own duration
+const ESTIMATE_WEIGHT: i64 = 4;
+
+/// Per-tag-type counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TagStats {
+    /// Successful writes
+    pub writes: u32,
+    /// Failed writes
+    pub failures: u32,
+    /// Total time of successful writes, including tag detection (ms)
+    pub write_ms: u64,
+    /// Write time estimate weighted toward recent writes (ms, 0 = none yet)
+    pub estimate_ms: u32,
+    /// Outcomes of the last `recent_len` attempts, newest in bit 0 (1 = failed)
+    recent: u16,
+    recent_len: u8,
+}
+
+impl TagStats {
+    /// Shift one outcome into the recent window
+    fn push_recent(&mut self, failed: bool) {
+        self.recent = (self.recent << 1) | failed as u16;
+        self.recent &= (1 << RECENT_WINDOW) - 1;
+        self.recent_len = (self.recent_len + 1).min(RECENT_WINDOW);
+    }
+
+    /// True once enough recent attempts have failed to warrant a hint
+    pub fn failing(&self) -> bool {
+        if self.recent_len < ALERT_MIN_ATTEMPTS {
+            return false;
+        }
+        let failed = self.recent.count_ones();
+        failed * 100 >= ALERT_FAILURE_PERCENT * self.recent_len as u32
+    }
+
+    /// Average successful write time (ms)
+    pub fn average_ms(&self) -> Option<u64> {
+        (self.writes > 0).then(|| self.write_ms / self.writes as u64)
+    }
+
+    /// Fold a successful write's duration into the estimate
+    ///
+    /// Recent writes count most, so the estimate follows changes in reader,
+    /// firmware or tag batch instead of averaging them away.
+    fn calibrate(&mut self, elapsed_ms: u32) {
+        if self.estimate_ms == 0 {
+            self.estimate_ms = elapsed_ms;
+        } else {
+            let delta = (elapsed_ms as i64 - self.estimate_ms as i64) / ESTIMATE_WEIGHT;
+            self.estimate_ms = (self.estimate_ms as i64 + delta).max(1) as u32;
+        }
+    }
+}
+
+/// Lifetime counters
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Successful image writes
+    pub tags_written: u32,
+    /// Image bytes delivered by successful writes
+    pub bytes_written: u64,
+    /// Failed writes, indexed like `FAILURE_KINDS`
+    failures: [u32; FAILURE_KINDS.len()],
+    /// Counters indexed like `TagType::ALL`
+    per_tag: Vec<TagStats>,
+}
+
+/// File key prefix for a tag's counters
+fn tag_key(tag: &TagType, field: &str) -> String {
+    alloc::format!("tag.{}.{}", tag.part_number.replace(' ', "_"), field)
+}
+
+impl Stats {
+    /// Load stats from the SD card (zeroed if missing)
+    pub unsafe fn load() -> Self {
+        unsafe {
+            let entries = storage::read_kv(STATS_PATH);
+            let mut failures = [0; FAILURE_KINDS.len()];
+            for (count, (_, key, _)) in failures.iter_mut().zip(FAILURE_KINDS.iter()) {
+                *count = storage::get_parsed(&entries, key, 0);
+            }
+            let per_tag = TagType::ALL
+                .iter()
+                .map(|tag| {
+                    let mut stats = TagStats {
+                        writes: storage::get_parsed(&entries, &tag_key(tag, "writes"), 0),
+                        failures: storage::get_parsed(&entries, &tag_key(tag, "fails"), 0),
+                        write_ms: storage::get_parsed(&entries, &tag_key(tag, "write_ms"), 0),
+                        estimate_ms: storage::get_parsed(&entries, &tag_key(tag, "estimate_ms"), 0),
+                        recent: storage::get_parsed(&entries, &tag_key(tag, "recent"), 0),
+                        recent_len: storage::get_parsed(&entries, &tag_key(tag, "recent_len"), 0),
+                    };
+                    // Files from before the estimate existed start from the average
+                    if stats.estimate_ms == 0 {
+                        stats.estimate_ms = stats.average_ms().unwrap_or(0) as u32;
+                    }
+                    stats
+                })
+                .collect();
+            Self {
+                tags_written: storage::get_parsed(&entries, "tags_written", 0),
+                bytes_written: storage::get_parsed(&entries, "bytes_written", 0),
+                failures,
+                per_tag,
+            }
+        }
+    }
+
+    /// Save stats to the SD card
+    pub unsafe fn save(&self) -> bool {
+        unsafe {
+            let mut entries: Vec<(String, String)> = Vec::new();
+            entries.push(("tags_written".to_string(), self.tags_written.to_string()));
+            entries.push(("bytes_written".to_string(), self.bytes_written.to_string()));
+            for (count, (_, key, _)) in self.failures.iter().zip(FAILURE_KINDS.iter()) {
+                entries.push((key.to_string(), count.to_string()));
+            }
+            for (tag, stats) in TagType::ALL.iter().zip(self.per_tag.iter()) {
+                entries.push((tag_key(tag, "writes"), stats.writes.to_string()));
+                entries.push((tag_key(tag, "fails"), stats.failures.to_string()));
+                entries.push((tag_key(tag, "write_ms"), stats.write_ms.to_string()));
+                entries.push((tag_key(tag, "estimate_ms"), stats.estimate_ms.to_string()));
+                entries.push((tag_key(tag, "recent"), stats.recent.to_string()));
+                entries.push((tag_key(tag, "recent_len"), stats.recent_len.to_string()));
+            }
+            storage::write_kv(STATS_PATH, &entries)
+        }
+    }
+
+    /// Counters for `tag`
+    fn tag_mut(&mut self, tag: &TagType) -> Option<&mut TagStats> {
+        let idx = TagType::ALL.iter().position(|t| t.part_number == tag.part_number)?;
+        if self.per_tag.len() < TagType::ALL.len() {
+            self.per_tag.resize(TagType::ALL.len(), TagStats::default());
+        }
+        self.per_tag.get_mut(idx)
+    }
+
+    /// Expected duration of a write to `tag` (ms)
+    ///
+    /// Tag types that have not been written yet borrow the mean estimate of
+    /// the tag types sharing their protocol, since the protocol's fixed
+    /// refresh waits dominate the time. None without any history.
+    pub fn estimate_ms(&self, tag: &TagType) -> Option<u32> {
+        let mut siblings = (0u64, 0u64);
+        for (other, stats) in TagType::ALL.iter().zip(self.per_tag.iter()) {
+            if stats.estimate_ms == 0 {
+                continue;
+            }
+            if other.part_number == tag.part_number {
+                return Some(stats.estimate_ms);
+            }
+            if other.protocol == tag.protocol {
+                siblings = (siblings.0 + stats.estimate_ms as u64, siblings.1 + 1);
+            }
+        }
+        (siblings.1 > 0).then(|| (siblings.0 / siblings.1) as u32)
+    }
+
+    /// Count a successful write and award the dolphin deed
+    pub unsafe fn record_write(&mut self, tag: &TagType, bytes: usize, elapsed_ms: u32) {
+        unsafe {
+            self.tags_written = self.tags_written.saturating_add(1);
+            self.bytes_written = self.bytes_written.saturating_add(bytes as u64);
+            if let Some(stats) = self.tag_mut(tag) {
+                stats.writes = stats.writes.saturating_add(1);
+                stats.write_ms = stats.write_ms.saturating_add(elapsed_ms as u64);
+                stats.calibrate(elapsed_ms);
+                stats.push_recent(false);
+            }
+            self.save();
+            sys::dolphin_deed(sys::DolphinDeedNfcSave);
+        }
+    }
+
+    /// Count a failed write
+    ///
+    /// Returns true if the tag type's recent failure rate is over the alert
+    /// threshold.
+    pub unsafe fn record_failure(&mut self, tag: &TagType, error: NfcError) -> bool {
+        unsafe {
+            let kind = discriminant(&error);
+            if let Some(idx) = FAILURE_KINDS.iter().position(|(e, _, _)| discriminant(e) == kind) {
+                self.failures[idx] = self.failures[idx].saturating_add(1);
+            }
+            let mut failing = false;
+            if let Some(stats) = self.tag_mut(tag) {
+                stats.failures = stats.failures.saturating_add(1);
+                stats.push_recent(true);
+                failing = stats.failing();
+            }
+            self.save();
+            failing
+        }
+    }
+
+    /// Multi-line summary for the Statistics screen
+    pub fn summary(&self) -> String {
+        let total_failures: u32 = self.failures.iter().sum();
+        let mut text = alloc::format!(
+            "Writes: {} ok, {} failed\nData: {} KB\n",
+            self.tags_written,
+            total_failures,
+            self.bytes_written / 1024
+        );
+
+        if total_failures > 0 {
+            text.push_str("Failures:\n");
+            for (count, (_, _, label)) in self.failures.iter().zip(FAILURE_KINDS.iter()) {
+                if *count > 0 {
+                    text.push_str(&alloc::format!(" {}: {}\n", label, count));
+                }
+            }
+        }
+
+        for (tag, stats) in TagType::ALL.iter().zip(self.per_tag.iter()) {
+            if stats.writes == 0 && stats.failures == 0 {
+                continue;
+            }
+            text.push_str(&alloc::format!(
+                "{}:\n {} ok, {} failed\n",
+                tag.part_number, stats.writes, stats.failures
+            ));
+            if let Some(avg) = stats.average_ms() {
+                text.push_str(&alloc::format!(
+                    " avg {}.{}s, next ~{}s\n",
+                    avg / 1000,
+                    (avg % 1000) / 100,
+                    stats.estimate_ms.div_ceil(1000)
+                ));
+            }
+        }
+        text
+    }
+}
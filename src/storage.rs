@@ -0,0 +1,181 @@
+//! Small `key=value` files under the app's data directory
+//!
+//! Stats and settings are stored as one `key=value` pair per line so they
+//! stay readable (and editable) from a PC. Unknown keys are ignored on load.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use flipperzero_sys as sys;
+
+/// App data directory on the SD card
+pub const APP_DATA_DIR: &CStr = c"/ext/apps_data/eink_nfc_img";
+
+/// Sample images, copied from the repository's `assets/` folder along with
+/// the app
+pub const SAMPLES_DIR: &CStr = c"/ext/apps_assets/eink_nfc_img/samples";
+
+/// Largest file read back (these files are a few lines long)
+const MAX_FILE_SIZE: usize = 4096;
+
+/// Longest file name `list_dir` reads, with its terminator
+const MAX_NAME_LEN: usize = 256;
+
+/// Read `key=value` lines from `path`
+///
+/// A missing or unreadable file reads as empty, so callers fall back to
+/// their defaults.
+pub unsafe fn read_kv(path: &CStr) -> Vec<(String, String)> {
+    unsafe {
+        let contents = read_file(path, MAX_FILE_SIZE).unwrap_or_default();
+        String::from_utf8_lossy(&contents)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (String::from(key.trim()), String::from(value.trim())))
+            .collect()
+    }
+}
+
+/// The first `max_len` bytes of the file at `path`; None if it can't be
+/// opened
+pub unsafe fn read_file(path: &CStr, max_len: usize) -> Option<Vec<u8>> {
+    unsafe {
+        let storage = sys::furi_record_open(c"storage".as_ptr()) as *mut sys::Storage;
+        let file = sys::storage_file_alloc(storage);
+
+        let mut contents = None;
+        if sys::storage_file_open(file, path.as_ptr(), sys::FSAM_READ, sys::FSOM_OPEN_EXISTING) {
+            let size = core::cmp::min(sys::storage_file_size(file) as usize, max_len);
+            let mut data = vec![0u8; size];
+            let read = sys::storage_file_read(file, data.as_mut_ptr() as *mut _, size);
+            data.truncate(read);
+            contents = Some(data);
+            sys::storage_file_close(file);
+        }
+
+        sys::storage_file_free(file);
+        sys::furi_record_close(c"storage".as_ptr());
+        contents
+    }
+}
+
+/// Replace `path` with `key=value` lines, creating the data directory
+///
+/// Returns false if the file could not be written (e.g. no SD card).
+pub unsafe fn write_kv<K: AsRef<str>>(path: &CStr, entries: &[(K, String)]) -> bool {
+    unsafe {
+        let mut contents = String::new();
+        for (key, value) in entries {
+            contents.push_str(key.as_ref());
+            contents.push('=');
+            contents.push_str(value);
+            contents.push('\n');
+        }
+
+        make_data_dir();
+        write_file(path, contents.as_bytes())
+    }
+}
+
+/// Create the app's data directory if it is missing
+pub unsafe fn make_data_dir() {
+    unsafe { make_dir(APP_DATA_DIR) }
+}
+
+/// Create the directory `path` if it is missing (its parent must exist)
+pub unsafe fn make_dir(path: &CStr) {
+    unsafe {
+        let storage = sys::furi_record_open(c"storage".as_ptr()) as *mut sys::Storage;
+        sys::storage_simply_mkdir(storage, path.as_ptr());
+        sys::furi_record_close(c"storage".as_ptr());
+    }
+}
+
+/// Modification time (seconds) and size of the file at `path`
+///
+/// None if it doesn't exist or the card can't be read.
+pub unsafe fn file_stamp(path: &CStr) -> Option<(u32, u64)> {
+    unsafe {
+        let storage = sys::furi_record_open(c"storage".as_ptr()) as *mut sys::Storage;
+        let mut info: sys::FileInfo = core::mem::zeroed();
+        let mut timestamp = 0u32;
+        let ok = sys::storage_common_stat(storage, path.as_ptr(), &mut info) == sys::FSE_OK
+            && sys::storage_common_timestamp(storage, path.as_ptr(), &mut timestamp)
+                == sys::FSE_OK;
+        sys::furi_record_close(c"storage".as_ptr());
+        ok.then_some((timestamp, info.size))
+    }
+}
+
+/// Replace `path` with `data`
+///
+/// Returns false if the file could not be written.
+pub unsafe fn write_file(path: &CStr, data: &[u8]) -> bool {
+    unsafe {
+        let storage = sys::furi_record_open(c"storage".as_ptr()) as *mut sys::Storage;
+        let file = sys::storage_file_alloc(storage);
+
+        let mut ok = false;
+        if sys::storage_file_open(file, path.as_ptr(), sys::FSAM_WRITE, sys::FSOM_CREATE_ALWAYS) {
+            let written = sys::storage_file_write(file, data.as_ptr() as *const _, data.len());
+            ok = written == data.len();
+            sys::storage_file_close(file);
+        }
+
+        sys::storage_file_free(file);
+        sys::furi_record_close(c"storage".as_ptr());
+        ok
+    }
+}
+
+/// Names of the files in the directory `path`, in the order the card
+/// lists them; subdirectories are left out
+///
+/// A missing or unreadable directory lists as empty.
+pub unsafe fn list_dir(path: &CStr) -> Vec<String> {
+    unsafe {
+        let storage = sys::furi_record_open(c"storage".as_ptr()) as *mut sys::Storage;
+        let dir = sys::storage_file_alloc(storage);
+
+        let mut names = Vec::new();
+        if sys::storage_dir_open(dir, path.as_ptr()) {
+            let mut info: sys::FileInfo = core::mem::zeroed();
+            let mut name = [0u8; MAX_NAME_LEN];
+            let capacity = name.len() as u16;
+            while sys::storage_dir_read(dir, &mut info, name.as_mut_ptr() as *mut _, capacity) {
+                if sys::file_info_is_dir(&info) {
+                    continue;
+                }
+                if let Ok(name) = CStr::from_bytes_until_nul(&name) {
+                    names.push(String::from_utf8_lossy(name.to_bytes()).into_owned());
+                }
+            }
+            sys::storage_dir_close(dir);
+        }
+
+        sys::storage_file_free(dir);
+        sys::furi_record_close(c"storage".as_ptr());
+        names
+    }
+}
+
+/// Delete `path` if it exists
+pub unsafe fn remove(path: &CStr) {
+    unsafe {
+        let storage = sys::furi_record_open(c"storage".as_ptr()) as *mut sys::Storage;
+        sys::storage_simply_remove(storage, path.as_ptr());
+        sys::furi_record_close(c"storage".as_ptr());
+    }
+}
+
+/// Look up `key` and parse it, falling back to `default`
+pub fn get_parsed<T: core::str::FromStr>(entries: &[(String, String)], key: &str, default: T) -> T {
+    entries
+        .iter()
+        .find(|(k, _)| k == key)
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -2,6 +2,19 @@
 //!
 //! This module provides type-safe abstractions for different display types,
 //! including their image format requirements and protocol selection.
+//! `TagType::ALL` doubles as the tag database: the tag menu is built from it
+//! and part numbers/aliases can be searched. Entries marked `experimental`
+//! have register tables nobody has confirmed on hardware yet; they are only
+//! listed when the "Experimental" setting is on.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use flipperzero_sys as sys;
+
+use crate::controller::{Controller, FaceSelect, Geometry};
 
 /// Marker type for BWR (3-color: Black, White, Red) image format
 #[derive(Debug, Clone, Copy)]
@@ -11,6 +24,14 @@ pub struct Bwr;
 #[derive(Debug, Clone, Copy)]
 pub struct Bwry;
 
+/// Marker type for monochrome (Black, White) image format
+#[derive(Debug, Clone, Copy)]
+pub struct Mono;
+
+/// Marker type for ACeP (7-color) image format
+#[derive(Debug, Clone, Copy)]
+pub struct Acep;
+
 /// Image format enum for runtime selection
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ImageFormat {
@@ -18,6 +39,39 @@ pub enum ImageFormat {
     Bwr,
     /// 4-color: Black, White, Red, Yellow
     Bwry,
+    /// 2-color: Black, White
+    Mono,
+    /// 7-color ACeP: Black, White, Green, Blue, Red, Yellow, Orange
+    Acep,
+}
+
+/// Native orientation of a panel's RAM (the direction its rows run)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanelOrientation {
+    /// RAM rows run along the short side
+    Portrait,
+    /// RAM rows run along the long side
+    Landscape,
+}
+
+/// Corner the panel starts scanning its RAM from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanDirection {
+    /// First RAM byte is the image's top-left pixel
+    Normal,
+    /// First RAM byte is the image's bottom-right pixel (mounted upside down)
+    Reversed,
+}
+
+/// Order a panel takes the bytes of its packed frame in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanOrder {
+    /// Row after row, each left to right
+    RowMajor,
+    /// Byte column after byte column, each top to bottom
+    ColumnMajor,
+    /// Row after row, every second row's bytes right to left
+    Serpentine,
 }
 
 /// Protocol enum for runtime selection
@@ -27,6 +81,103 @@ pub enum Protocol {
     IsodepBwry,
     /// IsoDep GenB protocol for 3-color displays
     IsodepGenb,
+    /// ST25DV fast-transfer mailbox (ISO 15693) for DIY frames
+    St25dvMailbox,
+    /// NTAG21x page writes (ISO 14443-3A) for e-ink price tags
+    NtagPages,
+    /// Compressed image file upload for electronic shelf labels
+    IsodepEsl,
+}
+
+impl Protocol {
+    /// NFC protocol the tag answers on
+    pub fn nfc_protocol(self) -> sys::NfcProtocol {
+        match self {
+            Protocol::IsodepBwry | Protocol::IsodepGenb | Protocol::IsodepEsl => {
+                sys::NfcProtocolIso14443_4a
+            }
+            Protocol::St25dvMailbox => sys::NfcProtocolIso15693_3,
+            Protocol::NtagPages => sys::NfcProtocolIso14443_3a,
+        }
+    }
+
+    /// True for ISO 14443-4 (IsoDep) protocols, which an external PN532 can carry
+    pub fn is_isodep(self) -> bool {
+        self.nfc_protocol() == sys::NfcProtocolIso14443_4a
+    }
+}
+
+/// Waits a write makes for the tag (ms)
+///
+/// The IsoDep state machines sleep only for these and for the register
+/// delays a controller needs whatever the panel (UC8151 power-on), so a
+/// slower panel is tuned in its `TagType` entry. ST25DV and NTAG writes
+/// keep their poll timing in their own configs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingProfile {
+    /// After GPIO/power steps 0 and 1
+    pub gpio_ms: [u32; 2],
+    /// After the panel setup: GenB's controller init table, or each of
+    /// BWRY's display init and 4-color mode registers
+    pub setup_ms: u32,
+    /// After the last image packet, before the refresh command
+    pub data_ms: u32,
+    /// Before the first status poll; not spent if the tag held its reply
+    /// to the refresh command until done
+    pub refresh_ms: u32,
+    /// Between status polls
+    pub poll_ms: u32,
+    /// Status polls after the refresh before the write gives up with
+    /// `NfcError::RefreshTimeout`
+    pub max_polls: u32,
+    /// After clearing the first cleanup register (BWRY)
+    pub cleanup_ms: u32,
+}
+
+impl TimingProfile {
+    /// GenB, as the official app sends it
+    pub const GENB: TimingProfile = TimingProfile {
+        gpio_ms: [50, 50],
+        setup_ms: 100,
+        data_ms: 0,
+        refresh_ms: 4000,
+        poll_ms: 200,
+        max_polls: 150,
+        cleanup_ms: 0,
+    };
+
+    /// BWRY, as the official app sends it; four colors refresh slowly
+    pub const BWRY: TimingProfile = TimingProfile {
+        gpio_ms: [50, 200],
+        setup_ms: 100,
+        data_ms: 50,
+        refresh_ms: 10000,
+        poll_ms: 400,
+        max_polls: 100,
+        cleanup_ms: 200,
+    };
+
+    /// Shelf labels, which decode the uploaded file before refreshing
+    pub const ESL: TimingProfile = TimingProfile {
+        gpio_ms: [0, 0],
+        setup_ms: 0,
+        data_ms: 0,
+        refresh_ms: 2000,
+        poll_ms: 200,
+        max_polls: 150,
+        cleanup_ms: 0,
+    };
+
+    /// No waits, for protocols that don't use the profile
+    pub const NONE: TimingProfile = TimingProfile {
+        gpio_ms: [0, 0],
+        setup_ms: 0,
+        data_ms: 0,
+        refresh_ms: 0,
+        poll_ms: 0,
+        max_polls: 0,
+        cleanup_ms: 0,
+    };
 }
 
 /// Tag type combining display info, image format, and protocol
@@ -35,43 +186,277 @@ pub enum Protocol {
 pub struct TagType {
     /// Human-readable display name
     pub name: &'static str,
-    /// Display width in pixels
+    /// Vendor part number printed on the tag or its box
+    pub part_number: &'static str,
+    /// Other names the tag is sold under
+    pub aliases: &'static [&'static str],
+    /// Image width in pixels, as the tag is viewed
     pub width: usize,
-    /// Display height in pixels
+    /// Image height in pixels, as the tag is viewed
     pub height: usize,
+    /// Native RAM orientation; images are rotated to match when packing
+    pub orientation: PanelOrientation,
+    /// RAM scan direction relative to the viewed image
+    pub scan: ScanDirection,
+    /// Byte order of the packed frame (see `pack`)
+    pub scan_order: ScanOrder,
     /// Required image format
     pub image_format: ImageFormat,
     /// NFC protocol to use
     pub protocol: Protocol,
+    /// Panel controller behind register-passthrough protocols (GenB)
+    pub controller: Option<Controller>,
+    /// Buffer-select registers of the second display on dual-face badges
+    /// (GenB only); both faces are uploaded in one session
+    pub rear_face: Option<FaceSelect>,
+    /// Delays of the write sequence
+    pub timing: TimingProfile,
+    /// Register tables not yet confirmed on a real tag; hidden unless enabled
+    pub experimental: bool,
 }
 
 impl TagType {
     /// 1.54inch e-Paper Y - BWRY 4-color display
+    ///
+    /// This is the DMPL0154FN1 tag; the BWRY sequence is the protocol its
+    /// official app uses, so it needs no separate protocol variant.
     pub const EPAPER_Y_154: TagType = TagType {
         name: "1.54inch e-Paper Y",
+        part_number: "DMPL0154FN1",
+        aliases: &["1.54 BWRY"],
         width: 200,
         height: 200,
         image_format: ImageFormat::Bwry,
         protocol: Protocol::IsodepBwry,
+        orientation: PanelOrientation::Portrait,
+        scan: ScanDirection::Normal,
+        scan_order: ScanOrder::RowMajor,
+        controller: None,
+        rear_face: None,
+        timing: TimingProfile::BWRY,
+        experimental: false,
     };
 
     /// 1.54inch e-Paper B - BWR 3-color display
     pub const EPAPER_B_154: TagType = TagType {
         name: "1.54inch e-Paper B",
+        part_number: "GDEY0154D90LT",
+        aliases: &["1.54 BWR"],
         width: 200,
         height: 200,
         image_format: ImageFormat::Bwr,
         protocol: Protocol::IsodepGenb,
+        orientation: PanelOrientation::Portrait,
+        scan: ScanDirection::Normal,
+        scan_order: ScanOrder::RowMajor,
+        controller: Some(Controller::Ssd1680),
+        rear_face: None,
+        timing: TimingProfile::GENB,
+        experimental: false,
     };
 
-    /// All supported tag types
+    /// 1.54inch e-Paper B, 152x152 variant - BWR over GenB with UC8151 tables
+    /// (experimental)
+    ///
+    /// Planes are 2,888 bytes; resolution and buffer sizes follow the geometry.
+    pub const EPAPER_B_154_152: TagType = TagType {
+        name: "1.54inch e-Paper B 152",
+        part_number: "GDEW0154Z17",
+        aliases: &["1.54 BWR 152x152"],
+        width: 152,
+        height: 152,
+        image_format: ImageFormat::Bwr,
+        protocol: Protocol::IsodepGenb,
+        orientation: PanelOrientation::Portrait,
+        scan: ScanDirection::Normal,
+        scan_order: ScanOrder::RowMajor,
+        controller: Some(Controller::Uc8151),
+        rear_face: None,
+        // The UC8151 init table settles at power-on, not at its end
+        timing: TimingProfile { setup_ms: 0, ..TimingProfile::GENB },
+        experimental: true,
+    };
+
+    /// DIY e-paper frame behind an ST25DV dynamic tag - BWR buffer via mailbox
+    /// (experimental)
+    pub const ST25DV_FRAME: TagType = TagType {
+        name: "ST25DV e-Paper frame",
+        part_number: "ST25DV04K",
+        aliases: &["ST25DV16K", "ST25DV64K"],
+        width: 200,
+        height: 200,
+        image_format: ImageFormat::Bwr,
+        protocol: Protocol::St25dvMailbox,
+        orientation: PanelOrientation::Portrait,
+        scan: ScanDirection::Normal,
+        scan_order: ScanOrder::RowMajor,
+        controller: None,
+        rear_face: None,
+        timing: TimingProfile::NONE,
+        experimental: true,
+    };
+
+    /// NTAG-based e-ink price tag - BWR buffer via page windows (experimental)
+    pub const NTAG_PRICE_TAG: TagType = TagType {
+        name: "NTAG e-ink price tag",
+        part_number: "NTAG216",
+        aliases: &["NTAG213", "NTAG215"],
+        width: 200,
+        height: 200,
+        image_format: ImageFormat::Bwr,
+        protocol: Protocol::NtagPages,
+        orientation: PanelOrientation::Portrait,
+        scan: ScanDirection::Normal,
+        scan_order: ScanOrder::RowMajor,
+        controller: None,
+        rear_face: None,
+        timing: TimingProfile::NONE,
+        experimental: true,
+    };
+
+    /// NFC electronic shelf label - BWR planes in a compressed image file
+    /// (experimental)
+    pub const ESL_154: TagType = TagType {
+        name: "1.54inch shelf label",
+        part_number: "ESL 1.54",
+        aliases: &["ESL"],
+        width: 200,
+        height: 200,
+        image_format: ImageFormat::Bwr,
+        protocol: Protocol::IsodepEsl,
+        orientation: PanelOrientation::Portrait,
+        scan: ScanDirection::Normal,
+        scan_order: ScanOrder::RowMajor,
+        controller: None,
+        rear_face: None,
+        timing: TimingProfile::ESL,
+        experimental: true,
+    };
+
+    /// 2.13inch e-Paper B - BWR over GenB with SSD1680 tables (experimental)
+    ///
+    /// The panel's RAM rows run along the 122-pixel side, so landscape images
+    /// are rotated when packing.
+    pub const EPAPER_B_213: TagType = TagType {
+        name: "2.13inch e-Paper B",
+        part_number: "GDEY0213Z98",
+        aliases: &["2.13 BWR"],
+        width: 250,
+        height: 122,
+        image_format: ImageFormat::Bwr,
+        protocol: Protocol::IsodepGenb,
+        orientation: PanelOrientation::Portrait,
+        scan: ScanDirection::Normal,
+        scan_order: ScanOrder::RowMajor,
+        controller: Some(Controller::Ssd1680),
+        rear_face: None,
+        timing: TimingProfile::GENB,
+        experimental: true,
+    };
+
+    /// 2.9inch e-Paper B - BWR over GenB with SSD1680 tables (experimental)
+    pub const EPAPER_B_290: TagType = TagType {
+        name: "2.9inch e-Paper B",
+        part_number: "GDEY029Z95",
+        aliases: &["2.9 BWR"],
+        width: 296,
+        height: 128,
+        image_format: ImageFormat::Bwr,
+        protocol: Protocol::IsodepGenb,
+        orientation: PanelOrientation::Portrait,
+        scan: ScanDirection::Normal,
+        scan_order: ScanOrder::RowMajor,
+        controller: Some(Controller::Ssd1680),
+        rear_face: None,
+        timing: TimingProfile::GENB,
+        experimental: true,
+    };
+
+    /// All supported tag types, experimental ones last
     pub const ALL: &'static [TagType] = &[
         Self::EPAPER_Y_154,
         Self::EPAPER_B_154,
+        Self::EPAPER_B_154_152,
+        Self::ST25DV_FRAME,
+        Self::NTAG_PRICE_TAG,
+        Self::ESL_154,
+        Self::EPAPER_B_213,
+        Self::EPAPER_B_290,
     ];
 
+    /// Packed frame buffer size in bytes
+    ///
+    /// BWRY packs 2 bits per pixel into one buffer; BWR sends a 1bpp B/W
+    /// plane followed by a 1bpp red plane. Both are 10,000 bytes at 200x200.
+    /// Mono is one 1bpp plane and ACeP 4 bits per pixel.
+    pub fn image_data_size(&self) -> usize {
+        let (width, height) = self.native_size();
+        match self.image_format {
+            ImageFormat::Bwry => (width * 2).div_ceil(8) * height,
+            ImageFormat::Bwr => self.geometry().plane_size() * 2,
+            ImageFormat::Mono => self.geometry().plane_size(),
+            ImageFormat::Acep => (width * 4).div_ceil(8) * height,
+        }
+    }
+
+    /// True if the viewed image is a quarter turn from the panel's RAM rows
+    pub fn needs_rotation(&self) -> bool {
+        self.width != self.height
+            && (self.width > self.height) != (self.orientation == PanelOrientation::Landscape)
+    }
+
+    /// Width and height in the panel's native RAM order
+    pub fn native_size(&self) -> (usize, usize) {
+        if self.needs_rotation() {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    /// Panel geometry for controller window registers (native RAM order)
+    pub fn geometry(&self) -> Geometry {
+        let (width, height) = self.native_size();
+        Geometry { width, height }
+    }
+
     /// Get tag type by index
     pub fn get(index: usize) -> Option<&'static TagType> {
         Self::ALL.get(index)
     }
+
+    /// True if `query` (case-insensitive) appears in the name, part number or an alias
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_ascii_uppercase();
+        !query.is_empty()
+            && core::iter::once(self.name)
+                .chain(core::iter::once(self.part_number))
+                .chain(self.aliases.iter().copied())
+                .any(|s| s.to_ascii_uppercase().contains(&query))
+    }
+
+    /// Find the first tag matching `query`, returning its index in `ALL`
+    ///
+    /// Experimental tags only match when `experimental` is set.
+    pub fn find(query: &str, experimental: bool) -> Option<usize> {
+        Self::ALL
+            .iter()
+            .position(|tag| (experimental || !tag.experimental) && tag.matches(query))
+    }
+
+    /// Distinct panel sizes of the verified tags, in table order
+    pub fn sizes() -> Vec<(usize, usize)> {
+        let mut sizes: Vec<(usize, usize)> = Vec::new();
+        for tag in Self::ALL.iter().filter(|tag| !tag.experimental) {
+            if !sizes.contains(&(tag.width, tag.height)) {
+                sizes.push((tag.width, tag.height));
+            }
+        }
+        sizes
+    }
+
+    /// Menu label: display name and part number
+    pub fn label(&self) -> String {
+        alloc::format!("{} ({})", self.name, self.part_number)
+    }
 }
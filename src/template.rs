@@ -0,0 +1,166 @@
+//! Label and badge templates
+//!
+//! A template (`.template`) lays a name badge or shelf label out once: boxes
+//! for text typed in on the Flipper and, optionally, a logo. Opening one
+//! asks for each field in turn, fits every answer into its box at the
+//! largest font scale that fits and renders the label for the tag.
+//!
+//! It is a `key=value` file like a recipe. Boxes are `x,y,width,height` in
+//! panel pixels from the top left. A `field` adds its alignment and the
+//! prompt shown when asking for it (last, so it may contain commas); fields
+//! are asked for in file order. The `logo` file is found like a recipe's
+//! image and is scaled into its box. `tag` is optional: without it the tag
+//! is picked from the list as usual.
+//!
+//! ```text
+//! tag=DMPL0154FN1
+//! logo=4,4,64,64,logo.bmp
+//! field=72,8,124,56,left,Name
+//! field=4,76,192,28,center,Title
+//! field=4,112,192,20,center,Company
+//! ```
+
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use crate::image::{
+    self, AnyImage, DecodeObserver, ImageError, ImageResult, TextAlign, TextBlock, TextStyle,
+    BLACK,
+};
+use crate::quantize::QuantizerKind;
+use crate::recipe;
+use crate::resample::Rect;
+use crate::storage;
+use crate::tag_type::TagType;
+
+/// A box the user fills with text
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub area: Rect,
+    pub align: TextAlign,
+    /// Header of the text input that asks for it
+    pub prompt: CString,
+}
+
+/// An image file scaled into a box
+#[derive(Debug, Clone)]
+pub struct Logo {
+    pub area: Rect,
+    /// Image file, absolute
+    pub path: CString,
+}
+
+/// A label layout read from the SD card
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// Tag type, by `TagType::part_number`, if the template names one
+    pub tag: Option<&'static TagType>,
+    pub logo: Option<Logo>,
+    pub fields: Vec<Field>,
+}
+
+/// `x,y,width,height`, each a whole number; None if any is missing or the
+/// box is empty
+fn parse_rect(parts: &[&str]) -> Option<Rect> {
+    let number = |i: usize| parts.get(i)?.trim().parse::<usize>().ok();
+    let rect = Rect { left: number(0)?, top: number(1)?, width: number(2)?, height: number(3)? };
+    (rect.width > 0 && rect.height > 0).then_some(rect)
+}
+
+impl Template {
+    /// Read the template at `path`
+    ///
+    /// None if the file is unreadable, has no fields, names a tag type this
+    /// build doesn't know or has a malformed `field` or `logo` line; a typo
+    /// should not quietly drop part of the label.
+    pub unsafe fn load(path: &CStr) -> Option<Self> {
+        unsafe {
+            let mut template = Template { tag: None, logo: None, fields: Vec::new() };
+            for (key, value) in storage::read_kv(path) {
+                match key.as_str() {
+                    "tag" => {
+                        let tag = TagType::ALL.iter().find(|tag| tag.part_number == value)?;
+                        template.tag = Some(tag);
+                    }
+                    "logo" => {
+                        let parts: Vec<&str> = value.splitn(5, ',').collect();
+                        let file = parts.get(4).map(|file| file.trim()).filter(|f| !f.is_empty())?;
+                        let path = CString::new(recipe::resolve(path, file)?).ok()?;
+                        template.logo = Some(Logo { area: parse_rect(&parts)?, path });
+                    }
+                    "field" => {
+                        let parts: Vec<&str> = value.splitn(6, ',').collect();
+                        let align = TextAlign::from_key(parts.get(4)?.trim())?;
+                        let prompt = parts.get(5).map(|prompt| prompt.trim())?;
+                        template.fields.push(Field {
+                            area: parse_rect(&parts)?,
+                            align,
+                            prompt: CString::new(prompt).ok()?,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            (!template.fields.is_empty()).then_some(template)
+        }
+    }
+
+    /// Whether every box lies on `tag`'s panel
+    pub fn fits(&self, tag: &TagType) -> bool {
+        let areas = self.fields.iter().map(|field| field.area);
+        areas.chain(self.logo.as_ref().map(|logo| logo.area)).all(|area| {
+            area.left + area.width <= tag.width && area.top + area.height <= tag.height
+        })
+    }
+
+    /// Render the label for `tag` with one answer per field, in order
+    ///
+    /// Empty answers leave their box blank. Fails with `InvalidSize` if a
+    /// box is off the panel or an answer doesn't fit its box, and with the
+    /// decoder's error if the logo can't be read. The logo is decoded with
+    /// `quantizer`; text is black.
+    pub fn render(
+        &self,
+        answers: &[String],
+        tag: &TagType,
+        quantizer: QuantizerKind,
+        observer: &mut dyn DecodeObserver,
+    ) -> ImageResult<AnyImage> {
+        if !self.fits(tag) {
+            return Err(ImageError::InvalidSize);
+        }
+        let logo = match &self.logo {
+            Some(Logo { area, path }) => {
+                let size = (area.width, area.height);
+                Some((*area, image::load_region(path, size, tag, quantizer)?))
+            }
+            None => None,
+        };
+        let blocks = self
+            .fields
+            .iter()
+            .zip(answers)
+            .filter(|(_, answer)| !answer.trim().is_empty())
+            .map(|(field, answer)| {
+                let style = TextStyle { scale: None, align: field.align };
+                TextBlock::fit(answer, style, field.area).ok_or(ImageError::InvalidSize)
+            })
+            .collect::<ImageResult<Vec<_>>>()?;
+
+        image::render(tag, observer, &mut |y, colors| {
+            if let Some((area, pixels)) = &logo
+                && let Some(row) = y.checked_sub(area.top).filter(|&row| row < area.height)
+            {
+                let row = &pixels[row * area.width..(row + 1) * area.width];
+                colors[area.left..area.left + area.width].copy_from_slice(row);
+            }
+            for block in &blocks {
+                block.paint(y, colors, BLACK);
+            }
+        })
+    }
+}
@@ -0,0 +1,218 @@
+//! Black/white threshold screen
+//!
+//! Opened from the write menu for an image file. The file is read once into
+//! a thumbnail of its colors; every Left/Right press matches that thumbnail
+//! to the panel again with the new threshold, so the effect shows at once
+//! instead of after a full decode. OK keeps the threshold (the image is then
+//! decoded with it) and Back leaves the old one.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicU8, Ordering};
+use flipperzero_sys as sys;
+
+use crate::image::{DecodeOptions, Rotation};
+use crate::input;
+use crate::preview::{Thumbnail, PREVIEW_SIZE};
+use crate::quantize::{AccentPull, QuantizerKind, Rgb, Threshold};
+use crate::resample::Fit;
+
+/// Still adjusting
+const PENDING: u8 = 0;
+/// Keep the new threshold
+const APPLY: u8 = 1;
+/// Keep the old one
+const CANCEL: u8 = 2;
+
+/// What ended the threshold screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThresholdOutcome {
+    Apply(Threshold),
+    Cancel,
+}
+
+/// View model shared with the GUI thread
+struct ThresholdModel {
+    thumbnail: Thumbnail,
+    threshold: Threshold,
+}
+
+/// Threshold view plus the thumbnail it matches
+pub struct ThresholdView {
+    view: *mut sys::View,
+    decision: AtomicU8,
+    /// `PREVIEW_SIZE` rows of source colors
+    pixels: Vec<Rgb>,
+    palette: &'static [Rgb],
+    /// Conversion the thumbnail is matched with; its threshold is adjusted
+    options: DecodeOptions,
+    view_dispatcher: *mut sys::ViewDispatcher,
+    /// Custom event sent once OK or Back is pressed
+    event: u32,
+}
+
+impl ThresholdView {
+    pub fn new() -> Self {
+        Self {
+            view: core::ptr::null_mut(),
+            decision: AtomicU8::new(PENDING),
+            pixels: Vec::new(),
+            palette: &[],
+            options: DecodeOptions {
+                quantizer: QuantizerKind::Nearest,
+                rotation: Rotation::None,
+                fit: Fit::Exact,
+                threshold: Threshold::DEFAULT,
+                accent_pull: AccentPull::OFF,
+            },
+            view_dispatcher: core::ptr::null_mut(),
+            event: 0,
+        }
+    }
+
+    /// Allocate the view; `self` must stay at a fixed address while it exists
+    pub unsafe fn alloc(&mut self) -> bool {
+        unsafe {
+            self.view = sys::view_alloc();
+            if self.view.is_null() {
+                return false;
+            }
+            sys::view_allocate_model(
+                self.view,
+                sys::ViewModelTypeLocking,
+                core::mem::size_of::<ThresholdModel>(),
+            );
+            sys::view_set_context(self.view, self as *mut _ as *mut c_void);
+            sys::view_set_draw_callback(self.view, Some(draw_callback));
+            sys::view_set_input_callback(self.view, Some(input_callback));
+            true
+        }
+    }
+
+    pub unsafe fn free(&mut self) {
+        unsafe {
+            if !self.view.is_null() {
+                sys::view_free(self.view);
+                self.view = core::ptr::null_mut();
+            }
+        }
+    }
+
+    pub fn view(&self) -> *mut sys::View {
+        self.view
+    }
+
+    /// Start adjusting the threshold of `options` for the thumbnail
+    /// `pixels`, matched to `palette`
+    ///
+    /// `event` goes to `view_dispatcher` once the user decides.
+    pub unsafe fn reset(
+        &mut self,
+        pixels: Vec<Rgb>,
+        palette: &'static [Rgb],
+        options: DecodeOptions,
+        view_dispatcher: *mut sys::ViewDispatcher,
+        event: u32,
+    ) {
+        self.pixels = pixels;
+        self.palette = palette;
+        self.options = options;
+        self.view_dispatcher = view_dispatcher;
+        self.event = event;
+        self.decision.store(PENDING, Ordering::Relaxed);
+        unsafe { self.publish() };
+    }
+
+    /// How the screen was left; the thumbnail is dropped
+    pub fn outcome(&mut self) -> ThresholdOutcome {
+        self.pixels = Vec::new();
+        match self.decision.load(Ordering::Relaxed) {
+            APPLY => ThresholdOutcome::Apply(self.options.threshold),
+            _ => ThresholdOutcome::Cancel,
+        }
+    }
+
+    /// Match the thumbnail with the current threshold into the view model
+    unsafe fn publish(&mut self) {
+        let mut thumbnail = Thumbnail::EMPTY;
+        let mut quantizer = self.options.build_quantizer(self.palette, PREVIEW_SIZE);
+        for (py, row) in self.pixels.chunks(PREVIEW_SIZE).enumerate() {
+            for (px, color) in row.iter().enumerate() {
+                thumbnail.set(px, py, quantizer.quantize(px, *color));
+            }
+            quantizer.next_row();
+        }
+        unsafe {
+            let model = sys::view_get_model(self.view) as *mut ThresholdModel;
+            (*model).thumbnail = thumbnail;
+            (*model).threshold = self.options.threshold;
+            sys::view_commit_model(self.view, true);
+        }
+    }
+}
+
+/// Draw the thumbnail, the level and the key hints
+unsafe extern "C" fn draw_callback(canvas: *mut sys::Canvas, model: *mut c_void) {
+    unsafe {
+        let model = &*(model as *const ThresholdModel);
+        model.thumbnail.draw(canvas);
+
+        sys::canvas_set_font(canvas, sys::FontPrimary);
+        sys::canvas_draw_str(canvas, 68, 12, c"Threshold".as_ptr());
+
+        let text = alloc::format!("< {} >", model.threshold);
+        if let Ok(c_text) = alloc::ffi::CString::new(text) {
+            sys::canvas_draw_str(canvas, 68, 28, c_text.as_ptr());
+        }
+
+        sys::canvas_set_font(canvas, sys::FontSecondary);
+        sys::canvas_draw_str(canvas, 68, 40, c"Right: darker".as_ptr());
+        let hints = [(sys::InputKeyOk, "apply", 52), (sys::InputKeyBack, "cancel", 62)];
+        for (key, action, y) in hints {
+            let hint = alloc::format!("{}: {}", input::label(key).to_str().unwrap_or(""), action);
+            if let Ok(c_hint) = alloc::ffi::CString::new(hint) {
+                sys::canvas_draw_str(canvas, 68, y, c_hint.as_ptr());
+            }
+        }
+    }
+}
+
+/// Left/Right move the threshold; OK applies it and Back keeps the old one
+unsafe extern "C" fn input_callback(event: *mut sys::InputEvent, context: *mut c_void) -> bool {
+    unsafe {
+        let screen = &mut *(context as *mut ThresholdView);
+        let key = input::remap((*event).key);
+        let kind = (*event).type_;
+        let pressed = kind == sys::InputTypeShort || kind == sys::InputTypeRepeat;
+        if key == sys::InputKeyLeft || key == sys::InputKeyRight {
+            if pressed {
+                let threshold = screen.options.threshold;
+                screen.options.threshold = if key == sys::InputKeyLeft {
+                    threshold.lower()
+                } else {
+                    threshold.higher()
+                };
+                screen.publish();
+            }
+            return true;
+        }
+        let decision = if key == sys::InputKeyOk {
+            APPLY
+        } else if key == sys::InputKeyBack {
+            CANCEL
+        } else {
+            return false;
+        };
+        if kind == sys::InputTypeShort
+            && screen
+                .decision
+                .compare_exchange(PENDING, decision, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            sys::view_dispatcher_send_custom_event(screen.view_dispatcher, screen.event);
+        }
+        true
+    }
+}
@@ -0,0 +1,407 @@
+//! Transport abstraction between protocol state machines and NFC readers
+//!
+//! Protocols talk to tags only through `Transport`: activate a tag, exchange
+//! ISO 14443-4 blocks with it, deactivate it. `PollerTransport` is the
+//! default and runs on the Flipper's own NFC HAL, with the block layer in
+//! `iso_dep`; `Pn532` drives an external reader on the GPIO UART, and
+//! `MockTransport` answers in place of a tag for dry runs and checks.
+//! Blocks cross the trait as byte slices, so the HAL's `BitBuffer`s stay
+//! inside the back ends that need them. Further back ends (ISO 15693
+//! readers) plug in here without touching protocol logic.
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ffi::c_void;
+use flipperzero_sys as sys;
+
+use crate::iso_dep::IsoDep;
+use crate::nfc_guard::NfcGuard;
+use crate::protocol_common::{log_error, log_info, NfcError, NfcResult, TagWait, CANCEL_POLL_MS};
+
+/// A link that carries APDUs to one tag at a time
+pub trait Transport {
+    /// Wait for a tag and bring it up to the point where blocks can be exchanged
+    unsafe fn activate(&mut self) -> NfcResult<()>;
+
+    /// Send the block `tx` and put the reply in `rx`
+    fn exchange(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool;
+
+    /// Send the block `tx` as one link of a chained command (I-block with
+    /// the chaining bit set); true once the tag has acknowledged it and
+    /// waits for the rest
+    fn exchange_chained(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool;
+
+    /// Most bytes `exchange` carries in one block; longer commands are split
+    /// into a chain (see `protocol_common::send_command`)
+    fn max_block(&self) -> usize;
+
+    /// How long the tag held its reply to the last exchange with waiting
+    /// time extensions (S(WTX)); 0 if it answered within its frame waiting
+    /// time, or if the reader handles WTX out of sight
+    fn held_ms(&self) -> u32;
+
+    /// Release the tag; `activate` may be called again afterwards
+    unsafe fn deactivate(&mut self);
+
+    /// UID of the activated tag (empty if the reader did not report one)
+    fn uid(&self) -> Vec<u8>;
+
+    /// Historical bytes of the activated tag's ATS (empty if it sent none)
+    fn historical_bytes(&self) -> Vec<u8>;
+}
+
+/// State shared with the poller callback on the NFC thread
+struct Session {
+    /// Set by the callback once the tag is ready, cleared on stop
+    active: bool,
+    /// Ask the callback to return control to the poller
+    stop: bool,
+    /// Block to send and its reply, unframed
+    tx_buf: *mut sys::BitBuffer,
+    rx_buf: *mut sys::BitBuffer,
+    /// Send the pending block with the chaining bit set
+    chain: bool,
+    /// Block layer of the activated tag
+    iso_dep: IsoDep,
+    /// Framed blocks as sent and received
+    frame_tx: *mut sys::BitBuffer,
+    frame_rx: *mut sys::BitBuffer,
+    /// Outcome of the last exchange
+    ok: bool,
+    /// App thread -> callback: an exchange or stop is pending
+    request: *mut sys::FuriSemaphore,
+    /// Callback -> app thread: tag ready or exchange finished
+    done: *mut sys::FuriSemaphore,
+}
+
+/// Capacity of the frame buffers: the largest frame a tag may accept
+const FRAME_CAPACITY: usize = 256;
+
+/// Capacity of the block buffers: a reply the tag chained over several
+/// frames is put back together in them
+const BLOCK_CAPACITY: usize = 512;
+
+/// Built-in ISO 14443-4A reader
+///
+/// The NFC HAL only allows frames to be sent from inside the poller
+/// callback, so once the tag is ready the callback stays in a loop and
+/// carries out exchanges posted by the app thread until `deactivate`. The
+/// poller is an ISO 14443-3A one; the callback sends RATS and frames the
+/// blocks (`IsoDep`).
+pub struct PollerTransport {
+    nfc: NfcGuard,
+    poller: *mut sys::NfcPoller,
+    session: UnsafeCell<Session>,
+}
+
+impl PollerTransport {
+    /// Claim the NFC hardware and allocate the poller
+    pub fn new() -> NfcResult<Self> {
+        unsafe {
+            let nfc = NfcGuard::acquire()?;
+            let poller = sys::nfc_poller_alloc(nfc.nfc(), sys::NfcProtocolIso14443_3a);
+            if poller.is_null() {
+                return Err(NfcError::AllocFailed);
+            }
+            let transport = Self {
+                nfc,
+                poller,
+                session: UnsafeCell::new(Session {
+                    active: false,
+                    stop: false,
+                    tx_buf: sys::bit_buffer_alloc(BLOCK_CAPACITY),
+                    rx_buf: sys::bit_buffer_alloc(BLOCK_CAPACITY),
+                    chain: false,
+                    iso_dep: IsoDep::DEFAULT,
+                    frame_tx: sys::bit_buffer_alloc(FRAME_CAPACITY),
+                    frame_rx: sys::bit_buffer_alloc(FRAME_CAPACITY),
+                    ok: false,
+                    request: sys::furi_semaphore_alloc(1, 0),
+                    done: sys::furi_semaphore_alloc(1, 0),
+                }),
+            };
+            let session = &*transport.session.get();
+            if session.request.is_null() || session.done.is_null() {
+                return Err(NfcError::AllocFailed);
+            }
+            let buffers = [session.tx_buf, session.rx_buf, session.frame_tx, session.frame_rx];
+            if buffers.iter().any(|buffer| buffer.is_null()) {
+                return Err(NfcError::AllocFailed);
+            }
+            Ok(transport)
+        }
+    }
+
+    /// Hand a block to the poller callback and wait for the reply
+    fn post(&mut self, tx: &[u8], rx: &mut Vec<u8>, chain: bool) -> bool {
+        unsafe {
+            let session = self.session.get();
+            if !(*session).active {
+                return false;
+            }
+            let (tx_buf, rx_buf) = ((*session).tx_buf, (*session).rx_buf);
+            sys::bit_buffer_reset(tx_buf);
+            sys::bit_buffer_reset(rx_buf);
+            sys::bit_buffer_copy_bytes(tx_buf, tx.as_ptr(), tx.len());
+            (*session).chain = chain;
+            sys::furi_semaphore_release((*session).request);
+            sys::furi_semaphore_acquire((*session).done, sys::FuriWaitForever);
+            rx.clear();
+            let rx_size = sys::bit_buffer_get_size_bytes(rx_buf);
+            rx.extend((0..rx_size).map(|i| sys::bit_buffer_get_byte(rx_buf, i)));
+            (*session).ok
+        }
+    }
+
+    /// Stop the poller while `activate` is still waiting for a tag
+    ///
+    /// The callback may be bringing a tag up at this moment, so the stop is
+    /// posted before the poller is stopped; it then leaves its loop at once.
+    /// Whatever it did not take is cleared for the next `activate`.
+    unsafe fn abandon(&mut self) {
+        unsafe {
+            let session = self.session.get();
+            (*session).stop = true;
+            sys::furi_semaphore_release((*session).request);
+            sys::nfc_poller_stop(self.poller);
+            sys::furi_semaphore_acquire((*session).request, 0);
+            sys::furi_semaphore_acquire((*session).done, 0);
+            log_info!("Stopped waiting for a tag");
+        }
+    }
+
+    /// Poller callback: wait for the tag, then serve exchanges until stopped
+    unsafe extern "C" fn poller_callback(
+        event: sys::NfcGenericEvent,
+        context: *mut c_void,
+    ) -> sys::NfcCommand {
+        unsafe {
+            let session = &mut *(context as *mut Session);
+
+            // Keep polling until the tag is ready
+            let event_data = event.event_data as *const sys::Iso14443_3aPollerEvent;
+            if event_data.is_null() || (*event_data).type_ != sys::Iso14443_3aPollerEventTypeReady {
+                return sys::NfcCommandContinue;
+            }
+            log_info!("Tag detected!");
+
+            let poller = event.instance as *mut sys::Iso14443_3aPoller;
+            let frames = (session.frame_tx, session.frame_rx);
+            session.iso_dep = match IsoDep::activate(poller, frames.0, frames.1) {
+                Some(iso_dep) => iso_dep,
+                // Not an ISO 14443-4 tag, or it left: select again
+                None => return sys::NfcCommandReset,
+            };
+            session.active = true;
+            sys::furi_semaphore_release(session.done);
+
+            loop {
+                sys::furi_semaphore_acquire(session.request, sys::FuriWaitForever);
+                if session.stop {
+                    session.iso_dep.deselect(poller, frames);
+                    session.active = false;
+                    return sys::NfcCommandStop;
+                }
+                let (tx_buf, rx_buf) = (session.tx_buf, session.rx_buf);
+                let chain = session.chain;
+                session.ok = session.iso_dep.exchange(poller, frames, tx_buf, rx_buf, chain);
+                sys::furi_semaphore_release(session.done);
+            }
+        }
+    }
+}
+
+impl Transport for PollerTransport {
+    unsafe fn activate(&mut self) -> NfcResult<()> {
+        unsafe {
+            let session = self.session.get();
+            (*session).stop = false;
+            sys::nfc_poller_start(self.poller, Some(Self::poller_callback), session as *mut c_void);
+            let (wait, timeout) = (TagWait::start(), sys::furi_ms_to_ticks(CANCEL_POLL_MS));
+            while sys::furi_semaphore_acquire((*session).done, timeout) != sys::FuriStatusOk {
+                if let Err(e) = wait.check() {
+                    self.abandon();
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn exchange(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool {
+        self.post(tx, rx, false)
+    }
+
+    fn exchange_chained(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool {
+        self.post(tx, rx, true)
+    }
+
+    /// The tag's frame size from its ATS, less the PCB and CRC bytes
+    fn max_block(&self) -> usize {
+        unsafe { (*self.session.get()).iso_dep.max_block() }
+    }
+
+    fn held_ms(&self) -> u32 {
+        unsafe { (*self.session.get()).iso_dep.held_ms() }
+    }
+
+    unsafe fn deactivate(&mut self) {
+        unsafe {
+            let session = self.session.get();
+            if (*session).active {
+                (*session).stop = true;
+                sys::furi_semaphore_release((*session).request);
+            }
+            sys::nfc_poller_stop(self.poller);
+        }
+    }
+
+    fn uid(&self) -> Vec<u8> {
+        unsafe {
+            let data = sys::nfc_poller_get_data(self.poller) as *const sys::Iso14443_3aData;
+            if data.is_null() {
+                return Vec::new();
+            }
+            let mut uid_len = 0usize;
+            let uid = sys::iso14443_3a_get_uid(data, &mut uid_len);
+            if uid.is_null() {
+                return Vec::new();
+            }
+            core::slice::from_raw_parts(uid, uid_len).to_vec()
+        }
+    }
+
+    fn historical_bytes(&self) -> Vec<u8> {
+        unsafe { (*self.session.get()).iso_dep.historical().to_vec() }
+    }
+}
+
+impl Drop for PollerTransport {
+    fn drop(&mut self) {
+        unsafe {
+            let session = &mut *self.session.get();
+            if !session.request.is_null() {
+                sys::furi_semaphore_free(session.request);
+            }
+            if !session.done.is_null() {
+                sys::furi_semaphore_free(session.done);
+            }
+            for buffer in [session.tx_buf, session.rx_buf, session.frame_tx, session.frame_rx] {
+                if !buffer.is_null() {
+                    sys::bit_buffer_free(buffer);
+                }
+            }
+            // The guard frees the NFC instance after this
+            sys::nfc_poller_free(self.poller);
+        }
+    }
+}
+
+/// Status word the mock answers a command with when no reply is scripted
+const MOCK_REPLY: [u8; 2] = [0x90, 0x00];
+
+/// Block size of the mock: a tag announcing 64-byte frames, so image
+/// packets are chained as they are on most tags
+const MOCK_MAX_BLOCK: usize = 61;
+
+/// Stand-in for a tag, for "Simulate Write" and protocol checks
+///
+/// Every command is answered at once, with the next scripted reply or else
+/// `90 00`, and every chained block is acknowledged, so a protocol runs its
+/// whole sequence, delays and all, without hardware. Status polls get no
+/// status byte and read as ready; read-backs and the temperature read get
+/// no data and are skipped. It has no UID, so `identify` learns nothing
+/// from a simulated write. A recording mock also keeps every command it
+/// was sent, chained links joined; Simulate Write does not record, so a
+/// large frame is not held twice.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    active: bool,
+    /// Blocks exchanged since `activate`
+    frames: usize,
+    /// Keep the commands sent in `sent`
+    record: bool,
+    /// Commands sent, in order, if recording
+    sent: Vec<Vec<u8>>,
+    /// Links of the chained command being received, if recording
+    chain: Vec<u8>,
+    /// Replies for the next commands, in order
+    replies: VecDeque<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    /// A mock that keeps the commands it is sent
+    pub fn recording() -> Self {
+        Self { record: true, ..Self::default() }
+    }
+
+    /// Answer the next command not yet answered with `reply`
+    pub fn reply(&mut self, reply: &[u8]) {
+        self.replies.push_back(reply.to_vec());
+    }
+
+    /// Commands sent so far, chained links joined
+    pub fn sent(&self) -> &[Vec<u8>] {
+        &self.sent
+    }
+}
+
+impl Transport for MockTransport {
+    unsafe fn activate(&mut self) -> NfcResult<()> {
+        log_info!("Mock tag activated");
+        self.active = true;
+        self.frames = 0;
+        Ok(())
+    }
+
+    fn exchange(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool {
+        self.frames += 1;
+        if self.record {
+            let mut command = core::mem::take(&mut self.chain);
+            command.extend_from_slice(tx);
+            self.sent.push(command);
+        }
+        rx.clear();
+        match self.replies.pop_front() {
+            Some(reply) => rx.extend_from_slice(&reply),
+            None => rx.extend_from_slice(&MOCK_REPLY),
+        }
+        self.active
+    }
+
+    fn exchange_chained(&mut self, tx: &[u8], rx: &mut Vec<u8>) -> bool {
+        self.frames += 1;
+        if self.record {
+            self.chain.extend_from_slice(tx);
+        }
+        rx.clear();
+        self.active
+    }
+
+    fn max_block(&self) -> usize {
+        MOCK_MAX_BLOCK
+    }
+
+    fn held_ms(&self) -> u32 {
+        0
+    }
+
+    unsafe fn deactivate(&mut self) {
+        if self.active {
+            log_info!("Mock tag released after {} blocks", self.frames);
+        }
+        self.active = false;
+    }
+
+    fn uid(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn historical_bytes(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,48 @@
+//! Background worker thread for long-running jobs
+//!
+//! Jobs run on their own FuriThread so the view dispatcher keeps handling
+//! input and redraws. A job reports completion by sending a custom event to
+//! the dispatcher; the handler then joins the worker and reads the result.
+
+use core::ffi::{c_void, CStr};
+use flipperzero_sys as sys;
+
+/// Stack size for worker threads
+pub const WORKER_STACK_SIZE: u32 = 4096;
+
+/// Entry point of a worker job
+pub type WorkerCallback = unsafe extern "C" fn(context: *mut c_void) -> i32;
+
+/// A started worker thread
+pub struct Worker {
+    thread: *mut sys::FuriThread,
+}
+
+impl Worker {
+    /// Start `callback(context)` on a new thread
+    ///
+    /// `context` must stay valid until `join` returns.
+    pub unsafe fn start(name: &CStr, callback: WorkerCallback, context: *mut c_void) -> Option<Self> {
+        unsafe {
+            let thread = sys::furi_thread_alloc_ex(
+                name.as_ptr(),
+                WORKER_STACK_SIZE,
+                Some(callback),
+                context,
+            );
+            if thread.is_null() {
+                return None;
+            }
+            sys::furi_thread_start(thread);
+            Some(Self { thread })
+        }
+    }
+
+    /// Wait for the job to return and free the thread
+    pub unsafe fn join(self) {
+        unsafe {
+            sys::furi_thread_join(self.thread);
+            sys::furi_thread_free(self.thread);
+        }
+    }
+}
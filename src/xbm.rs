@@ -0,0 +1,166 @@
+//! X bitmap (XBM) decoding
+//!
+//! XBM files are C source: `#define NAME_width W`, `#define NAME_height H`
+//! and a `NAME_bits[]` array of hex bytes. Each row is padded to a whole
+//! byte and pixels are stored least significant bit first. Set bits are
+//! black, clear bits white.
+
+use flipperzero_sys as sys;
+
+use crate::image::{ImageError, ImageResult, RowReader};
+use crate::quantize::Rgb;
+
+/// Bytes fetched from the file per read
+const BUFFER_SIZE: usize = 256;
+
+/// Longest token kept; longer identifiers are cut short, which is harmless
+/// because only their `_width`/`_height` endings matter
+const TOKEN_LEN: usize = 64;
+
+/// Streaming reader over an open XBM file
+///
+/// The file handle stays owned by the caller, which closes it.
+pub struct XbmReader {
+    file: *mut sys::File,
+    buf: [u8; BUFFER_SIZE],
+    pos: usize,
+    len: usize,
+    token: [u8; TOKEN_LEN],
+    width: usize,
+    height: usize,
+}
+
+/// True for bytes that can be part of an identifier, number or `#define`
+fn is_word(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'#'
+}
+
+impl XbmReader {
+    /// Read the size defines, leaving the reader at the first bitmap byte
+    pub unsafe fn new(file: *mut sys::File) -> ImageResult<Self> {
+        unsafe {
+            let mut reader = Self {
+                file,
+                buf: [0; BUFFER_SIZE],
+                pos: 0,
+                len: 0,
+                token: [0; TOKEN_LEN],
+                width: 0,
+                height: 0,
+            };
+
+            // Defines come first; the array's opening brace ends the header
+            loop {
+                let len = reader.next_token()?;
+                match &reader.token[..len] {
+                    b"{" => break,
+                    b"#define" => {
+                        let len = reader.next_token()?;
+                        let name_is_width = reader.token[..len].ends_with(b"_width");
+                        let name_is_height = reader.token[..len].ends_with(b"_height");
+                        let value = reader.number()?;
+                        if name_is_width {
+                            reader.width = value as usize;
+                        } else if name_is_height {
+                            reader.height = value as usize;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            if reader.width == 0 || reader.height == 0 {
+                return Err(ImageError::InvalidFormat);
+            }
+            Ok(reader)
+        }
+    }
+
+    /// Parse the next token as a decimal or `0x` hex number
+    unsafe fn number(&mut self) -> ImageResult<u32> {
+        unsafe {
+            let len = self.next_token()?;
+            let token = core::str::from_utf8(&self.token[..len]).map_err(|_| ImageError::InvalidFormat)?;
+            let parsed = match token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => token.parse(),
+            };
+            parsed.map_err(|_| ImageError::InvalidFormat)
+        }
+    }
+
+    /// Read the next word (or a lone `{`) into `token`, returning its length
+    ///
+    /// Punctuation between words (`,`, `=`, `[]`, `;`, ...) is skipped.
+    unsafe fn next_token(&mut self) -> ImageResult<usize> {
+        unsafe {
+            let mut byte = self.byte()?;
+            while !is_word(byte) && byte != b'{' {
+                byte = self.byte()?;
+            }
+            if byte == b'{' {
+                self.token[0] = byte;
+                return Ok(1);
+            }
+
+            let mut len = 0;
+            while is_word(byte) {
+                if len < TOKEN_LEN {
+                    self.token[len] = byte;
+                    len += 1;
+                }
+                byte = match self.byte() {
+                    Ok(b) => b,
+                    Err(ImageError::Truncated) => break,
+                    Err(e) => return Err(e),
+                };
+            }
+            Ok(len)
+        }
+    }
+
+    /// Next byte of the file; running out is `Truncated`
+    unsafe fn byte(&mut self) -> ImageResult<u8> {
+        unsafe {
+            if self.pos == self.len {
+                self.len = sys::storage_file_read(self.file, self.buf.as_mut_ptr() as *mut _, BUFFER_SIZE);
+                self.pos = 0;
+                if self.len == 0 {
+                    return Err(ImageError::Truncated);
+                }
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            Ok(byte)
+        }
+    }
+}
+
+impl RowReader for XbmReader {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn format_name(&self) -> &'static str {
+        "XBM"
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        1
+    }
+
+    unsafe fn read_row(&mut self, row: &mut [Rgb]) -> ImageResult<()> {
+        unsafe {
+            let mut bits = 0u8;
+            for (x, pixel) in row.iter_mut().enumerate() {
+                if x % 8 == 0 {
+                    // X10 files use 16-bit words, which don't fit a byte
+                    bits = u8::try_from(self.number()?).map_err(|_| ImageError::InvalidFormat)?;
+                }
+                let black = bits & (1 << (x % 8)) != 0;
+                *pixel = if black { Rgb::new(0, 0, 0) } else { Rgb::new(255, 255, 255) };
+            }
+            Ok(())
+        }
+    }
+}